@@ -0,0 +1,149 @@
+// Mono downmix and left/right swap for each output device, for listeners who hear on one side
+// only and for speakers that are wired the wrong way round. Both are part of the device's DSP
+// profile, so they follow the device around and are done in the processing path.
+
+use eframe::egui;
+use egui::{Color32, RichText};
+use serde::{Deserialize, Serialize};
+
+use crate::AudioApp;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct AccessibilityPrefs {
+    // Global hotkeys that toggle the options for the current output, e.g. "Ctrl+Alt+M"
+    pub mono_hotkey: Option<String>,
+    pub swap_hotkey: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ChannelOption {
+    Mono,
+    SwapChannels,
+}
+
+impl ChannelOption {
+    pub fn label(self) -> &'static str {
+        match self {
+            ChannelOption::Mono => "Mono",
+            ChannelOption::SwapChannels => "Swap L/R",
+        }
+    }
+}
+
+impl AudioApp {
+    pub fn channel_option(&self, device: &str, option: ChannelOption) -> bool {
+        self.device_profile(device).is_some_and(|profile| match option {
+            ChannelOption::Mono => profile.mono,
+            ChannelOption::SwapChannels => profile.swap_channels,
+        })
+    }
+
+    // Turn an option on or off for a device. Turning one on gives the device a profile if it
+    // has none and switches profiles on, since that's where the audio is changed. Returns a
+    // note saying so when that happened.
+    fn set_channel_option(&mut self, device: &str, option: ChannelOption, on: bool) -> Option<String> {
+        let had_profile = self.device_profile(device).is_some();
+        if !on && !had_profile {
+            return None;
+        }
+        let id = self.device_id(device);
+        let profile = self.settings.eq.profiles.entry(id).or_default();
+        profile.name = device.to_string();
+        match option {
+            ChannelOption::Mono => profile.mono = on,
+            ChannelOption::SwapChannels => profile.swap_channels = on,
+        }
+        // Everything the device plays now goes through the app, so say so
+        let note = (on && !(had_profile && self.settings.eq.enabled)).then(|| {
+            self.settings.eq.enabled = true;
+            format!(
+                "{} is done by processing the audio: {} now has a DSP profile and device profiles are on",
+                option.label(),
+                self.settings.devices.display_name(device)
+            )
+        });
+        self.settings.save();
+        self.reapply_device_profile(device);
+        note
+    }
+
+    // Hotkey action: flip an option for the current output and say what happened
+    pub fn toggle_channel_option(&mut self, option: ChannelOption) {
+        let Some(device) = self.current_output() else {
+            return;
+        };
+        let on = !self.channel_option(&device, option);
+        // The note already names the option and the device
+        let message = self.set_channel_option(&device, option, on).unwrap_or_else(|| {
+            format!("{} {} for {}", option.label(), if on { "on" } else { "off" }, self.settings.devices.display_name(&device))
+        });
+        self.notifications.push(message);
+    }
+
+    // Shown on the main page under the device picker
+    pub fn channel_options_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(device) = self.current_output() else {
+            return;
+        };
+        ui.horizontal(|ui| {
+            for (option, hint) in [
+                (ChannelOption::Mono, "Mix left and right together and play the mix on both sides"),
+                (ChannelOption::SwapChannels, "Play the left channel on the right and the right on the left"),
+            ] {
+                let mut on = self.channel_option(&device, option);
+                if ui.toggle_value(&mut on, option.label()).on_hover_text(hint).changed() {
+                    if let Some(note) = self.set_channel_option(&device, option, on) {
+                        self.notifications.push(note);
+                    }
+                }
+            }
+
+            // The options only work while the audio goes through the processing path
+            let wanted = self.channel_option(&device, ChannelOption::Mono) || self.channel_option(&device, ChannelOption::SwapChannels);
+            if wanted && !self.eq.is_running() {
+                ui.label(RichText::new("⚠ not active").color(Color32::from_rgb(255, 190, 96)))
+                    .on_hover_text("Audio isn't being processed. See the EQ tab for why.");
+            }
+        });
+    }
+
+    pub fn accessibility_ui(&mut self, ui: &mut egui::Ui) {
+        ui.label("Hotkeys that toggle mono and swap L/R for the current output:");
+        let mut hotkeys_changed = false;
+        egui::Grid::new("accessibility_hotkeys").num_columns(3).spacing([8.0, 4.0]).show(ui, |ui| {
+            let prefs = &mut self.settings.accessibility;
+            for (label, hotkey, hint) in
+                [("Mono:", &mut prefs.mono_hotkey, "Ctrl+Alt+M"), ("Swap L/R:", &mut prefs.swap_hotkey, "Ctrl+Alt+S")]
+            {
+                ui.label(label);
+                let mut text = hotkey.clone().unwrap_or_default();
+                let response = ui.add(egui::TextEdit::singleline(&mut text).hint_text(hint).desired_width(120.0));
+                if response.changed() {
+                    *hotkey = if text.trim().is_empty() { None } else { Some(text) };
+                }
+                if response.lost_focus() {
+                    hotkeys_changed = true;
+                }
+                match hotkey {
+                    Some(hotkey) if crate::hotkeys::parse_hotkey(hotkey).is_none() => {
+                        ui.colored_label(Color32::RED, "invalid");
+                    }
+                    _ => {
+                        ui.label("");
+                    }
+                }
+                ui.end_row();
+            }
+        });
+        if cfg!(target_os = "linux") {
+            ui.label(RichText::new("On Linux global hotkeys need an X11 session").weak());
+        } else if !cfg!(target_os = "windows") {
+            ui.label(RichText::new("Global hotkeys only work on Windows and Linux").weak());
+        }
+        if hotkeys_changed {
+            self.settings.save();
+            self.restart_hotkeys();
+        }
+    }
+}
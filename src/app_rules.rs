@@ -0,0 +1,563 @@
+// Rules that react to applications: when an app starts playing, switch devices, set the
+// volume or mute everything else, and put it all back once the app has gone away.
+
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::endpoints::{self, Flow};
+use crate::scenes::AppVolume;
+use crate::sessions::AudioSession;
+use crate::AudioApp;
+
+// Apps often close and reopen their stream, e.g. between tracks or when joining a call,
+// so wait a little before deciding one has really gone
+const REVERT_DELAY: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct AppRulePrefs {
+    pub enabled: bool,
+    pub rules: Vec<AppRule>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct AppRule {
+    // Executable name, e.g. "Zoom" or "firefox"
+    pub app: String,
+    pub output: Option<String>,
+    pub input: Option<String>,
+    // Volume of the output, 0.0 to 1.0
+    pub volume: Option<f32>,
+    pub mute_others: bool,
+}
+
+impl AppRule {
+    pub fn matches(&self, app: &str) -> bool {
+        let name = self.app.trim();
+        let name = name.strip_suffix(".exe").unwrap_or(name);
+        !name.is_empty() && name.eq_ignore_ascii_case(app)
+    }
+}
+
+// What a rule changed, so it can be undone. Each value is (before, after): we only put
+// something back if it still has the value we gave it, so later changes by hand stick.
+struct Applied {
+    // Position in the rule list, and the rule as it was applied
+    idx: usize,
+    rule: AppRule,
+    output: Option<(String, String)>,
+    input: Option<(String, String)>,
+    // Device, volume before, volume after
+    volume: Option<(String, f32, f32)>,
+    muted_apps: Vec<String>,
+    // When the app was last seen with a session open
+    last_seen: Instant,
+}
+
+// How things stand when a rule is undone
+#[derive(Default)]
+struct Current {
+    output: Option<String>,
+    outputs: Vec<String>,
+    input: Option<String>,
+    inputs: Vec<String>,
+    // Volume of the device the rule set, if it's known
+    volume: Option<f32>,
+    apps: Vec<AppVolume>,
+}
+
+// What undoing a rule puts back
+#[derive(Debug, Default, PartialEq)]
+struct Restore {
+    output: Option<String>,
+    input: Option<String>,
+    // Device and volume
+    volume: Option<(String, f32)>,
+    unmute: Vec<String>,
+}
+
+impl Applied {
+    // Only what still has the value the rule gave it goes back, and only to devices that are
+    // still there
+    fn restore(&self, current: &Current) -> Restore {
+        let back = |change: &Option<(String, String)>, now: &Option<String>, names: &[String]| {
+            change
+                .as_ref()
+                .filter(|(from, to)| now.as_ref() == Some(to) && names.contains(from))
+                .map(|(from, _)| from.clone())
+        };
+        Restore {
+            output: back(&self.output, &current.output, &current.outputs),
+            input: back(&self.input, &current.input, &current.inputs),
+            volume: self
+                .volume
+                .as_ref()
+                .filter(|(_, _, after)| current.volume.is_none_or(|volume| (volume - after).abs() < 0.01))
+                .map(|(device, before, _)| (device.clone(), *before)),
+            unmute: current
+                .apps
+                .iter()
+                .filter(|app| app.muted && self.muted_apps.contains(&app.name))
+                .map(|app| app.name.clone())
+                .collect(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct AppRules {
+    applied: Vec<Applied>,
+    // The rules as of the last finished edit. Half-typed names and dragged volumes in the
+    // settings don't count until the user is done with them.
+    rules: Vec<AppRule>,
+    editing: bool,
+}
+
+impl AppRules {
+    fn is_applied(&self, idx: usize) -> bool {
+        self.applied.iter().any(|applied| applied.idx == idx)
+    }
+
+    // Catch up with the rules in the settings and the sessions as of `now`. Returns the rules
+    // to undo, because their app has gone or they were edited or removed in the meantime, and
+    // the rules whose app has started playing.
+    fn update(&mut self, rules: &[AppRule], sessions: &[AudioSession], now: Instant) -> (Vec<Applied>, Vec<(usize, AppRule)>) {
+        for applied in &mut self.applied {
+            if sessions.iter().any(|session| applied.rule.matches(&session.app)) {
+                applied.last_seen = now;
+            }
+        }
+
+        if !self.editing {
+            self.rules = rules.to_vec();
+        }
+
+        let rules = &self.rules;
+        let (finished, kept): (Vec<Applied>, Vec<Applied>) = std::mem::take(&mut self.applied)
+            .into_iter()
+            .partition(|applied| now - applied.last_seen > REVERT_DELAY || rules.get(applied.idx) != Some(&applied.rule));
+        self.applied = kept;
+
+        let starting = self
+            .rules
+            .iter()
+            .enumerate()
+            .filter(|(idx, _)| !self.is_applied(*idx))
+            .filter(|(_, rule)| sessions.iter().any(|session| session.active && rule.matches(&session.app)))
+            .map(|(idx, rule)| (idx, rule.clone()))
+            .collect();
+        (finished, starting)
+    }
+}
+
+impl AudioApp {
+    // Undo everything when the rules are turned off
+    fn stop_app_rules(&mut self) {
+        while let Some(applied) = self.app_rules.applied.pop() {
+            self.revert_app_rule(applied);
+        }
+    }
+
+    pub fn handle_app_rules(&mut self) {
+        if !self.settings.app_rules.enabled {
+            return;
+        }
+
+        let (finished, starting) = self.app_rules.update(&self.settings.app_rules.rules, &self.sessions, Instant::now());
+        for applied in finished.into_iter().rev() {
+            self.revert_app_rule(applied);
+        }
+        for (idx, rule) in starting {
+            let applied = self.apply_app_rule(idx, rule);
+            self.app_rules.applied.push(applied);
+        }
+    }
+
+    // The output device selected in the picker
+    pub fn current_output(&self) -> Option<String> {
+        self.selected_device_idx.map(|idx| self.device_names[idx].clone())
+    }
+
+    // The per-app volumes. The controller only knows the sessions that were open when it was
+    // created, so it's only recreated when an app has opened one since.
+    pub fn current_app_volumes(&mut self) -> Vec<AppVolume> {
+        let apps = self.app_volumes();
+        if self.sessions.iter().all(|session| apps.iter().any(|app| app.name == session.app)) {
+            return apps;
+        }
+        self.reload_audio_controller();
+        self.app_volumes()
+    }
+
+    fn apply_app_rule(&mut self, idx: usize, rule: AppRule) -> Applied {
+        let mut changes = Vec::new();
+
+        let output = rule.output.as_deref().and_then(|name| self.find_device(Flow::Output, name));
+        let previous_output = self.current_output();
+        let output = match (output, previous_output) {
+            (Some(to), Some(from)) if to != from => {
+                self.set_default_device_by_name(&to);
+                self.selected_device_idx = self.device_names.iter().position(|name| name == &to);
+                changes.push(format!("output to {}", self.settings.devices.display_name(&to)));
+                Some((from, to))
+            }
+            _ => None,
+        };
+
+        let input = rule.input.as_deref().and_then(|name| self.find_device(Flow::Input, name));
+        let input = match (input, self.default_input_name.clone()) {
+            (Some(to), Some(from)) if to != from => {
+                self.set_default_input_device_by_name(&to);
+                changes.push(format!("input to {}", self.settings.devices.display_name(&to)));
+                Some((from, to))
+            }
+            _ => None,
+        };
+
+        // Set the volume of the device the rule switched to, which may not have become the
+        // default yet, or otherwise of the current output
+        let volume = rule.volume.and_then(|volume| {
+            let device = self.current_output()?;
+            let before = self
+                .output_endpoints
+                .iter()
+                .find(|endpoint| endpoint.name == device)
+                .and_then(|endpoint| endpoint.volume)
+                .unwrap_or(self.volume);
+            if output.is_none() {
+                self.set_volume(volume);
+            } else if !endpoints::set_endpoint_volume(Flow::Output, &device, volume) {
+                return None;
+            }
+            changes.push(format!("volume to {}%", (volume * 100.0).round()));
+            Some((device, before, volume))
+        });
+
+        let mut muted_apps = Vec::new();
+        if rule.mute_others {
+            for app in self.current_app_volumes() {
+                if !app.muted && !rule.matches(&app.name) {
+                    self.set_app_mute(&app.name, true);
+                    muted_apps.push(app.name);
+                }
+            }
+            if !muted_apps.is_empty() {
+                changes.push(format!("muted {}", muted_apps.join(", ")));
+            }
+        }
+
+        if !changes.is_empty() {
+            self.notifications.push(format!("{} started: {}", rule.app, changes.join(", ")));
+        }
+
+        Applied { idx, rule, output, input, volume, muted_apps, last_seen: Instant::now() }
+    }
+
+    fn revert_app_rule(&mut self, applied: Applied) {
+        let current = Current {
+            output: self.current_output(),
+            outputs: self.device_names.clone(),
+            input: self.default_input_name.clone(),
+            inputs: self.input_device_names.clone(),
+            volume: applied.volume.as_ref().and_then(|(device, ..)| {
+                let endpoint = self.output_endpoints.iter().find(|endpoint| &endpoint.name == device)?;
+                endpoint.volume
+            }),
+            apps: if applied.muted_apps.is_empty() { Vec::new() } else { self.current_app_volumes() },
+        };
+        let restore = applied.restore(&current);
+        let mut restored = restore.output.is_some() || restore.input.is_some() || !restore.unmute.is_empty();
+
+        if let Some(from) = &restore.output {
+            self.set_default_device_by_name(from);
+            self.selected_device_idx = self.device_names.iter().position(|name| name == from);
+        }
+        if let Some(from) = &restore.input {
+            self.set_default_input_device_by_name(from);
+        }
+        if let Some((device, before)) = &restore.volume {
+            restored |= endpoints::set_endpoint_volume(Flow::Output, device, *before);
+            if self.current_output().as_ref() == Some(device) && applied.output.is_none() {
+                self.set_volume(*before);
+                restored = true;
+            }
+        }
+        for app in &restore.unmute {
+            self.set_app_mute(app, false);
+        }
+
+        if restored {
+            self.notifications.push(format!("{} closed, restored the previous settings", applied.rule.app));
+        }
+    }
+
+    pub fn app_rules_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+        if ui
+            .checkbox(&mut self.settings.app_rules.enabled, "Change settings while these apps are playing")
+            .changed()
+        {
+            self.settings.save();
+            if !self.settings.app_rules.enabled {
+                self.stop_app_rules();
+            }
+        }
+
+        let mut running: Vec<String> = self.sessions.iter().map(|session| session.app.clone()).collect();
+        running.sort_by_key(|app| app.to_lowercase());
+        running.dedup();
+
+        let device_combo = |ui: &mut egui::Ui, id: (&str, usize), value: &mut Option<String>, names: &[String], app: &AudioApp| {
+            let mut changed = false;
+            let selected = value.as_deref().map(|name| app.settings.devices.display_name(name)).unwrap_or("Don't change");
+            egui::ComboBox::from_id_source(id).selected_text(selected).width(120.0).show_ui(ui, |ui| {
+                changed |= ui.selectable_value(value, None, "Don't change").changed();
+                for name in names {
+                    let label = app.settings.devices.display_name(name);
+                    changed |= ui.selectable_value(value, Some(name.clone()), label).changed();
+                }
+            });
+            changed
+        };
+
+        let mut rules = std::mem::take(&mut self.settings.app_rules.rules);
+        let mut remove = None;
+        let mut editing = false;
+        egui::Grid::new("app_rules").num_columns(6).striped(true).spacing([8.0, 4.0]).show(ui, |ui| {
+            ui.label(RichText::new("App").strong());
+            ui.label(RichText::new("Output").strong());
+            ui.label(RichText::new("Input").strong());
+            ui.label(RichText::new("Volume").strong());
+            ui.label(RichText::new("Mute others").strong());
+            ui.end_row();
+
+            for (idx, rule) in rules.iter_mut().enumerate() {
+                let active = self.app_rules.is_applied(idx);
+                ui.horizontal(|ui| {
+                    let marker = if active { RichText::new("●").color(egui::Color32::GREEN) } else { RichText::new("○").weak() };
+                    ui.label(marker).on_hover_text(if active { "Playing, rule applied" } else { "Waiting for the app" });
+                    let name = ui.add(egui::TextEdit::singleline(&mut rule.app).desired_width(90.0).hint_text("App"));
+                    changed |= name.changed();
+                    editing |= name.has_focus();
+                    ui.menu_button("▾", |ui| {
+                        if running.is_empty() {
+                            ui.label("No apps playing");
+                        }
+                        for app in &running {
+                            if ui.button(app).clicked() {
+                                rule.app = app.clone();
+                                changed = true;
+                                ui.close_menu();
+                            }
+                        }
+                    });
+                });
+
+                changed |= device_combo(ui, ("app_rule_output", idx), &mut rule.output, &self.device_names, self);
+                changed |= device_combo(ui, ("app_rule_input", idx), &mut rule.input, &self.input_device_names, self);
+
+                ui.horizontal(|ui| {
+                    let mut set_volume = rule.volume.is_some();
+                    if ui.checkbox(&mut set_volume, "").changed() {
+                        rule.volume = set_volume.then_some(self.volume);
+                        changed = true;
+                    }
+                    if let Some(volume) = &mut rule.volume {
+                        let mut percent = (*volume * 100.0).round();
+                        let response = ui.add(egui::DragValue::new(&mut percent).clamp_range(0.0..=100.0).suffix("%"));
+                        if response.changed() {
+                            *volume = percent / 100.0;
+                            changed = true;
+                        }
+                        editing |= response.has_focus() || response.dragged();
+                    }
+                });
+                changed |= ui.checkbox(&mut rule.mute_others, "").changed();
+
+                if ui.small_button("🗑").clicked() {
+                    remove = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+
+        if let Some(idx) = remove {
+            rules.remove(idx);
+            changed = true;
+            // Undo the removed rule, and keep the ones after it applied at their new positions
+            if let Some(pos) = self.app_rules.applied.iter().position(|applied| applied.idx == idx) {
+                let applied = self.app_rules.applied.remove(pos);
+                self.revert_app_rule(applied);
+            }
+            for applied in &mut self.app_rules.applied {
+                if applied.idx > idx {
+                    applied.idx -= 1;
+                }
+            }
+            if idx < self.app_rules.rules.len() {
+                self.app_rules.rules.remove(idx);
+            }
+        }
+        self.app_rules.editing = editing;
+        if ui.button("➕ Add rule").clicked() {
+            rules.push(AppRule::default());
+            changed = true;
+        }
+        self.settings.app_rules.rules = rules;
+
+        if self.settings.app_rules.enabled && cfg!(not(any(target_os = "windows", target_os = "linux"))) {
+            ui.label(RichText::new("Apps can't be watched on this system").weak());
+        }
+
+        if changed {
+            self.settings.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(app: &str) -> AppRule {
+        AppRule { app: app.to_string(), output: Some("Headset".to_string()), ..AppRule::default() }
+    }
+
+    fn session(app: &str, active: bool) -> AudioSession {
+        AudioSession { app: app.to_string(), pid: 1, active, streams: Vec::new() }
+    }
+
+    fn applied(idx: usize, rule: AppRule, last_seen: Instant) -> Applied {
+        Applied { idx, rule, output: None, input: None, volume: None, muted_apps: Vec::new(), last_seen }
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn matches_app_names() {
+        assert!(rule("Zoom").matches("Zoom"));
+        assert!(rule("zoom").matches("Zoom"));
+        assert!(rule("Zoom.exe").matches("Zoom"));
+        assert!(rule(" Zoom.exe ").matches("Zoom"));
+        assert!(!rule("Zoom").matches("ZoomIt"));
+        assert!(!rule("").matches(""));
+        assert!(!rule(".exe").matches(""));
+    }
+
+    #[test]
+    fn applies_while_playing_and_reverts_once_gone() {
+        let rules = [rule("firefox"), rule("Zoom")];
+        let mut app_rules = AppRules::default();
+        let start = Instant::now();
+
+        // Open but paused doesn't count
+        let (finished, starting) = app_rules.update(&rules, &[session("Zoom", false)], start);
+        assert!(finished.is_empty() && starting.is_empty());
+
+        let (finished, starting) = app_rules.update(&rules, &[session("Zoom", true)], start);
+        assert!(finished.is_empty());
+        assert!(starting == [(1, rules[1].clone())]);
+        app_rules.applied.push(applied(1, rules[1].clone(), start));
+
+        // Applied once, however long it plays
+        let (_, starting) = app_rules.update(&rules, &[session("Zoom", true)], start + Duration::from_secs(10));
+        assert!(starting.is_empty());
+
+        let (finished, _) = app_rules.update(&rules, &[], start + Duration::from_secs(20));
+        assert_eq!(finished.len(), 1);
+        assert!(app_rules.applied.is_empty());
+    }
+
+    #[test]
+    fn waits_for_the_app_to_come_back() {
+        let rules = [rule("Zoom")];
+        let mut app_rules = AppRules::default();
+        let start = Instant::now();
+        app_rules.applied.push(applied(0, rules[0].clone(), start));
+        let at = |secs: f32| start + Duration::from_secs_f32(secs);
+
+        assert!(app_rules.update(&rules, &[], at(2.0)).0.is_empty());
+        // Back with a new stream, paused for now, which starts the wait over
+        assert!(app_rules.update(&rules, &[session("Zoom", false)], at(2.5)).0.is_empty());
+        assert!(app_rules.update(&rules, &[], at(5.0)).0.is_empty());
+        assert!(app_rules.update(&rules, &[], at(5.4)).0.is_empty());
+        assert_eq!(app_rules.update(&rules, &[], at(5.6)).0.len(), 1);
+    }
+
+    #[test]
+    fn edited_rules_are_undone_right_away() {
+        let mut rules = vec![rule("Zoom")];
+        let mut app_rules = AppRules::default();
+        let start = Instant::now();
+        app_rules.applied.push(applied(0, rules[0].clone(), start));
+        app_rules.update(&rules, &[session("Zoom", true)], start);
+
+        // Not while the name is still being typed
+        app_rules.editing = true;
+        rules[0].app = "Zoo".to_string();
+        assert!(app_rules.update(&rules, &[session("Zoom", true)], start).0.is_empty());
+
+        app_rules.editing = false;
+        let (finished, starting) = app_rules.update(&rules, &[session("Zoom", true)], start);
+        assert_eq!(finished.len(), 1);
+        assert!(starting.is_empty());
+    }
+
+    #[test]
+    fn restores_only_what_wasnt_changed_by_hand() {
+        let applied = Applied {
+            output: Some(("Speakers".to_string(), "Headset".to_string())),
+            input: Some(("Webcam".to_string(), "Headset mic".to_string())),
+            volume: Some(("Headset".to_string(), 0.3, 0.8)),
+            muted_apps: names(&["firefox", "Spotify"]),
+            ..applied(0, rule("Zoom"), Instant::now())
+        };
+        let untouched = Current {
+            output: Some("Headset".to_string()),
+            outputs: names(&["Speakers", "Headset"]),
+            input: Some("Headset mic".to_string()),
+            inputs: names(&["Webcam", "Headset mic"]),
+            volume: Some(0.8),
+            apps: vec![
+                AppVolume { name: "firefox".to_string(), volume: 1.0, muted: true },
+                AppVolume { name: "Spotify".to_string(), volume: 1.0, muted: true },
+                AppVolume { name: "Zoom".to_string(), volume: 1.0, muted: false },
+            ],
+        };
+        assert_eq!(
+            applied.restore(&untouched),
+            Restore {
+                output: Some("Speakers".to_string()),
+                input: Some("Webcam".to_string()),
+                volume: Some(("Headset".to_string(), 0.3)),
+                unmute: names(&["firefox", "Spotify"]),
+            }
+        );
+
+        // Switched, turned down and unmuted by hand while the rule was applied
+        let mut changed = Current {
+            output: Some("Speakers".to_string()),
+            input: Some("Webcam".to_string()),
+            volume: Some(0.5),
+            ..untouched
+        };
+        changed.apps[1].muted = false;
+        assert_eq!(applied.restore(&changed), Restore { unmute: names(&["firefox"]), ..Restore::default() });
+
+        // Devices that have gone away can't be switched back to
+        let unplugged = Current {
+            output: Some("Headset".to_string()),
+            outputs: names(&["Headset"]),
+            input: Some("Headset mic".to_string()),
+            inputs: names(&["Headset mic"]),
+            volume: None,
+            apps: Vec::new(),
+        };
+        assert_eq!(applied.restore(&unplugged), Restore { volume: Some(("Headset".to_string(), 0.3)), ..Restore::default() });
+    }
+}
@@ -0,0 +1,128 @@
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::endpoints::FormFactor;
+use crate::AudioApp;
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct AutoMutePrefs {
+    // Mute when playback moves from headphones to speakers
+    pub enabled: bool,
+    // Also press the media play/pause key
+    pub pause_media: bool,
+}
+
+// The auto-mute options, shared with the device watcher so it can act the moment the system
+// reports the change instead of waiting for the next frame
+#[derive(Default)]
+pub struct AutoMuteSwitch {
+    enabled: AtomicBool,
+    pause_media: AtomicBool,
+}
+
+impl AutoMuteSwitch {
+    pub fn set(&self, prefs: &AutoMutePrefs) {
+        self.enabled.store(prefs.enabled, Ordering::Relaxed);
+        self.pause_media.store(prefs.pause_media, Ordering::Relaxed);
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    pub fn pause_media(&self) -> bool {
+        self.pause_media.load(Ordering::Relaxed)
+    }
+}
+
+// Whether playback moving from `from` to `to` should mute `to`: it went from something you
+// wear to something the whole room hears, and nobody chose that. Either the same endpoint
+// changed what it is, as when a jack switches from headphones to speakers, or the previous
+// device went away. Switching by hand, from the picker, a hotkey, the API or the system's own
+// settings, leaves the previous device present and never mutes.
+pub fn should_auto_mute(from: FormFactor, to: FormFactor, same_device: bool, from_present: bool) -> bool {
+    from.is_personal() && !to.is_personal() && (same_device || !from_present)
+}
+
+// Stop whatever is playing. On Windows this presses the media play/pause key, which is a
+// toggle, which is why we only ever do this together with muting. On Linux the MPRIS players
+// are asked to pause.
+pub fn pause_playback() {
+    #[cfg(target_os = "windows")]
+    unsafe {
+        use windows::Win32::UI::Input::KeyboardAndMouse::{
+            SendInput, INPUT, INPUT_0, INPUT_KEYBOARD, KEYBDINPUT, KEYBD_EVENT_FLAGS, KEYEVENTF_KEYUP,
+            VK_MEDIA_PLAY_PAUSE,
+        };
+
+        let key = |flags| INPUT {
+            r#type: INPUT_KEYBOARD,
+            Anonymous: INPUT_0 {
+                ki: KEYBDINPUT { wVk: VK_MEDIA_PLAY_PAUSE, wScan: 0, dwFlags: flags, time: 0, dwExtraInfo: 0 },
+            },
+        };
+        let inputs = [key(KEYBD_EVENT_FLAGS(0)), key(KEYEVENTF_KEYUP)];
+        SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+    }
+
+    #[cfg(target_os = "linux")]
+    crate::now_playing::pause_all();
+}
+
+impl AudioApp {
+    pub fn auto_mute_ui(&mut self, ui: &mut egui::Ui) {
+        let prefs = &mut self.settings.auto_mute;
+        let mut changed = ui
+            .checkbox(&mut prefs.enabled, "Mute when headphones are unplugged and playback moves to speakers")
+            .changed();
+        ui.add_enabled_ui(prefs.enabled, |ui| {
+            changed |= ui.checkbox(&mut prefs.pause_media, "Also pause media").changed();
+        });
+
+        if changed {
+            self.auto_mute.set(&self.settings.auto_mute);
+            self.settings.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use FormFactor::*;
+
+    #[test]
+    fn mutes_only_unchosen_moves_to_speakers() {
+        // (from, to, same device, previous still present, mute)
+        let cases = [
+            // A jack switching from headphones to speakers
+            (Headphones, Speakers, true, true, true),
+            (Headset, Speakers, true, true, true),
+            // Headphones unplugged or out of range, and the system moved to another device
+            (Headphones, Speakers, false, false, true),
+            (Headset, Display, false, false, true),
+            (Handset, Unknown, false, false, true),
+            (Headphones, LineLevel, false, false, true),
+            // Picked by hand: the headphones are still there
+            (Headphones, Speakers, false, true, false),
+            (Headset, Display, false, true, false),
+            // Still something you wear
+            (Headphones, Headset, false, false, false),
+            (Headset, Headphones, true, true, false),
+            // Not coming from something you wear
+            (Speakers, Speakers, false, false, false),
+            (Speakers, Headphones, true, true, false),
+            (Unknown, Speakers, false, false, false),
+            (Display, Speakers, true, true, false),
+        ];
+        for (from, to, same_device, from_present, expected) in cases {
+            assert_eq!(
+                should_auto_mute(from, to, same_device, from_present),
+                expected,
+                "{from:?} -> {to:?}, same device {same_device}, still present {from_present}"
+            );
+        }
+    }
+}
@@ -0,0 +1,625 @@
+// Per-channel volume of the output device: a balance slider for stereo and a fader per speaker
+// for surround layouts. On Windows these are the endpoint's channel volumes, the same ones the
+// Levels > Balance dialog changes; on Linux they're the sink's per-channel volume.
+
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::AudioApp;
+
+// Channel volumes are re-read this often while they're shown, to catch changes made elsewhere
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+// Level a balance is kept at on a silent pair: too quiet to hear, but enough to hold the
+// difference between the sides until the volume comes back up
+const SILENT: f32 = 0.001;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct ChannelPrefs {
+    // Moving one fader moves the others with it, keeping the balance between them
+    pub locked: bool,
+}
+
+impl Default for ChannelPrefs {
+    fn default() -> Self {
+        Self { locked: true }
+    }
+}
+
+// Where a channel's speaker sits, in the order Windows' channel masks list them
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Speaker {
+    FrontLeft,
+    FrontRight,
+    FrontCenter,
+    LowFrequency,
+    BackLeft,
+    BackRight,
+    FrontLeftOfCenter,
+    FrontRightOfCenter,
+    BackCenter,
+    SideLeft,
+    SideRight,
+    TopCenter,
+    TopFrontLeft,
+    TopFrontCenter,
+    TopFrontRight,
+    TopBackLeft,
+    TopBackCenter,
+    TopBackRight,
+    Mono,
+    // A channel the layout doesn't name, numbered from 1
+    Other(usize),
+}
+
+impl Speaker {
+    // Bit n of a Windows channel mask is MASK_ORDER[n]
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    const MASK_ORDER: [Speaker; 18] = [
+        Self::FrontLeft,
+        Self::FrontRight,
+        Self::FrontCenter,
+        Self::LowFrequency,
+        Self::BackLeft,
+        Self::BackRight,
+        Self::FrontLeftOfCenter,
+        Self::FrontRightOfCenter,
+        Self::BackCenter,
+        Self::SideLeft,
+        Self::SideRight,
+        Self::TopCenter,
+        Self::TopFrontLeft,
+        Self::TopFrontCenter,
+        Self::TopFrontRight,
+        Self::TopBackLeft,
+        Self::TopBackCenter,
+        Self::TopBackRight,
+    ];
+
+    // The speakers in a Windows channel mask, for a device with `channels` channels
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    fn from_mask(mask: u32, channels: usize) -> Vec<Speaker> {
+        let mut speakers: Vec<Speaker> =
+            Self::MASK_ORDER.iter().enumerate().filter(|(bit, _)| mask & (1 << bit) != 0).map(|(_, speaker)| *speaker).collect();
+        speakers.truncate(channels);
+        while speakers.len() < channels {
+            speakers.push(Self::Other(speakers.len() + 1));
+        }
+        speakers
+    }
+
+    // Without a mask, go by the channel count alone
+    #[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+    fn default_layout(channels: usize) -> Vec<Speaker> {
+        match channels {
+            1 => vec![Self::Mono],
+            _ => (0..channels)
+                .map(|idx| match idx {
+                    0 => Self::FrontLeft,
+                    1 => Self::FrontRight,
+                    _ => Self::Other(idx + 1),
+                })
+                .collect(),
+        }
+    }
+
+    // PulseAudio's channel position names, as in "front-left"
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn from_pulse(name: &str, idx: usize) -> Speaker {
+        match name {
+            "mono" => Self::Mono,
+            "front-left" | "left" => Self::FrontLeft,
+            "front-right" | "right" => Self::FrontRight,
+            "front-center" | "center" => Self::FrontCenter,
+            "lfe" | "subwoofer" => Self::LowFrequency,
+            "rear-left" => Self::BackLeft,
+            "rear-right" => Self::BackRight,
+            "front-left-of-center" => Self::FrontLeftOfCenter,
+            "front-right-of-center" => Self::FrontRightOfCenter,
+            "rear-center" => Self::BackCenter,
+            "side-left" => Self::SideLeft,
+            "side-right" => Self::SideRight,
+            "top-center" => Self::TopCenter,
+            "top-front-left" => Self::TopFrontLeft,
+            "top-front-center" => Self::TopFrontCenter,
+            "top-front-right" => Self::TopFrontRight,
+            "top-rear-left" => Self::TopBackLeft,
+            "top-rear-center" => Self::TopBackCenter,
+            "top-rear-right" => Self::TopBackRight,
+            _ => Self::Other(idx + 1),
+        }
+    }
+
+    pub fn short(self) -> String {
+        let short = match self {
+            Self::FrontLeft => "L",
+            Self::FrontRight => "R",
+            Self::FrontCenter => "C",
+            Self::LowFrequency => "LFE",
+            Self::BackLeft => "BL",
+            Self::BackRight => "BR",
+            Self::FrontLeftOfCenter => "FLC",
+            Self::FrontRightOfCenter => "FRC",
+            Self::BackCenter => "BC",
+            Self::SideLeft => "SL",
+            Self::SideRight => "SR",
+            Self::TopCenter => "TC",
+            Self::TopFrontLeft => "TFL",
+            Self::TopFrontCenter => "TFC",
+            Self::TopFrontRight => "TFR",
+            Self::TopBackLeft => "TBL",
+            Self::TopBackCenter => "TBC",
+            Self::TopBackRight => "TBR",
+            Self::Mono => "M",
+            Self::Other(number) => return format!("{number}"),
+        };
+        short.to_string()
+    }
+
+    pub fn label(self) -> String {
+        let label = match self {
+            Self::FrontLeft => "Front left",
+            Self::FrontRight => "Front right",
+            Self::FrontCenter => "Centre",
+            Self::LowFrequency => "Subwoofer",
+            Self::BackLeft => "Back left",
+            Self::BackRight => "Back right",
+            Self::FrontLeftOfCenter => "Front left of centre",
+            Self::FrontRightOfCenter => "Front right of centre",
+            Self::BackCenter => "Back centre",
+            Self::SideLeft => "Side left",
+            Self::SideRight => "Side right",
+            Self::TopCenter => "Top centre",
+            Self::TopFrontLeft => "Top front left",
+            Self::TopFrontCenter => "Top front centre",
+            Self::TopFrontRight => "Top front right",
+            Self::TopBackLeft => "Top back left",
+            Self::TopBackCenter => "Top back centre",
+            Self::TopBackRight => "Top back right",
+            Self::Mono => "Mono",
+            Self::Other(number) => return format!("Channel {number}"),
+        };
+        label.to_string()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Channels {
+    pub speakers: Vec<Speaker>,
+    // Volume of each channel, 0 to 1
+    pub volumes: Vec<f32>,
+}
+
+impl Channels {
+    // A stereo pair whose first two channels are left and right
+    fn is_stereo(&self) -> bool {
+        self.speakers == [Speaker::FrontLeft, Speaker::FrontRight]
+    }
+
+    // -1 is all left, 1 all right
+    pub fn balance(&self) -> f32 {
+        let (left, right) = (self.volumes[0], self.volumes[1]);
+        let loudest = left.max(right);
+        if loudest <= 0.0 {
+            return 0.0;
+        }
+        (right - left) / loudest
+    }
+
+    // Keep the louder side where it is and turn the other one down
+    pub fn set_balance(&mut self, balance: f32) {
+        let balance = balance.clamp(-1.0, 1.0);
+        let loudest = match self.volumes[0].max(self.volumes[1]) {
+            loudest if loudest <= 0.0 && balance != 0.0 => SILENT,
+            loudest => loudest,
+        };
+        self.volumes[0] = loudest * (1.0 - balance.max(0.0));
+        self.volumes[1] = loudest * (1.0 + balance.min(0.0));
+    }
+
+    // Move channel `idx` to `volume`. When locked, the others follow in proportion, or all
+    // jump to the same level if the channel was silent.
+    pub fn set_channel(&mut self, idx: usize, volume: f32, locked: bool) {
+        let volume = volume.clamp(0.0, 1.0);
+        let previous = self.volumes[idx];
+        if !locked {
+            self.volumes[idx] = volume;
+        } else if previous > 0.001 {
+            // Don't let the loudest channel be pushed past full, or past where it already is
+            // when the sound server has it boosted beyond that
+            let loudest = self.volumes.iter().fold(0.0f32, |loudest, volume| loudest.max(*volume));
+            let ratio = (volume / previous).min(loudest.max(1.0) / loudest);
+            for other in &mut self.volumes {
+                *other *= ratio;
+            }
+        } else {
+            self.volumes.iter_mut().for_each(|other| *other = volume);
+        }
+    }
+}
+
+// Read the channel volumes of an output device, given its name on Windows and its sink on Linux
+pub fn read_channels(name: &str) -> Result<Channels, String> {
+    #[cfg(target_os = "windows")]
+    {
+        unsafe { windows_impl::read_channels(name).map_err(|err| err.message().to_string()) }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        linux_impl::read_channels(name)
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        let _ = name;
+        Err("Channel volumes aren't supported on this system".to_string())
+    }
+}
+
+// Set every channel's volume at once
+pub fn write_channels(name: &str, volumes: &[f32]) -> Result<(), String> {
+    #[cfg(target_os = "windows")]
+    {
+        unsafe { windows_impl::write_channels(name, volumes).map_err(|err| err.message().to_string()) }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        linux_impl::write_channels(name, volumes)
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        let _ = (name, volumes);
+        Err("Channel volumes aren't supported on this system".to_string())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows_impl {
+    use super::{Channels, Speaker};
+    use crate::endpoints::windows_impl::find_device;
+    use crate::endpoints::Flow;
+    use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
+    use windows::Win32::Media::Audio::{IAudioClient, WAVEFORMATEX, WAVEFORMATEXTENSIBLE};
+    use windows::Win32::System::Com::{CoTaskMemFree, CLSCTX_ALL};
+
+    const WAVE_FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+    // The speaker layout from the format the device mixes in
+    unsafe fn channel_mask(device: &windows::Win32::Media::Audio::IMMDevice) -> Option<u32> {
+        let client: IAudioClient = device.Activate(CLSCTX_ALL, None).ok()?;
+        let format = client.GetMixFormat().ok()?;
+        let tag = std::ptr::addr_of!((*format).wFormatTag).read_unaligned();
+        let mask = (tag == WAVE_FORMAT_EXTENSIBLE)
+            .then(|| std::ptr::addr_of!((*(format as *const WAVEFORMATEXTENSIBLE)).dwChannelMask).read_unaligned());
+        CoTaskMemFree(Some(format as *const WAVEFORMATEX as *const _));
+        mask
+    }
+
+    pub unsafe fn read_channels(name: &str) -> windows::core::Result<Channels> {
+        let device = find_device(Flow::Output, name)?;
+        let volume: IAudioEndpointVolume = device.Activate(CLSCTX_ALL, None)?;
+        let count = volume.GetChannelCount()?;
+        let mut volumes = Vec::with_capacity(count as usize);
+        for channel in 0..count {
+            volumes.push(volume.GetChannelVolumeLevelScalar(channel)?);
+        }
+        let speakers = match channel_mask(&device) {
+            Some(mask) if mask != 0 => Speaker::from_mask(mask, count as usize),
+            _ => Speaker::default_layout(count as usize),
+        };
+        Ok(Channels { speakers, volumes })
+    }
+
+    pub unsafe fn write_channels(name: &str, volumes: &[f32]) -> windows::core::Result<()> {
+        let volume: IAudioEndpointVolume = find_device(Flow::Output, name)?.Activate(CLSCTX_ALL, None)?;
+        for (channel, level) in volumes.iter().enumerate() {
+            volume.SetChannelVolumeLevelScalar(channel as u32, level.clamp(0.0, 1.0), std::ptr::null())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux_impl {
+    use super::{Channels, Speaker};
+    use crate::endpoints::pulse::pactl;
+
+    // PulseAudio's 100%
+    const NORM: f32 = 65536.0;
+
+    // "Volume: front-left: 32768 /  50% / -18.06 dB,   front-right: 32768 /  50% / -18.06 dB"
+    fn parse_volume(text: &str) -> Option<Channels> {
+        let line = text.lines().find_map(|line| line.trim().strip_prefix("Volume:"))?;
+        let mut channels = Channels { speakers: Vec::new(), volumes: Vec::new() };
+        for (idx, part) in line.split(',').enumerate() {
+            let (position, rest) = part.split_once(':')?;
+            let raw: f32 = rest.split('/').next()?.trim().parse().ok()?;
+            channels.speakers.push(Speaker::from_pulse(position.trim(), idx));
+            channels.volumes.push(raw / NORM);
+        }
+        (!channels.volumes.is_empty()).then_some(channels)
+    }
+
+    pub fn read_channels(sink: &str) -> Result<Channels, String> {
+        let text = pactl(&["get-sink-volume", sink])?;
+        parse_volume(&text).ok_or_else(|| "Couldn't read the channel volumes".to_string())
+    }
+
+    pub fn write_channels(sink: &str, volumes: &[f32]) -> Result<(), String> {
+        // Levels above 100% are the sound server's boost, and are kept as they are
+        let levels: Vec<String> = volumes.iter().map(|volume| ((volume.max(0.0) * NORM).round() as u32).to_string()).collect();
+        let mut args = vec!["set-sink-volume", sink];
+        args.extend(levels.iter().map(String::as_str));
+        pactl(&args).map(|_| ())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn speakers(text: &str) -> Vec<Speaker> {
+            parse_volume(text).unwrap().speakers
+        }
+
+        #[test]
+        fn parses_sink_volumes() {
+            let mono = "Volume: mono: 32768 /  50% / -18.06 dB\n        balance 0.00\n";
+            assert_eq!(parse_volume(mono), Some(Channels { speakers: vec![Speaker::Mono], volumes: vec![0.5] }));
+
+            // Boosted past 100% on the right
+            let stereo = "Volume: front-left: 65536 / 100% / 0.00 dB,   front-right: 98304 / 150% / 10.57 dB\n        balance 0.33\n";
+            let channels = parse_volume(stereo).unwrap();
+            assert!(channels.is_stereo());
+            assert_eq!(channels.volumes, [1.0, 1.5]);
+
+            let surround = "Volume: front-left: 52429 /  80% / -5.81 dB,   front-right: 52429 /  80% / -5.81 dB,   \
+                front-center: 45875 /  70% / -9.29 dB,   lfe: 32768 /  50% / -18.06 dB,   \
+                rear-left: 52429 /  80% / -5.81 dB,   rear-right: 52429 /  80% / -5.81 dB\n        balance 0.00\n";
+            assert_eq!(
+                speakers(surround),
+                [
+                    Speaker::FrontLeft,
+                    Speaker::FrontRight,
+                    Speaker::FrontCenter,
+                    Speaker::LowFrequency,
+                    Speaker::BackLeft,
+                    Speaker::BackRight
+                ]
+            );
+            assert_eq!(parse_volume(surround).unwrap().volumes[3], 0.5);
+        }
+
+        #[test]
+        fn refuses_other_output() {
+            assert_eq!(parse_volume(""), None);
+            assert_eq!(parse_volume("Failed to get sink volume: No such entity\n"), None);
+            assert_eq!(parse_volume("Volume: front-left: loud\n"), None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stereo(left: f32, right: f32) -> Channels {
+        Channels { speakers: vec![Speaker::FrontLeft, Speaker::FrontRight], volumes: vec![left, right] }
+    }
+
+    fn surround(volumes: &[f32]) -> Channels {
+        Channels { speakers: Speaker::default_layout(volumes.len()), volumes: volumes.to_vec() }
+    }
+
+    fn assert_near(actual: &[f32], expected: &[f32]) {
+        assert!(actual.iter().zip(expected).all(|(actual, expected)| (actual - expected).abs() < 1e-5), "{actual:?} != {expected:?}");
+    }
+
+    #[test]
+    fn balance_round_trips() {
+        for balance in [-1.0, -0.5, -0.1, 0.0, 0.25, 1.0] {
+            let mut channels = stereo(0.8, 0.8);
+            channels.set_balance(balance);
+            assert!((channels.balance() - balance).abs() < 1e-5, "{balance}: {:?}", channels.volumes);
+            // The louder side stays where it was
+            assert_eq!(channels.volumes[0].max(channels.volumes[1]), 0.8);
+        }
+
+        let mut channels = stereo(0.3, 0.6);
+        assert_eq!(channels.balance(), 0.5);
+        channels.set_balance(0.0);
+        assert_eq!(channels.volumes, [0.6, 0.6]);
+        // Out of range is as far as it goes
+        channels.set_balance(-3.0);
+        assert_eq!(channels.volumes, [0.6, 0.0]);
+    }
+
+    #[test]
+    fn balance_of_a_silent_pair() {
+        let mut channels = stereo(0.0, 0.0);
+        assert_eq!(channels.balance(), 0.0);
+        channels.set_balance(0.0);
+        assert_eq!(channels.volumes, [0.0, 0.0]);
+        channels.set_balance(-0.5);
+        assert!((channels.balance() + 0.5).abs() < 1e-5);
+        assert!(channels.volumes.iter().all(|volume| *volume <= SILENT));
+    }
+
+    #[test]
+    fn boosted_balance_stays_boosted() {
+        let mut channels = stereo(1.5, 1.5);
+        channels.set_balance(0.5);
+        assert_eq!(channels.volumes, [0.75, 1.5]);
+    }
+
+    #[test]
+    fn unlocked_channels_move_alone() {
+        let mut channels = surround(&[0.5, 0.5, 0.4, 0.2]);
+        channels.set_channel(2, 0.9, false);
+        assert_eq!(channels.volumes, [0.5, 0.5, 0.9, 0.2]);
+        channels.set_channel(3, 1.7, false);
+        assert_eq!(channels.volumes, [0.5, 0.5, 0.9, 1.0]);
+    }
+
+    #[test]
+    fn locked_channels_move_together() {
+        let mut channels = surround(&[0.5, 0.5, 0.4, 0.2]);
+        channels.set_channel(0, 0.25, true);
+        assert_near(&channels.volumes, &[0.25, 0.25, 0.2, 0.1]);
+
+        // The loudest can't go past full, so the rest stop short too
+        channels.set_channel(3, 0.8, true);
+        assert_near(&channels.volumes, &[1.0, 1.0, 0.8, 0.4]);
+
+        // From silence they all come up together
+        let mut channels = surround(&[0.0, 0.0, 0.0]);
+        channels.set_channel(1, 0.3, true);
+        assert_eq!(channels.volumes, [0.3, 0.3, 0.3]);
+    }
+
+    // Levels the sound server boosts past 100% are left alone when another fader moves
+    #[test]
+    fn boosted_channels_stay_boosted() {
+        let mut channels = stereo(1.0, 1.5);
+        channels.set_channel(0, 0.5, false);
+        assert_eq!(channels.volumes, [0.5, 1.5]);
+
+        let mut channels = stereo(1.0, 1.5);
+        channels.set_channel(0, 0.5, true);
+        assert_near(&channels.volumes, &[0.5, 0.75]);
+        // Turning up doesn't boost any further
+        let mut channels = stereo(0.5, 1.5);
+        channels.set_channel(0, 0.8, true);
+        assert_near(&channels.volumes, &[0.5, 1.5]);
+    }
+}
+
+#[derive(Default)]
+pub struct ChannelsUi {
+    // Device the channels were read from, what they're read and written through, and when
+    device: Option<String>,
+    target: String,
+    read_at: Option<Instant>,
+    channels: Option<Result<Channels, String>>,
+}
+
+impl ChannelsUi {
+    // Look the device up again, after devices or the default changed
+    pub fn forget_device(&mut self) {
+        self.device = None;
+    }
+}
+
+impl AudioApp {
+    // What a device's channels are read and written through: on Linux the sink whose
+    // description is its name, or else the one "default" plays on, since cpal's names for ALSA
+    // devices rarely match a sink and the default is what the master volume controls anyway
+    fn channel_target(&self, device: &str) -> String {
+        #[cfg(target_os = "linux")]
+        {
+            let sink = crate::endpoints::pulse::list_sinks().into_iter().find(|sink| sink.description == device);
+            sink.map(|sink| sink.name).or_else(|| self.real_default_sink()).unwrap_or_else(|| "@DEFAULT_SINK@".to_string())
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        {
+            device.to_string()
+        }
+    }
+
+    fn refresh_channels(&mut self, device: &str) {
+        let stale = self.channels_ui.read_at.is_none_or(|read_at| read_at.elapsed() >= REFRESH_INTERVAL);
+        if self.channels_ui.device.as_deref() != Some(device) {
+            self.channels_ui.target = self.channel_target(device);
+            self.channels_ui.device = Some(device.to_string());
+        } else if !stale {
+            return;
+        }
+        self.channels_ui.channels = Some(read_channels(&self.channels_ui.target));
+        self.channels_ui.read_at = Some(Instant::now());
+    }
+
+    // Balance and per-channel faders for the selected output
+    pub fn channels_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(device) = self.selected_device_idx.map(|idx| self.device_names[idx].clone()) else {
+            ui.label(RichText::new("No output selected").italics());
+            return;
+        };
+        self.refresh_channels(&device);
+
+        let mut channels = match &self.channels_ui.channels {
+            Some(Ok(channels)) => channels.clone(),
+            Some(Err(err)) => {
+                ui.label(RichText::new(format!("Channel volumes aren't available: {err}")).weak());
+                return;
+            }
+            None => return,
+        };
+
+        // The channel map, e.g. "L R C LFE SL SR"
+        let layout: Vec<String> = channels.speakers.iter().map(|speaker| speaker.short()).collect();
+        ui.horizontal(|ui| {
+            ui.label(RichText::new(format!("{} channels: {}", channels.speakers.len(), layout.join(" "))).weak());
+            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                if ui.toggle_value(&mut self.settings.channels.locked, "🔗 Lock channels").on_hover_text("Move all channels together").changed() {
+                    self.settings.save();
+                }
+            });
+        });
+
+        let before = channels.clone();
+        let locked = self.settings.channels.locked;
+
+        if channels.is_stereo() {
+            let mut balance = channels.balance();
+            ui.horizontal(|ui| {
+                ui.label("Balance:");
+                ui.label("L");
+                let response = ui.add(egui::Slider::new(&mut balance, -1.0..=1.0).show_value(false));
+                ui.label("R");
+                if response.changed() {
+                    channels.set_balance(balance);
+                }
+                if ui.add_enabled(balance.abs() > 0.005, egui::Button::new("Centre").small()).clicked() {
+                    channels.set_balance(0.0);
+                }
+            });
+        }
+
+        if channels.speakers.len() > 1 {
+            ui.horizontal(|ui| {
+                for idx in 0..channels.speakers.len() {
+                    let speaker = channels.speakers[idx];
+                    ui.vertical(|ui| {
+                        let mut volume = channels.volumes[idx];
+                        let response = ui
+                            .add(egui::Slider::new(&mut volume, 0.0..=1.0).vertical().show_value(false).trailing_fill(true))
+                            .on_hover_text(format!("{}: {:.0}%", speaker.label(), volume * 100.0));
+                        if response.changed() {
+                            channels.set_channel(idx, volume, locked);
+                        }
+                        ui.label(RichText::new(speaker.short()).small());
+                    });
+                }
+            });
+        }
+
+        if channels != before {
+            match write_channels(&self.channels_ui.target, &channels.volumes) {
+                Ok(()) => {
+                    self.channels_ui.channels = Some(Ok(channels));
+                    self.channels_ui.read_at = Some(Instant::now());
+                }
+                Err(err) => {
+                    eprintln!("ERROR: Couldn't set the channel volumes of {device}: {err}");
+                    self.channels_ui.channels = Some(Err(err));
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,89 @@
+// Command line handling, so scenes can be applied from scripts and shortcuts
+// without opening the window.
+
+use std::path::PathBuf;
+
+pub enum CliCommand {
+    // No arguments: start the GUI as usual
+    Gui,
+    ApplyScene(String),
+    ListScenes,
+    // Serve the HTTP API, MQTT, OSC and MIDI from a pretend audio system, for testing clients.
+    // The options replace the saved settings; port 0 picks a free one.
+    Simulate(SimulateOptions),
+    Help,
+}
+
+#[derive(Default)]
+pub struct SimulateOptions {
+    pub port: Option<u16>,
+    pub token: Option<String>,
+    // Read from this file instead of the saved settings
+    pub settings: Option<PathBuf>,
+}
+
+pub const USAGE: &str = "\
+Usage: audioapp2 [OPTIONS]
+
+Options:
+  --scene <NAME>    Apply a saved scene and exit
+  --list-scenes     Print the names of all saved scenes and exit
+  --simulate        Serve the HTTP API, MQTT, OSC and MIDI from a simulated audio system, without a window
+    --port <PORT>   With --simulate: serve HTTP on this port instead of the saved one (0 picks a free port)
+    --token <TOKEN> With --simulate: require this token instead of the saved one
+    --settings <FILE>
+                    With --simulate: read the settings from this file instead of the saved ones
+  -h, --help        Print this help";
+
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<CliCommand, String> {
+    let mut args = args.into_iter();
+    let Some(first) = args.next() else {
+        return Ok(CliCommand::Gui);
+    };
+
+    let command = match first.as_str() {
+        "--scene" => match args.next() {
+            Some(name) => CliCommand::ApplyScene(name),
+            None => return Err("--scene needs a scene name".to_string()),
+        },
+        "--list-scenes" => CliCommand::ListScenes,
+        "--simulate" => {
+            let mut options = SimulateOptions::default();
+            while let Some(option) = args.next() {
+                match option.as_str() {
+                    "--port" => match args.next().and_then(|value| value.parse().ok()) {
+                        Some(port) => options.port = Some(port),
+                        None => return Err("--port needs a port number".to_string()),
+                    },
+                    "--token" => match args.next() {
+                        Some(token) if !token.is_empty() => options.token = Some(token),
+                        _ => return Err("--token needs a token".to_string()),
+                    },
+                    "--settings" => match args.next() {
+                        Some(path) => options.settings = Some(PathBuf::from(path)),
+                        None => return Err("--settings needs a file".to_string()),
+                    },
+                    other => return Err(format!("Unexpected argument '{other}'")),
+                }
+            }
+            CliCommand::Simulate(options)
+        }
+        "-h" | "--help" => CliCommand::Help,
+        other => return Err(format!("Unknown argument '{other}'")),
+    };
+
+    if let Some(extra) = args.next() {
+        return Err(format!("Unexpected argument '{extra}'"));
+    }
+    Ok(command)
+}
+
+// Release builds use the windows subsystem and have no console of their own,
+// so borrow the console of the shell that started us to make output visible
+pub fn attach_console() {
+    #[cfg(target_os = "windows")]
+    unsafe {
+        use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+        let _ = AttachConsole(ATTACH_PARENT_PROCESS);
+    }
+}
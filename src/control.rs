@@ -0,0 +1,395 @@
+// Remote control of a running instance. Servers such as the IPC socket run on their own
+// threads and turn whatever their clients send into `Command`s; the UI thread executes them
+// against the app and broadcasts `ControlEvent`s whenever the state changes.
+
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::endpoints::Flow;
+use crate::AudioApp;
+
+// How long a server waits for the UI thread to answer a request
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    GetState,
+    ListDevices,
+    SetDefaultDevice { flow: Flow, name: String },
+    SetVolume(f32),
+    SetMute(bool),
+    ToggleMute,
+    ListApps,
+    SetAppVolume { app: String, volume: f32 },
+    SetAppMute { app: String, muted: bool },
+    ListScenes,
+    ApplyScene(String),
+}
+
+// Parameters, as they appear in requests
+#[derive(Deserialize)]
+struct DeviceParams {
+    name: String,
+    #[serde(default = "output")]
+    flow: Flow,
+}
+
+fn output() -> Flow {
+    Flow::Output
+}
+
+#[derive(Deserialize)]
+struct VolumeParams {
+    volume: f32,
+}
+
+#[derive(Deserialize)]
+struct MuteParams {
+    muted: bool,
+}
+
+#[derive(Deserialize)]
+struct AppVolumeParams {
+    app: String,
+    volume: f32,
+}
+
+#[derive(Deserialize)]
+struct AppMuteParams {
+    app: String,
+    muted: bool,
+}
+
+#[derive(Deserialize)]
+struct SceneParams {
+    name: String,
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: &Value) -> Result<T, ControlError> {
+    serde_json::from_value(params.clone()).map_err(|err| ControlError::InvalidParams(err.to_string()))
+}
+
+fn volume(volume: f32) -> Result<f32, ControlError> {
+    if (0.0..=1.0).contains(&volume) {
+        Ok(volume)
+    } else {
+        Err(ControlError::InvalidParams("volume must be between 0.0 and 1.0".to_string()))
+    }
+}
+
+impl Command {
+    // Build a command from a method name such as "set_volume" and its named parameters
+    pub fn from_method(method: &str, value: &Value) -> Result<Self, ControlError> {
+        let command = match method {
+            "get_state" => Command::GetState,
+            "list_devices" => Command::ListDevices,
+            "set_default_device" => {
+                let DeviceParams { name, flow } = params(value)?;
+                Command::SetDefaultDevice { flow, name }
+            }
+            "set_volume" => Command::SetVolume(volume(params::<VolumeParams>(value)?.volume)?),
+            "set_mute" => Command::SetMute(params::<MuteParams>(value)?.muted),
+            "toggle_mute" => Command::ToggleMute,
+            "list_apps" => Command::ListApps,
+            "set_app_volume" => {
+                let AppVolumeParams { app, volume: level } = params(value)?;
+                Command::SetAppVolume { app, volume: volume(level)? }
+            }
+            "set_app_mute" => {
+                let AppMuteParams { app, muted } = params(value)?;
+                Command::SetAppMute { app, muted }
+            }
+            "list_scenes" => Command::ListScenes,
+            "apply_scene" => Command::ApplyScene(params::<SceneParams>(value)?.name),
+            other => return Err(ControlError::UnknownMethod(other.to_string())),
+        };
+        Ok(command)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ControlError {
+    UnknownMethod(String),
+    InvalidParams(String),
+    // The command was understood but couldn't be carried out
+    Failed(String),
+    // The UI thread didn't answer in time, or has shut down
+    Unavailable,
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ControlError::UnknownMethod(method) => write!(f, "Unknown method '{method}'"),
+            ControlError::InvalidParams(reason) => write!(f, "Invalid parameters: {reason}"),
+            ControlError::Failed(reason) => write!(f, "{reason}"),
+            ControlError::Unavailable => write!(f, "The app is not responding"),
+        }
+    }
+}
+
+// Everything clients are told about when it changes
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct ControlState {
+    pub output_device: Option<String>,
+    pub input_device: Option<String>,
+    pub volume: f32,
+    pub muted: bool,
+    pub outputs: Vec<String>,
+    pub inputs: Vec<String>,
+}
+
+// Named after what happened, the same as the "event" field clients see
+#[allow(clippy::enum_variant_names)]
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ControlEvent {
+    VolumeChanged { volume: f32 },
+    MuteChanged { muted: bool },
+    DefaultDeviceChanged { flow: Flow, name: Option<String> },
+    DevicesChanged { outputs: Vec<String>, inputs: Vec<String> },
+}
+
+impl ControlEvent {
+    // The name clients use to pick which events they want, same as the "event" field
+    pub fn name(&self) -> &'static str {
+        match self {
+            ControlEvent::VolumeChanged { .. } => "volume_changed",
+            ControlEvent::MuteChanged { .. } => "mute_changed",
+            ControlEvent::DefaultDeviceChanged { .. } => "default_device_changed",
+            ControlEvent::DevicesChanged { .. } => "devices_changed",
+        }
+    }
+}
+
+// The events that take `previous` to `current`
+pub fn events_between(previous: &ControlState, current: &ControlState) -> Vec<ControlEvent> {
+    let mut events = Vec::new();
+    if previous.outputs != current.outputs || previous.inputs != current.inputs {
+        events.push(ControlEvent::DevicesChanged { outputs: current.outputs.clone(), inputs: current.inputs.clone() });
+    }
+    if previous.output_device != current.output_device {
+        events.push(ControlEvent::DefaultDeviceChanged { flow: Flow::Output, name: current.output_device.clone() });
+    }
+    if previous.input_device != current.input_device {
+        events.push(ControlEvent::DefaultDeviceChanged { flow: Flow::Input, name: current.input_device.clone() });
+    }
+    if previous.volume != current.volume {
+        events.push(ControlEvent::VolumeChanged { volume: current.volume });
+    }
+    if previous.muted != current.muted {
+        events.push(ControlEvent::MuteChanged { muted: current.muted });
+    }
+    events
+}
+
+// A command waiting for the UI thread, with the way back to whoever sent it
+pub struct Request {
+    pub command: Command,
+    reply: Sender<Result<Value, ControlError>>,
+}
+
+impl Request {
+    pub fn respond(self, result: Result<Value, ControlError>) {
+        // The server may have given up waiting; nothing to do about that
+        let _ = self.reply.send(result);
+    }
+}
+
+// The servers' side of the hub: send commands, subscribe to events. Cheap to clone.
+#[derive(Clone)]
+pub struct ControlHandle {
+    requests: Sender<Request>,
+    subscribers: Arc<Mutex<Vec<Sender<ControlEvent>>>>,
+    // The window's context, to wake the UI thread when a request arrives. egui only runs a
+    // frame when something asks for one.
+    context: Arc<OnceLock<egui::Context>>,
+}
+
+impl ControlHandle {
+    // Run a command on the UI thread and wait for the result
+    pub fn call(&self, command: Command) -> Result<Value, ControlError> {
+        let (reply, response) = mpsc::channel();
+        self.requests.send(Request { command, reply }).map_err(|_| ControlError::Unavailable)?;
+        if let Some(context) = self.context.get() {
+            context.request_repaint();
+        }
+        response.recv_timeout(REPLY_TIMEOUT).map_err(|_| ControlError::Unavailable)?
+    }
+
+    // Receive every event from now on, until the receiver is dropped
+    pub fn subscribe(&self) -> Receiver<ControlEvent> {
+        let (sender, receiver) = mpsc::channel();
+        if let Ok(mut subscribers) = self.subscribers.lock() {
+            subscribers.push(sender);
+        }
+        receiver
+    }
+}
+
+// The UI thread's side of the hub
+pub struct ControlHub {
+    requests: Receiver<Request>,
+    handle: ControlHandle,
+    // What subscribers were last told about
+    published: Option<ControlState>,
+}
+
+impl ControlHub {
+    pub fn new() -> Self {
+        let (sender, requests) = mpsc::channel();
+        let handle = ControlHandle { requests: sender, subscribers: Arc::default(), context: Arc::default() };
+        Self { requests, handle, published: None }
+    }
+
+    // Let requests wake up the window they're answered from
+    pub fn set_context(&self, context: egui::Context) {
+        let _ = self.handle.context.set(context);
+    }
+
+    pub fn handle(&self) -> ControlHandle {
+        self.handle.clone()
+    }
+
+    // Return the next waiting request, if any
+    pub fn poll(&self) -> Option<Request> {
+        self.requests.try_recv().ok()
+    }
+
+    // Tell subscribers about anything that changed since the last call
+    pub fn publish(&mut self, state: ControlState) {
+        let events = match &self.published {
+            Some(previous) => events_between(previous, &state),
+            None => Vec::new(),
+        };
+        self.published = Some(state);
+        if events.is_empty() {
+            return;
+        }
+
+        if let Ok(mut subscribers) = self.handle.subscribers.lock() {
+            // Sending fails once a subscriber has gone away, which is our cue to forget it
+            subscribers.retain(|subscriber| events.iter().all(|event| subscriber.send(event.clone()).is_ok()));
+        }
+    }
+}
+
+impl AudioApp {
+    pub fn control_state(&self) -> ControlState {
+        ControlState {
+            output_device: self.selected_device_idx.map(|idx| self.device_names[idx].clone()),
+            input_device: self.default_input_name.clone(),
+            volume: self.volume,
+            muted: self.is_muted,
+            outputs: self.device_names.clone(),
+            inputs: self.input_device_names.clone(),
+        }
+    }
+
+    // Carry out commands from remote clients, then let subscribers know what changed
+    pub fn handle_control_requests(&mut self) {
+        while let Some(request) = self.control.poll() {
+            let result = self.execute(&request.command);
+            request.respond(result);
+        }
+        let state = self.control_state();
+        self.control.publish(state);
+    }
+
+    // Find a device by its system name or its alias
+    pub fn find_device(&self, flow: Flow, name: &str) -> Option<String> {
+        let names = match flow {
+            Flow::Output => &self.device_names,
+            Flow::Input => &self.input_device_names,
+        };
+        names
+            .iter()
+            .find(|device| device.as_str() == name)
+            .or_else(|| names.iter().find(|device| self.settings.devices.display_name(device) == name))
+            .cloned()
+    }
+
+    fn device_list(&self, flow: Flow) -> Value {
+        let (names, default) = match flow {
+            Flow::Output => (&self.device_names, self.selected_device_idx.map(|idx| &self.device_names[idx])),
+            Flow::Input => (&self.input_device_names, self.default_input_name.as_ref()),
+        };
+        names
+            .iter()
+            .map(|name| {
+                json!({
+                    "name": name,
+                    "display_name": self.settings.devices.display_name(name),
+                    "default": Some(name) == default,
+                    "hidden": self.settings.devices.is_hidden(name),
+                })
+            })
+            .collect()
+    }
+
+    pub fn execute(&mut self, command: &Command) -> Result<Value, ControlError> {
+        match command {
+            Command::GetState => Ok(json!(self.control_state())),
+            Command::ListDevices => Ok(json!({
+                "outputs": self.device_list(Flow::Output),
+                "inputs": self.device_list(Flow::Input),
+            })),
+            Command::SetDefaultDevice { flow, name } => {
+                let Some(device) = self.find_device(*flow, name) else {
+                    return Err(ControlError::Failed(format!("No device named '{name}'")));
+                };
+                match flow {
+                    Flow::Output => {
+                        if let Some(idx) = self.device_names.iter().position(|other| other == &device) {
+                            self.set_default_device(idx);
+                        }
+                    }
+                    Flow::Input => self.set_default_input_device_by_name(&device),
+                }
+                Ok(json!({ "name": device }))
+            }
+            Command::SetVolume(volume) => {
+                self.set_volume(*volume);
+                Ok(json!({ "volume": self.volume }))
+            }
+            Command::SetMute(muted) => {
+                self.set_mute(*muted);
+                Ok(json!({ "muted": self.is_muted }))
+            }
+            Command::ToggleMute => {
+                self.toggle_mute();
+                Ok(json!({ "muted": self.is_muted }))
+            }
+            Command::ListApps => Ok(json!(self.remote_app_volumes())),
+            Command::SetAppVolume { app, volume } => {
+                if !self.remote_app_volumes().iter().any(|other| &other.name == app) {
+                    return Err(ControlError::Failed(format!("No application named '{app}'")));
+                }
+                self.set_app_volume(app, *volume);
+                Ok(json!({ "app": app, "volume": volume }))
+            }
+            Command::SetAppMute { app, muted } => {
+                if !self.remote_app_volumes().iter().any(|other| &other.name == app) {
+                    return Err(ControlError::Failed(format!("No application named '{app}'")));
+                }
+                self.set_app_mute(app, *muted);
+                Ok(json!({ "app": app, "muted": muted }))
+            }
+            Command::ListScenes => {
+                Ok(self.settings.scenes.iter().map(|scene| json!(scene.name)).collect())
+            }
+            Command::ApplyScene(name) => {
+                if self.apply_scene(name) {
+                    Ok(json!({ "name": name }))
+                } else {
+                    Err(ControlError::Failed(format!("No scene named '{name}'")))
+                }
+            }
+        }
+    }
+}
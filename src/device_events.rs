@@ -0,0 +1,403 @@
+// Notifications about audio devices coming and going, delivered as they happen
+// instead of by re-reading the device list every frame.
+
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+
+use crate::auto_mute::AutoMuteSwitch;
+use crate::endpoints::Flow;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DeviceEvent {
+    // A device was plugged in, unplugged, enabled or disabled
+    DevicesChanged,
+    // The system default device changed for the given direction
+    DefaultChanged(Flow),
+    // Playback moved from headphones to speakers and the new device was muted
+    AutoMuted { from: String, to: String },
+}
+
+// Watches for device changes until dropped
+pub struct DeviceWatcher {
+    receiver: Receiver<DeviceEvent>,
+    #[cfg(target_os = "windows")]
+    registration: Option<windows_impl::Registration>,
+}
+
+impl DeviceWatcher {
+    pub fn start(auto_mute: Arc<AutoMuteSwitch>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        #[cfg(target_os = "windows")]
+        {
+            let registration = unsafe { windows_impl::Registration::new(sender, auto_mute) }
+                .map_err(|err| eprintln!("ERROR: Couldn't watch for device changes: {err}"))
+                .ok();
+            Self { receiver, registration }
+        }
+
+        #[cfg(not(target_os = "windows"))]
+        {
+            #[cfg(target_os = "linux")]
+            linux_impl::spawn_sink_watcher(sender.clone(), auto_mute);
+            // Form factors aren't known here, so there's nothing to auto-mute on
+            #[cfg(not(target_os = "linux"))]
+            drop(auto_mute);
+
+            spawn_poller(sender);
+            Self { receiver }
+        }
+    }
+
+    // Return the next event, if any
+    pub fn poll(&self) -> Option<DeviceEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        if let Some(registration) = self.registration.take() {
+            unsafe { registration.unregister() };
+        }
+    }
+}
+
+// Without endpoint notifications, compare the device lists on a background thread
+#[cfg(not(target_os = "windows"))]
+fn spawn_poller(sender: Sender<DeviceEvent>) {
+    use cpal::traits::{DeviceTrait, HostTrait};
+    use std::time::Duration;
+
+    fn snapshot() -> (Vec<String>, Vec<String>, Option<String>, Option<String>) {
+        let host = cpal::default_host();
+        let names = |devices: Result<Vec<cpal::Device>, _>| -> Vec<String> {
+            devices.unwrap_or_default().iter().filter_map(|device| device.name().ok()).collect()
+        };
+        (
+            names(host.output_devices().map(|devices| devices.collect())),
+            names(host.input_devices().map(|devices| devices.collect())),
+            host.default_output_device().and_then(|device| device.name().ok()),
+            host.default_input_device().and_then(|device| device.name().ok()),
+        )
+    }
+
+    std::thread::spawn(move || {
+        let mut previous = snapshot();
+        loop {
+            std::thread::sleep(Duration::from_secs(2));
+            let current = snapshot();
+
+            let mut events = Vec::new();
+            if current.0 != previous.0 || current.1 != previous.1 {
+                events.push(DeviceEvent::DevicesChanged);
+            }
+            if current.2 != previous.2 {
+                events.push(DeviceEvent::DefaultChanged(Flow::Output));
+            }
+            if current.3 != previous.3 {
+                events.push(DeviceEvent::DefaultChanged(Flow::Input));
+            }
+
+            for event in events {
+                if sender.send(event).is_err() {
+                    return; // The watcher was dropped
+                }
+            }
+            previous = current;
+        }
+    });
+}
+
+// PulseAudio tells `pactl subscribe` about every change to sinks, cards and the default, which
+// is quick enough to mute before much sound gets out
+#[cfg(target_os = "linux")]
+mod linux_impl {
+    use super::{Arc, AutoMuteSwitch, DeviceEvent, Flow, Sender};
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
+
+    use crate::auto_mute::{pause_playback, should_auto_mute};
+    use crate::endpoints::pulse::{self, Sink};
+
+    fn default_sink(sinks: &[Sink]) -> Option<Sink> {
+        let name = pulse::default_sink_name()?;
+        sinks.iter().find(|sink| sink.name == name).cloned()
+    }
+
+    pub fn spawn_sink_watcher(sender: Sender<DeviceEvent>, auto_mute: Arc<AutoMuteSwitch>) {
+        std::thread::spawn(move || {
+            let child = Command::new("pactl").arg("subscribe").env("LC_ALL", "C").stdout(Stdio::piped()).spawn();
+            let Some(stdout) = child.ok().and_then(|mut child| child.stdout.take()) else {
+                // No pactl or no sound server; there's nothing to watch
+                return;
+            };
+
+            let mut previous = default_sink(&pulse::list_sinks());
+            for line in BufReader::new(stdout).lines() {
+                let Ok(line) = line else {
+                    break;
+                };
+                // e.g. "Event 'change' on sink #1", "Event 'change' on card #0" when a port
+                // changes, or "Event 'change' on server" when the default does
+                if !(line.contains(" on sink #") || line.contains(" on card #") || line.ends_with(" on server")) {
+                    continue;
+                }
+
+                let sinks = pulse::list_sinks();
+                let current = default_sink(&sinks);
+                // cpal only ever sees "default", so the poller can't tell when the sink behind it changes
+                if previous.as_ref().map(|sink| &sink.name) != current.as_ref().map(|sink| &sink.name)
+                    && sender.send(DeviceEvent::DefaultChanged(Flow::Output)).is_err()
+                {
+                    return; // The watcher was dropped
+                }
+                let (Some(from), Some(to)) = (&previous, &current) else {
+                    previous = current;
+                    continue;
+                };
+
+                let same_device = from.name == to.name;
+                let from_present = sinks.iter().any(|sink| sink.name == from.name);
+                if auto_mute.enabled() && !to.muted && should_auto_mute(from.form_factor, to.form_factor, same_device, from_present) {
+                    match pulse::set_sink_mute(&to.name, true) {
+                        Ok(()) => {
+                            if auto_mute.pause_media() {
+                                pause_playback();
+                            }
+                            let event = DeviceEvent::AutoMuted { from: from.description.clone(), to: to.description.clone() };
+                            if sender.send(event).is_err() {
+                                return; // The watcher was dropped
+                            }
+                        }
+                        Err(err) => eprintln!("ERROR: Couldn't mute {}: {err}", to.description),
+                    }
+                }
+                previous = current;
+            }
+        });
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows_impl {
+    use super::{Arc, AutoMuteSwitch, DeviceEvent, Flow, Sender};
+    use std::sync::mpsc::{self, Receiver};
+    use windows::core::{implement, Result, HSTRING, PCWSTR};
+    use windows::Win32::Media::Audio::{
+        eCapture, eMultimedia, eRender, EDataFlow, ERole, IMMDeviceEnumerator, IMMNotificationClient,
+        IMMNotificationClient_Impl, DEVICE_STATE_ACTIVE, PKEY_AudioEndpoint_FormFactor, PKEY_AudioEndpoint_JackSubType,
+    };
+    use windows::Win32::System::Com::{CoInitializeEx, COINIT_MULTITHREADED};
+    use windows::Win32::UI::Shell::PropertiesSystem::PROPERTYKEY;
+
+    use crate::auto_mute::{pause_playback, should_auto_mute};
+    use crate::endpoints::{self, Endpoint, EndpointState};
+
+    // What Windows reported, passed on as is. The callbacks run on the audio service's threads,
+    // where looking devices up or waiting on a lock can stall or deadlock it.
+    enum Notification {
+        // Something was added, or changed in a way we couldn't make out
+        ListChanged,
+        StateChanged { id: String, active: bool },
+        NewDefault(Flow, Option<String>),
+        // An endpoint's form factor or jack changed, e.g. headphones pulled out of a jack
+        // that then plays on speakers
+        KindChanged(String),
+    }
+
+    // COM object Windows calls back into, on its own threads, whenever endpoints change
+    #[implement(IMMNotificationClient)]
+    struct NotificationClient {
+        notifications: Sender<Notification>,
+    }
+
+    impl NotificationClient {
+        fn send(&self, notification: Notification) -> Result<()> {
+            let _ = self.notifications.send(notification);
+            Ok(())
+        }
+    }
+
+    impl IMMNotificationClient_Impl for NotificationClient {
+        fn OnDeviceStateChanged(&self, device_id: &PCWSTR, new_state: u32) -> Result<()> {
+            match unsafe { device_id.to_string() } {
+                Ok(id) => self.send(Notification::StateChanged { id, active: new_state == DEVICE_STATE_ACTIVE }),
+                Err(_) => self.send(Notification::ListChanged),
+            }
+        }
+
+        fn OnDeviceAdded(&self, _device_id: &PCWSTR) -> Result<()> {
+            self.send(Notification::ListChanged)
+        }
+
+        fn OnDeviceRemoved(&self, device_id: &PCWSTR) -> Result<()> {
+            match unsafe { device_id.to_string() } {
+                Ok(id) => self.send(Notification::StateChanged { id, active: false }),
+                Err(_) => self.send(Notification::ListChanged),
+            }
+        }
+
+        fn OnDefaultDeviceChanged(&self, flow: EDataFlow, role: ERole, device_id: &PCWSTR) -> Result<()> {
+            // This fires once per role; the multimedia role is the one the rest of the app uses
+            if role != eMultimedia {
+                return Ok(());
+            }
+
+            let flow = match flow {
+                f if f == eRender => Flow::Output,
+                f if f == eCapture => Flow::Input,
+                _ => return Ok(()),
+            };
+            let id = if device_id.is_null() { None } else { unsafe { device_id.to_string() }.ok() };
+            self.send(Notification::NewDefault(flow, id))
+        }
+
+        fn OnPropertyValueChanged(&self, device_id: &PCWSTR, key: &PROPERTYKEY) -> Result<()> {
+            if *key != PKEY_AudioEndpoint_FormFactor && *key != PKEY_AudioEndpoint_JackSubType {
+                return Ok(());
+            }
+            match unsafe { device_id.to_string() } {
+                Ok(id) => self.send(Notification::KindChanged(id)),
+                Err(_) => Ok(()),
+            }
+        }
+    }
+
+    // Turns the notifications into events for the UI on a thread of its own, muting right away
+    // if we just went from headphones to speakers: waiting for the UI thread would let a moment
+    // of audio through
+    struct Worker {
+        events: Sender<DeviceEvent>,
+        auto_mute: Arc<AutoMuteSwitch>,
+        enumerator: IMMDeviceEnumerator,
+        // The default output as we last saw it, to tell what kind of device we're leaving
+        default_output: Option<Endpoint>,
+        // The default output went away before Windows told us which device replaced it
+        default_lost: bool,
+    }
+
+    impl Worker {
+        unsafe fn describe(&self, id: &str) -> Option<Endpoint> {
+            let device = self.enumerator.GetDevice(&HSTRING::from(id)).ok()?;
+            endpoints::windows_impl::describe(&device)
+        }
+
+        fn is_default(&self, id: &str) -> bool {
+            self.default_output.as_ref().is_some_and(|endpoint| endpoint.id == id)
+        }
+
+        fn run(mut self, notifications: Receiver<Notification>) {
+            for notification in notifications {
+                let event = match notification {
+                    Notification::ListChanged => Some(DeviceEvent::DevicesChanged),
+                    Notification::StateChanged { id, active } => {
+                        if !active && self.is_default(&id) {
+                            self.default_lost = true;
+                        }
+                        Some(DeviceEvent::DevicesChanged)
+                    }
+                    Notification::NewDefault(Flow::Output, id) => {
+                        unsafe { self.default_output_changed(id) };
+                        Some(DeviceEvent::DefaultChanged(Flow::Output))
+                    }
+                    Notification::NewDefault(flow, _) => Some(DeviceEvent::DefaultChanged(flow)),
+                    Notification::KindChanged(id) => {
+                        if self.is_default(&id) {
+                            unsafe { self.default_output_kind_changed() };
+                        }
+                        None
+                    }
+                };
+                if let Some(event) = event {
+                    if self.events.send(event).is_err() {
+                        return; // The watcher was dropped
+                    }
+                }
+            }
+        }
+
+        // The default output is now the device with the given ID
+        unsafe fn default_output_changed(&mut self, id: Option<String>) {
+            let current = id.and_then(|id| self.describe(&id));
+            let previous = std::mem::replace(&mut self.default_output, current.clone());
+            let lost = std::mem::take(&mut self.default_lost);
+            let (Some(previous), Some(current)) = (previous, current) else {
+                return;
+            };
+
+            // Windows has usually marked the old device unplugged by now, even if that
+            // notification is still on its way
+            let present = !lost && self.describe(&previous.id).is_some_and(|endpoint| endpoint.state == EndpointState::Active);
+            self.auto_mute(&previous, &current, false, present);
+        }
+
+        // The default output is the same device but has become a different kind of device
+        unsafe fn default_output_kind_changed(&mut self) {
+            let Some(previous) = self.default_output.take() else {
+                return;
+            };
+            let Some(current) = self.describe(&previous.id) else {
+                self.default_output = Some(previous);
+                return;
+            };
+            self.default_output = Some(current.clone());
+            self.auto_mute(&previous, &current, true, true);
+        }
+
+        unsafe fn auto_mute(&self, previous: &Endpoint, current: &Endpoint, same_device: bool, present: bool) {
+            if !self.auto_mute.enabled() || !should_auto_mute(previous.form_factor, current.form_factor, same_device, present) {
+                return;
+            }
+
+            let result = self
+                .enumerator
+                .GetDevice(&HSTRING::from(current.id.as_str()))
+                .and_then(|device| endpoints::windows_impl::mute_device(&device));
+            if let Err(err) = result {
+                eprintln!("ERROR: Couldn't mute {}: {err}", current.name);
+                return;
+            }
+            if self.auto_mute.pause_media() {
+                pause_playback();
+            }
+            let _ = self.events.send(DeviceEvent::AutoMuted { from: previous.name.clone(), to: current.name.clone() });
+        }
+    }
+
+    pub struct Registration {
+        enumerator: IMMDeviceEnumerator,
+        client: IMMNotificationClient,
+    }
+
+    impl Registration {
+        pub unsafe fn new(events: Sender<DeviceEvent>, auto_mute: Arc<AutoMuteSwitch>) -> Result<Self> {
+            let enumerator = endpoints::windows_impl::enumerator()?;
+
+            // The worker stops once the client, and with it the last sender, is released
+            let (notifications, receiver) = mpsc::channel();
+            std::thread::spawn(move || unsafe {
+                let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+                let enumerator = match endpoints::windows_impl::enumerator() {
+                    Ok(enumerator) => enumerator,
+                    Err(err) => {
+                        eprintln!("ERROR: Couldn't watch for device changes: {err}");
+                        return;
+                    }
+                };
+                let default_output = endpoints::windows_impl::default_device(Flow::Output)
+                    .and_then(|device| endpoints::windows_impl::describe(&device));
+                Worker { events, auto_mute, enumerator, default_output, default_lost: false }.run(receiver);
+            });
+
+            let client: IMMNotificationClient = NotificationClient { notifications }.into();
+            enumerator.RegisterEndpointNotificationCallback(&client)?;
+            Ok(Self { enumerator, client })
+        }
+
+        pub unsafe fn unregister(self) {
+            let _ = self.enumerator.UnregisterEndpointNotificationCallback(&self.client);
+        }
+    }
+}
@@ -0,0 +1,515 @@
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::endpoints::EndpointState;
+use crate::profiles::profile_menu_ui;
+use crate::AudioApp;
+
+// How many characters of a device name fit in the picker before it gets elided
+pub const MAX_NAME_GRAPHEMES: usize = 25;
+
+// How many recently used devices to remember
+const MAX_RECENT: usize = 5;
+
+// How the user wants their devices presented, keyed by the raw device name
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct DevicePrefs {
+    // Friendly names, e.g. "Speakers (Realtek(R) Audio)" -> "Desk speakers"
+    pub aliases: HashMap<String, String>,
+    // Devices left out of the picker
+    pub hidden: Vec<String>,
+    // Favorites, listed first and in this order
+    pub pinned: Vec<String>,
+    // Most recently selected devices, newest first
+    pub recent: Vec<String>,
+}
+
+impl DevicePrefs {
+    // The alias if there is one, otherwise the raw name
+    pub fn display_name<'a>(&'a self, name: &'a str) -> &'a str {
+        self.aliases.get(name).map(String::as_str).unwrap_or(name)
+    }
+
+    pub fn set_alias(&mut self, name: &str, alias: &str) {
+        let alias = alias.trim();
+        if alias.is_empty() || alias == name {
+            self.aliases.remove(name);
+        } else {
+            self.aliases.insert(name.to_string(), alias.to_string());
+        }
+    }
+
+    pub fn is_hidden(&self, name: &str) -> bool {
+        self.hidden.iter().any(|hidden| hidden == name)
+    }
+
+    pub fn set_hidden(&mut self, name: &str, hidden: bool) {
+        self.hidden.retain(|other| other != name);
+        if hidden {
+            self.hidden.push(name.to_string());
+        }
+    }
+
+    pub fn is_pinned(&self, name: &str) -> bool {
+        self.pinned.iter().any(|pinned| pinned == name)
+    }
+
+    pub fn set_pinned(&mut self, name: &str, pinned: bool) {
+        self.pinned.retain(|other| other != name);
+        if pinned {
+            self.pinned.push(name.to_string());
+        }
+    }
+
+    // Move a pinned device one place up (negative) or down (positive) in the favorites. It
+    // swaps with the nearest favorite among `present`, skipping ones that aren't connected and
+    // so aren't listed.
+    pub fn move_pinned(&mut self, name: &str, offset: isize, present: &[String]) {
+        let Some(pos) = self.pinned.iter().position(|pinned| pinned == name) else {
+            return;
+        };
+        let listed = |idx: &usize| present.contains(&self.pinned[*idx]);
+        let target = if offset < 0 { (0..pos).rev().find(listed) } else { (pos + 1..self.pinned.len()).find(listed) };
+        if let Some(target) = target {
+            self.pinned.swap(pos, target);
+        }
+    }
+
+    // Remember a device as the most recently used one
+    pub fn note_recent(&mut self, name: &str) {
+        self.recent.retain(|other| other != name);
+        self.recent.insert(0, name.to_string());
+        self.recent.truncate(MAX_RECENT);
+    }
+
+    // Indices into `names` in the order they should be listed: pinned favorites first,
+    // then everything else in the order the system reports them. Hidden devices are left out.
+    pub fn arrange(&self, names: &[String]) -> Vec<usize> {
+        let mut order: Vec<usize> = self
+            .pinned
+            .iter()
+            .filter_map(|pinned| names.iter().position(|name| name == pinned))
+            .collect();
+
+        for (idx, name) in names.iter().enumerate() {
+            if !self.is_pinned(name) && !self.is_hidden(name) {
+                order.push(idx);
+            }
+        }
+
+        order.retain(|&idx| !self.is_hidden(&names[idx]));
+        order
+    }
+}
+
+// Shorten text to at most `max` user-perceived characters, ending in an ellipsis.
+// Works on grapheme clusters so accents, emoji and CJK names are never cut in half.
+pub fn elide(text: &str, max: usize) -> String {
+    let graphemes: Vec<&str> = text.graphemes(true).collect();
+    if graphemes.len() <= max {
+        return text.to_string();
+    }
+    if max == 0 {
+        return String::new();
+    }
+
+    let mut elided: String = graphemes[..max.saturating_sub(1)].concat();
+    elided.push('…');
+    elided
+}
+
+// Score how well `query` matches `text` as a fuzzy subsequence, ignoring case.
+// Returns None if some character of the query doesn't appear in order. Runs of consecutive
+// characters and matches at the start of words score higher, so "spk rtk" finds
+// "Speakers (Realtek(R) Audio)" and prefers it over names that merely contain those letters.
+pub fn fuzzy_score(query: &str, text: &str) -> Option<i32> {
+    let text: Vec<char> = text.chars().flat_map(char::to_lowercase).collect();
+    let mut score = 0;
+    let mut pos = 0;
+    let mut previous_match: Option<usize> = None;
+
+    for wanted in query.chars().flat_map(char::to_lowercase) {
+        if wanted.is_whitespace() {
+            // Spaces separate words in the query but don't need to match anything
+            previous_match = None;
+            continue;
+        }
+
+        let offset = text[pos..].iter().position(|&c| c == wanted)?;
+        let idx = pos + offset;
+
+        score += 1;
+        if previous_match == Some(idx.wrapping_sub(1)) {
+            score += 5; // Consecutive characters
+        }
+        if idx == 0 || !text[idx - 1].is_alphanumeric() {
+            score += 3; // Start of a word
+        }
+
+        previous_match = Some(idx);
+        pos = idx + 1;
+    }
+
+    Some(score)
+}
+
+// Indices of the devices in `names` the picker should list for `query`.
+// With no search text: recently used devices, then favorites, then the rest.
+// Otherwise: best fuzzy matches on name or alias, with recently used ones breaking ties.
+pub fn picker_matches(prefs: &DevicePrefs, names: &[String], query: &str) -> Vec<usize> {
+    let visible = prefs.arrange(names);
+    let query = query.trim();
+
+    if query.is_empty() {
+        let recent: Vec<usize> =
+            prefs.recent.iter().filter_map(|recent| visible.iter().copied().find(|&idx| &names[idx] == recent)).collect();
+        let rest = visible.into_iter().filter(|idx| !recent.contains(idx));
+        return recent.iter().copied().chain(rest).collect();
+    }
+
+    let recency = |name: &str| prefs.recent.iter().position(|recent| recent == name).unwrap_or(MAX_RECENT);
+    let mut scored: Vec<(i32, usize, usize)> = visible
+        .into_iter()
+        .filter_map(|idx| {
+            let name = &names[idx];
+            let score = fuzzy_score(query, name).max(fuzzy_score(query, prefs.display_name(name)))?;
+            Some((score, recency(name), idx))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    scored.into_iter().map(|(_, _, idx)| idx).collect()
+}
+
+// UI-only state for managing devices
+#[derive(Default)]
+pub struct DevicesUi {
+    // Alias being typed for each device, committed when the text box loses focus
+    alias_edits: HashMap<String, String>,
+    // Text typed into the device picker
+    query: String,
+    // Whether the picker list is expanded
+    picker_open: bool,
+    // Row highlighted with the arrow keys
+    highlighted: usize,
+}
+
+impl AudioApp {
+    // Name to show for a device: its alias, shortened to fit the picker
+    pub fn device_label(&self, name: &str) -> String {
+        elide(self.settings.devices.display_name(name), MAX_NAME_GRAPHEMES)
+    }
+
+    // Filterable device list: type to search, arrow keys to move, Enter to switch
+    pub fn device_picker_ui(&mut self, ui: &mut egui::Ui) {
+        let current = self.selected_device_idx.map(|idx| self.device_label(&self.device_names[idx]));
+        let hint = current.clone().unwrap_or_else(|| "Select a device".to_string());
+
+        let search_id = ui.make_persistent_id("device_search");
+        let search = ui
+            .horizontal(|ui| {
+                let search = ui.add(
+                    egui::TextEdit::singleline(&mut self.devices_ui.query)
+                        .id(search_id)
+                        .hint_text(format!("🔍 {hint}"))
+                        .desired_width(ui.available_width() - 32.0),
+                );
+                let arrow = if self.devices_ui.picker_open { "⏶" } else { "⏷" };
+                if ui.button(arrow).clicked() {
+                    self.devices_ui.picker_open = !self.devices_ui.picker_open;
+                }
+                search
+            })
+            .inner;
+
+        if let Some(idx) = self.selected_device_idx {
+            search.clone().on_hover_text(&self.device_names[idx]);
+        }
+
+        // Start typing anywhere in the window to search
+        if ui.memory(|memory| memory.focus().is_none()) {
+            let typed = ui.input(|input| input.events.iter().any(|event| matches!(event, egui::Event::Text(_))));
+            if typed {
+                ui.memory_mut(|memory| memory.request_focus(search_id));
+            }
+        }
+
+        if search.gained_focus() {
+            self.refresh_endpoints();
+        }
+        if search.changed() || search.gained_focus() {
+            self.devices_ui.picker_open = true;
+            self.devices_ui.highlighted = 0;
+        }
+
+        if !self.devices_ui.picker_open {
+            return;
+        }
+
+        let matches = picker_matches(&self.settings.devices, &self.device_names, &self.devices_ui.query);
+        let mut chosen = None;
+        let mut profile_action = None;
+
+        // Keyboard navigation while the search box has focus
+        if search.has_focus() || search.lost_focus() {
+            ui.input_mut(|input| {
+                if input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowDown) {
+                    self.devices_ui.highlighted += 1;
+                }
+                if input.consume_key(egui::Modifiers::NONE, egui::Key::ArrowUp) {
+                    self.devices_ui.highlighted = self.devices_ui.highlighted.saturating_sub(1);
+                }
+            });
+        }
+        self.devices_ui.highlighted = self.devices_ui.highlighted.min(matches.len().saturating_sub(1));
+
+        if search.lost_focus() {
+            if ui.input(|input| input.key_pressed(egui::Key::Enter)) {
+                chosen = matches.get(self.devices_ui.highlighted).copied();
+            } else if ui.input(|input| input.key_pressed(egui::Key::Escape)) {
+                self.devices_ui.picker_open = false;
+            }
+        }
+
+        egui::ScrollArea::vertical().id_source("device_picker").max_height(180.0).show(ui, |ui| {
+            for (row, &idx) in matches.iter().enumerate() {
+                let name = &self.device_names[idx];
+                let endpoint = self.output_endpoints.iter().find(|endpoint| &endpoint.name == name);
+
+                let icon = endpoint.map(|endpoint| endpoint.form_factor.icon()).unwrap_or("🔊");
+                let mut details = endpoint.map(|endpoint| endpoint.form_factor.label().to_string()).unwrap_or_default();
+                if let Some(volume) = endpoint.and_then(|endpoint| endpoint.volume) {
+                    let muted = endpoint.and_then(|endpoint| endpoint.muted).unwrap_or(false);
+                    details.push_str(&format!(" · {}%{}", (volume * 100.0).round() as i32, if muted { " 🔇" } else { "" }));
+                }
+                if self.settings.devices.recent.contains(name) && self.devices_ui.query.trim().is_empty() {
+                    details.push_str(" · recent");
+                }
+                let has_profile = self.device_profile(name).is_some();
+                if has_profile {
+                    details.push_str(" · DSP");
+                }
+
+                let highlighted = row == self.devices_ui.highlighted;
+                let is_current = self.selected_device_idx == Some(idx);
+                let label = format!("{} {}", icon, self.device_label(name));
+
+                let response = ui
+                    .horizontal(|ui| {
+                        let response = ui.add_sized(
+                            [ui.available_width() - 130.0, 20.0],
+                            egui::SelectableLabel::new(is_current || highlighted, RichText::new(label).strong()),
+                        );
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            ui.label(RichText::new(details).weak());
+                        });
+                        response
+                    })
+                    .inner
+                    .on_hover_text(name);
+                response.context_menu(|ui| {
+                    if let Some(action) = profile_menu_ui(ui, has_profile) {
+                        profile_action = Some((idx, action));
+                    }
+                });
+
+                if highlighted && search.has_focus() {
+                    response.scroll_to_me(None);
+                }
+                if response.clicked() {
+                    chosen = Some(idx);
+                }
+            }
+
+            if matches.is_empty() {
+                ui.label(RichText::new("No matching devices").italics());
+            }
+
+            // Show devices that exist but can't be used right now, so it's clear where they went
+            let query = self.devices_ui.query.trim();
+            for endpoint in &self.output_endpoints {
+                if endpoint.state == EndpointState::Active || self.settings.devices.is_hidden(&endpoint.name) {
+                    continue;
+                }
+                let display = self.settings.devices.display_name(&endpoint.name);
+                if !query.is_empty() && fuzzy_score(query, &endpoint.name).max(fuzzy_score(query, display)).is_none() {
+                    continue;
+                }
+                ui.horizontal(|ui| {
+                    ui.add_enabled(
+                        false,
+                        egui::SelectableLabel::new(false, format!("{} {}", endpoint.form_factor.icon(), elide(display, MAX_NAME_GRAPHEMES))),
+                    );
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        ui.label(RichText::new(endpoint.state.label()).weak());
+                    });
+                });
+            }
+        });
+
+        if let Some((idx, action)) = profile_action {
+            let name = self.device_names[idx].clone();
+            self.apply_profile_action(&name, action);
+        }
+        if let Some(idx) = chosen {
+            self.set_default_device(idx);
+            self.devices_ui.query.clear();
+            self.devices_ui.picker_open = false;
+        }
+    }
+
+    // Rename, hide and reorder the devices shown in the picker
+    pub fn device_manager_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = false;
+
+        // Pinned favorites first, then the rest, with hidden devices at the end
+        let mut order: Vec<usize> = self.settings.devices.arrange(&self.device_names);
+        for (idx, name) in self.device_names.iter().enumerate() {
+            if self.settings.devices.is_hidden(name) {
+                order.push(idx);
+            }
+        }
+
+        egui::Grid::new("device_manager").num_columns(3).striped(true).show(ui, |ui| {
+            for idx in order {
+                let name = self.device_names[idx].clone();
+                let prefs = &mut self.settings.devices;
+
+                // Alias editor, with the raw name as the hint and tooltip
+                let alias = self
+                    .devices_ui
+                    .alias_edits
+                    .entry(name.clone())
+                    .or_insert_with(|| prefs.aliases.get(&name).cloned().unwrap_or_default());
+                let response = ui
+                    .add(egui::TextEdit::singleline(alias).hint_text(elide(&name, MAX_NAME_GRAPHEMES)).desired_width(200.0))
+                    .on_hover_text(&name);
+                if response.lost_focus() {
+                    prefs.set_alias(&name, alias);
+                    changed = true;
+                }
+
+                ui.horizontal(|ui| {
+                    let mut pinned = prefs.is_pinned(&name);
+                    if ui.toggle_value(&mut pinned, "📌").on_hover_text("Pin to the top").changed() {
+                        prefs.set_pinned(&name, pinned);
+                        changed = true;
+                    }
+
+                    let mut hidden = prefs.is_hidden(&name);
+                    if ui.toggle_value(&mut hidden, "🚫").on_hover_text("Hide from the list").changed() {
+                        prefs.set_hidden(&name, hidden);
+                        changed = true;
+                    }
+                });
+
+                // Reorder favorites
+                ui.horizontal(|ui| {
+                    if prefs.is_pinned(&name) {
+                        if ui.small_button("⬆").clicked() {
+                            prefs.move_pinned(&name, -1, &self.device_names);
+                            changed = true;
+                        }
+                        if ui.small_button("⬇").clicked() {
+                            prefs.move_pinned(&name, 1, &self.device_names);
+                            changed = true;
+                        }
+                    }
+                });
+                ui.end_row();
+            }
+        });
+
+        if self.device_names.is_empty() {
+            ui.label(RichText::new("No output devices found").italics());
+        }
+
+        if changed {
+            self.settings.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn elides_whole_characters() {
+        assert_eq!(elide("Speakers", 25), "Speakers");
+        assert_eq!(elide("Speakers (Realtek(R) Audio)", 10), "Speakers …");
+        // Multibyte characters count once each and aren't cut in half
+        assert_eq!(elide("スピーカー (Realtek オーディオ)", 6), "スピーカー…");
+        assert_eq!(elide("🎧🎧🎧 Headphones", 3), "🎧🎧…");
+        // A combining accent stays with its letter
+        assert_eq!(elide("Cafe\u{301} speakers", 5), "Cafe\u{301}…");
+        assert_eq!(elide("👩‍👩‍👧 family", 2), "👩‍👩‍👧…");
+        assert_eq!(elide("Speakers", 1), "…");
+        assert_eq!(elide("Speakers", 0), "");
+        assert_eq!(elide("", 0), "");
+    }
+
+    #[test]
+    fn fuzzy_scores_prefer_words_and_runs() {
+        let realtek = "Speakers (Realtek(R) Audio)";
+        assert!(fuzzy_score("spk rtk", realtek).is_some());
+        assert!(fuzzy_score("SPEAK", realtek).is_some());
+        assert_eq!(fuzzy_score("xyz", realtek), None);
+        // Letters have to come in order
+        assert_eq!(fuzzy_score("kps", "Speakers"), None);
+        assert_eq!(fuzzy_score("", realtek), Some(0));
+
+        // Consecutive letters beat scattered ones, and the start of a word beats the middle
+        assert!(fuzzy_score("head", "Headphones") > fuzzy_score("head", "Hi-Fi earbud adapter"));
+        assert!(fuzzy_score("ph", "Phone speaker") > fuzzy_score("ph", "Headphones"));
+    }
+
+    // Move up and down skip favorites that aren't connected, which aren't listed
+    #[test]
+    fn moves_past_missing_favorites() {
+        let mut prefs = DevicePrefs { pinned: names(&["A", "gone", "B", "also gone", "C"]), ..Default::default() };
+        let present = names(&["C", "B", "A"]);
+
+        prefs.move_pinned("B", -1, &present);
+        assert_eq!(prefs.pinned, names(&["B", "gone", "A", "also gone", "C"]));
+        prefs.move_pinned("A", 1, &present);
+        assert_eq!(prefs.pinned, names(&["B", "gone", "C", "also gone", "A"]));
+        assert_eq!(prefs.arrange(&present).iter().map(|&idx| present[idx].as_str()).collect::<Vec<_>>(), ["B", "C", "A"]);
+
+        // Nothing to swap with at either end
+        prefs.move_pinned("B", -1, &present);
+        prefs.move_pinned("A", 1, &present);
+        prefs.move_pinned("not pinned", 1, &present);
+        assert_eq!(prefs.pinned, names(&["B", "gone", "C", "also gone", "A"]));
+    }
+
+    #[test]
+    fn picker_lists_recent_favorites_then_the_rest() {
+        let devices = names(&["Speakers (Realtek(R) Audio)", "Headphones (USB)", "Monitor (HDMI)", "Headset (Bluetooth)"]);
+        let mut prefs = DevicePrefs {
+            pinned: names(&["Headset (Bluetooth)"]),
+            hidden: names(&["Monitor (HDMI)"]),
+            recent: names(&["Headphones (USB)"]),
+            ..Default::default()
+        };
+        assert_eq!(picker_matches(&prefs, &devices, ""), [1, 3, 0]);
+        assert_eq!(picker_matches(&prefs, &devices, "  "), [1, 3, 0]);
+
+        // Hidden devices can't be found, and aliases can
+        assert_eq!(picker_matches(&prefs, &devices, "hdmi"), Vec::<usize>::new());
+        prefs.set_alias("Speakers (Realtek(R) Audio)", "Desk");
+        assert_eq!(picker_matches(&prefs, &devices, "desk"), [0]);
+
+        // Equally good matches are ordered by how recently they were used
+        assert_eq!(picker_matches(&prefs, &devices, "head"), [1, 3]);
+        prefs.note_recent("Headset (Bluetooth)");
+        assert_eq!(picker_matches(&prefs, &devices, "head"), [3, 1]);
+    }
+}
@@ -0,0 +1,411 @@
+// Reading and writing EqualizerAPO's config.txt format, which is also what AutoEq produces for
+// its ParametricEQ.txt and FixedBandEQ.txt files:
+//
+//   Preamp: -6.2 dB
+//   Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+//   Filter 2: ON PK Fc 180 Hz Gain -3.0 dB Q 1.40
+//   Include: headphones.txt
+//
+// Only the parts that map onto the app's EQ are understood. Everything else is skipped and
+// reported with its line, rather than guessed at.
+
+use std::collections::HashSet;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use super::eq::{Band, BandKind, Equalizer, MAX_BANDS};
+
+// Q of a Butterworth filter, used when a file doesn't give one
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+// Include files can include others; stop somewhere in case they go round in circles
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+// A line that couldn't be used
+#[derive(Debug, Clone, PartialEq)]
+pub struct Skipped {
+    pub file: String,
+    pub line: usize,
+    pub text: String,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+pub struct Import {
+    pub eq: Equalizer,
+    pub skipped: Vec<Skipped>,
+}
+
+// Read a config file and everything it includes
+pub fn load(path: &Path) -> Result<Import, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+    let mut import = Import::default();
+    let mut visited = HashSet::new();
+    visited.insert(path.canonicalize().unwrap_or_else(|_| path.to_path_buf()));
+    parse_into(&mut import, &text, &file_label(path), path.parent(), &mut visited, 0);
+    Ok(import)
+}
+
+fn file_label(path: &Path) -> String {
+    path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+}
+
+fn parse_into(import: &mut Import, text: &str, file: &str, dir: Option<&Path>, visited: &mut HashSet<PathBuf>, depth: usize) {
+    // Filters after "Channel: L" only apply to some channels, which the app's EQ can't do
+    let mut all_channels = true;
+
+    for (idx, raw) in text.lines().enumerate() {
+        let line = raw.trim();
+        let mut skip = |reason: &str| {
+            import.skipped.push(Skipped { file: file.to_string(), line: idx + 1, text: line.to_string(), reason: reason.to_string() });
+        };
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((command, rest)) = line.split_once(':') else {
+            skip("not a command");
+            continue;
+        };
+        let command = command.trim();
+        let rest = rest.trim();
+        // "Filter 3" and "Filter" are the same command
+        let keyword = command.split_whitespace().next().unwrap_or_default().to_ascii_lowercase();
+
+        match keyword.as_str() {
+            "preamp" => match parse_preamp(rest) {
+                Some(db) => import.eq.preamp_db += db,
+                None => skip("couldn't read the preamp gain"),
+            },
+            "filter" if !all_channels => skip("filters for some channels only aren't supported"),
+            "filter" if import.eq.bands.len() >= MAX_BANDS => skip(&format!("only the first {MAX_BANDS} filters are used")),
+            "filter" => match parse_filter(rest) {
+                Ok(band) => import.eq.bands.push(band),
+                Err(reason) => skip(&reason),
+            },
+            "channel" => {
+                all_channels = rest.eq_ignore_ascii_case("all");
+                if !all_channels {
+                    skip("per-channel settings aren't supported, the filters that follow are skipped");
+                }
+            }
+            "include" => {
+                let path = dir.map(|dir| dir.join(rest)).unwrap_or_else(|| PathBuf::from(rest));
+                let key = path.canonicalize().unwrap_or_else(|_| path.clone());
+                if depth >= MAX_INCLUDE_DEPTH {
+                    skip("includes are nested too deeply");
+                    continue;
+                }
+                if !visited.insert(key) {
+                    skip("file is already included");
+                    continue;
+                }
+                match std::fs::read_to_string(&path) {
+                    Ok(text) => parse_into(import, &text, &file_label(&path), path.parent(), visited, depth + 1),
+                    Err(err) => skip(&format!("couldn't read it: {err}")),
+                }
+            }
+            "graphiceq" => skip("graphic EQ isn't supported, use the ParametricEQ.txt file instead"),
+            _ => skip(&format!("{command} isn't supported")),
+        }
+    }
+}
+
+// "-6.2 dB"
+fn parse_preamp(text: &str) -> Option<f32> {
+    let mut words = text.split_whitespace();
+    let value = words.next()?.parse().ok()?;
+    match words.next() {
+        None => Some(value),
+        Some(unit) if unit.eq_ignore_ascii_case("db") => Some(value),
+        Some(_) => None,
+    }
+}
+
+// "ON PK Fc 180 Hz Gain -3.0 dB Q 1.40"
+fn parse_filter(text: &str) -> Result<Band, String> {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    let mut words = words.as_slice();
+
+    let enabled = match words.first().map(|word| word.to_ascii_uppercase()) {
+        Some(state) if state == "ON" => true,
+        Some(state) if state == "OFF" => false,
+        _ => return Err("expected ON or OFF".to_string()),
+    };
+    words = &words[1..];
+
+    let Some(kind) = words.first().map(|word| word.to_ascii_uppercase()) else {
+        return Err("filter type missing".to_string());
+    };
+    words = &words[1..];
+
+    // Shelves may give their steepness in dB per octave instead of a Q, e.g. "LS 6dB"
+    let mut slope_db = None;
+    if let Some(slope) = words.first().and_then(|word| word.strip_suffix("dB").or_else(|| word.strip_suffix("db"))) {
+        slope_db = Some(slope.parse::<f32>().map_err(|_| format!("couldn't read the slope '{}'", words[0]))?);
+        words = &words[1..];
+    }
+
+    let (kind, takes_gain, takes_q) = match kind.as_str() {
+        "PK" | "PEQ" | "MODAL" => (BandKind::Peaking, true, true),
+        "LS" | "LSC" => (BandKind::LowShelf, true, true),
+        "HS" | "HSC" => (BandKind::HighShelf, true, true),
+        "LP" => (BandKind::LowPass, false, false),
+        "LPQ" => (BandKind::LowPass, false, true),
+        "HP" => (BandKind::HighPass, false, false),
+        "HPQ" => (BandKind::HighPass, false, true),
+        "BP" => return Err("band-pass filters aren't supported".to_string()),
+        "NO" => return Err("notch filters aren't supported".to_string()),
+        "AP" => return Err("all-pass filters aren't supported".to_string()),
+        other => return Err(format!("unknown filter type {other}")),
+    };
+
+    let mut band = Band { kind, enabled, freq: 0.0, gain_db: 0.0, q: DEFAULT_Q };
+    let mut freq = None;
+    let mut bandwidth = None;
+    let mut q = None;
+    while let [name, rest @ ..] = words {
+        let name = name.to_ascii_lowercase();
+        // "BW Oct 1.0" is the one parameter with its unit first
+        if name == "bw" && takes_q {
+            let [unit, value, tail @ ..] = rest else {
+                return Err("couldn't read the bandwidth".to_string());
+            };
+            if !unit.eq_ignore_ascii_case("oct") {
+                return Err(format!("bandwidth in {unit} isn't supported"));
+            }
+            bandwidth = Some(value.parse::<f32>().map_err(|_| format!("couldn't read the bandwidth '{value}'"))?);
+            words = tail;
+            continue;
+        }
+
+        let [value, tail @ ..] = rest else {
+            return Err(format!("{name} has no value"));
+        };
+        let number: f32 = value.parse().map_err(|_| format!("couldn't read {name} '{value}'"))?;
+        // Units are optional
+        let (unit, tail) = match tail {
+            [unit, after @ ..] if ["hz", "khz", "db"].contains(&unit.to_ascii_lowercase().as_str()) => {
+                (Some(unit.to_ascii_lowercase()), after)
+            }
+            _ => (None, tail),
+        };
+        words = tail;
+
+        match name.as_str() {
+            "fc" if unit.as_deref() == Some("khz") => freq = Some(number * 1000.0),
+            "fc" => freq = Some(number),
+            "gain" if takes_gain => band.gain_db = number,
+            "q" if takes_q => q = Some(number),
+            other => return Err(format!("{other} isn't supported for {} filters", band.kind.label().to_lowercase())),
+        }
+    }
+
+    band.freq = freq.ok_or("frequency missing")?;
+    if !(1.0..=100_000.0).contains(&band.freq) {
+        return Err(format!("frequency {} Hz is out of range", band.freq));
+    }
+    band.q = match (q, bandwidth, slope_db) {
+        (Some(q), _, _) => q,
+        (None, Some(octaves), _) => bandwidth_to_q(octaves),
+        (None, None, Some(slope)) => slope_to_q(band.gain_db, slope),
+        (None, None, None) => DEFAULT_Q,
+    };
+    if band.q <= 0.0 || !band.q.is_finite() {
+        return Err("Q must be above zero".to_string());
+    }
+    Ok(band)
+}
+
+fn bandwidth_to_q(octaves: f32) -> f32 {
+    let factor = 2f32.powf(octaves);
+    factor.sqrt() / (factor - 1.0)
+}
+
+// A shelf's steepness, where 12 dB per octave is the steepest it gets without overshooting
+// (shelf slope S = 1 in the Audio EQ Cookbook)
+fn slope_to_q(gain_db: f32, slope_db: f32) -> f32 {
+    let a = 10f32.powf(gain_db / 40.0);
+    let s = (slope_db / 12.0).clamp(0.01, 1.0);
+    1.0 / ((a + 1.0 / a) * (1.0 / s - 1.0) + 2.0).sqrt()
+}
+
+// Write the EQ in EqualizerAPO's format, which AutoEq, Peace and Wavelet also read
+pub fn export(eq: &Equalizer) -> String {
+    let mut text = String::new();
+    let _ = writeln!(text, "Preamp: {:.1} dB", eq.preamp_db);
+    for (idx, band) in eq.bands.iter().enumerate() {
+        let state = if band.enabled { "ON" } else { "OFF" };
+        let _ = match band.kind {
+            BandKind::Peaking => {
+                writeln!(text, "Filter {}: {state} PK Fc {} Hz Gain {:.1} dB Q {:.2}", idx + 1, band.freq, band.gain_db, band.q)
+            }
+            BandKind::LowShelf => {
+                writeln!(text, "Filter {}: {state} LSC Fc {} Hz Gain {:.1} dB Q {:.2}", idx + 1, band.freq, band.gain_db, band.q)
+            }
+            BandKind::HighShelf => {
+                writeln!(text, "Filter {}: {state} HSC Fc {} Hz Gain {:.1} dB Q {:.2}", idx + 1, band.freq, band.gain_db, band.q)
+            }
+            BandKind::LowPass => writeln!(text, "Filter {}: {state} LPQ Fc {} Hz Q {:.2}", idx + 1, band.freq, band.q),
+            BandKind::HighPass => writeln!(text, "Filter {}: {state} HPQ Fc {} Hz Q {:.2}", idx + 1, band.freq, band.q),
+        };
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::test_support::{band, reference_curve, TempDir};
+
+    fn parse(text: &str) -> Import {
+        let mut import = Import::default();
+        parse_into(&mut import, text, "config.txt", None, &mut HashSet::new(), 0);
+        import
+    }
+
+    fn skipped_lines(import: &Import) -> Vec<(&str, usize)> {
+        import.skipped.iter().map(|skipped| (skipped.file.as_str(), skipped.line)).collect()
+    }
+
+    // AutoEq's ParametricEQ.txt, as downloaded
+    #[test]
+    fn reads_autoeq_parametric_eq() {
+        let import = parse(
+            "Preamp: -6.2 dB\n\
+             Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70\n\
+             Filter 2: ON PK Fc 180 Hz Gain -3.0 dB Q 1.40\n\
+             Filter 3: ON PK Fc 2900 Hz Gain 4.0 dB Q 2.00\n\
+             Filter 10: ON HSC Fc 10000 Hz Gain -2.0 dB Q 0.70\n",
+        );
+        let expected = Equalizer {
+            preamp_db: -6.2,
+            bands: vec![
+                band(BandKind::LowShelf, 105.0, 5.5, 0.7),
+                band(BandKind::Peaking, 180.0, -3.0, 1.4),
+                band(BandKind::Peaking, 2900.0, 4.0, 2.0),
+                band(BandKind::HighShelf, 10000.0, -2.0, 0.7),
+            ],
+        };
+        assert_eq!(import.eq, expected);
+        assert!(import.skipped.is_empty());
+    }
+
+    // AutoEq's FixedBandEQ.txt: ten peaks an octave apart, for EQs that can't move their bands
+    #[test]
+    fn reads_autoeq_fixed_band_eq() {
+        let freqs = [31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0];
+        let gains = [5.9, 3.4, -0.6, -1.8, 0.3, 0.0, -2.6, 3.1, -4.4, -0.4];
+        let mut text = "Preamp: -6.8 dB\n".to_string();
+        for (idx, (freq, gain)) in freqs.iter().zip(gains).enumerate() {
+            text.push_str(&format!("Filter {}: ON PK Fc {freq} Hz Gain {gain:.1} dB Q 1.41\n", idx + 1));
+        }
+        let import = parse(&text);
+
+        assert_eq!(import.eq.preamp_db, -6.8);
+        let expected: Vec<Band> = freqs.iter().zip(gains).map(|(freq, gain)| band(BandKind::Peaking, *freq, gain, 1.41)).collect();
+        assert_eq!(import.eq.bands, expected);
+        assert!(import.skipped.is_empty());
+    }
+
+    #[test]
+    fn preamps_add_up() {
+        let import = parse("Preamp: -3 dB\nPreamp: -1.5\nPreamp: loud\nPreamp: 2 Hz\n");
+        assert_eq!(import.eq.preamp_db, -4.5);
+        assert_eq!(skipped_lines(&import), [("config.txt", 3), ("config.txt", 4)]);
+    }
+
+    // Filters written the other ways EqualizerAPO allows: kHz, bandwidth in octaves, shelves by
+    // slope, and high- and low-pass filters with or without a Q
+    #[test]
+    fn reads_other_filter_forms() {
+        let import = parse(
+            "Filter: ON PK Fc 1 kHz Gain -4 dB BW Oct 1\n\
+             Filter: OFF HP Fc 30 Hz\n\
+             Filter: ON LS 12dB Fc 200 Hz Gain 3 dB\n\
+             Filter: ON LPQ Fc 16000 Hz Q 0.5\n",
+        );
+        let kinds: Vec<BandKind> = import.eq.bands.iter().map(|band| band.kind).collect();
+        assert_eq!(kinds, [BandKind::Peaking, BandKind::HighPass, BandKind::LowShelf, BandKind::LowPass]);
+        assert_eq!(import.eq.bands[0].freq, 1000.0);
+        assert!((import.eq.bands[0].q - std::f32::consts::SQRT_2).abs() < 1e-4);
+        assert!(!import.eq.bands[1].enabled);
+        assert_eq!(import.eq.bands[1].q, DEFAULT_Q);
+        // The steepest shelf is a Butterworth one
+        assert!((import.eq.bands[2].q - DEFAULT_Q).abs() < 1e-4);
+        assert_eq!(import.eq.bands[3].q, 0.5);
+        assert!(import.skipped.is_empty());
+    }
+
+    // Everything that can't be used is reported with its line and why, and the rest still loads
+    #[test]
+    fn reports_unsupported_lines() {
+        let import = parse(
+            "# Comments and blank lines are fine\n\
+             \n\
+             Filter: ON BP Fc 500 Hz Q 2\n\
+             Delay: 10 ms\n\
+             GraphicEQ: 20 0; 20000 0\n\
+             Filter: ON PK Fc Gain 3 dB\n\
+             Filter: ON PK Fc 0 Hz Gain 1 dB Q 1\n\
+             Filter: ON HP Fc 30 Hz Gain 3 dB\n\
+             Filter: ON PK Fc 1000 Hz Gain 1 dB Q 1\n\
+             Channel: L\n\
+             Filter: ON PK Fc 100 Hz Gain 2 dB Q 1\n\
+             Channel: all\n\
+             Filter: ON LP Fc 16000 Hz\n\
+             nonsense\n",
+        );
+        let reasons: Vec<(usize, &str)> = import.skipped.iter().map(|skipped| (skipped.line, skipped.reason.as_str())).collect();
+        assert_eq!(
+            reasons,
+            [
+                (3, "band-pass filters aren't supported"),
+                (4, "Delay isn't supported"),
+                (5, "graphic EQ isn't supported, use the ParametricEQ.txt file instead"),
+                (6, "couldn't read fc 'Gain'"),
+                (7, "frequency 0 Hz is out of range"),
+                (8, "gain isn't supported for high-pass filters"),
+                (10, "per-channel settings aren't supported, the filters that follow are skipped"),
+                (11, "filters for some channels only aren't supported"),
+                (14, "not a command"),
+            ]
+        );
+        assert_eq!(import.skipped[1].text, "Delay: 10 ms");
+        let kinds: Vec<BandKind> = import.eq.bands.iter().map(|band| band.kind).collect();
+        assert_eq!(kinds, [BandKind::Peaking, BandKind::LowPass]);
+    }
+
+    // Includes are read relative to the file, once each, and what's skipped in them is reported
+    // under their own name
+    #[test]
+    fn follows_includes() {
+        let dir = TempDir::new("apo-include");
+        std::fs::write(dir.path("room.txt"), "Preamp: -2 dB\nFilter: ON PK Fc 1 kHz Gain -4 dB Q 1\nDelay: 1 ms\n").unwrap();
+        std::fs::write(
+            dir.path("config.txt"),
+            "Preamp: -3 dB\n\
+             Include: room.txt\n\
+             Include: room.txt\n\
+             Include: config.txt\n\
+             Include: missing.txt\n\
+             Filter: ON HS Fc 8000 Hz Gain 2 dB Q 0.7\n",
+        )
+        .unwrap();
+        let import = load(&dir.path("config.txt")).unwrap();
+
+        assert_eq!(import.eq.preamp_db, -5.0);
+        assert_eq!(import.eq.bands, [band(BandKind::Peaking, 1000.0, -4.0, 1.0), band(BandKind::HighShelf, 8000.0, 2.0, 0.7)]);
+        assert_eq!(skipped_lines(&import), [("room.txt", 3), ("config.txt", 3), ("config.txt", 4), ("config.txt", 5)]);
+        assert_eq!(import.skipped[1].reason, "file is already included");
+        assert!(import.skipped[3].reason.starts_with("couldn't read it"));
+    }
+
+    // Exporting and importing again gives back the same EQ
+    #[test]
+    fn export_reads_back_unchanged() {
+        let mut eq = reference_curve();
+        eq.bands[2].enabled = false;
+        let import = parse(&export(&eq));
+        assert_eq!(import.eq, eq);
+        assert!(import.skipped.is_empty());
+    }
+}
@@ -0,0 +1,124 @@
+// Second-order IIR sections, designed with the formulas from Robert Bristow-Johnson's Audio EQ
+// Cookbook, the same ones EqualizerAPO and most parametric EQs use. Coefficients and state are
+// kept in f64: low bass bands at 48 kHz put the poles very close to the unit circle, where f32
+// rounding turns into audible noise.
+
+use std::f64::consts::PI;
+
+// Normalised so a0 is 1
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Coefficients {
+    pub b0: f64,
+    pub b1: f64,
+    pub b2: f64,
+    pub a1: f64,
+    pub a2: f64,
+}
+
+impl Coefficients {
+    // Passes everything through unchanged
+    pub const IDENTITY: Self = Self { b0: 1.0, b1: 0.0, b2: 0.0, a1: 0.0, a2: 0.0 };
+
+    fn normalise(b0: f64, b1: f64, b2: f64, a0: f64, a1: f64, a2: f64) -> Self {
+        Self { b0: b0 / a0, b1: b1 / a0, b2: b2 / a0, a1: a1 / a0, a2: a2 / a0 }
+    }
+
+    // Filters at or above Nyquist can't be built, so they're pulled just below it
+    fn omega(freq: f64, sample_rate: f64) -> f64 {
+        2.0 * PI * freq.clamp(1.0, sample_rate * 0.499) / sample_rate
+    }
+
+    pub fn peaking(freq: f64, gain_db: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = Self::omega(freq, sample_rate);
+        let a = 10f64.powf(gain_db / 40.0);
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        Self::normalise(1.0 + alpha * a, -2.0 * cos, 1.0 - alpha * a, 1.0 + alpha / a, -2.0 * cos, 1.0 - alpha / a)
+    }
+
+    pub fn low_shelf(freq: f64, gain_db: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = Self::omega(freq, sample_rate);
+        let a = 10f64.powf(gain_db / 40.0);
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::normalise(
+            a * ((a + 1.0) - (a - 1.0) * cos + root),
+            2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+            a * ((a + 1.0) - (a - 1.0) * cos - root),
+            (a + 1.0) + (a - 1.0) * cos + root,
+            -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+            (a + 1.0) + (a - 1.0) * cos - root,
+        )
+    }
+
+    pub fn high_shelf(freq: f64, gain_db: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = Self::omega(freq, sample_rate);
+        let a = 10f64.powf(gain_db / 40.0);
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        let root = 2.0 * a.sqrt() * alpha;
+        Self::normalise(
+            a * ((a + 1.0) + (a - 1.0) * cos + root),
+            -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+            a * ((a + 1.0) + (a - 1.0) * cos - root),
+            (a + 1.0) - (a - 1.0) * cos + root,
+            2.0 * ((a - 1.0) - (a + 1.0) * cos),
+            (a + 1.0) - (a - 1.0) * cos - root,
+        )
+    }
+
+    pub fn low_pass(freq: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = Self::omega(freq, sample_rate);
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        Self::normalise((1.0 - cos) / 2.0, 1.0 - cos, (1.0 - cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    pub fn high_pass(freq: f64, q: f64, sample_rate: f64) -> Self {
+        let w0 = Self::omega(freq, sample_rate);
+        let alpha = w0.sin() / (2.0 * q.max(0.01));
+        let cos = w0.cos();
+        Self::normalise((1.0 + cos) / 2.0, -(1.0 + cos), (1.0 + cos) / 2.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha)
+    }
+
+    // Gain at `freq`, as a factor, found by evaluating the transfer function on the unit circle
+    pub fn magnitude(&self, freq: f64, sample_rate: f64) -> f64 {
+        let w = 2.0 * PI * freq / sample_rate;
+        let (c1, s1) = (w.cos(), w.sin());
+        let (c2, s2) = ((2.0 * w).cos(), (2.0 * w).sin());
+        let num_re = self.b0 + self.b1 * c1 + self.b2 * c2;
+        let num_im = -(self.b1 * s1 + self.b2 * s2);
+        let den_re = 1.0 + self.a1 * c1 + self.a2 * c2;
+        let den_im = -(self.a1 * s1 + self.a2 * s2);
+        ((num_re * num_re + num_im * num_im) / (den_re * den_re + den_im * den_im)).sqrt()
+    }
+}
+
+// One filter for one channel, in transposed direct form II
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Biquad {
+    s1: f64,
+    s2: f64,
+}
+
+impl Biquad {
+    #[inline]
+    pub fn process(&mut self, c: &Coefficients, x: f64) -> f64 {
+        let y = c.b0 * x + self.s1;
+        self.s1 = c.b1 * x - c.a1 * y + self.s2;
+        self.s2 = c.b2 * x - c.a2 * y;
+        y
+    }
+
+    // Once the input goes quiet the state decays into subnormal numbers, which are very slow
+    // on x86. Called once per block.
+    pub fn flush_denormals(&mut self) {
+        if self.s1.abs() < 1e-30 {
+            self.s1 = 0.0;
+        }
+        if self.s2.abs() < 1e-30 {
+            self.s2 = 0.0;
+        }
+    }
+}
@@ -0,0 +1,504 @@
+// Room correction by convolution: each channel is run through a long impulse response, as
+// made by REW or DRC-FIR, which can correct far more than a handful of EQ bands can.
+//
+// Responses are often tens of thousands of samples long, too many to run directly, so they're
+// applied in the frequency domain with uniformly partitioned convolution: the response is cut
+// into blocks of BLOCK samples, each block's spectrum is multiplied with the spectra of the
+// matching recent input blocks, and the sum turned back into samples (overlap-save). The cost
+// hardly depends on the response's length, but output can only be produced a whole block at a
+// time, so the sound is delayed by BLOCK frames.
+
+use realfft::num_complex::Complex;
+use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use super::resample_response;
+
+// Frames per partition, and so the latency the convolution adds
+pub const BLOCK: usize = 128;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct ConvolutionSettings {
+    pub enabled: bool,
+    // One WAV file whose channels go to the output's channels in order (a mono one goes to
+    // all of them), or one file per channel
+    pub files: Vec<String>,
+    // 1 plays only the convolved sound, 0 only the original
+    pub mix: f32,
+}
+
+impl Default for ConvolutionSettings {
+    fn default() -> Self {
+        Self { enabled: false, files: Vec::new(), mix: 1.0 }
+    }
+}
+
+// Impulse responses for one or more channels
+pub struct ImpulseResponse {
+    pub sample_rate: f64,
+    pub channels: Vec<Vec<f32>>,
+}
+
+impl ImpulseResponse {
+    pub fn load(files: &[String]) -> Result<Self, String> {
+        let files: Vec<&str> = files.iter().map(|file| file.trim()).filter(|file| !file.is_empty()).collect();
+        match files[..] {
+            [] => Err("Choose an impulse response WAV file".to_string()),
+            [file] => {
+                let (sample_rate, channels) = read_wav(Path::new(file))?;
+                Ok(Self { sample_rate, channels })
+            }
+            _ => {
+                let mut sample_rate = 0.0;
+                let mut channels = Vec::new();
+                for file in files {
+                    let (rate, mut responses) = read_wav(Path::new(file))?;
+                    if sample_rate != 0.0 && rate != sample_rate {
+                        return Err(format!("{file} is at {rate} Hz but the others are at {sample_rate} Hz"));
+                    }
+                    sample_rate = rate;
+                    // Only the first channel of each file is used
+                    channels.push(responses.swap_remove(0));
+                }
+                Ok(Self { sample_rate, channels })
+            }
+        }
+    }
+
+    // Samples in the longest channel
+    pub fn len(&self) -> usize {
+        self.channels.iter().map(Vec::len).max().unwrap_or(0)
+    }
+
+    // Where the response is strongest, which is roughly how much it delays the sound. Linear
+    // phase filters from DRC-FIR peak in the middle.
+    pub fn peak(&self) -> usize {
+        self.channels
+            .iter()
+            .flat_map(|channel| channel.iter().enumerate())
+            .fold((0, 0.0f32), |best, (idx, value)| if value.abs() > best.1 { (idx, value.abs()) } else { best })
+            .0
+    }
+
+    fn for_channel(&self, channel: usize) -> Option<&[f32]> {
+        match self.channels.len() {
+            1 => self.channels.first(),
+            _ => self.channels.get(channel),
+        }
+        .map(Vec::as_slice)
+    }
+
+    // Resample the responses to `sample_rate`, cut them into blocks and transform them, for an
+    // output with `channels` channels. This takes far too long for the audio thread.
+    pub fn prepare(&self, sample_rate: u32, channels: usize) -> PreparedImpulse {
+        let mut fft = Fft::new();
+        let mut partitions = Vec::new();
+        let mut lanes = Vec::new();
+        for channel in 0..channels.max(1) {
+            let mut blocks = Vec::new();
+            if let Some(response) = self.for_channel(channel) {
+                let response = resample_response(response, self.sample_rate, sample_rate as f64);
+                for block in response.chunks(BLOCK) {
+                    let mut spectrum = fft.forward.make_output_vec();
+                    fft.spectrum(block, &mut spectrum);
+                    blocks.push(spectrum);
+                }
+            }
+            lanes.push(Lane {
+                history: vec![vec![Complex::default(); BLOCK + 1]; blocks.len()],
+                newest: 0,
+                input: vec![0.0; BLOCK * 2],
+                output: vec![0.0; BLOCK],
+                dry: vec![0.0; BLOCK],
+            });
+            partitions.push(blocks);
+        }
+        PreparedImpulse { partitions, lanes: Mutex::new(Some(lanes)) }
+    }
+}
+
+fn read_wav(path: &Path) -> Result<(f64, Vec<Vec<f32>>), String> {
+    let describe = |err: hound::Error| format!("Couldn't read {}: {err}", path.display());
+    let mut reader = hound::WavReader::open(path).map_err(describe)?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>().map_err(describe)?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect::<Result<_, _>>().map_err(describe)?
+        }
+    };
+    let count = spec.channels.max(1) as usize;
+    if samples.is_empty() {
+        return Err(format!("{} is empty", path.display()));
+    }
+    let channels = (0..count).map(|channel| samples.iter().skip(channel).step_by(count).copied().collect()).collect();
+    Ok((spec.sample_rate as f64, channels))
+}
+
+// Responses cut into blocks and transformed for one output's rate and channels, so the audio
+// thread only has to switch over to them
+pub struct PreparedImpulse {
+    // Spectra of each channel's blocks. Empty for channels without a response, which are only
+    // delayed to stay in step with the rest.
+    partitions: Vec<Vec<Vec<Complex<f32>>>>,
+    // The input history the blocks run over, made here as well and taken by the convolver that
+    // plays them
+    lanes: Mutex<Option<Vec<Lane>>>,
+}
+
+// FFTs and buffers shared by every channel
+struct Fft {
+    forward: Arc<dyn RealToComplex<f32>>,
+    inverse: Arc<dyn ComplexToReal<f32>>,
+    time: Vec<f32>,
+    sum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+}
+
+impl Fft {
+    fn new() -> Self {
+        let mut planner = RealFftPlanner::<f32>::new();
+        let forward = planner.plan_fft_forward(BLOCK * 2);
+        let inverse = planner.plan_fft_inverse(BLOCK * 2);
+        let scratch = vec![Complex::default(); forward.get_scratch_len().max(inverse.get_scratch_len())];
+        Self { time: forward.make_input_vec(), sum: forward.make_output_vec(), forward, inverse, scratch }
+    }
+
+    fn spectrum(&mut self, samples: &[f32], out: &mut [Complex<f32>]) {
+        self.time.fill(0.0);
+        self.time[..samples.len()].copy_from_slice(samples);
+        // Only fails on wrongly sized buffers, which are all made by the plan
+        let _ = self.forward.process_with_scratch(&mut self.time, out, &mut self.scratch);
+    }
+
+    // Convolve the latest block of a channel, leaving BLOCK new samples in its output
+    fn run(&mut self, lane: &mut Lane, partitions: &[Vec<Complex<f32>>]) {
+        let count = partitions.len();
+        if count > 0 {
+            lane.newest = (lane.newest + count - 1) % count;
+            let mut spectrum = std::mem::take(&mut lane.history[lane.newest]);
+            self.spectrum(&lane.input, &mut spectrum);
+            lane.history[lane.newest] = spectrum;
+
+            self.sum.fill(Complex::default());
+            for (age, partition) in partitions.iter().enumerate() {
+                let input = &lane.history[(lane.newest + age) % count];
+                for ((sum, x), h) in self.sum.iter_mut().zip(input).zip(partition) {
+                    *sum += x * h;
+                }
+            }
+            // A real signal's first and last bins have no imaginary part; rounding can leave some
+            let last = self.sum.len() - 1;
+            self.sum[0].im = 0.0;
+            self.sum[last].im = 0.0;
+            let _ = self.inverse.process_with_scratch(&mut self.sum, &mut self.time, &mut self.scratch);
+            let scale = 1.0 / (BLOCK * 2) as f32;
+            for (out, sample) in lane.output.iter_mut().zip(&self.time[BLOCK..]) {
+                *out = sample * scale;
+            }
+        }
+        lane.input.copy_within(BLOCK.., 0);
+    }
+}
+
+// One channel's recent input
+struct Lane {
+    // Spectra of the most recent input blocks, one per partition, newest at `newest`
+    history: Vec<Vec<Complex<f32>>>,
+    newest: usize,
+    // The previous block followed by the one being filled
+    input: Vec<f32>,
+    // Convolved and original samples of the previous block, being played out
+    output: Vec<f32>,
+    dry: Vec<f32>,
+}
+
+pub struct Convolver {
+    channels: usize,
+    enabled: bool,
+    mix: f32,
+    impulse: Option<Arc<PreparedImpulse>>,
+    fft: Fft,
+    lanes: Vec<Lane>,
+    position: usize,
+}
+
+impl Convolver {
+    pub fn new(channels: usize) -> Self {
+        Self {
+            channels: channels.max(1),
+            enabled: false,
+            mix: 1.0,
+            impulse: None,
+            fft: Fft::new(),
+            lanes: Vec::new(),
+            position: 0,
+        }
+    }
+
+    pub fn configure(&mut self, settings: &ConvolutionSettings) {
+        self.enabled = settings.enabled;
+        self.mix = settings.mix.clamp(0.0, 1.0);
+    }
+
+    // Switch to responses made by `ImpulseResponse::prepare` for this output. The same
+    // responses sent again, as happens whenever any other setting changes, are kept without a
+    // glitch.
+    pub fn set_impulse(&mut self, impulse: Option<Arc<PreparedImpulse>>) {
+        let same = match (&impulse, &self.impulse) {
+            (Some(new), Some(old)) => Arc::ptr_eq(new, old),
+            (None, None) => true,
+            _ => false,
+        };
+        if same {
+            return;
+        }
+        self.position = 0;
+        // Nobody else takes the lanes, so the lock is always free
+        let lanes = impulse.as_ref().and_then(|impulse| impulse.lanes.try_lock().ok()?.take());
+        match lanes {
+            Some(lanes) if lanes.len() == self.channels => {
+                self.impulse = impulse;
+                self.lanes = lanes;
+            }
+            // Made for another output, or already playing on one
+            _ => {
+                self.impulse = None;
+                self.lanes = Vec::new();
+            }
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.enabled && !self.lanes.is_empty()
+    }
+
+    // Frames the output lags behind the input
+    pub fn latency(&self) -> usize {
+        if self.is_active() {
+            BLOCK
+        } else {
+            0
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let (Some(impulse), true) = (&self.impulse, self.is_active()) else {
+            return;
+        };
+        let (fft, mix) = (&mut self.fft, self.mix);
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for ((sample, lane), partitions) in frame.iter_mut().zip(&mut self.lanes).zip(&impulse.partitions) {
+                let input = *sample;
+                lane.input[BLOCK + self.position] = input;
+                let (wet, dry) = (lane.output[self.position], lane.dry[self.position]);
+                lane.dry[self.position] = input;
+                *sample = if partitions.is_empty() { dry } else { dry + (wet - dry) * mix };
+            }
+            self.position += 1;
+            if self.position == BLOCK {
+                self.position = 0;
+                for (lane, partitions) in self.lanes.iter_mut().zip(&impulse.partitions) {
+                    fft.run(lane, partitions);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::test_support::*;
+    use crate::dsp::{Chain, ChainSettings};
+
+    // A made-up room: a few reflections and a decaying tail of noise
+    fn room_response(taps: usize, seed: u64) -> Vec<f32> {
+        let mut response: Vec<f32> = noise(taps, seed)
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| value * 0.05 * (-(idx as f32) / (taps as f32 / 5.0)).exp())
+            .collect();
+        response[40] += 0.8;
+        response[311] -= 0.3;
+        response[taps / 2] += 0.1;
+        response
+    }
+
+    // Convolution done the slow way, in double precision, as the answer to compare against
+    fn convolve_reference(signal: &[f32], response: &[f32]) -> Vec<f64> {
+        (0..signal.len())
+            .map(|idx| {
+                let first = (idx + 1).saturating_sub(response.len());
+                (first..=idx).map(|tap| signal[tap] as f64 * response[idx - tap] as f64).sum()
+            })
+            .collect()
+    }
+
+    fn write_wav_24(path: &Path, channels: u16, samples: &[f32]) {
+        let spec = hound::WavSpec { channels, sample_rate: SAMPLE_RATE, bits_per_sample: 24, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(path, spec).unwrap();
+        for sample in samples {
+            writer.write_sample((*sample * 8388608.0).round().clamp(-8388608.0, 8388607.0) as i32).unwrap();
+        }
+        writer.finalize().unwrap();
+    }
+
+    fn convolution(files: &[&Path], mix: f32) -> ChainSettings {
+        let files = files.iter().map(|file| file.display().to_string()).collect();
+        ChainSettings { convolution: ConvolutionSettings { enabled: true, files, mix }, ..Default::default() }
+    }
+
+    fn prepared(settings: &ChainSettings, channels: usize) -> Arc<PreparedImpulse> {
+        Arc::new(ImpulseResponse::load(&settings.convolution.files).unwrap().prepare(SAMPLE_RATE, channels))
+    }
+
+    // Partitioned FFT convolution against the direct sum, with a different response on each
+    // channel, one long and odd-sized. Every block size gives the same samples.
+    #[test]
+    fn matches_the_direct_sum() {
+        let dir = TempDir::new("convolution");
+        let responses = [room_response(4801, 7), room_response(777, 8)];
+        let files = [dir.path("room_left.wav"), dir.path("room_right.wav")];
+        for (file, response) in files.iter().zip(&responses) {
+            write_wav(file, 1, response);
+        }
+        let settings = convolution(&[&files[0], &files[1]], 1.0);
+        let (left, right) = (noise(SAMPLE_RATE as usize / 2, 9), noise(SAMPLE_RATE as usize / 2, 10));
+        let input = dir.path("noise.wav");
+        write_wav(&input, 2, &interleave(&left, &right));
+
+        let processed = process_file(&settings, &input, 1);
+        for block in [100, 441, 4096] {
+            assert!(process_file(&settings, &input, block) == processed, "blocks of {block} differ");
+        }
+
+        let mut worst: f64 = 0.0;
+        let mut peak: f64 = 0.0;
+        for (idx, (signal, response)) in [&left, &right].into_iter().zip(&responses).enumerate() {
+            let expected = convolve_reference(signal, response);
+            for (out, expected) in channel(&processed, 2, idx)[BLOCK..].iter().zip(&expected) {
+                worst = worst.max((*out as f64 - expected).abs());
+                peak = peak.max(expected.abs());
+            }
+        }
+        let error_db = 20.0 * (worst / peak).max(1e-30).log10();
+        assert!(error_db < -100.0, "largest error {error_db:.1} dB below the peak");
+    }
+
+    // With the mix all the way dry the output is the input, bit for bit, only later by the
+    // latency the chain reports. Half way is the average of both.
+    #[test]
+    fn mixes_wet_and_dry() {
+        let dir = TempDir::new("convolution-mix");
+        let response = dir.path("room.wav");
+        write_wav(&response, 1, &room_response(4801, 7));
+        let source = noise(SAMPLE_RATE as usize / 4, 11);
+        let run = |mix| {
+            let settings = convolution(&[&response], mix);
+            let mut chain = Chain::new(SAMPLE_RATE, 2);
+            chain.configure(&settings);
+            chain.set_impulse(Some(prepared(&settings, 2)));
+            let mut samples = source.clone();
+            for chunk in samples.chunks_mut(300) {
+                chain.process(chunk);
+            }
+            (chain.latency(), samples)
+        };
+
+        let (latency, dry) = run(0.0);
+        assert_eq!(latency, BLOCK);
+        assert!(dry[..latency * 2].iter().all(|sample| *sample == 0.0));
+        assert!(dry[latency * 2..] == source[..source.len() - latency * 2], "dry should be a delayed copy");
+        let (_, wet) = run(1.0);
+        let (_, half) = run(0.5);
+        let worst = half.iter().zip(&wet).zip(&dry).fold(0.0f32, |worst, ((half, wet), dry)| worst.max((half - (wet + dry) / 2.0).abs()));
+        assert!(worst < 1e-5, "half mix off by {worst:.1e}");
+    }
+
+    // A 24-bit stereo file feeds its channels to left and right, and a mono one feeds both
+    #[test]
+    fn reads_response_files() {
+        let dir = TempDir::new("convolution-files");
+        let responses = [room_response(1000, 12), room_response(1000, 13)];
+        let stereo = dir.path("stereo_24.wav");
+        let mono = dir.path("mono_24.wav");
+        write_wav_24(&stereo, 2, &interleave(&responses[0], &responses[1]));
+        write_wav_24(&mono, 1, &responses[0]);
+        let quantized = |response: &[f32]| response.iter().map(|value| (value * 8388608.0).round() / 8388608.0).collect::<Vec<f32>>();
+
+        let loaded = ImpulseResponse::load(&[stereo.display().to_string()]).unwrap();
+        assert_eq!(loaded.sample_rate, SAMPLE_RATE as f64);
+        assert_eq!(loaded.channels, [quantized(&responses[0]), quantized(&responses[1])]);
+
+        let impulse = noise(2000, 14);
+        let input = dir.path("impulse.wav");
+        write_wav(&input, 2, &interleave(&impulse, &impulse));
+        for (file, expected) in [(&stereo, [&responses[0], &responses[1]]), (&mono, [&responses[0], &responses[0]])] {
+            let processed = process_file(&convolution(&[file], 1.0), &input, 512);
+            for (idx, response) in expected.into_iter().enumerate() {
+                let reference = convolve_reference(&impulse, &quantized(response));
+                let out = channel(&processed, 2, idx);
+                let close = out[BLOCK..].iter().zip(&reference).all(|(out, expected)| (*out as f64 - expected).abs() < 1e-4);
+                assert!(close, "{} channel {idx}", file.display());
+            }
+        }
+    }
+
+    // The same prepared responses sent again carry on without a break, and responses prepared
+    // for another output, or already playing on one, are left alone
+    #[test]
+    fn switches_to_prepared_responses() {
+        let dir = TempDir::new("convolution-switch");
+        let response = dir.path("room.wav");
+        write_wav(&response, 1, &room_response(4801, 7));
+        let settings = convolution(&[&response], 1.0);
+        let source = interleave(&noise(SAMPLE_RATE as usize / 4, 15), &noise(SAMPLE_RATE as usize / 4, 16));
+
+        let impulse = prepared(&settings, 2);
+        let mut chain = Chain::new(SAMPLE_RATE, 2);
+        chain.configure(&settings);
+        chain.set_impulse(Some(impulse.clone()));
+        let mut once = source.clone();
+        chain.process(&mut once);
+
+        let mut chain = Chain::new(SAMPLE_RATE, 2);
+        chain.configure(&settings);
+        let mut resent = source.clone();
+        for chunk in resent.chunks_mut(1000) {
+            chain.set_impulse(Some(impulse.clone()));
+            chain.process(chunk);
+        }
+        // The first chain took the input history, so the second never started convolving
+        assert_eq!(chain.latency(), 0);
+        assert!(resent == source);
+
+        let impulse = prepared(&settings, 2);
+        let mut chain = Chain::new(SAMPLE_RATE, 2);
+        chain.configure(&settings);
+        for chunk in resent.chunks_mut(1000) {
+            chain.set_impulse(Some(impulse.clone()));
+            chain.process(chunk);
+        }
+        assert!(resent == once, "sending the same responses again should change nothing");
+
+        let mut surround = Chain::new(SAMPLE_RATE, 6);
+        surround.configure(&settings);
+        surround.set_impulse(Some(prepared(&settings, 2)));
+        assert_eq!(surround.latency(), 0, "responses for two channels shouldn't play on six");
+    }
+
+    // Responses are resampled to the output's rate when prepared
+    #[test]
+    fn prepares_for_the_output_rate() {
+        let impulse = ImpulseResponse { sample_rate: SAMPLE_RATE as f64, channels: vec![room_response(4800, 17)] };
+        let blocks = |rate| impulse.prepare(rate, 1).partitions[0].len();
+        assert_eq!(blocks(48000), 4800usize.div_ceil(BLOCK));
+        assert_eq!(blocks(96000), 9600usize.div_ceil(BLOCK));
+        assert_eq!(blocks(44100), 4410usize.div_ceil(BLOCK));
+    }
+}
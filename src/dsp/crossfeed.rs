@@ -0,0 +1,179 @@
+// Headphone crossfeed: some of each channel's bass is fed to the other ear, as happens with
+// speakers, so hard-panned recordings are less tiring to listen to. Two styles:
+//
+// Meier: each side gets the low-passed difference between the channels, so anything that's
+// already the same in both (a mono recording, a centred voice) passes through untouched. The
+// one-pole low-pass also delays what crosses over by a fraction of a millisecond, roughly what
+// the head does.
+//
+// Bauer, as in bs2b: the far ear gets a low-passed copy and the near ear loses the same amount
+// through a shelf with a higher corner, so bass is spread evenly while the top end stays put.
+// Centred sound dips by up to a dB or so between the two corners.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CrossfeedKind {
+    #[default]
+    Meier,
+    Bauer,
+}
+
+impl CrossfeedKind {
+    pub const ALL: [CrossfeedKind; 2] = [Self::Meier, Self::Bauer];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Meier => "Meier",
+            Self::Bauer => "Bauer (bs2b)",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct CrossfeedSettings {
+    pub enabled: bool,
+    pub kind: CrossfeedKind,
+    // 0 feeds nothing across, 1 makes the bass mono
+    pub strength: f32,
+    // Only frequencies below this cross over
+    pub cutoff_hz: f32,
+}
+
+impl Default for CrossfeedSettings {
+    fn default() -> Self {
+        Self { enabled: false, kind: CrossfeedKind::Meier, strength: 0.3, cutoff_hz: 700.0 }
+    }
+}
+
+pub struct Crossfeed {
+    sample_rate: f64,
+    channels: usize,
+    kind: CrossfeedKind,
+    // Share of the bass that crosses over
+    amount: f64,
+    // One-pole low-pass coefficients: of what crosses over, and of the Bauer shelf
+    alpha: f64,
+    alpha_shelf: f64,
+    low: [f64; 2],
+    shelf: [f64; 2],
+    enabled: bool,
+}
+
+impl Crossfeed {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Self {
+            sample_rate: sample_rate as f64,
+            channels: channels.max(1),
+            kind: CrossfeedKind::Meier,
+            amount: 0.0,
+            alpha: 0.0,
+            alpha_shelf: 0.0,
+            low: [0.0; 2],
+            shelf: [0.0; 2],
+            enabled: false,
+        }
+    }
+
+    fn one_pole(&self, cutoff: f64) -> f64 {
+        1.0 - (-2.0 * std::f64::consts::PI * cutoff / self.sample_rate).exp()
+    }
+
+    pub fn configure(&mut self, settings: &CrossfeedSettings) {
+        let cutoff = (settings.cutoff_hz as f64).clamp(50.0, self.sample_rate / 4.0);
+        self.kind = settings.kind;
+        self.alpha = self.one_pole(cutoff);
+        self.amount = settings.strength.clamp(0.0, 1.0) as f64 / 2.0;
+        // bs2b moves the shelf up by an octave for every 12 dB between the near and far ear
+        let feed_db = 20.0 * ((1.0 - self.amount) / self.amount.max(1e-6)).log10();
+        self.alpha_shelf = self.one_pole((cutoff * 2f64.powf(feed_db / 12.0)).min(self.sample_rate / 4.0));
+        // Only stereo has a left and right to mix
+        self.enabled = settings.enabled && self.amount > 0.0 && self.channels == 2;
+        if !self.enabled {
+            self.low = [0.0; 2];
+            self.shelf = [0.0; 2];
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.enabled {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(2) {
+            let (left, right) = (frame[0] as f64, frame[1] as f64);
+            self.low[0] += self.alpha * (left - self.low[0]);
+            self.low[1] += self.alpha * (right - self.low[1]);
+            match self.kind {
+                CrossfeedKind::Meier => {
+                    let difference = self.amount * (self.low[1] - self.low[0]);
+                    frame[0] = (left + difference) as f32;
+                    frame[1] = (right - difference) as f32;
+                }
+                CrossfeedKind::Bauer => {
+                    self.shelf[0] += self.alpha_shelf * (left - self.shelf[0]);
+                    self.shelf[1] += self.alpha_shelf * (right - self.shelf[1]);
+                    frame[0] = (left + self.amount * (self.low[1] - self.shelf[0])) as f32;
+                    frame[1] = (right + self.amount * (self.low[0] - self.shelf[1])) as f32;
+                }
+            }
+        }
+        for state in self.low.iter_mut().chain(&mut self.shelf) {
+            if state.abs() < 1e-30 {
+                *state = 0.0;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::test_support::*;
+    use crate::dsp::ChainSettings;
+
+    fn crossfeed_settings(kind: CrossfeedKind) -> ChainSettings {
+        ChainSettings { crossfeed: CrossfeedSettings { enabled: true, kind, ..Default::default() }, ..Default::default() }
+    }
+
+    // Meier crossfeed leaves sound that's the same on both sides alone, and moves bass to the
+    // other side when it's only on one
+    #[test]
+    fn meier_keeps_centred_sound() {
+        let dir = TempDir::new("crossfeed-meier");
+        let settings = crossfeed_settings(CrossfeedKind::Meier);
+        let centre = noise(SAMPLE_RATE as usize, 5);
+        let stereo = interleave(&centre, &centre);
+        assert!(process_samples(&dir, "centre.wav", &settings, 2, &stereo, 256) == stereo);
+
+        let bass = sine(100.0, 1.0, 0.5);
+        let silence = vec![0.0; bass.len()];
+        let processed = process_samples(&dir, "left_bass.wav", &settings, 2, &interleave(&bass, &silence), 256);
+        let crossed = rms_db(&channel(&processed, 2, 1)) - rms_db(&bass);
+        assert!(crossed > -20.0 && crossed < -6.0, "left-only bass reaches the right at {crossed:.1} dB");
+    }
+
+    // Bauer crossfeed spreads bass as far as the strength says and leaves the level of centred
+    // sound close to where it was
+    #[test]
+    fn bauer_spreads_bass() {
+        let dir = TempDir::new("crossfeed-bauer");
+        let settings = crossfeed_settings(CrossfeedKind::Bauer);
+        let bass = sine(100.0, 1.0, 0.5);
+        let silence = vec![0.0; bass.len()];
+        let processed = process_samples(&dir, "left_bass.wav", &settings, 2, &interleave(&bass, &silence), 256);
+        // Skip the first half, while the filters settle
+        let half = bass.len() / 2;
+        let crossed = rms_db(&channel(&processed, 2, 1)[half..]) - rms_db(&bass[half..]);
+        let expected = 20.0 * (settings.crossfeed.strength as f64 / 2.0).log10();
+        assert!((crossed - expected).abs() < 0.5, "left-only bass reaches the right at {crossed:.1} dB, expected {expected:.1} dB");
+
+        for freq in [50.0, 700.0, 2000.0, 10000.0] {
+            let tone = sine(freq, 1.0, 0.5);
+            let processed = process_samples(&dir, &format!("centre_{freq}.wav"), &settings, 2, &interleave(&tone, &tone), 256);
+            let change = rms_db(&channel(&processed, 2, 0)[half..]) - rms_db(&tone[half..]);
+            assert!(change.abs() < 1.5, "centred {freq} Hz changed by {change:+.2} dB");
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Parametric equalizer: a preamp followed by any number of biquad bands, run on every channel
+
+use serde::{Deserialize, Serialize};
+
+use super::biquad::{Biquad, Coefficients};
+use super::db_to_gain;
+
+// Bands an equalizer can have. The processor keeps state for this many, so adding a band
+// doesn't allocate on the audio thread.
+pub const MAX_BANDS: usize = 32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum BandKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    HighPass,
+    LowPass,
+}
+
+impl BandKind {
+    pub const ALL: [BandKind; 5] = [Self::Peaking, Self::LowShelf, Self::HighShelf, Self::HighPass, Self::LowPass];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Peaking => "Peak",
+            Self::LowShelf => "Low shelf",
+            Self::HighShelf => "High shelf",
+            Self::HighPass => "High-pass",
+            Self::LowPass => "Low-pass",
+        }
+    }
+
+    // High- and low-pass filters have no gain of their own
+    pub fn has_gain(self) -> bool {
+        !matches!(self, Self::HighPass | Self::LowPass)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct Band {
+    pub kind: BandKind,
+    pub enabled: bool,
+    // Centre frequency for peaks, corner frequency for the others, in Hz
+    pub freq: f32,
+    pub gain_db: f32,
+    pub q: f32,
+}
+
+impl Default for Band {
+    fn default() -> Self {
+        Self { kind: BandKind::Peaking, enabled: true, freq: 1000.0, gain_db: 0.0, q: std::f32::consts::FRAC_1_SQRT_2 }
+    }
+}
+
+impl Band {
+    pub fn coefficients(&self, sample_rate: f64) -> Coefficients {
+        let (freq, gain, q) = (self.freq as f64, self.gain_db as f64, self.q as f64);
+        match self.kind {
+            _ if !self.enabled => Coefficients::IDENTITY,
+            BandKind::Peaking => Coefficients::peaking(freq, gain, q, sample_rate),
+            BandKind::LowShelf => Coefficients::low_shelf(freq, gain, q, sample_rate),
+            BandKind::HighShelf => Coefficients::high_shelf(freq, gain, q, sample_rate),
+            BandKind::HighPass => Coefficients::high_pass(freq, q, sample_rate),
+            BandKind::LowPass => Coefficients::low_pass(freq, q, sample_rate),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+#[serde(default)]
+pub struct Equalizer {
+    // Applied before the bands, usually negative to leave room for boosts
+    pub preamp_db: f32,
+    pub bands: Vec<Band>,
+}
+
+impl Equalizer {
+    // Nothing to do: no enabled bands and no preamp
+    pub fn is_flat(&self) -> bool {
+        self.preamp_db == 0.0 && !self.bands.iter().any(|band| band.enabled)
+    }
+
+    // Gain of the whole curve at `freq`, in dB
+    pub fn response_db(&self, freq: f64, sample_rate: f64) -> f64 {
+        let bands: f64 = self
+            .bands
+            .iter()
+            .filter(|band| band.enabled)
+            .map(|band| band.coefficients(sample_rate).magnitude(freq, sample_rate).max(1e-12).log10() * 20.0)
+            .sum();
+        self.preamp_db as f64 + bands
+    }
+
+    // Highest point of the curve across the audible range, for warning about clipping
+    pub fn peak_db(&self, sample_rate: f64) -> f64 {
+        (0..=200)
+            .map(|step| 20.0 * 1000f64.powf(step as f64 / 200.0))
+            .filter(|freq| *freq < sample_rate / 2.0)
+            .map(|freq| self.response_db(freq, sample_rate))
+            .fold(f64::MIN, f64::max)
+    }
+}
+
+// Runs an Equalizer on interleaved audio
+pub struct EqProcessor {
+    sample_rate: f64,
+    channels: usize,
+    preamp: f64,
+    // The bands as last configured, and their coefficients
+    bands: Vec<Band>,
+    coefficients: Vec<Coefficients>,
+    // Which filters each band uses
+    slots: Vec<usize>,
+    // Slot-major: the filters for slot n are at n * channels ..
+    filters: Vec<Biquad>,
+    flat: bool,
+}
+
+impl EqProcessor {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        let channels = channels.max(1);
+        Self {
+            sample_rate: sample_rate as f64,
+            channels,
+            preamp: 1.0,
+            bands: Vec::with_capacity(MAX_BANDS),
+            coefficients: Vec::with_capacity(MAX_BANDS),
+            slots: Vec::with_capacity(MAX_BANDS),
+            filters: vec![Biquad::default(); MAX_BANDS * channels],
+            flat: true,
+        }
+    }
+
+    // Take on new settings. Filter state is kept so dragging a band doesn't click.
+    pub fn configure(&mut self, eq: &Equalizer) {
+        let bands = &eq.bands[..eq.bands.len().min(MAX_BANDS)];
+        self.preamp = db_to_gain(eq.preamp_db as f64);
+        self.coefficients.clear();
+        self.coefficients.extend(bands.iter().map(|band| band.coefficients(self.sample_rate)));
+        self.assign_slots(bands);
+        self.bands.clear();
+        self.bands.extend_from_slice(bands);
+        self.flat = eq.is_flat();
+    }
+
+    // Keep every band on the filters it had, so adding or removing one leaves the others
+    // undisturbed. Bands that are unchanged at the start and end of the list keep theirs, the
+    // ones in between take over from the bands they replace, as when one is dragged, and any
+    // left over are new and start from silence.
+    fn assign_slots(&mut self, bands: &[Band]) {
+        let old = &self.bands;
+        let common = old.len().min(bands.len());
+        let prefix = (0..common).take_while(|&idx| old[idx] == bands[idx]).count();
+        let suffix = (0..common - prefix).take_while(|&idx| old[old.len() - 1 - idx] == bands[bands.len() - 1 - idx]).count();
+
+        let mut slots = [usize::MAX; MAX_BANDS];
+        slots[..prefix].copy_from_slice(&self.slots[..prefix]);
+        slots[bands.len() - suffix..bands.len()].copy_from_slice(&self.slots[old.len() - suffix..]);
+        let replaced = old.len() - suffix - prefix;
+        for idx in prefix..bands.len() - suffix {
+            if idx - prefix < replaced {
+                slots[idx] = self.slots[idx];
+                continue;
+            }
+            let free = (0..MAX_BANDS).find(|slot| !slots[..bands.len()].contains(slot)).unwrap_or_default();
+            self.filters[free * self.channels..(free + 1) * self.channels].fill(Biquad::default());
+            slots[idx] = free;
+        }
+        self.slots.clear();
+        self.slots.extend_from_slice(&slots[..bands.len()]);
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.flat {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(self.channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let mut x = *sample as f64 * self.preamp;
+                for (slot, coefficients) in self.slots.iter().zip(&self.coefficients) {
+                    x = self.filters[slot * self.channels + channel].process(coefficients, x);
+                }
+                *sample = x as f32;
+            }
+        }
+        for filter in &mut self.filters {
+            filter.flush_denormals();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::test_support::*;
+    use crate::dsp::ChainSettings;
+
+    fn curve_settings() -> ChainSettings {
+        ChainSettings { eq: reference_curve(), ..Default::default() }
+    }
+
+    // The level of a processed sine matches what the curve says, at every frequency
+    #[test]
+    fn response_matches_the_curve() {
+        let dir = TempDir::new("eq-curve");
+        let eq = reference_curve();
+        for freq in [30.0, 60.0, 105.0, 180.0, 400.0, 1000.0, 2900.0, 4500.0, 6100.0, 10000.0, 15000.0, 19000.0] {
+            let tone = sine(freq, 1.0, 0.25);
+            let stereo = interleave(&tone, &tone);
+            let processed = process_samples(&dir, &format!("tone_{freq}.wav"), &curve_settings(), 2, &stereo, 480);
+            // Skip the first half, while the filters settle
+            let half = processed.len() / 2;
+            let measured = rms_db(&processed[half..]) - rms_db(&stereo[half..]);
+            let expected = eq.response_db(freq, SAMPLE_RATE as f64);
+            assert!((measured - expected).abs() < 0.05, "{freq} Hz: measured {measured:.3} dB, expected {expected:.3} dB");
+        }
+    }
+
+    // A flat curve leaves the file untouched, bit for bit
+    #[test]
+    fn flat_curve_is_bit_exact() {
+        let dir = TempDir::new("eq-flat");
+        let source = noise(SAMPLE_RATE as usize, 1);
+        assert_eq!(process_samples(&dir, "noise.wav", &ChainSettings::default(), 2, &source, 512), source);
+    }
+
+    // Audio devices ask for different block sizes; the result doesn't depend on them
+    #[test]
+    fn block_size_doesnt_change_the_result() {
+        let dir = TempDir::new("eq-blocks");
+        let input = dir.path("noise.wav");
+        write_wav(&input, 2, &noise(SAMPLE_RATE as usize, 1));
+        let first = process_file(&curve_settings(), &input, 1);
+        for block in [64, 441, 4096] {
+            assert!(process_file(&curve_settings(), &input, block) == first, "blocks of {block} frames differ");
+        }
+    }
+
+    // Each channel is filtered on its own: the left output depends only on the left input
+    #[test]
+    fn channels_are_independent() {
+        let dir = TempDir::new("eq-channels");
+        let left = noise(SAMPLE_RATE as usize / 2, 2);
+        let right = sine(440.0, 0.5, 0.5);
+        let stereo = process_samples(&dir, "stereo.wav", &curve_settings(), 2, &interleave(&left, &right), 256);
+        let mono = process_samples(&dir, "mono.wav", &curve_settings(), 1, &left, 256);
+        assert!(channel(&stereo, 2, 0) == mono);
+    }
+
+    // Very low, very narrow or very wide bands ring for a long time, but they stay finite and
+    // die away in silence
+    #[test]
+    fn extreme_settings_stay_stable() {
+        let dir = TempDir::new("eq-extreme");
+        let eq = Equalizer {
+            preamp_db: 0.0,
+            bands: vec![
+                band(BandKind::Peaking, 15.0, 30.0, 20.0),
+                band(BandKind::LowShelf, 10.0, -30.0, 0.05),
+                band(BandKind::HighPass, 10.0, 0.0, 20.0),
+                band(BandKind::Peaking, 23990.0, 30.0, 0.05),
+                band(BandKind::HighShelf, 30000.0, 12.0, 0.7),
+            ],
+        };
+        let second = SAMPLE_RATE as usize;
+        let mut samples = noise(second, 3);
+        samples.extend(std::iter::repeat_n(0.0, second * 20));
+        let processed = process_samples(&dir, "extreme.wav", &ChainSettings { eq, ..Default::default() }, 1, &samples, 128);
+
+        assert!(processed.iter().all(|sample| sample.is_finite()));
+        let early = rms_db(&processed[2 * second..3 * second]);
+        let late = rms_db(&processed[processed.len() - second..]);
+        assert!(late < early - 40.0, "{early:.0} dB after 1 s of silence, {late:.0} dB after 20 s");
+    }
+
+    // Adding or removing a band leaves the others playing as they were: with a band that
+    // does nothing coming and going, the result is the same as never having had it
+    #[test]
+    fn other_bands_carry_on_when_one_comes_or_goes() {
+        let curve = reference_curve();
+        let mut with_extra = curve.clone();
+        with_extra.bands.insert(3, Band { enabled: false, ..Band::default() });
+        let samples = noise(SAMPLE_RATE as usize, 4);
+        let quarter = samples.len() / 4;
+
+        let mut reference = EqProcessor::new(SAMPLE_RATE, 1);
+        reference.configure(&curve);
+        let mut expected = samples.clone();
+        reference.process(&mut expected);
+
+        let mut eq = EqProcessor::new(SAMPLE_RATE, 1);
+        let mut processed = samples.clone();
+        for (idx, block) in processed.chunks_mut(quarter).enumerate() {
+            eq.configure(if idx % 2 == 0 { &curve } else { &with_extra });
+            eq.process(block);
+        }
+        assert!(processed == expected);
+    }
+
+    // A band added where one was removed starts from silence rather than where that one left off
+    #[test]
+    fn new_bands_start_afresh() {
+        let curve = reference_curve();
+        let mut eq = EqProcessor::new(SAMPLE_RATE, 1);
+        eq.configure(&curve);
+        eq.process(&mut noise(1000, 5));
+        eq.configure(&Equalizer::default());
+        eq.configure(&curve);
+        let mut silence = vec![0.0; 1000];
+        eq.process(&mut silence);
+        assert!(silence.iter().all(|sample| *sample == 0.0));
+    }
+
+    #[test]
+    fn no_more_than_the_maximum_bands() {
+        let eq = Equalizer { preamp_db: 0.0, bands: vec![Band::default(); MAX_BANDS + 5] };
+        let mut processor = EqProcessor::new(SAMPLE_RATE, 2);
+        let filters = processor.filters.as_ptr();
+        processor.configure(&eq);
+        processor.process(&mut noise(256, 6));
+        assert_eq!(processor.slots.len(), MAX_BANDS);
+        assert_eq!(processor.filters.as_ptr(), filters);
+    }
+}
@@ -0,0 +1,1176 @@
+// Just enough of HDF5 to read SOFA files, which are netCDF-4 and so HDF5 underneath. Only what
+// SOFA writers produce is handled: numeric datasets and string attributes in the root group,
+// stored contiguously, compactly or in chunks, optionally with the shuffle and deflate filters.
+// Anything else is reported as unsupported rather than guessed at.
+//
+// The format is described at https://docs.hdfgroup.org/hdf5/develop/_f_m_t3.html
+
+use std::collections::{HashSet, VecDeque};
+use std::path::Path;
+
+type Result<T> = std::result::Result<T, String>;
+
+const SIGNATURE: &[u8; 8] = b"\x89HDF\r\n\x1a\n";
+
+// Object header message types
+const MSG_DATASPACE: u16 = 0x01;
+const MSG_LINK_INFO: u16 = 0x02;
+const MSG_DATATYPE: u16 = 0x03;
+const MSG_LINK: u16 = 0x06;
+const MSG_LAYOUT: u16 = 0x08;
+const MSG_FILTERS: u16 = 0x0B;
+const MSG_ATTRIBUTE: u16 = 0x0C;
+const MSG_CONTINUATION: u16 = 0x10;
+const MSG_SYMBOL_TABLE: u16 = 0x11;
+
+// Filter IDs
+const FILTER_DEFLATE: u16 = 1;
+const FILTER_SHUFFLE: u16 = 2;
+const FILTER_FLETCHER32: u16 = 3;
+
+// Deflate can't shrink data to less than about a thousandth, so a compressed dataset claiming
+// to be larger than this many times the file is damaged
+const DEFLATE_RATIO: usize = 1032;
+// Largest dataset read, far more than any set of head responses
+const MAX_DATASET: usize = 1 << 30;
+
+// A numeric dataset, converted to f64 and flattened in row-major order
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dataset {
+    pub shape: Vec<usize>,
+    pub values: Vec<f64>,
+}
+
+pub struct File {
+    data: Vec<u8>,
+    offset_size: usize,
+    length_size: usize,
+    // Addresses in the file are relative to this
+    base: usize,
+    root: u64,
+}
+
+struct Message {
+    kind: u16,
+    flags: u8,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Datatype {
+    Int { size: usize, signed: bool, big_endian: bool },
+    Float { size: usize, big_endian: bool },
+    String { size: usize },
+}
+
+impl Datatype {
+    fn size(&self) -> usize {
+        match *self {
+            Datatype::Int { size, .. } | Datatype::Float { size, .. } | Datatype::String { size } => size,
+        }
+    }
+
+    fn value(&self, bytes: &[u8]) -> Result<f64> {
+        let ordered = |big_endian: bool| {
+            let mut buf = [0u8; 8];
+            let n = bytes.len().min(8);
+            buf[..n].copy_from_slice(&bytes[..n]);
+            if big_endian {
+                buf[..n].reverse();
+            }
+            buf
+        };
+        match *self {
+            Datatype::Float { size: 4, big_endian } => {
+                let buf = ordered(big_endian);
+                Ok(f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]) as f64)
+            }
+            Datatype::Float { size: 8, big_endian } => Ok(f64::from_le_bytes(ordered(big_endian))),
+            Datatype::Int { size, signed, big_endian } if (1..=8).contains(&size) => {
+                let raw = u64::from_le_bytes(ordered(big_endian));
+                if signed {
+                    let shift = 64 - size * 8;
+                    Ok(((raw << shift) as i64 >> shift) as f64)
+                } else {
+                    Ok(raw as f64)
+                }
+            }
+            _ => Err("unsupported number format".to_string()),
+        }
+    }
+}
+
+enum Layout {
+    Compact(Vec<u8>),
+    Contiguous { address: Option<u64> },
+    Chunked { dims: Vec<usize>, index: ChunkIndex },
+}
+
+enum ChunkIndex {
+    // Version 1 B-tree, from layout messages before version 4
+    BTree(u64),
+    // A dataset that is one chunk: its address, and its size and filter mask when filtered
+    Single { address: u64, filtered: Option<(usize, u32)> },
+    // Every chunk stored in order, unfiltered
+    Implicit(u64),
+    FixedArray(u64),
+}
+
+struct Filter {
+    id: u16,
+    values: Vec<u32>,
+}
+
+// A stored chunk: where it starts in the dataset, where it is in the file and how it's filtered
+struct Chunk {
+    offsets: Vec<usize>,
+    address: u64,
+    size: usize,
+    filter_mask: u32,
+}
+
+// Little-endian reader over a slice of the file
+struct Cursor<'a> {
+    data: &'a [u8],
+    pos: usize,
+    offset_size: usize,
+    length_size: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn bytes(&mut self, count: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(count).filter(|end| *end <= self.data.len()).ok_or("the file is truncated")?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<()> {
+        self.bytes(count).map(|_| ())
+    }
+
+    fn uint(&mut self, size: usize) -> Result<u64> {
+        let bytes = self.bytes(size)?;
+        Ok(bytes.iter().rev().fold(0u64, |value, byte| (value << 8) | *byte as u64))
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.uint(2).map(|value| value as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.uint(4).map(|value| value as u32)
+    }
+
+    // An address, None if undefined (all bits set)
+    fn address(&mut self) -> Result<Option<u64>> {
+        let size = self.offset_size;
+        let value = self.uint(size)?;
+        let undefined = if size >= 8 { u64::MAX } else { (1u64 << (size * 8)) - 1 };
+        Ok((value != undefined).then_some(value))
+    }
+
+    fn required_address(&mut self) -> Result<u64> {
+        self.address()?.ok_or_else(|| "an address is missing".to_string())
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        let size = self.length_size;
+        self.uint(size)
+    }
+
+    fn signature(&mut self, expected: &[u8]) -> Result<()> {
+        if self.bytes(expected.len())? != expected {
+            return Err(format!("expected a {} block", String::from_utf8_lossy(expected)));
+        }
+        Ok(())
+    }
+}
+
+// Bytes needed to store values up to `max`
+fn bytes_for(max: u64) -> usize {
+    (64 - max.leading_zeros() as usize).div_ceil(8)
+}
+
+fn padded(len: usize) -> usize {
+    len.div_ceil(8) * 8
+}
+
+impl File {
+    pub fn open(path: &Path) -> Result<Self> {
+        let data = std::fs::read(path).map_err(|err| format!("Couldn't read {}: {err}", path.display()))?;
+        Self::parse(data).map_err(|err| format!("{} isn't a readable SOFA file: {err}", path.display()))
+    }
+
+    pub fn parse(data: Vec<u8>) -> Result<Self> {
+        // The superblock is at the start, or after a user block of 512, 1024, 2048... bytes
+        let start = std::iter::once(0)
+            .chain((9..).map(|shift| 1usize << shift))
+            .take_while(|start| start + SIGNATURE.len() <= data.len())
+            .find(|start| &data[*start..start + SIGNATURE.len()] == SIGNATURE)
+            .ok_or("not an HDF5 file")?;
+
+        let mut cursor = Cursor { data: &data, pos: start + SIGNATURE.len(), offset_size: 8, length_size: 8 };
+        let version = cursor.u8()?;
+        let (offset_size, length_size, base, root) = match version {
+            0 | 1 => {
+                cursor.skip(4)?;
+                let offset_size = cursor.u8()? as usize;
+                let length_size = cursor.u8()? as usize;
+                cursor.skip(1 + 4 + 4)?;
+                if version == 1 {
+                    cursor.skip(4)?;
+                }
+                cursor.offset_size = offset_size;
+                cursor.length_size = length_size;
+                let base = cursor.required_address()?;
+                cursor.skip(offset_size * 3)?;
+                // The root group's symbol table entry: name offset, then its object header
+                cursor.skip(offset_size)?;
+                let root = cursor.required_address()?;
+                (offset_size, length_size, base, root)
+            }
+            2 | 3 => {
+                let offset_size = cursor.u8()? as usize;
+                let length_size = cursor.u8()? as usize;
+                cursor.skip(1)?;
+                cursor.offset_size = offset_size;
+                cursor.length_size = length_size;
+                let base = cursor.required_address()?;
+                cursor.skip(offset_size * 2)?;
+                let root = cursor.required_address()?;
+                (offset_size, length_size, base, root)
+            }
+            version => return Err(format!("superblock version {version} isn't supported")),
+        };
+        if !(2..=8).contains(&offset_size) || !(2..=8).contains(&length_size) {
+            return Err("unusual address sizes".to_string());
+        }
+        let base = usize::try_from(base).ok().filter(|base| *base <= data.len()).ok_or("the base address is past the end of the file")?;
+        Ok(Self { data, offset_size, length_size, base, root })
+    }
+
+    // Where an address is in `data`
+    fn position(&self, address: u64) -> Result<usize> {
+        usize::try_from(address)
+            .ok()
+            .and_then(|address| self.base.checked_add(address))
+            .filter(|pos| *pos <= self.data.len())
+            .ok_or_else(|| "an address points past the end of the file".to_string())
+    }
+
+    // `length` bytes from an address
+    fn slice(&self, address: u64, length: usize) -> Result<&[u8]> {
+        let start = self.position(address)?;
+        start.checked_add(length).and_then(|end| self.data.get(start..end)).ok_or_else(|| "the data is truncated".to_string())
+    }
+
+    // The most bytes a dataset can hold: what's in the file, or what it could decompress to
+    fn size_limit(&self, compressed: bool) -> usize {
+        if compressed {
+            self.data.len().saturating_mul(DEFLATE_RATIO).min(MAX_DATASET)
+        } else {
+            self.data.len()
+        }
+    }
+
+    fn cursor(&self, address: u64) -> Result<Cursor<'_>> {
+        let pos = self.position(address)?;
+        Ok(Cursor { data: &self.data, pos, offset_size: self.offset_size, length_size: self.length_size })
+    }
+
+    // All messages of an object header, following continuation blocks
+    fn messages(&self, address: u64) -> Result<Vec<Message>> {
+        let mut messages = Vec::new();
+        let mut cursor = self.cursor(address)?;
+        // Blocks of messages still to read, and whether they use the version 2 layout
+        let mut blocks = VecDeque::new();
+        let v2 = cursor.data.get(cursor.pos..cursor.pos + 4) == Some(b"OHDR");
+        let mut creation_order = false;
+
+        if v2 {
+            cursor.skip(4)?;
+            let version = cursor.u8()?;
+            if version != 2 {
+                return Err(format!("object header version {version} isn't supported"));
+            }
+            let flags = cursor.u8()?;
+            creation_order = flags & 0x04 != 0;
+            if flags & 0x20 != 0 {
+                cursor.skip(16)?;
+            }
+            if flags & 0x10 != 0 {
+                cursor.skip(4)?;
+            }
+            let size = cursor.uint(1 << (flags & 0x03))? as usize;
+            blocks.push_back((cursor.pos, size));
+        } else {
+            let version = cursor.u8()?;
+            if version != 1 {
+                return Err(format!("object header version {version} isn't supported"));
+            }
+            cursor.skip(1)?;
+            let _count = cursor.u16()?;
+            cursor.skip(4)?;
+            let size = cursor.u32()? as usize;
+            // Messages start on the next multiple of 8 after the 12 byte prefix
+            blocks.push_back((cursor.pos + 4, size));
+        }
+
+        let mut first = true;
+        // Continuation blocks already read, so a damaged file can't send this round in circles
+        let mut visited = HashSet::new();
+        while let Some((start, size)) = blocks.pop_front() {
+            if !visited.insert(start) {
+                return Err("an object header continues into itself".to_string());
+            }
+            let mut cursor = Cursor { data: &self.data, pos: start, offset_size: self.offset_size, length_size: self.length_size };
+            let mut end = start.checked_add(size).filter(|end| *end <= self.data.len()).ok_or("an object header is truncated")?;
+            if v2 && !first {
+                // Continuation blocks have a signature up front and a checksum at the end
+                cursor.signature(b"OCHK")?;
+                end = end.checked_sub(4).ok_or("an object header is truncated")?;
+            }
+            first = false;
+            let header = if v2 { 4 + if creation_order { 2 } else { 0 } } else { 8 };
+            while cursor.pos + header <= end {
+                let (kind, length, flags) = if v2 {
+                    let kind = cursor.u8()? as u16;
+                    let length = cursor.u16()? as usize;
+                    let flags = cursor.u8()?;
+                    if creation_order {
+                        cursor.skip(2)?;
+                    }
+                    (kind, length, flags)
+                } else {
+                    let kind = cursor.u16()?;
+                    let length = cursor.u16()? as usize;
+                    let flags = cursor.u8()?;
+                    cursor.skip(3)?;
+                    (kind, length, flags)
+                };
+                let data = cursor.bytes(length)?.to_vec();
+                if kind == MSG_CONTINUATION {
+                    let mut continuation = Cursor { data: &data, pos: 0, offset_size: self.offset_size, length_size: self.length_size };
+                    let address = continuation.required_address()?;
+                    let length = continuation.length()? as usize;
+                    blocks.push_back((self.position(address)?, length));
+                } else {
+                    messages.push(Message { kind, flags, data });
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    fn cursor_over<'a>(&self, data: &'a [u8]) -> Cursor<'a> {
+        Cursor { data, pos: 0, offset_size: self.offset_size, length_size: self.length_size }
+    }
+
+    // Name -> object header address of every hard link in the root group
+    fn members(&self) -> Result<Vec<(String, u64)>> {
+        let messages = self.messages(self.root)?;
+        let mut members = Vec::new();
+        for message in &messages {
+            match message.kind {
+                MSG_SYMBOL_TABLE => {
+                    let mut cursor = self.cursor_over(&message.data);
+                    let btree = cursor.required_address()?;
+                    let heap = cursor.required_address()?;
+                    self.symbol_table(btree, heap, &mut members)?;
+                }
+                MSG_LINK => {
+                    if let Some(link) = self.link(&message.data)? {
+                        members.push(link);
+                    }
+                }
+                MSG_LINK_INFO => self.dense_links(&message.data, &mut members)?,
+                _ => {}
+            }
+        }
+        Ok(members)
+    }
+
+    // Groups from before HDF5 1.8: a B-tree of symbol table nodes, with names in a local heap
+    fn symbol_table(&self, btree: u64, heap: u64, members: &mut Vec<(String, u64)>) -> Result<()> {
+        let mut cursor = self.cursor(heap)?;
+        cursor.signature(b"HEAP")?;
+        cursor.skip(4)?;
+        cursor.length()?;
+        cursor.length()?;
+        let names = self.position(cursor.required_address()?)?;
+
+        let mut nodes = vec![btree];
+        let mut visited = HashSet::new();
+        while let Some(node) = nodes.pop() {
+            if !visited.insert(node) {
+                return Err("a group index loops back on itself".to_string());
+            }
+            let mut cursor = self.cursor(node)?;
+            cursor.signature(b"TREE")?;
+            if cursor.u8()? != 0 {
+                return Err("a group index has the wrong type".to_string());
+            }
+            let level = cursor.u8()?;
+            let entries = cursor.u16()? as usize;
+            cursor.skip(self.offset_size * 2)?;
+            for _ in 0..entries {
+                cursor.length()?;
+                let child = cursor.required_address()?;
+                if level > 0 {
+                    nodes.push(child);
+                    continue;
+                }
+                let mut symbols = self.cursor(child)?;
+                symbols.signature(b"SNOD")?;
+                symbols.skip(2)?;
+                let count = symbols.u16()?;
+                for _ in 0..count {
+                    let name = symbols.uint(self.offset_size)? as usize;
+                    let header = symbols.required_address()?;
+                    symbols.skip(4 + 4 + 16)?;
+                    members.push((self.c_string(names.checked_add(name).ok_or("a name is missing")?)?, header));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn c_string(&self, pos: usize) -> Result<String> {
+        let bytes = self.data.get(pos..).ok_or("a name is missing")?;
+        let end = bytes.iter().position(|byte| *byte == 0).ok_or("a name isn't terminated")?;
+        Ok(String::from_utf8_lossy(&bytes[..end]).into_owned())
+    }
+
+    // A link message. Soft and external links are skipped.
+    fn link(&self, data: &[u8]) -> Result<Option<(String, u64)>> {
+        let mut cursor = self.cursor_over(data);
+        cursor.skip(1)?;
+        let flags = cursor.u8()?;
+        let kind = if flags & 0x08 != 0 { cursor.u8()? } else { 0 };
+        if flags & 0x04 != 0 {
+            cursor.skip(8)?;
+        }
+        if flags & 0x10 != 0 {
+            cursor.skip(1)?;
+        }
+        let name_length = cursor.uint(1 << (flags & 0x03))? as usize;
+        let name = String::from_utf8_lossy(cursor.bytes(name_length)?).into_owned();
+        if kind != 0 {
+            return Ok(None);
+        }
+        Ok(Some((name, cursor.required_address()?)))
+    }
+
+    // Groups with many members keep their links in a fractal heap, indexed by a B-tree of name
+    // hashes. Reading the index's records gives the heap IDs of every link.
+    fn dense_links(&self, data: &[u8], members: &mut Vec<(String, u64)>) -> Result<()> {
+        let mut cursor = self.cursor_over(data);
+        cursor.skip(1)?;
+        let flags = cursor.u8()?;
+        if flags & 0x01 != 0 {
+            cursor.skip(8)?;
+        }
+        let Some(heap) = cursor.address()? else {
+            // The links are in link messages instead
+            return Ok(());
+        };
+        let names = cursor.required_address()?;
+        let heap = FractalHeap::read(self, heap)?;
+
+        let mut cursor = self.cursor(names)?;
+        cursor.signature(b"BTHD")?;
+        cursor.skip(2)?;
+        cursor.u32()?;
+        let record_size = cursor.u16()? as usize;
+        let depth = cursor.u16()?;
+        cursor.skip(2)?;
+        let root = cursor.required_address()?;
+        let records = cursor.u16()? as usize;
+        if depth > 0 {
+            return Err("groups this large aren't supported".to_string());
+        }
+
+        let mut cursor = self.cursor(root)?;
+        cursor.signature(b"BTLF")?;
+        cursor.skip(2)?;
+        for _ in 0..records {
+            let record = cursor.bytes(record_size)?;
+            // A name hash, then the heap ID
+            let object = heap.object(self, record.get(4..).ok_or("a group index record is too short")?)?;
+            if let Some(link) = self.link(&object)? {
+                members.push(link);
+            }
+        }
+        Ok(())
+    }
+
+    fn object_header(&self, name: &str) -> Result<u64> {
+        self.members()?
+            .into_iter()
+            .find(|(member, _)| member == name)
+            .map(|(_, address)| address)
+            .ok_or_else(|| format!("{name} is missing"))
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.object_header(name).is_ok()
+    }
+
+    // A dataset in the root group
+    pub fn dataset(&self, name: &str) -> Result<Dataset> {
+        let messages = self.messages(self.object_header(name)?)?;
+        let find = |kind| messages.iter().find(|message| message.kind == kind);
+        let shape = dataspace(self, &find(MSG_DATASPACE).ok_or("no dataspace")?.data)?;
+        let datatype = find(MSG_DATATYPE).ok_or("no datatype")?;
+        if datatype.flags & 0x02 != 0 {
+            return Err(format!("{name} uses a shared datatype, which isn't supported"));
+        }
+        let datatype = self.datatype(&datatype.data)?;
+        if matches!(datatype, Datatype::String { .. }) {
+            return Err(format!("{name} isn't numeric"));
+        }
+        let layout = self.layout(&find(MSG_LAYOUT).ok_or("no data layout")?.data)?;
+        let filters = match find(MSG_FILTERS) {
+            Some(message) => self.filters(&message.data)?,
+            None => Vec::new(),
+        };
+
+        let element = datatype.size();
+        if element == 0 {
+            return Err(format!("{name} has empty values"));
+        }
+        let compressed = matches!(layout, Layout::Chunked { .. }) && filters.iter().any(|filter| filter.id == FILTER_DEFLATE);
+        let size = byte_size(&shape, element, self.size_limit(compressed))?;
+        let count = size / element;
+        let raw = match layout {
+            Layout::Compact(bytes) => bytes,
+            Layout::Contiguous { address: None } => vec![0; size],
+            Layout::Contiguous { address: Some(address) } => self.slice(address, size)?.to_vec(),
+            Layout::Chunked { dims, index } => self.chunked(&shape, &dims, element, &index, &filters)?,
+        };
+        if raw.len() < size {
+            return Err(format!("{name} has less data than its shape says"));
+        }
+        let values = raw.chunks_exact(element).take(count).map(|bytes| datatype.value(bytes)).collect::<Result<_>>()?;
+        Ok(Dataset { shape, values })
+    }
+
+    fn datatype(&self, data: &[u8]) -> Result<Datatype> {
+        let mut cursor = self.cursor_over(data);
+        let class = cursor.u8()? & 0x0F;
+        let bits = cursor.bytes(3)?;
+        let size = cursor.u32()? as usize;
+        let big_endian = bits[0] & 0x01 != 0;
+        match class {
+            0 => Ok(Datatype::Int { size, signed: bits[0] & 0x08 != 0, big_endian }),
+            1 if size == 4 || size == 8 => Ok(Datatype::Float { size, big_endian }),
+            3 => Ok(Datatype::String { size }),
+            _ => Err(format!("datatype class {class} isn't supported")),
+        }
+    }
+
+    fn layout(&self, data: &[u8]) -> Result<Layout> {
+        let mut cursor = self.cursor_over(data);
+        let version = cursor.u8()?;
+        match version {
+            1 | 2 => {
+                let dimensionality = cursor.u8()? as usize;
+                let class = cursor.u8()?;
+                cursor.skip(5)?;
+                let address = if class != 0 { cursor.address()? } else { None };
+                let mut dims = Vec::new();
+                for _ in 0..dimensionality {
+                    dims.push(cursor.u32()? as usize);
+                }
+                match class {
+                    0 => {
+                        let size = cursor.u32()? as usize;
+                        Ok(Layout::Compact(cursor.bytes(size)?.to_vec()))
+                    }
+                    1 => Ok(Layout::Contiguous { address }),
+                    _ => {
+                        dims.pop();
+                        Ok(Layout::Chunked { dims, index: ChunkIndex::BTree(address.ok_or("a chunked dataset has no index")?) })
+                    }
+                }
+            }
+            3 | 4 => {
+                let class = cursor.u8()?;
+                match class {
+                    0 => {
+                        let size = cursor.u16()? as usize;
+                        Ok(Layout::Compact(cursor.bytes(size)?.to_vec()))
+                    }
+                    1 => Ok(Layout::Contiguous { address: cursor.address()? }),
+                    2 if version == 3 => {
+                        let dimensionality = cursor.u8()? as usize;
+                        let address = cursor.required_address()?;
+                        let mut dims = Vec::new();
+                        for _ in 0..dimensionality {
+                            dims.push(cursor.u32()? as usize);
+                        }
+                        // The last dimension is the size of an element
+                        dims.pop();
+                        Ok(Layout::Chunked { dims, index: ChunkIndex::BTree(address) })
+                    }
+                    2 => {
+                        let flags = cursor.u8()?;
+                        let dimensionality = cursor.u8()? as usize;
+                        let encoded = cursor.u8()? as usize;
+                        let mut dims = Vec::new();
+                        for _ in 0..dimensionality {
+                            dims.push(cursor.uint(encoded)? as usize);
+                        }
+                        dims.pop();
+                        let index_type = cursor.u8()?;
+                        let index = match index_type {
+                            1 => {
+                                let filtered = if flags & 0x02 != 0 { Some((cursor.length()? as usize, cursor.u32()?)) } else { None };
+                                ChunkIndex::Single { address: cursor.required_address()?, filtered }
+                            }
+                            2 => ChunkIndex::Implicit(cursor.required_address()?),
+                            3 => {
+                                cursor.skip(1)?;
+                                ChunkIndex::FixedArray(cursor.required_address()?)
+                            }
+                            kind => return Err(format!("chunk index type {kind} isn't supported")),
+                        };
+                        Ok(Layout::Chunked { dims, index })
+                    }
+                    class => Err(format!("layout class {class} isn't supported")),
+                }
+            }
+            version => Err(format!("layout version {version} isn't supported")),
+        }
+    }
+
+    fn filters(&self, data: &[u8]) -> Result<Vec<Filter>> {
+        let mut cursor = self.cursor_over(data);
+        let version = cursor.u8()?;
+        let count = cursor.u8()?;
+        if version == 1 {
+            cursor.skip(6)?;
+        }
+        let mut filters = Vec::new();
+        for _ in 0..count {
+            let id = cursor.u16()?;
+            let name_length = if version == 1 || id >= 256 { cursor.u16()? as usize } else { 0 };
+            cursor.u16()?;
+            let value_count = cursor.u16()? as usize;
+            cursor.skip(if version == 1 { padded(name_length) } else { name_length })?;
+            let mut values = Vec::new();
+            for _ in 0..value_count {
+                values.push(cursor.u32()?);
+            }
+            if version == 1 && value_count % 2 == 1 {
+                cursor.skip(4)?;
+            }
+            filters.push(Filter { id, values });
+        }
+        Ok(filters)
+    }
+
+    // Gather every stored chunk into one row-major buffer
+    fn chunked(&self, shape: &[usize], dims: &[usize], element: usize, index: &ChunkIndex, filters: &[Filter]) -> Result<Vec<u8>> {
+        if dims.len() != shape.len() || dims.contains(&0) {
+            return Err("chunk and dataset shapes don't match".to_string());
+        }
+        let compressed = filters.iter().any(|filter| filter.id == FILTER_DEFLATE);
+        let size = byte_size(shape, element, self.size_limit(compressed))?;
+        let chunk_bytes = byte_size(dims, element, self.size_limit(compressed))?;
+
+        let chunks = match *index {
+            ChunkIndex::BTree(address) => self.chunk_btree(address, dims.len())?,
+            ChunkIndex::Single { address, filtered } => {
+                let (size, filter_mask) = filtered.unwrap_or((chunk_bytes, 0));
+                vec![Chunk { offsets: vec![0; dims.len()], address, size, filter_mask }]
+            }
+            ChunkIndex::Implicit(address) => chunk_origins(shape, dims)?
+                .into_iter()
+                .enumerate()
+                .map(|(idx, offsets)| {
+                    let address = (idx as u64).checked_mul(chunk_bytes as u64).and_then(|offset| offset.checked_add(address));
+                    let address = address.ok_or("a chunk is past the end of the file")?;
+                    Ok(Chunk { offsets, address, size: chunk_bytes, filter_mask: 0 })
+                })
+                .collect::<Result<_>>()?,
+            ChunkIndex::FixedArray(address) => {
+                let entries = self.fixed_array(address, !filters.is_empty(), chunk_bytes)?;
+                chunk_origins(shape, dims)?
+                    .into_iter()
+                    .zip(entries)
+                    .filter_map(|(offsets, entry)| entry.map(|(address, size, filter_mask)| Chunk { offsets, address, size, filter_mask }))
+                    .collect()
+            }
+        };
+
+        let mut out = vec![0u8; size];
+        for chunk in chunks {
+            if chunk.offsets.len() != dims.len() {
+                return Err("a chunk has the wrong number of dimensions".to_string());
+            }
+            let stored = self.slice(chunk.address, chunk.size).map_err(|_| "a chunk is truncated")?;
+            let bytes = unfilter(stored, filters, chunk.filter_mask, element, chunk_bytes)?;
+            if bytes.len() < chunk_bytes {
+                return Err("a chunk is smaller than it should be".to_string());
+            }
+            copy_chunk(&bytes, &chunk.offsets, dims, shape, element, &mut out);
+        }
+        Ok(out)
+    }
+
+    fn chunk_btree(&self, address: u64, rank: usize) -> Result<Vec<Chunk>> {
+        let mut chunks = Vec::new();
+        let mut nodes = vec![address];
+        // Nodes already read, so a damaged file can't send this round in circles
+        let mut visited = HashSet::new();
+        while let Some(node) = nodes.pop() {
+            if !visited.insert(node) {
+                return Err("a chunk index loops back on itself".to_string());
+            }
+            let mut cursor = self.cursor(node)?;
+            cursor.signature(b"TREE")?;
+            if cursor.u8()? != 1 {
+                return Err("a chunk index has the wrong type".to_string());
+            }
+            let level = cursor.u8()?;
+            let entries = cursor.u16()? as usize;
+            cursor.skip(self.offset_size * 2)?;
+            for _ in 0..entries {
+                let size = cursor.u32()? as usize;
+                let filter_mask = cursor.u32()?;
+                let mut offsets = Vec::with_capacity(rank);
+                for _ in 0..rank {
+                    offsets.push(cursor.uint(8)? as usize);
+                }
+                // The element size dimension, always 0
+                cursor.uint(8)?;
+                let child = cursor.required_address()?;
+                if level > 0 {
+                    nodes.push(child);
+                } else {
+                    chunks.push(Chunk { offsets, address: child, size, filter_mask });
+                }
+            }
+        }
+        Ok(chunks)
+    }
+
+    // Entries of a fixed array chunk index: address, stored size and filter mask, or None for a
+    // chunk that was never written
+    #[allow(clippy::type_complexity)]
+    fn fixed_array(&self, address: u64, filtered: bool, chunk_bytes: usize) -> Result<Vec<Option<(u64, usize, u32)>>> {
+        let mut cursor = self.cursor(address)?;
+        cursor.signature(b"FAHD")?;
+        cursor.skip(2)?;
+        let entry_size = cursor.u8()? as usize;
+        let page_bits = cursor.u8()?;
+        let count = cursor.length()? as usize;
+        let block = cursor.required_address()?;
+        if page_bits < 64 && count > 1usize << page_bits {
+            return Err("paged chunk indexes aren't supported".to_string());
+        }
+
+        // Filtered entries hold the chunk's stored size between its address and filter mask
+        let size_bytes = entry_size.checked_sub(self.offset_size + 4).filter(|size| (1..=8).contains(size));
+        let size_bytes = match (filtered, size_bytes) {
+            (true, None) => return Err("a chunk index has entries of the wrong size".to_string()),
+            (_, size_bytes) => size_bytes.unwrap_or(0),
+        };
+
+        let mut cursor = self.cursor(block)?;
+        cursor.signature(b"FADB")?;
+        cursor.skip(2 + self.offset_size)?;
+        let stored = if filtered { entry_size } else { self.offset_size };
+        if count.checked_mul(stored).is_none_or(|size| size > cursor.data.len() - cursor.pos) {
+            return Err("a chunk index is truncated".to_string());
+        }
+        let mut entries = Vec::with_capacity(count);
+        for _ in 0..count {
+            let address = cursor.address()?;
+            if filtered {
+                let size = cursor.uint(size_bytes)? as usize;
+                let filter_mask = cursor.u32()?;
+                entries.push(address.map(|address| (address, size, filter_mask)));
+            } else {
+                entries.push(address.map(|address| (address, chunk_bytes, 0)));
+            }
+        }
+        Ok(entries)
+    }
+
+    // A string attribute of the root group (`object` empty) or of a dataset in it
+    pub fn attribute(&self, object: &str, name: &str) -> Result<Option<String>> {
+        let address = if object.is_empty() { self.root } else { self.object_header(object)? };
+        for message in self.messages(address)? {
+            if message.kind != MSG_ATTRIBUTE {
+                continue;
+            }
+            let mut cursor = self.cursor_over(&message.data);
+            let version = cursor.u8()?;
+            cursor.skip(1)?;
+            let name_size = cursor.u16()? as usize;
+            let datatype_size = cursor.u16()? as usize;
+            let dataspace_size = cursor.u16()? as usize;
+            if version == 3 {
+                cursor.skip(1)?;
+            }
+            let pad = |size: usize| if version == 1 { padded(size) } else { size };
+            let attribute_name = cursor.bytes(pad(name_size))?;
+            let attribute_name = attribute_name[..name_size.saturating_sub(1)].to_vec();
+            if attribute_name != name.as_bytes() {
+                continue;
+            }
+            let datatype = self.datatype(cursor.bytes(pad(datatype_size))?)?;
+            let shape = dataspace(self, cursor.bytes(pad(dataspace_size))?)?;
+            let Datatype::String { size } = datatype else {
+                return Ok(None);
+            };
+            let remaining = cursor.data.len() - cursor.pos;
+            let length = shape.iter().try_fold(size, |length, dim| length.checked_mul(*dim)).unwrap_or(remaining);
+            let bytes = cursor.bytes(length.min(remaining))?;
+            let text = String::from_utf8_lossy(bytes);
+            return Ok(Some(text.trim_end_matches(['\0', ' ']).to_string()));
+        }
+        Ok(None)
+    }
+}
+
+fn dataspace(file: &File, data: &[u8]) -> Result<Vec<usize>> {
+    let mut cursor = file.cursor_over(data);
+    let version = cursor.u8()?;
+    let rank = cursor.u8()? as usize;
+    cursor.u8()?;
+    match version {
+        1 => cursor.skip(5)?,
+        2 => {
+            // Null dataspaces hold nothing
+            if cursor.u8()? == 2 {
+                return Ok(vec![0]);
+            }
+        }
+        version => return Err(format!("dataspace version {version} isn't supported")),
+    }
+    let mut shape = Vec::with_capacity(rank);
+    for _ in 0..rank {
+        shape.push(cursor.length()? as usize);
+    }
+    Ok(shape)
+}
+
+// Bytes taken by an array of `dims` elements, refused when over `limit`
+fn byte_size(dims: &[usize], element: usize, limit: usize) -> Result<usize> {
+    dims.iter()
+        .try_fold(element, |size, dim| size.checked_mul(*dim))
+        .filter(|size| *size <= limit)
+        .ok_or_else(|| "a dataset is larger than the file could hold".to_string())
+}
+
+// Where every chunk starts, in the row-major order chunks are indexed in. There are never more
+// chunks than elements, which the caller has already checked the file can hold.
+fn chunk_origins(shape: &[usize], dims: &[usize]) -> Result<Vec<Vec<usize>>> {
+    let grid: Vec<usize> = shape.iter().zip(dims).map(|(size, dim)| size.div_ceil(*dim)).collect();
+    let total = grid.iter().try_fold(1usize, |total, size| total.checked_mul(*size)).ok_or("there are too many chunks")?;
+    Ok((0..total)
+        .map(|mut idx| {
+            let mut offsets = vec![0; grid.len()];
+            for axis in (0..grid.len()).rev() {
+                offsets[axis] = idx % grid[axis] * dims[axis];
+                idx /= grid[axis];
+            }
+            offsets
+        })
+        .collect())
+}
+
+// Undo a chunk's filters, last applied first. Bits set in the mask mark filters that were skipped.
+// Nothing decompresses to more than a chunk, plus a checksum.
+fn unfilter(stored: &[u8], filters: &[Filter], mask: u32, element: usize, chunk_bytes: usize) -> Result<Vec<u8>> {
+    let mut bytes = stored.to_vec();
+    for (idx, filter) in filters.iter().enumerate().rev() {
+        if mask & (1 << idx) != 0 {
+            continue;
+        }
+        bytes = match filter.id {
+            FILTER_DEFLATE => {
+                miniz_oxide::inflate::decompress_to_vec_zlib_with_limit(&bytes, chunk_bytes + 4)
+                    .map_err(|err| format!("a chunk won't decompress: {err}"))?
+            }
+            FILTER_SHUFFLE => {
+                let size = filter.values.first().map(|size| *size as usize).unwrap_or(element).max(1);
+                unshuffle(&bytes, size)
+            }
+            FILTER_FLETCHER32 => {
+                bytes.truncate(bytes.len().saturating_sub(4));
+                bytes
+            }
+            id => return Err(format!("filter {id} isn't supported")),
+        };
+    }
+    Ok(bytes)
+}
+
+// The shuffle filter stores the first byte of every element, then the second byte of every
+// element, and so on
+fn unshuffle(bytes: &[u8], size: usize) -> Vec<u8> {
+    let count = bytes.len() / size;
+    let mut out = bytes.to_vec();
+    for element in 0..count {
+        for byte in 0..size {
+            out[element * size + byte] = bytes[byte * count + element];
+        }
+    }
+    out
+}
+
+// Copy the parts of a chunk that fall inside the dataset. Edge chunks stick out past it.
+fn copy_chunk(bytes: &[u8], offsets: &[usize], dims: &[usize], shape: &[usize], element: usize, out: &mut [u8]) {
+    let rank = dims.len();
+    if rank == 0 {
+        let n = element.min(out.len());
+        out[..n].copy_from_slice(&bytes[..n]);
+        return;
+    }
+    let total: usize = dims.iter().product();
+    // Rows along the last dimension are contiguous in both, so copy a row at a time
+    let row = dims[rank - 1];
+    for start in (0..total).step_by(row) {
+        let mut idx = start;
+        let mut target = 0;
+        let mut inside = true;
+        for axis in (0..rank).rev() {
+            let position = offsets[axis].saturating_add(idx % dims[axis]);
+            idx /= dims[axis];
+            if position >= shape[axis] {
+                inside = false;
+                break;
+            }
+            let stride: usize = shape[axis + 1..].iter().product();
+            target += position * stride;
+        }
+        if !inside {
+            continue;
+        }
+        let width = row.min(shape[rank - 1] - offsets[rank - 1]);
+        out[target * element..(target + width) * element].copy_from_slice(&bytes[start * element..(start + width) * element]);
+    }
+}
+
+// Managed objects of a fractal heap, found through its doubling table of blocks
+struct FractalHeap {
+    id_offset_bytes: usize,
+    id_length_bytes: usize,
+    start_block: u64,
+    width: usize,
+    root: u64,
+    rows: usize,
+    max_direct_rows: usize,
+    filtered: bool,
+}
+
+impl FractalHeap {
+    fn read(file: &File, address: u64) -> Result<Self> {
+        let mut cursor = file.cursor(address)?;
+        cursor.signature(b"FRHP")?;
+        cursor.skip(1)?;
+        cursor.u16()?;
+        let filter_length = cursor.u16()?;
+        cursor.skip(1)?;
+        let max_managed = cursor.u32()? as u64;
+        cursor.length()?;
+        cursor.address()?;
+        cursor.length()?;
+        cursor.address()?;
+        for _ in 0..8 {
+            cursor.length()?;
+        }
+        let width = cursor.u16()? as usize;
+        let start_block = cursor.length()?;
+        let max_direct = cursor.length()?;
+        let max_heap_bits = cursor.u16()? as usize;
+        cursor.u16()?;
+        let root = cursor.required_address()?;
+        let rows = cursor.u16()? as usize;
+        if start_block == 0 || width == 0 || max_direct < start_block {
+            return Err("a heap is damaged".to_string());
+        }
+        let log2 = |value: u64| 63 - value.leading_zeros() as usize;
+        Ok(Self {
+            id_offset_bytes: max_heap_bits.div_ceil(8),
+            id_length_bytes: bytes_for(max_direct.min(max_managed)),
+            start_block,
+            width,
+            root,
+            rows,
+            max_direct_rows: log2(max_direct) - log2(start_block) + 2,
+            filtered: filter_length > 0,
+        })
+    }
+
+    fn object(&self, file: &File, id: &[u8]) -> Result<Vec<u8>> {
+        let kind = (id.first().ok_or("an empty heap ID")? >> 4) & 0x03;
+        if kind == 2 {
+            // Tiny objects live in the ID itself
+            let length = (id[0] & 0x0F) as usize + 1;
+            return id.get(1..1 + length).map(<[u8]>::to_vec).ok_or_else(|| "a heap ID is truncated".to_string());
+        }
+        if kind != 0 {
+            return Err("huge heap objects aren't supported".to_string());
+        }
+        if self.filtered {
+            return Err("filtered heaps aren't supported".to_string());
+        }
+        let mut cursor = file.cursor_over(&id[1..]);
+        let offset = cursor.uint(self.id_offset_bytes)?;
+        let length = cursor.uint(self.id_length_bytes)? as usize;
+
+        let (block, block_offset) = self.block_for(file, offset)?;
+        let address = block.checked_add(offset - block_offset).ok_or("a heap object is out of range")?;
+        file.slice(address, length).map(<[u8]>::to_vec).map_err(|_| "a heap object is truncated".to_string())
+    }
+
+    // Address and heap offset of the direct block holding `offset`
+    fn block_for(&self, file: &File, offset: u64) -> Result<(u64, u64)> {
+        if self.rows == 0 {
+            return Ok((self.root, 0));
+        }
+        let mut cursor = file.cursor(self.root)?;
+        cursor.signature(b"FHIB")?;
+        cursor.skip(1 + file.offset_size + self.id_offset_bytes)?;
+        let mut block_offset = 0u64;
+        for row in 0..self.rows {
+            let size = if row < 2 { self.start_block } else { self.start_block.checked_shl(row as u32 - 1).ok_or("a heap is damaged")? };
+            for _ in 0..self.width {
+                if row >= self.max_direct_rows {
+                    return Err("heaps this large aren't supported".to_string());
+                }
+                let address = cursor.address()?;
+                if offset < block_offset.saturating_add(size) {
+                    return address.map(|address| (address, block_offset)).ok_or_else(|| "a heap block is missing".to_string());
+                }
+                block_offset = block_offset.saturating_add(size);
+            }
+        }
+        Err("a heap object is out of range".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::sofa_writer::{self, Measurement, SofaFile};
+
+    // A small SOFA file: four measurements of 16 taps, chunked and compressed
+    fn fixture() -> Vec<u8> {
+        let measurements: Vec<Measurement> = (0..4)
+            .map(|step| {
+                let response = |ear: usize| (0..16).map(|tap| ((step * 16 + tap) as f32 * 0.01) * if ear == 0 { 1.0 } else { -1.0 }).collect();
+                Measurement { azimuth: step as f64 * 90.0, elevation: 0.0, left: response(0), right: response(1) }
+            })
+            .collect();
+        sofa_writer::bytes(&SofaFile { sample_rate: 48000.0, measurements: &measurements, cartesian: false, delay: [1.0, 2.0], chunk: 3 })
+    }
+
+    // Read everything a SOFA file is opened for
+    fn read_all(data: Vec<u8>) -> Result<Vec<Dataset>> {
+        let file = File::parse(data)?;
+        file.attribute("SourcePosition", "Type")?;
+        ["Data.IR", "Data.Delay", "Data.SamplingRate", "SourcePosition"].into_iter().map(|name| file.dataset(name)).collect()
+    }
+
+    // A file made of one structure at address 0, for checking it alone
+    fn file_of(data: Vec<u8>) -> File {
+        File { data, offset_size: 8, length_size: 8, base: 0, root: 0 }
+    }
+
+    #[test]
+    fn reads_the_fixture() {
+        let datasets = read_all(fixture()).unwrap();
+        assert_eq!(datasets[0].shape, [4, 2, 16]);
+        // Measurement 1, right ear, tap 5
+        assert_eq!(datasets[0].values[(2 + 1) * 16 + 5], -0.21f32 as f64);
+        assert_eq!(datasets[1].values, [1.0, 2.0]);
+        assert_eq!(datasets[2].values, [48000.0]);
+        assert_eq!(datasets[3].values[3..6], [90.0, 0.0, 1.2]);
+    }
+
+    // Every cut-off copy of the file is refused, none of them panic
+    #[test]
+    fn refuses_truncated_files() {
+        let data = fixture();
+        for length in 0..data.len() {
+            assert!(read_all(data[..length].to_vec()).is_err(), "cut to {length} of {} bytes", data.len());
+        }
+    }
+
+    // Damaging any byte gives an error or different values, never a panic or a runaway
+    // allocation
+    #[test]
+    fn survives_damaged_bytes() {
+        let data = fixture();
+        for pos in 0..data.len() {
+            for damage in [0xFF, 0x80, 0x01] {
+                let mut damaged = data.clone();
+                damaged[pos] ^= damage;
+                let _ = read_all(damaged);
+            }
+        }
+    }
+
+    // A dataspace far larger than the file is refused before anything is allocated for it
+    #[test]
+    fn refuses_oversized_datasets() {
+        let data = fixture();
+        // Data.SamplingRate is the only dataset with one dimension
+        let mut dataspace = vec![1, 1, 0, 0, 0, 0, 0, 0];
+        dataspace.extend(1u64.to_le_bytes());
+        let pos = data.windows(dataspace.len()).position(|window| window == dataspace).unwrap() + 8;
+        for size in [1u64 << 40, u64::MAX / 4, u64::MAX] {
+            let mut damaged = data.clone();
+            damaged[pos..pos + 8].copy_from_slice(&size.to_le_bytes());
+            let err = File::parse(damaged).unwrap().dataset("Data.SamplingRate").unwrap_err();
+            assert_eq!(err, "a dataset is larger than the file could hold");
+        }
+    }
+
+    // A chunk index node that lists itself as its child
+    #[test]
+    fn refuses_looping_chunk_indexes() {
+        let mut node = b"TREE".to_vec();
+        node.extend([1, 1, 1, 0]);
+        node.extend([u64::MAX.to_le_bytes(), u64::MAX.to_le_bytes()].concat());
+        // One entry: size, filter mask, a one-dimensional offset and the element dimension,
+        // then the child's address
+        node.extend([0u8; 4 + 4 + 8 + 8]);
+        node.extend(0u64.to_le_bytes());
+        let err = file_of(node).chunk_btree(0, 1).err().unwrap();
+        assert_eq!(err, "a chunk index loops back on itself");
+    }
+
+    // Fixed array entries too small to hold an address, size and filter mask
+    #[test]
+    fn refuses_short_fixed_array_entries() {
+        let block = 64u64;
+        let mut data = b"FAHD".to_vec();
+        data.extend([0, 0]);
+        // Entry size, page bits, entry count and the data block's address
+        data.extend([8, 10]);
+        data.extend(2u64.to_le_bytes());
+        data.extend(block.to_le_bytes());
+        data.resize(block as usize, 0);
+        data.extend(b"FADB");
+        data.extend([0, 0]);
+        data.extend(0u64.to_le_bytes());
+        data.extend([0u8; 32]);
+
+        let file = file_of(data);
+        assert_eq!(file.fixed_array(0, true, 64).err().unwrap(), "a chunk index has entries of the wrong size");
+        // Unfiltered entries are only addresses, which fit
+        assert_eq!(file.fixed_array(0, false, 64).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,63 @@
+// Peak limiter at the end of the chain, so EQ boosts and crossfeed can't push the output past
+// full scale. The gain drops at once when a frame would go over the ceiling and recovers
+// smoothly afterwards, so the output never exceeds the ceiling.
+
+use serde::{Deserialize, Serialize};
+
+use super::db_to_gain;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct LimiterSettings {
+    pub enabled: bool,
+    // Highest level let through, in dB below full scale
+    pub ceiling_db: f32,
+    // Time for the gain to recover by about two thirds
+    pub release_ms: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self { enabled: false, ceiling_db: -1.0, release_ms: 150.0 }
+    }
+}
+
+pub struct Limiter {
+    sample_rate: f64,
+    channels: usize,
+    ceiling: f64,
+    release: f64,
+    gain: f64,
+    enabled: bool,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Self { sample_rate: sample_rate as f64, channels: channels.max(1), ceiling: 1.0, release: 0.0, gain: 1.0, enabled: false }
+    }
+
+    pub fn configure(&mut self, settings: &LimiterSettings) {
+        self.enabled = settings.enabled;
+        self.ceiling = db_to_gain(settings.ceiling_db.min(0.0) as f64);
+        let release_samples = (settings.release_ms.max(1.0) as f64 / 1000.0) * self.sample_rate;
+        self.release = (-1.0 / release_samples).exp();
+        if !self.enabled {
+            self.gain = 1.0;
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if !self.enabled {
+            return;
+        }
+        for frame in samples.chunks_exact_mut(self.channels) {
+            // All channels share one gain so the stereo image doesn't shift
+            let peak = frame.iter().fold(0.0f64, |peak, sample| peak.max((*sample as f64).abs()));
+            let wanted = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+            self.gain = if wanted < self.gain { wanted } else { wanted + (self.gain - wanted) * self.release };
+            for sample in frame {
+                *sample = (*sample as f64 * self.gain) as f32;
+            }
+        }
+    }
+}
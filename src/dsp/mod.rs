@@ -0,0 +1,270 @@
+// Audio processing for sound that passes through the app. Nothing in here knows about devices,
+// threads or the UI: a Chain is given settings and blocks of interleaved f32 samples, which is
+// all the tests need to drive it from WAV files.
+
+pub mod apo;
+pub mod biquad;
+pub mod convolver;
+pub mod crossfeed;
+pub mod eq;
+pub mod hdf5;
+pub mod limiter;
+pub mod sweep;
+pub mod virtualizer;
+
+#[cfg(test)]
+mod sofa_writer;
+#[cfg(test)]
+mod test_support;
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use convolver::{ConvolutionSettings, Convolver, ImpulseResponse, PreparedImpulse};
+use crossfeed::{Crossfeed, CrossfeedSettings};
+use eq::{EqProcessor, Equalizer};
+use limiter::{Limiter, LimiterSettings};
+use virtualizer::{Hrtf, HrtfFilters, VirtualizerSettings};
+
+pub fn db_to_gain(db: f64) -> f64 {
+    10f64.powf(db / 20.0)
+}
+
+// Resample an impulse response with a windowed sinc, keeping its frequency response
+pub fn resample_response(response: &[f32], from: f64, to: f64) -> Vec<f32> {
+    if (from - to).abs() < 0.5 {
+        return response.to_vec();
+    }
+    const HALF_WIDTH: f64 = 16.0;
+    let ratio = to / from;
+    // When going down, filter out what the new rate can't hold
+    let cutoff = ratio.min(1.0);
+    let length = (response.len() as f64 * ratio).ceil() as usize;
+    (0..length)
+        .map(|idx| {
+            let time = idx as f64 / ratio;
+            let reach = HALF_WIDTH / cutoff;
+            let first = (time - reach).ceil().max(0.0) as usize;
+            let last = ((time + reach).floor() as usize).min(response.len().saturating_sub(1));
+            let mut sum = 0.0;
+            for (tap, value) in response.iter().enumerate().take(last + 1).skip(first) {
+                let x = (time - tap as f64) * cutoff;
+                let sinc = if x.abs() < 1e-9 { 1.0 } else { (std::f64::consts::PI * x).sin() / (std::f64::consts::PI * x) };
+                let window = 0.5 + 0.5 * (std::f64::consts::PI * x / HALF_WIDTH).cos();
+                sum += *value as f64 * sinc * window;
+            }
+            (sum * cutoff / ratio) as f32
+        })
+        .collect()
+}
+
+// Files the settings point at, loaded by the UI and handed to the audio thread with them
+#[derive(Clone, Default)]
+pub struct LoadedFiles {
+    pub hrtf: Option<Arc<Hrtf>>,
+    pub impulse: Option<Arc<ImpulseResponse>>,
+}
+
+// The loaded files made ready for one output, so the audio thread only has to switch over to
+// them: head responses as filters for the source's speakers, impulse responses cut into blocks
+#[derive(Clone, Default)]
+pub struct PreparedFiles {
+    pub hrtf: Option<Arc<HrtfFilters>>,
+    pub impulse: Option<Arc<PreparedImpulse>>,
+    // What they were made from and for, so files that didn't change aren't made again
+    from: LoadedFiles,
+    format: Option<Format>,
+}
+
+// An output's rate, the channels it's fed and the channels it plays
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Format {
+    pub sample_rate: u32,
+    pub source_channels: usize,
+    pub channels: usize,
+}
+
+fn same<T>(a: &Option<Arc<T>>, b: &Option<Arc<T>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
+impl PreparedFiles {
+    // Prepare `files` for `format`, keeping what was already made from the same files. Slow,
+    // so never called on the audio thread.
+    pub fn update(&mut self, files: &LoadedFiles, format: Format) {
+        let reuse = self.format == Some(format);
+        if !reuse || !same(&files.hrtf, &self.from.hrtf) {
+            self.hrtf = files.hrtf.as_ref().and_then(|hrtf| hrtf.filters(format.sample_rate, format.source_channels)).map(Arc::new);
+        }
+        if !reuse || !same(&files.impulse, &self.from.impulse) {
+            self.impulse = files.impulse.as_ref().map(|impulse| Arc::new(impulse.prepare(format.sample_rate, format.channels)));
+        }
+        self.from = files.clone();
+        self.format = Some(format);
+    }
+}
+
+// Everything the chain can do, as saved in the settings
+#[derive(Serialize, Deserialize, Clone, PartialEq, Default, Debug)]
+#[serde(default)]
+pub struct ChainSettings {
+    // Swap the left and right channels, for speakers wired the wrong way round
+    pub swap_channels: bool,
+    // Play the left and right channels mixed together on both sides
+    pub mono: bool,
+    pub eq: Equalizer,
+    // -1 plays only the left channel, 1 only the right
+    pub balance: f32,
+    pub crossfeed: CrossfeedSettings,
+    pub limiter: LimiterSettings,
+    // Surround to headphones. Run by the pipeline ahead of the chain, see virtualizer.rs.
+    pub virtualizer: VirtualizerSettings,
+    // Room correction impulse responses, run after the EQ
+    pub convolution: ConvolutionSettings,
+}
+
+impl ChainSettings {
+    // True if processing would leave the audio untouched
+    pub fn is_bypass(&self) -> bool {
+        !self.swap_channels
+            && !self.mono
+            && self.eq.is_flat()
+            && self.balance == 0.0
+            && !self.crossfeed.enabled
+            && !self.limiter.enabled
+            && !self.virtualizer.enabled
+            && !self.convolution.enabled
+    }
+}
+
+// The processors in the order they run
+pub struct Chain {
+    channels: usize,
+    swap_channels: bool,
+    mono: bool,
+    eq: EqProcessor,
+    convolver: Convolver,
+    crossfeed: Crossfeed,
+    // Gains of the first two channels
+    balance: [f32; 2],
+    limiter: Limiter,
+}
+
+impl Chain {
+    pub fn new(sample_rate: u32, channels: usize) -> Self {
+        Self {
+            channels: channels.max(1),
+            swap_channels: false,
+            mono: false,
+            eq: EqProcessor::new(sample_rate, channels),
+            convolver: Convolver::new(channels),
+            crossfeed: Crossfeed::new(sample_rate, channels),
+            balance: [1.0; 2],
+            limiter: Limiter::new(sample_rate, channels),
+        }
+    }
+
+    pub fn configure(&mut self, settings: &ChainSettings) {
+        self.swap_channels = settings.swap_channels;
+        self.mono = settings.mono;
+        self.eq.configure(&settings.eq);
+        self.convolver.configure(&settings.convolution);
+        self.crossfeed.configure(&settings.crossfeed);
+        // Turning towards one side only lowers the other, so the centre doesn't get louder
+        let balance = settings.balance.clamp(-1.0, 1.0);
+        self.balance = [(1.0 - balance).min(1.0), (1.0 + balance).min(1.0)];
+        self.limiter.configure(&settings.limiter);
+    }
+
+    pub fn set_impulse(&mut self, impulse: Option<Arc<PreparedImpulse>>) {
+        self.convolver.set_impulse(impulse);
+    }
+
+    // Frames the chain delays the sound by
+    pub fn latency(&self) -> usize {
+        self.convolver.latency()
+    }
+
+    // Process interleaved samples in place
+    pub fn process(&mut self, samples: &mut [f32]) {
+        // Both only touch the front left and right of a surround layout
+        if self.channels >= 2 && (self.swap_channels || self.mono) {
+            for frame in samples.chunks_exact_mut(self.channels) {
+                if self.swap_channels {
+                    frame.swap(0, 1);
+                }
+                if self.mono {
+                    let mixed = (frame[0] + frame[1]) * 0.5;
+                    frame[0] = mixed;
+                    frame[1] = mixed;
+                }
+            }
+        }
+        self.eq.process(samples);
+        self.convolver.process(samples);
+        self.crossfeed.process(samples);
+        if self.channels >= 2 && self.balance != [1.0; 2] {
+            for frame in samples.chunks_exact_mut(self.channels) {
+                frame[0] *= self.balance[0];
+                frame[1] *= self.balance[1];
+            }
+        }
+        self.limiter.process(samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use test_support::*;
+
+    // Boosted loud noise never comes out above the limiter's ceiling
+    #[test]
+    fn limiter_holds_the_ceiling() {
+        let dir = TempDir::new("limiter");
+        let mut settings = ChainSettings { eq: reference_curve(), ..Default::default() };
+        settings.eq.preamp_db = 12.0;
+        settings.limiter = LimiterSettings { enabled: true, ceiling_db: -1.0, release_ms: 100.0 };
+        let source: Vec<f32> = noise(SAMPLE_RATE as usize, 4).iter().map(|sample| sample * 0.9).collect();
+        let processed = process_samples(&dir, "loud.wav", &settings, 2, &source, 333);
+
+        let peak = processed.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        let peak_db = 20.0 * (peak as f64).log10();
+        assert!(peak_db <= -1.0 + 1e-4, "peak {peak_db:.3} dB for a -1 dB ceiling");
+    }
+
+    // Balance only turns the far side down
+    #[test]
+    fn balance_lowers_one_side() {
+        let dir = TempDir::new("balance");
+        let tone = sine(440.0, 0.5, 0.5);
+        let settings = ChainSettings { balance: 0.5, ..Default::default() };
+        let processed = process_samples(&dir, "balance.wav", &settings, 2, &interleave(&tone, &tone), 256);
+
+        let left = rms_db(&channel(&processed, 2, 0)) - rms_db(&tone);
+        let right = rms_db(&channel(&processed, 2, 1)) - rms_db(&tone);
+        assert!((left + 6.02).abs() < 0.01, "left {left:+.2} dB");
+        assert!(right.abs() < 1e-6, "right {right:+.2} dB");
+    }
+
+    // Swapping puts the left input on the right, and mono puts half of each side on both
+    #[test]
+    fn swap_and_mono() {
+        let dir = TempDir::new("swap-mono");
+        let left = sine(440.0, 0.5, 0.5);
+        let right = noise(left.len(), 6);
+        let stereo = interleave(&left, &right);
+
+        let swapped = process_samples(&dir, "swap.wav", &ChainSettings { swap_channels: true, ..Default::default() }, 2, &stereo, 256);
+        assert!(swapped == interleave(&right, &left));
+
+        let processed = process_samples(&dir, "mono.wav", &ChainSettings { mono: true, ..Default::default() }, 2, &stereo, 256);
+        let mixed: Vec<f32> = left.iter().zip(&right).map(|(l, r)| (l + r) * 0.5).collect();
+        assert!(channel(&processed, 2, 0) == mixed);
+        assert!(channel(&processed, 2, 1) == mixed);
+    }
+}
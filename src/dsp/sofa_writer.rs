@@ -0,0 +1,304 @@
+// Writes small SOFA files for the tests, in the oldest HDF5 layout: a version 0
+// superblock, a symbol table for the root group and version 1 object headers. The impulse
+// responses are chunked and compressed the way SOFA tools store them, so reading them back goes
+// through the same paths as a downloaded file.
+
+use std::path::Path;
+
+const UNDEFINED: u64 = u64::MAX;
+
+pub struct Measurement {
+    // Degrees anticlockwise from straight ahead, and up from the horizontal
+    pub azimuth: f64,
+    pub elevation: f64,
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+}
+
+pub struct SofaFile<'a> {
+    pub sample_rate: f64,
+    pub measurements: &'a [Measurement],
+    // Store positions as x, y, z instead of azimuth, elevation, distance
+    pub cartesian: bool,
+    // Broadband delay of each ear, in samples
+    pub delay: [f64; 2],
+    // Measurements per stored chunk of Data.IR
+    pub chunk: usize,
+}
+
+fn u16le(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn u32le(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn u64le(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn pad8(out: &mut Vec<u8>) {
+    out.resize(out.len().div_ceil(8) * 8, 0);
+}
+
+fn message(kind: u16, mut data: Vec<u8>) -> Vec<u8> {
+    pad8(&mut data);
+    let mut out = Vec::new();
+    u16le(&mut out, kind);
+    u16le(&mut out, data.len() as u16);
+    out.extend_from_slice(&[0; 4]);
+    out.extend(data);
+    out
+}
+
+fn object_header(messages: &[Vec<u8>]) -> Vec<u8> {
+    let body: Vec<u8> = messages.concat();
+    let mut out = vec![1, 0];
+    u16le(&mut out, messages.len() as u16);
+    u32le(&mut out, 1);
+    u32le(&mut out, body.len() as u32);
+    out.extend_from_slice(&[0; 4]);
+    out.extend(body);
+    out
+}
+
+fn f64_type() -> Vec<u8> {
+    let mut out = vec![0x11, 0x20, 63, 0];
+    u32le(&mut out, 8);
+    u16le(&mut out, 0);
+    u16le(&mut out, 64);
+    out.extend_from_slice(&[52, 11, 0, 52]);
+    u32le(&mut out, 1023);
+    out
+}
+
+fn string_type(size: usize) -> Vec<u8> {
+    let mut out = vec![0x13, 0, 0, 0];
+    u32le(&mut out, size as u32);
+    out
+}
+
+fn dataspace(shape: &[usize]) -> Vec<u8> {
+    let mut out = vec![1, shape.len() as u8, 0, 0, 0, 0, 0, 0];
+    for size in shape {
+        u64le(&mut out, *size as u64);
+    }
+    out
+}
+
+fn attribute(name: &str, value: &str) -> Vec<u8> {
+    let datatype = string_type(value.len());
+    let space = dataspace(&[]);
+    let mut out = vec![1, 0];
+    u16le(&mut out, name.len() as u16 + 1);
+    u16le(&mut out, datatype.len() as u16);
+    u16le(&mut out, space.len() as u16);
+    for part in [format!("{name}\0").into_bytes(), datatype, space] {
+        out.extend(part);
+        pad8(&mut out);
+    }
+    out.extend_from_slice(value.as_bytes());
+    message(0x0C, out)
+}
+
+fn doubles(values: &[f64]) -> Vec<u8> {
+    values.iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+struct Writer {
+    out: Vec<u8>,
+}
+
+impl Writer {
+    fn append(&mut self, bytes: &[u8]) -> u64 {
+        pad8(&mut self.out);
+        let address = self.out.len() as u64;
+        self.out.extend_from_slice(bytes);
+        address
+    }
+
+    fn contiguous(&mut self, shape: &[usize], values: &[f64], attributes: Vec<Vec<u8>>) -> u64 {
+        let data = doubles(values);
+        let address = self.append(&data);
+        let mut layout = vec![3, 1];
+        u64le(&mut layout, address);
+        u64le(&mut layout, data.len() as u64);
+        let mut messages = vec![message(0x01, dataspace(shape)), message(0x03, f64_type()), message(0x08, layout)];
+        messages.extend(attributes);
+        self.append(&object_header(&messages))
+    }
+
+    fn compact(&mut self, shape: &[usize], values: &[f64]) -> u64 {
+        let data = doubles(values);
+        let mut layout = vec![3, 0];
+        u16le(&mut layout, data.len() as u16);
+        layout.extend(data);
+        self.append(&object_header(&[message(0x01, dataspace(shape)), message(0x03, f64_type()), message(0x08, layout)]))
+    }
+
+    // Chunks of `chunk` rows along the first dimension, shuffled and deflated
+    fn chunked(&mut self, shape: &[usize], values: &[f64], chunk: usize) -> u64 {
+        let row: usize = shape[1..].iter().product();
+        let mut dims = shape.to_vec();
+        dims[0] = chunk;
+        let mut entries = Vec::new();
+        for first in (0..shape[0]).step_by(chunk) {
+            // Edge chunks are stored full size
+            let mut rows = values[first * row..(first + chunk).min(shape[0]) * row].to_vec();
+            rows.resize(chunk * row, 0.0);
+            let bytes = doubles(&rows);
+            let count = bytes.len() / 8;
+            let mut shuffled = vec![0u8; bytes.len()];
+            for (idx, byte) in bytes.iter().enumerate() {
+                shuffled[idx % 8 * count + idx / 8] = *byte;
+            }
+            let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&shuffled, 6);
+            let address = self.append(&compressed);
+            entries.push((first, address, compressed.len()));
+        }
+
+        let mut tree = b"TREE".to_vec();
+        tree.extend_from_slice(&[1, 0]);
+        u16le(&mut tree, entries.len() as u16);
+        u64le(&mut tree, UNDEFINED);
+        u64le(&mut tree, UNDEFINED);
+        for (first, address, size) in &entries {
+            u32le(&mut tree, *size as u32);
+            u32le(&mut tree, 0);
+            u64le(&mut tree, *first as u64);
+            for _ in 1..shape.len() {
+                u64le(&mut tree, 0);
+            }
+            u64le(&mut tree, 0);
+            u64le(&mut tree, *address);
+        }
+        u32le(&mut tree, 0);
+        u32le(&mut tree, 0);
+        for size in shape {
+            u64le(&mut tree, *size as u64);
+        }
+        u64le(&mut tree, 0);
+        let tree = self.append(&tree);
+
+        let mut layout = vec![3, 2, shape.len() as u8 + 1];
+        u64le(&mut layout, tree);
+        for size in &dims {
+            u32le(&mut layout, *size as u32);
+        }
+        u32le(&mut layout, 8);
+        let mut filters = vec![1, 2, 0, 0, 0, 0, 0, 0];
+        for id in [2u16, 1] {
+            u16le(&mut filters, id);
+            u16le(&mut filters, 0);
+            u16le(&mut filters, 0);
+            u16le(&mut filters, 1);
+            u32le(&mut filters, if id == 2 { 8 } else { 6 });
+            u32le(&mut filters, 0);
+        }
+        self.append(&object_header(&[
+            message(0x01, dataspace(shape)),
+            message(0x03, f64_type()),
+            message(0x0B, filters),
+            message(0x08, layout),
+        ]))
+    }
+}
+
+pub fn write(path: &Path, sofa: &SofaFile) {
+    std::fs::write(path, bytes(sofa)).unwrap();
+}
+
+pub fn bytes(sofa: &SofaFile) -> Vec<u8> {
+    let count = sofa.measurements.len();
+    let taps = sofa.measurements.iter().map(|measurement| measurement.left.len().max(measurement.right.len())).max().unwrap_or(0);
+    let mut ir = Vec::with_capacity(count * 2 * taps);
+    let mut positions = Vec::with_capacity(count * 3);
+    for measurement in sofa.measurements {
+        for ear in [&measurement.left, &measurement.right] {
+            ir.extend(ear.iter().map(|value| *value as f64));
+            ir.extend(std::iter::repeat_n(0.0, taps - ear.len()));
+        }
+        let (azimuth, elevation) = (measurement.azimuth.to_radians(), measurement.elevation.to_radians());
+        if sofa.cartesian {
+            positions.extend([azimuth.cos() * elevation.cos() * 1.2, azimuth.sin() * elevation.cos() * 1.2, elevation.sin() * 1.2]);
+        } else {
+            positions.extend([measurement.azimuth, measurement.elevation, 1.2]);
+        }
+    }
+
+    // The superblock is written last, once the root group's address is known
+    let mut writer = Writer { out: vec![0; 96] };
+    let kind = if sofa.cartesian { "cartesian" } else { "spherical" };
+    let mut members = vec![
+        ("Data.Delay", writer.compact(&[1, 2], &sofa.delay)),
+        ("Data.IR", writer.chunked(&[count, 2, taps], &ir, sofa.chunk.max(1))),
+        ("Data.SamplingRate", writer.contiguous(&[1], &[sofa.sample_rate], Vec::new())),
+        ("SourcePosition", writer.contiguous(&[count, 3], &positions, vec![attribute("Type", kind)])),
+    ];
+    members.sort_by_key(|(name, _)| *name);
+
+    // Names go in the local heap, after an empty one at offset 0
+    let mut names = vec![0u8; 8];
+    let mut offsets = Vec::new();
+    for (name, _) in &members {
+        offsets.push(names.len() as u64);
+        names.extend_from_slice(name.as_bytes());
+        names.push(0);
+        pad8(&mut names);
+    }
+    let names_address = writer.append(&names);
+    let mut heap = b"HEAP".to_vec();
+    heap.extend_from_slice(&[0; 4]);
+    u64le(&mut heap, names.len() as u64);
+    u64le(&mut heap, UNDEFINED);
+    u64le(&mut heap, names_address);
+    let heap = writer.append(&heap);
+
+    let mut symbols = b"SNOD".to_vec();
+    symbols.extend_from_slice(&[1, 0]);
+    u16le(&mut symbols, members.len() as u16);
+    for ((_, header), offset) in members.iter().zip(&offsets) {
+        u64le(&mut symbols, *offset);
+        u64le(&mut symbols, *header);
+        symbols.extend_from_slice(&[0; 24]);
+    }
+    let symbols = writer.append(&symbols);
+
+    let mut tree = b"TREE".to_vec();
+    tree.extend_from_slice(&[0, 0]);
+    u16le(&mut tree, 1);
+    u64le(&mut tree, UNDEFINED);
+    u64le(&mut tree, UNDEFINED);
+    u64le(&mut tree, 0);
+    u64le(&mut tree, symbols);
+    u64le(&mut tree, *offsets.last().unwrap_or(&0));
+    let tree = writer.append(&tree);
+
+    let mut table = Vec::new();
+    u64le(&mut table, tree);
+    u64le(&mut table, heap);
+    let root = writer.append(&object_header(&[
+        message(0x11, table),
+        attribute("Conventions", "SOFA"),
+        attribute("SOFAConventions", "SimpleFreeFieldHRIR"),
+    ]));
+
+    let mut superblock = b"\x89HDF\r\n\x1a\n".to_vec();
+    superblock.extend_from_slice(&[0, 0, 0, 0, 0, 8, 8, 0]);
+    u16le(&mut superblock, 4);
+    u16le(&mut superblock, 16);
+    u32le(&mut superblock, 0);
+    u64le(&mut superblock, 0);
+    u64le(&mut superblock, UNDEFINED);
+    u64le(&mut superblock, writer.out.len() as u64);
+    u64le(&mut superblock, UNDEFINED);
+    u64le(&mut superblock, 0);
+    u64le(&mut superblock, root);
+    u32le(&mut superblock, 1);
+    u32le(&mut superblock, 0);
+    u64le(&mut superblock, tree);
+    u64le(&mut superblock, heap);
+    writer.out[..superblock.len()].copy_from_slice(&superblock);
+    writer.out
+}
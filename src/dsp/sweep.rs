@@ -0,0 +1,379 @@
+// Measuring a speaker, a room or a whole playback chain with a logarithmic sine sweep, as
+// described by Angelo Farina. The sweep is played and recorded, and dividing the recording's
+// spectrum by the sweep's leaves the impulse response of everything in between. Harmonic
+// distortion caused by the sweep lands before the impulse, where it's cut off, so speakers can
+// be measured at realistic levels.
+
+use realfft::num_complex::Complex;
+use realfft::RealFftPlanner;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+use super::db_to_gain;
+
+// Silence recorded after the sweep, for the room's reverb and the devices' latency. It's also
+// how long the impulse response that's kept is.
+pub const TAIL_SECONDS: f64 = 1.0;
+// Kept ahead of the impulse's peak. The sweep only covers part of the spectrum, and the edges
+// of that band ring on both sides of the peak for tens of milliseconds at the bass end; cutting
+// that off would bend the low end of the response. Harmonic distortion lands further ahead,
+// 0.1 s for the shortest sweep.
+const PRE_SECONDS: f64 = 0.05;
+// Fades at either end of the sweep, so it doesn't start or stop with a click
+const FADE_IN_FRACTION: f64 = 0.05;
+const FADE_OUT_SECONDS: f64 = 0.005;
+// Keeps the division from blowing up where the sweep has no energy, relative to its loudest bin
+const REGULARIZATION: f64 = 1e-6;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(default)]
+pub struct SweepSettings {
+    pub start_hz: f64,
+    pub end_hz: f64,
+    pub seconds: f64,
+    // Peak level of the sweep in dBFS
+    pub level_db: f64,
+}
+
+impl Default for SweepSettings {
+    fn default() -> Self {
+        Self { start_hz: 20.0, end_hz: 20000.0, seconds: 5.0, level_db: -18.0 }
+    }
+}
+
+// The sweep at `sample_rate`, without the silence after it
+pub fn sweep(settings: &SweepSettings, sample_rate: u32) -> Vec<f32> {
+    let rate = sample_rate as f64;
+    let end = settings.end_hz.clamp(2.0, rate * 0.49);
+    let start = settings.start_hz.clamp(1.0, end / 2.0);
+    let length = (settings.seconds.clamp(1.0, 60.0) * rate) as usize;
+    let duration = length as f64 / rate;
+    let octaves = (end / start).ln();
+    let amplitude = db_to_gain(settings.level_db.min(0.0));
+    let fade_in = ((length as f64 * FADE_IN_FRACTION) as usize).max(1);
+    let fade_out = ((rate * FADE_OUT_SECONDS) as usize).max(1);
+
+    (0..length)
+        .map(|idx| {
+            let time = idx as f64 / rate;
+            let phase = 2.0 * std::f64::consts::PI * start * duration / octaves * ((time / duration * octaves).exp() - 1.0);
+            let fade = if idx < fade_in {
+                0.5 - 0.5 * (std::f64::consts::PI * idx as f64 / fade_in as f64).cos()
+            } else if length - idx <= fade_out {
+                0.5 - 0.5 * (std::f64::consts::PI * (length - idx) as f64 / fade_out as f64).cos()
+            } else {
+                1.0
+            };
+            (amplitude * fade * phase.sin()) as f32
+        })
+        .collect()
+}
+
+fn spectrum(samples: &[f32], size: usize) -> Vec<Complex<f64>> {
+    let fft = RealFftPlanner::<f64>::new().plan_fft_forward(size);
+    let mut input = fft.make_input_vec();
+    for (out, sample) in input.iter_mut().zip(samples) {
+        *out = *sample as f64;
+    }
+    let mut output = fft.make_output_vec();
+    // Only fails on wrongly sized buffers, which are all made by the plan
+    let _ = fft.process(&mut input, &mut output);
+    output
+}
+
+fn inverse(mut spectrum: Vec<Complex<f64>>, size: usize) -> Vec<f64> {
+    let fft = RealFftPlanner::<f64>::new().plan_fft_inverse(size);
+    // A real signal's first and last bins have no imaginary part; rounding can leave some
+    let last = spectrum.len() - 1;
+    spectrum[0].im = 0.0;
+    spectrum[last].im = 0.0;
+    let mut output = fft.make_output_vec();
+    let _ = fft.process(&mut spectrum, &mut output);
+    output.iter_mut().for_each(|sample| *sample /= size as f64);
+    output
+}
+
+// Convolve two signals in the frequency domain. The result is as long as both together.
+pub fn convolve(signal: &[f32], response: &[f32]) -> Vec<f32> {
+    let length = signal.len() + response.len().max(1) - 1;
+    let size = length.next_power_of_two().max(2);
+    let product = spectrum(signal, size).iter().zip(spectrum(response, size)).map(|(x, h)| x * h).collect();
+    inverse(product, size)[..length].iter().map(|sample| *sample as f32).collect()
+}
+
+// What a measurement found
+pub struct Response {
+    pub sample_rate: f64,
+    // Starts a little ahead of the peak and lasts TAIL_SECONDS after it
+    pub impulse: Vec<f32>,
+    // Where the peak is in `impulse`
+    pub peak: usize,
+    // Samples from the start of the recording to the peak: the devices' latency plus the time
+    // sound takes to reach the microphone
+    pub delay: usize,
+    // Loudest sample of the recording, in dBFS, to tell whether the level was right
+    pub recorded_peak_db: f64,
+    // Power of the impulse at each bin, summed up to that bin, for smoothing over any band
+    cumulative: Vec<f64>,
+    bin_hz: f64,
+}
+
+// Recover the impulse response from a recording of `sweep`. The recording may start and end
+// anywhere, as long as it holds the whole sweep and some silence after it.
+pub fn deconvolve(sweep: &[f32], recording: &[f32], sample_rate: f64) -> Result<Response, String> {
+    let recorded_peak = recording.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())) as f64;
+    let recorded_peak_db = 20.0 * recorded_peak.max(1e-30).log10();
+    if recorded_peak_db < -100.0 {
+        return Err("Nothing was recorded: check the input and its level".to_string());
+    }
+    if sweep.is_empty() {
+        return Err("The sweep is empty".to_string());
+    }
+
+    let size = (recording.len() + sweep.len()).next_power_of_two();
+    let played = spectrum(sweep, size);
+    let heard = spectrum(recording, size);
+    let floor = played.iter().map(|bin| bin.norm_sqr()).fold(0.0, f64::max) * REGULARIZATION;
+    let divided = heard.iter().zip(&played).map(|(heard, played)| heard * played.conj() / (played.norm_sqr() + floor)).collect();
+    let response = inverse(divided, size);
+
+    // Distortion wraps around to the end, so the impulse is looked for where the recording is
+    let delay = response[..recording.len()]
+        .iter()
+        .enumerate()
+        .fold((0, 0.0f64), |best, (idx, value)| if value.abs() > best.1 { (idx, value.abs()) } else { best })
+        .0;
+    // The division is circular, so what comes before the start of the recording is at the end
+    let pre = (PRE_SECONDS * sample_rate) as usize;
+    let start = delay + size - pre;
+    let length = (TAIL_SECONDS * sample_rate) as usize + pre;
+    let impulse: Vec<f32> = (start..start + length.min(size)).map(|idx| response[idx % size] as f32).collect();
+
+    let fft_size = impulse.len().next_power_of_two().max(1 << 16);
+    let mut total = 0.0;
+    let cumulative = spectrum(&impulse, fft_size)
+        .iter()
+        .map(|bin| {
+            total += bin.norm_sqr();
+            total
+        })
+        .collect();
+    Ok(Response {
+        sample_rate,
+        impulse,
+        peak: pre,
+        delay,
+        recorded_peak_db,
+        cumulative,
+        bin_hz: sample_rate / fft_size as f64,
+    })
+}
+
+impl Response {
+    // Level at `freq` in dB, averaged over `smoothing` octaves around it (0 for none)
+    pub fn magnitude_db(&self, freq: f64, smoothing: f64) -> f64 {
+        let last = self.cumulative.len() - 1;
+        let bin = |freq: f64| (freq / self.bin_hz).round().clamp(0.0, last as f64) as usize;
+        let centre = bin(freq);
+        let (low, high) = if smoothing > 0.0 {
+            let half = 2f64.powf(smoothing / 2.0);
+            (bin(freq / half).min(centre), bin(freq * half).max(centre))
+        } else {
+            (centre, centre)
+        };
+        let below = if low == 0 { 0.0 } else { self.cumulative[low - 1] };
+        let power = (self.cumulative[high] - below) / (high - low + 1) as f64;
+        10.0 * power.max(1e-30).log10()
+    }
+
+    // Frequencies to list the response at: 48 per octave from 10 Hz to just below Nyquist
+    pub fn frequencies(&self) -> impl Iterator<Item = f64> {
+        let top = self.sample_rate * 0.48;
+        (0..).map(|step| 10.0 * 2f64.powf(step as f64 / 48.0)).take_while(move |freq| *freq <= top)
+    }
+
+    // The frequency response as CSV, in the form REW and spreadsheets import
+    pub fn to_csv(&self, smoothing: f64) -> String {
+        let mut csv = String::from("frequency_hz,magnitude_db\n");
+        for freq in self.frequencies() {
+            csv.push_str(&format!("{freq:.3},{:.3}\n", self.magnitude_db(freq, smoothing)));
+        }
+        csv
+    }
+
+    // The impulse response as a mono 32-bit float WAV file
+    pub fn write_wav(&self, path: &Path) -> Result<(), String> {
+        let describe = |err: hound::Error| format!("Couldn't write {}: {err}", path.display());
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: self.sample_rate.round() as u32,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let mut writer = hound::WavWriter::create(path, spec).map_err(describe)?;
+        for sample in &self.impulse {
+            writer.write_sample(*sample).map_err(describe)?;
+        }
+        writer.finalize().map_err(describe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::biquad::{Biquad, Coefficients};
+    use crate::dsp::resample_response;
+    use crate::dsp::test_support::*;
+
+    // The sweep followed by the silence recorded after it
+    fn sweep_signal(settings: &SweepSettings) -> (Vec<f32>, Vec<f32>) {
+        let sweep = sweep(settings, SAMPLE_RATE);
+        let mut signal = sweep.clone();
+        signal.resize(sweep.len() + (TAIL_SECONDS * SAMPLE_RATE as f64) as usize, 0.0);
+        (sweep, signal)
+    }
+
+    // Largest difference between a measured response and the expected one, in dB. It starts an
+    // octave above the sweep, since the sweep fades in over its first half octave and the edge of
+    // its band ripples.
+    fn response_error(response: &Response, top: f64, expected: impl Fn(f64) -> f64) -> f64 {
+        response
+            .frequencies()
+            .filter(|freq| (40.0..=top).contains(freq))
+            .map(|freq| (response.magnitude_db(freq, 0.0) - expected(freq)).abs())
+            .fold(0.0, f64::max)
+    }
+
+    // A direct sound and one echo, played through the recording's delay
+    #[test]
+    fn finds_a_known_impulse() {
+        let rate = SAMPLE_RATE as f64;
+        let (sweep, signal) = sweep_signal(&SweepSettings::default());
+        let mut echo = vec![0.0; 2000 + 301];
+        echo[2000] = 0.5;
+        echo[2300] = -0.25;
+        let response = deconvolve(&sweep, &convolve(&signal, &echo), rate).unwrap();
+
+        assert_eq!(response.delay, 2000);
+        let peak = response.impulse.iter().enumerate().fold(0, |best, (idx, value)| if value.abs() > response.impulse[best].abs() { idx } else { best });
+        assert_eq!(peak, response.peak);
+        // The sweep stops at 20 kHz, which rounds off the peaks, but the echo keeps its level
+        // against the direct sound
+        let direct = response.impulse[response.peak];
+        let reflected = response.impulse[response.peak + 300];
+        assert!(direct > 0.4, "peak {direct}");
+        assert!((reflected / direct + 0.5).abs() < 0.01, "echo at {} of the peak", reflected / direct);
+
+        // 0.5 - 0.25 z^-300 swings between 0.25 and 0.75
+        let power = |freq: f64| {
+            let phase = 2.0 * std::f64::consts::PI * freq * 300.0 / rate;
+            (0.5 - 0.25 * Complex::new(phase.cos(), -phase.sin())).norm_sqr()
+        };
+        let worst = response_error(&response, 16000.0, |freq| 10.0 * power(freq).log10());
+        assert!(worst < 0.1, "{worst:.3} dB from the echo's response");
+        // Smoothed over an octave, the comb's notches fill in
+        let (low, high) = (1000.0 / 2f64.sqrt(), 1000.0 * 2f64.sqrt());
+        let average = (0..1000).map(|step| power(low + (high - low) * step as f64 / 1000.0)).sum::<f64>() / 1000.0;
+        let smoothed = response.magnitude_db(1000.0, 1.0);
+        assert!((smoothed - 10.0 * average.log10()).abs() < 0.1, "{smoothed:.3} dB smoothed at 1 kHz");
+    }
+
+    // A sweep recorded straight back, late and at another rate, gives a flat response and the
+    // right delay
+    #[test]
+    fn measures_a_loopback() {
+        let rate = SAMPLE_RATE as f64;
+        let (sweep, signal) = sweep_signal(&SweepSettings::default());
+        let mut recording = vec![0.0; 1234];
+        recording.extend_from_slice(&signal);
+        let response = deconvolve(&sweep, &recording, rate).unwrap();
+        assert_eq!(response.delay, 1234);
+        assert!(response_error(&response, 16000.0, |_| 0.0) < 0.1);
+        assert!((response.recorded_peak_db - -18.0).abs() < 0.01);
+
+        // An input running at 44.1 kHz, resampled to the sweep's rate as the app does
+        let recorded = resample_response(&recording, rate, 44100.0);
+        let resampled = deconvolve(&sweep, &resample_response(&recorded, 44100.0, rate), rate).unwrap();
+        assert_eq!(resampled.delay, 1234);
+        assert!(response_error(&resampled, 16000.0, |_| 0.0) < 0.1);
+    }
+
+    #[test]
+    fn measures_filters() {
+        let rate = SAMPLE_RATE as f64;
+        let filters = [
+            Coefficients::peaking(100.0, 6.0, 2.0, rate),
+            Coefficients::high_shelf(5000.0, -4.0, 0.7, rate),
+            Coefficients::high_pass(40.0, 0.7, rate),
+        ];
+        let (sweep, signal) = sweep_signal(&SweepSettings::default());
+        let mut states = [Biquad::default(); 3];
+        let mut recording = vec![0.0; 500];
+        let hiss = noise(signal.len(), 15);
+        for (sample, hiss) in signal.iter().zip(&hiss) {
+            let filtered = filters.iter().zip(&mut states).fold(*sample as f64, |sample, (filter, state)| state.process(filter, sample));
+            recording.push(filtered as f32 + hiss * 1e-4);
+        }
+        let response = deconvolve(&sweep, &recording, rate).unwrap();
+        let expected = |freq: f64| filters.iter().map(|filter| 20.0 * filter.magnitude(freq, rate).log10()).sum::<f64>();
+        let worst = response_error(&response, 16000.0, expected);
+        assert!(worst < 0.1, "{worst:.3} dB from the filters' response with noise at -80 dBFS");
+    }
+
+    // Harmonic distortion shows up before the impulse and is cut off, so a distorting system
+    // still measures flat. The recording goes through an input's AC coupling, since the second
+    // harmonic comes with DC, and is only checked where the third harmonic doesn't alias.
+    #[test]
+    fn ignores_distortion() {
+        let rate = SAMPLE_RATE as f64;
+        let settings = SweepSettings { level_db: -6.0, ..Default::default() };
+        let (sweep, signal) = sweep_signal(&settings);
+        let coupling = Coefficients::high_pass(5.0, 0.7, rate);
+        let mut state = Biquad::default();
+        let recording: Vec<f32> =
+            signal.iter().map(|x| x + 0.2 * x * x + 0.05 * x * x * x).map(|x| state.process(&coupling, x as f64) as f32).collect();
+        let response = deconvolve(&sweep, &recording, rate).unwrap();
+        assert_eq!(response.delay, 0);
+        // The third harmonic term also adds 3/4 of its level to the fundamental
+        let amplitude = db_to_gain(settings.level_db);
+        let compression = 20.0 * (1.0 + 0.75 * 0.05 * amplitude * amplitude).log10();
+        let worst = response_error(&response, 6000.0, |freq| compression + 20.0 * coupling.magnitude(freq, rate).log10());
+        assert!(worst < 0.1, "{worst:.3} dB from flat up to 6 kHz with 2nd and 3rd harmonic distortion");
+    }
+
+    #[test]
+    fn refuses_silence() {
+        let (sweep, signal) = sweep_signal(&SweepSettings::default());
+        assert!(deconvolve(&sweep, &vec![0.0; signal.len()], SAMPLE_RATE as f64).is_err());
+        assert!(deconvolve(&[], &signal, SAMPLE_RATE as f64).is_err());
+    }
+
+    // The CSV holds the plotted curve and the WAV the impulse, unchanged
+    #[test]
+    fn exports_measurements() {
+        let (sweep, signal) = sweep_signal(&SweepSettings::default());
+        let mut recording = vec![0.0; 100];
+        recording.extend(signal.iter().map(|sample| sample * 0.5));
+        let response = deconvolve(&sweep, &recording, SAMPLE_RATE as f64).unwrap();
+
+        let csv = response.to_csv(1.0 / 12.0);
+        assert!(csv.starts_with("frequency_hz,magnitude_db\n"));
+        let rows: Vec<(f64, f64)> = csv
+            .lines()
+            .skip(1)
+            .filter_map(|line| line.split_once(','))
+            .filter_map(|(freq, db)| Some((freq.parse().ok()?, db.parse().ok()?)))
+            .collect();
+        assert_eq!(rows.len(), response.frequencies().count());
+        for (freq, db) in rows.iter().filter(|(freq, _)| (40.0..=16000.0).contains(freq)) {
+            assert!((db + 6.0206).abs() < 0.1, "{db} dB at {freq} Hz");
+        }
+
+        let dir = TempDir::new("sweep");
+        let path = dir.path("measured_impulse.wav");
+        response.write_wav(&path).unwrap();
+        let (spec, samples) = read_wav(&path);
+        assert_eq!((spec.sample_rate, spec.channels), (SAMPLE_RATE, 1));
+        assert_eq!(samples, response.impulse);
+    }
+}
@@ -0,0 +1,130 @@
+// Shared by the DSP tests: WAV files in a temporary folder, run through the chain the way the
+// audio callback would, and test signals that come out the same on every run
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use super::convolver::ImpulseResponse;
+use super::eq::{Band, BandKind, Equalizer};
+use super::{Chain, ChainSettings};
+
+pub const SAMPLE_RATE: u32 = 48000;
+
+// A folder for one test's files, removed when the test ends
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let path = std::env::temp_dir().join(format!("audioapp2-dsp-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self, file: &str) -> PathBuf {
+        self.0.join(file)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+pub fn read_wav(path: &Path) -> (hound::WavSpec, Vec<f32>) {
+    let mut reader = hound::WavReader::open(path).unwrap();
+    let spec = reader.spec();
+    let samples = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().map(Result::unwrap).collect(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.unwrap() as f32 / scale).collect()
+        }
+    };
+    (spec, samples)
+}
+
+pub fn write_wav(path: &Path, channels: u16, samples: &[f32]) {
+    let spec = hound::WavSpec { channels, sample_rate: SAMPLE_RATE, bits_per_sample: 32, sample_format: hound::SampleFormat::Float };
+    let mut writer = hound::WavWriter::create(path, spec).unwrap();
+    for sample in samples {
+        writer.write_sample(*sample).unwrap();
+    }
+    writer.finalize().unwrap();
+}
+
+// Run a WAV file through the chain in blocks of `block` frames, returning what comes out
+pub fn process_file(settings: &ChainSettings, input: &Path, block: usize) -> Vec<f32> {
+    let (spec, mut samples) = read_wav(input);
+    let channels = spec.channels as usize;
+    let mut chain = Chain::new(spec.sample_rate, channels);
+    chain.configure(settings);
+    if settings.convolution.enabled {
+        let impulse = ImpulseResponse::load(&settings.convolution.files).unwrap();
+        chain.set_impulse(Some(Arc::new(impulse.prepare(spec.sample_rate, channels))));
+    }
+    for chunk in samples.chunks_mut(block * channels) {
+        chain.process(chunk);
+    }
+    samples
+}
+
+// Write `samples` to `name` and run them through the chain
+pub fn process_samples(dir: &TempDir, name: &str, settings: &ChainSettings, channels: u16, samples: &[f32], block: usize) -> Vec<f32> {
+    let input = dir.path(name);
+    write_wav(&input, channels, samples);
+    process_file(settings, &input, block)
+}
+
+pub fn sine(freq: f64, seconds: f64, amplitude: f64) -> Vec<f32> {
+    let frames = (SAMPLE_RATE as f64 * seconds) as usize;
+    (0..frames)
+        .map(|idx| (amplitude * (2.0 * std::f64::consts::PI * freq * idx as f64 / SAMPLE_RATE as f64).sin()) as f32)
+        .collect()
+}
+
+// Noise between -1 and 1 that's the same for the same seed
+pub fn noise(frames: usize, seed: u64) -> Vec<f32> {
+    let mut state = seed;
+    (0..frames)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+        })
+        .collect()
+}
+
+pub fn interleave(left: &[f32], right: &[f32]) -> Vec<f32> {
+    left.iter().zip(right).flat_map(|(l, r)| [*l, *r]).collect()
+}
+
+pub fn channel(samples: &[f32], channels: usize, idx: usize) -> Vec<f32> {
+    samples.iter().skip(idx).step_by(channels).copied().collect()
+}
+
+pub fn rms_db(samples: &[f32]) -> f64 {
+    let power = samples.iter().map(|sample| (*sample as f64).powi(2)).sum::<f64>() / samples.len() as f64;
+    10.0 * power.max(1e-30).log10()
+}
+
+pub fn band(kind: BandKind, freq: f32, gain_db: f32, q: f32) -> Band {
+    Band { kind, enabled: true, freq, gain_db, q }
+}
+
+// A curve like a headphone correction preset, using every kind of band
+pub fn reference_curve() -> Equalizer {
+    Equalizer {
+        preamp_db: -4.0,
+        bands: vec![
+            band(BandKind::HighPass, 25.0, 0.0, 0.71),
+            band(BandKind::LowShelf, 105.0, 5.5, 0.7),
+            band(BandKind::Peaking, 180.0, -3.0, 1.4),
+            band(BandKind::Peaking, 2900.0, 4.0, 2.0),
+            band(BandKind::Peaking, 6100.0, -6.5, 4.5),
+            band(BandKind::HighShelf, 10000.0, -2.0, 0.7),
+            band(BandKind::LowPass, 18000.0, 0.0, 0.71),
+        ],
+    }
+}
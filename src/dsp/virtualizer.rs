@@ -0,0 +1,430 @@
+// Virtual surround for headphones: every speaker of a 5.1 or 7.1 (or stereo or quad) source is
+// played through the head-related impulse responses measured from where that speaker would
+// stand, and the results are summed into the two ears. The responses come from a SOFA file
+// (SimpleFreeFieldHRIR), as published for dummy heads and for people measured in labs.
+//
+// This runs before the rest of the chain, in place of the plain channel mapping, since it's the
+// only step that takes more channels in than it gives out.
+
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+
+use super::{hdf5, resample_response};
+
+// Longest impulse response used, in samples at the output rate. Head responses are over in a
+// few milliseconds; longer files mostly hold room and silence, which would only cost CPU.
+const MAX_TAPS: usize = 256;
+// Samples kept ahead of the earliest onset, so the first peak isn't clipped
+const ONSET_MARGIN: usize = 8;
+// The LFE channel has no direction and goes to both ears, 3 dB down as in common downmixes
+const LFE_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+#[serde(default)]
+pub struct VirtualizerSettings {
+    pub enabled: bool,
+    // The SOFA file to take the head responses from
+    pub sofa: String,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Speaker {
+    // Degrees anticlockwise from straight ahead, as SOFA counts them, so left is positive
+    At(f64),
+    Lfe,
+}
+
+// Where each channel's speaker stands, in the usual WAVE channel order, for the layouts we know
+pub fn layout(channels: usize) -> Option<&'static [Speaker]> {
+    use Speaker::{At, Lfe};
+    match channels {
+        2 => Some(&[At(30.0), At(-30.0)]),
+        4 => Some(&[At(30.0), At(-30.0), At(135.0), At(-135.0)]),
+        6 => Some(&[At(30.0), At(-30.0), At(0.0), Lfe, At(110.0), At(-110.0)]),
+        8 => Some(&[At(30.0), At(-30.0), At(0.0), Lfe, At(150.0), At(-150.0), At(90.0), At(-90.0)]),
+        _ => None,
+    }
+}
+
+// A set of head-related impulse responses
+pub struct Hrtf {
+    pub sample_rate: f64,
+    // Unit vectors towards each measured source: x ahead, y to the left, z up
+    directions: Vec<[f64; 3]>,
+    // Left and right ear responses of each measurement
+    responses: Vec<[Vec<f32>; 2]>,
+}
+
+impl Hrtf {
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = hdf5::File::open(path)?;
+        Self::from_sofa(&file).map_err(|err| format!("{}: {err}", path.display()))
+    }
+
+    pub fn from_sofa(file: &hdf5::File) -> Result<Self, String> {
+        let ir = file.dataset("Data.IR")?;
+        let [measurements, receivers, taps] = ir.shape[..] else {
+            return Err("Data.IR should have three dimensions".to_string());
+        };
+        if receivers != 2 {
+            return Err(format!("there are {receivers} receivers instead of two ears"));
+        }
+        if measurements == 0 || taps == 0 {
+            return Err("there are no measurements".to_string());
+        }
+        let sample_rate = file.dataset("Data.SamplingRate")?.values.first().copied().unwrap_or(0.0);
+        if !(1000.0..=768000.0).contains(&sample_rate) {
+            return Err(format!("the sample rate of {sample_rate} Hz makes no sense"));
+        }
+
+        let positions = file.dataset("SourcePosition")?;
+        if positions.shape.last() != Some(&3) || positions.values.len() / 3 != measurements {
+            return Err("SourcePosition doesn't have a position for every measurement".to_string());
+        }
+        let cartesian = file.attribute("SourcePosition", "Type")?.is_some_and(|kind| kind.eq_ignore_ascii_case("cartesian"));
+        let directions = positions
+            .values
+            .chunks_exact(3)
+            .map(|position| {
+                let [x, y, z] = if cartesian {
+                    [position[0], position[1], position[2]]
+                } else {
+                    let (azimuth, elevation) = (position[0].to_radians(), position[1].to_radians());
+                    [azimuth.cos() * elevation.cos(), azimuth.sin() * elevation.cos(), elevation.sin()]
+                };
+                let length = (x * x + y * y + z * z).sqrt().max(1e-9);
+                [x / length, y / length, z / length]
+            })
+            .collect();
+
+        // Broadband delays in samples, per ear and possibly per measurement
+        let delays = if file.has("Data.Delay") { file.dataset("Data.Delay")?.values } else { Vec::new() };
+        if delays.iter().any(|delay| *delay > sample_rate) {
+            return Err("Data.Delay holds delays of more than a second".to_string());
+        }
+        let delay = |measurement: usize, ear: usize| {
+            let idx = if delays.len() >= measurements * 2 { measurement * 2 + ear } else { ear };
+            delays.get(idx).map(|delay| delay.max(0.0).round() as usize).unwrap_or(0)
+        };
+
+        let responses = (0..measurements)
+            .map(|measurement| {
+                [0, 1].map(|ear| {
+                    let start = (measurement * 2 + ear) * taps;
+                    let mut response = vec![0.0; delay(measurement, ear)];
+                    response.extend(ir.values[start..start + taps].iter().map(|value| *value as f32));
+                    response
+                })
+            })
+            .collect();
+        Ok(Self { sample_rate, directions, responses })
+    }
+
+    pub fn measurements(&self) -> usize {
+        self.responses.len()
+    }
+
+    // The measurement closest to a direction on the horizontal plane
+    pub fn nearest(&self, azimuth: f64) -> usize {
+        let (y, x) = azimuth.to_radians().sin_cos();
+        let mut best = 0;
+        let mut best_dot = f64::MIN;
+        for (idx, direction) in self.directions.iter().enumerate() {
+            let dot = direction[0] * x + direction[1] * y;
+            if dot > best_dot {
+                best = idx;
+                best_dot = dot;
+            }
+        }
+        best
+    }
+
+    pub fn response(&self, measurement: usize) -> &[Vec<f32>; 2] {
+        &self.responses[measurement]
+    }
+
+    // Filters for the speakers of a source with `source_channels` channels, played at
+    // `sample_rate`. None for layouts without known speaker positions.
+    pub fn filters(&self, sample_rate: u32, source_channels: usize) -> Option<HrtfFilters> {
+        let speakers = layout(source_channels)?;
+        let sample_rate = sample_rate as f64;
+        let mut lfe = None;
+        let mut responses = Vec::new();
+        for (channel, speaker) in speakers.iter().enumerate() {
+            match speaker {
+                Speaker::At(azimuth) => {
+                    let response = self.response(self.nearest(*azimuth));
+                    responses.push((channel, response.clone().map(|ear| resample_response(&ear, self.sample_rate, sample_rate))));
+                }
+                Speaker::Lfe => lfe = Some(channel),
+            }
+        }
+
+        // Drop the silence before the sound reaches the nearest ear, the same for every filter
+        // so the differences in arrival time between the ears stay
+        let threshold = responses
+            .iter()
+            .flat_map(|(_, ears)| ears.iter().flatten())
+            .fold(0.0f32, |peak, value| peak.max(value.abs()))
+            * 0.01;
+        let onset = responses
+            .iter()
+            .flat_map(|(_, ears)| ears.iter())
+            .filter_map(|ear| ear.iter().position(|value| value.abs() > threshold))
+            .min()
+            .unwrap_or(0)
+            .saturating_sub(ONSET_MARGIN);
+        let taps = responses
+            .iter()
+            .flat_map(|(_, ears)| ears.iter())
+            .map(|ear| ear.len().saturating_sub(onset))
+            .max()
+            .unwrap_or(0)
+            .clamp(1, MAX_TAPS);
+
+        // Give the response from straight ahead the energy of a plain impulse, so switching the
+        // virtualizer on doesn't change the loudness much
+        let reference = self.response(self.nearest(0.0)).clone().map(|ear| resample_response(&ear, self.sample_rate, sample_rate));
+        let energy = reference.iter().map(|ear| ear.iter().map(|value| (*value as f64).powi(2)).sum::<f64>()).sum::<f64>() / 2.0;
+        let gain = if energy > 1e-12 { (1.0 / energy.sqrt()) as f32 } else { 1.0 };
+
+        let sources = responses
+            .into_iter()
+            .map(|(channel, ears)| {
+                let filters = ears.map(|ear| {
+                    let mut filter: Vec<f32> = ear.iter().skip(onset).take(taps).map(|value| value * gain).collect();
+                    filter.resize(taps, 0.0);
+                    // A short fade at the end, in case the response was cut off
+                    let fade = (taps / 16).max(1);
+                    for (idx, value) in filter.iter_mut().rev().take(fade).enumerate() {
+                        *value *= idx as f32 / fade as f32;
+                    }
+                    filter.reverse();
+                    filter
+                });
+                (channel, filters)
+            })
+            .collect();
+        Some(HrtfFilters { sources, lfe, taps })
+    }
+}
+
+// The ear filters of every speaker in one layout, made for one output rate. Resampling and
+// trimming the responses takes too long for the audio thread, so these are made beforehand and
+// handed over ready to use.
+pub struct HrtfFilters {
+    // (channel, filters) per speaker, filters stored back to front so each output sample is a
+    // dot product with the history
+    sources: Vec<(usize, [Vec<f32>; 2])>,
+    lfe: Option<usize>,
+    taps: usize,
+}
+
+pub struct Virtualizer {
+    source_channels: usize,
+    channels: usize,
+    enabled: bool,
+    filters: Option<Arc<HrtfFilters>>,
+    // Recent input of each source channel, room for the longest filter twice over: each sample
+    // is written twice so the last `taps` are always one contiguous slice
+    history: Vec<Vec<f32>>,
+    position: usize,
+}
+
+impl Virtualizer {
+    pub fn new(source_channels: usize, channels: usize) -> Self {
+        let source_channels = source_channels.max(1);
+        let history = if layout(source_channels).is_some() { vec![vec![0.0; MAX_TAPS * 2]; source_channels] } else { Vec::new() };
+        Self { source_channels, channels: channels.max(1), enabled: false, filters: None, history, position: 0 }
+    }
+
+    pub fn configure(&mut self, settings: &VirtualizerSettings) {
+        self.enabled = settings.enabled;
+    }
+
+    // Switch to filters made by `Hrtf::filters` for this source's layout. The same filters sent
+    // again along with other settings are kept as they are.
+    pub fn set_filters(&mut self, filters: Option<Arc<HrtfFilters>>) {
+        let same = match (&filters, &self.filters) {
+            (Some(new), Some(old)) => Arc::ptr_eq(new, old),
+            (None, None) => true,
+            _ => false,
+        };
+        if same {
+            return;
+        }
+        // Filters for another layout can't be run on this source
+        let fits = |filters: &HrtfFilters| {
+            filters.taps <= MAX_TAPS && filters.sources.iter().all(|(channel, _)| *channel < self.history.len())
+        };
+        self.filters = filters.filter(|filters| fits(filters));
+        for history in &mut self.history {
+            history.fill(0.0);
+        }
+        self.position = 0;
+    }
+
+    // Whether `process` should be used in place of mapping the channels
+    pub fn is_active(&self) -> bool {
+        self.enabled && self.filters.as_ref().is_some_and(|filters| !filters.sources.is_empty()) && self.channels == 2
+    }
+
+    // Mix interleaved frames of the source's channels down to interleaved stereo
+    pub fn process(&mut self, input: &[f32], output: &mut [f32]) {
+        let Some(filters) = &self.filters else {
+            return;
+        };
+        let taps = filters.taps;
+        for (frame, out) in input.chunks_exact(self.source_channels).zip(output.chunks_exact_mut(2)) {
+            let lfe = filters.lfe.map(|channel| frame[channel] * LFE_GAIN).unwrap_or(0.0);
+            let mut ears = [lfe; 2];
+            for (channel, ear_filters) in &filters.sources {
+                let history = &mut self.history[*channel];
+                let sample = frame[*channel];
+                history[self.position] = sample;
+                history[self.position + taps] = sample;
+                let recent = &history[self.position + 1..self.position + 1 + taps];
+                for (ear, filter) in ears.iter_mut().zip(ear_filters) {
+                    *ear += recent.iter().zip(filter).map(|(x, h)| x * h).sum::<f32>();
+                }
+            }
+            out.copy_from_slice(&ears);
+            self.position = (self.position + 1) % taps;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp::sofa_writer::{self, Measurement, SofaFile};
+    use crate::dsp::test_support::*;
+
+    // Samples before sound reaches an ear, and the ear's response: a crude spherical head where
+    // the far ear hears later and duller
+    const HEAD_ONSET: usize = 20;
+
+    fn ear_delay(azimuth: f64, ear: f64) -> usize {
+        let facing = (azimuth - ear).to_radians().cos();
+        HEAD_ONSET + (0.09 / 343.0 * SAMPLE_RATE as f64 * (1.0 - facing)).round() as usize
+    }
+
+    fn ear_response(azimuth: f64, ear: f64) -> Vec<f32> {
+        let facing = (azimuth - ear).to_radians().cos();
+        let mut response = vec![0.0; 64];
+        let delay = ear_delay(azimuth, ear);
+        response[delay] = (0.6 + 0.4 * facing) as f32;
+        response[delay + 1] = (0.2 * (1.0 - facing)) as f32;
+        response
+    }
+
+    // Measurements every 15 degrees around the horizontal plane
+    fn synthetic_head() -> Vec<Measurement> {
+        (0..24)
+            .map(|step| {
+                let azimuth = step as f64 * 15.0 - 165.0;
+                Measurement { azimuth, elevation: 0.0, left: ear_response(azimuth, 90.0), right: ear_response(azimuth, -90.0) }
+            })
+            .collect()
+    }
+
+    fn write_head(dir: &TempDir, name: &str, head: &[Measurement], cartesian: bool, delay: [f64; 2], chunk: usize) -> Hrtf {
+        let path = dir.path(name);
+        sofa_writer::write(&path, &SofaFile { sample_rate: SAMPLE_RATE as f64, measurements: head, cartesian, delay, chunk });
+        Hrtf::load(&path).unwrap()
+    }
+
+    // Where sound from a channel first shows up in the output
+    fn onset(samples: &[f32]) -> Option<usize> {
+        let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+        samples.iter().position(|sample| sample.abs() > peak * 0.01)
+    }
+
+    // Downmix interleaved surround to stereo in blocks of `block` frames
+    fn virtualize(hrtf: &Hrtf, channels: usize, surround: &[f32], block: usize) -> Vec<f32> {
+        let mut virtualizer = Virtualizer::new(channels, 2);
+        virtualizer.configure(&VirtualizerSettings { enabled: true, sofa: String::new() });
+        virtualizer.set_filters(hrtf.filters(SAMPLE_RATE, channels).map(Arc::new));
+        assert!(virtualizer.is_active(), "no filters for {channels} channels");
+        let mut stereo = vec![0.0; surround.len() / channels * 2];
+        for (input, output) in surround.chunks(block * channels).zip(stereo.chunks_mut(block * 2)) {
+            virtualizer.process(input, output);
+        }
+        stereo
+    }
+
+    // The SOFA reader gets back exactly what was written, whether positions are stored as
+    // angles or coordinates and with delays added in front
+    #[test]
+    fn sofa_files_read_back() {
+        let dir = TempDir::new("sofa");
+        let head = synthetic_head();
+        let from_angles = write_head(&dir, "spherical.sofa", &head, false, [0.0, 0.0], 5);
+        let from_coordinates = write_head(&dir, "cartesian.sofa", &head, true, [2.0, 3.0], 7);
+        assert_eq!(from_angles.measurements(), head.len());
+        assert_eq!(from_angles.sample_rate, SAMPLE_RATE as f64);
+
+        for azimuth in [30.0, -30.0, 0.0, 105.0, -150.0, 180.0] {
+            let expected = head.iter().find(|measurement| measurement.azimuth == azimuth || (azimuth == 180.0 && measurement.azimuth == -180.0));
+            let expected = expected.unwrap_or_else(|| panic!("nothing measured at {azimuth}"));
+            let [left, right] = from_angles.response(from_angles.nearest(azimuth));
+            assert!(*left == expected.left && *right == expected.right, "{azimuth} degrees from angles");
+            let [left, right] = from_coordinates.response(from_coordinates.nearest(azimuth));
+            assert!(left[..2] == [0.0; 2] && left[2..] == expected.left[..], "{azimuth} degrees from coordinates, left");
+            assert!(right[..3] == [0.0; 3] && right[3..] == expected.right[..], "{azimuth} degrees from coordinates, right");
+        }
+    }
+
+    // 5.1 through the virtualizer: the front left speaker is heard on the left first, the
+    // centre equally in both ears and the LFE straight in both, whatever the block size
+    #[test]
+    fn places_speakers() {
+        let dir = TempDir::new("virtualizer");
+        let hrtf = write_head(&dir, "head.sofa", &synthetic_head(), false, [0.0, 0.0], 5);
+        let frames = SAMPLE_RATE as usize / 2;
+        let mut surround = vec![0.0f32; frames * 6];
+        let (front_left, centre, lfe) = (100, 2000, 4000);
+        surround[front_left * 6] = 0.5;
+        surround[centre * 6 + 2] = 0.5;
+        surround[lfe * 6 + 3] = 0.5;
+
+        let stereo = virtualize(&hrtf, 6, &surround, 1);
+        for block in [480, 4096] {
+            assert!(virtualize(&hrtf, 6, &surround, block) == stereo, "blocks of {block} differ");
+        }
+        let (left, right) = (channel(&stereo, 2, 0), channel(&stereo, 2, 1));
+        let window = |samples: &[f32], start: usize| samples[start..start + 256].to_vec();
+
+        let (fl_left, fl_right) = (window(&left, front_left), window(&right, front_left));
+        let louder = rms_db(&fl_left) - rms_db(&fl_right);
+        assert!(louder > 3.0, "front left is only {louder:.1} dB louder on the left");
+        let lag = onset(&fl_right).unwrap() as i64 - onset(&fl_left).unwrap() as i64;
+        let expected_lag = ear_delay(30.0, -90.0) as i64 - ear_delay(30.0, 90.0) as i64;
+        assert_eq!(lag, expected_lag, "the right ear should hear front left later");
+
+        assert!(window(&left, centre) == window(&right, centre), "the centre should be the same in both ears");
+        let lfe_level = LFE_GAIN * 0.5;
+        assert_eq!((left[lfe], right[lfe]), (lfe_level, lfe_level));
+    }
+
+    // Filters are only made for layouts with known speaker positions, and filters for one
+    // layout aren't run on another
+    #[test]
+    fn filters_fit_the_layout() {
+        let dir = TempDir::new("virtualizer-layouts");
+        let hrtf = write_head(&dir, "head.sofa", &synthetic_head(), false, [0.0, 0.0], 5);
+        assert!(hrtf.filters(SAMPLE_RATE, 3).is_none());
+        for channels in [2, 4, 6, 8] {
+            let filters = hrtf.filters(SAMPLE_RATE, channels).unwrap();
+            assert!(filters.taps <= MAX_TAPS);
+            let speakers = layout(channels).unwrap().iter().filter(|speaker| **speaker != Speaker::Lfe).count();
+            assert_eq!(filters.sources.len(), speakers, "{channels} channels");
+        }
+
+        let mut virtualizer = Virtualizer::new(2, 2);
+        virtualizer.configure(&VirtualizerSettings { enabled: true, sofa: String::new() });
+        virtualizer.set_filters(hrtf.filters(SAMPLE_RATE, 8).map(Arc::new));
+        assert!(!virtualizer.is_active());
+    }
+}
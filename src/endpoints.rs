@@ -0,0 +1,537 @@
+// Details about audio endpoints that cpal doesn't expose: stable IDs, what kind of device
+// they are, whether they're plugged in and their current volume.
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flow {
+    Output,
+    Input,
+}
+
+// What sort of device an endpoint is, as reported by the driver
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub enum FormFactor {
+    Speakers,
+    Headphones,
+    Headset,
+    Handset,
+    Microphone,
+    LineLevel,
+    Digital,
+    Display,
+    Network,
+    Unknown,
+}
+
+impl FormFactor {
+    pub fn icon(self) -> &'static str {
+        match self {
+            FormFactor::Speakers => "🔈",
+            FormFactor::Headphones | FormFactor::Headset => "🎧",
+            FormFactor::Handset => "📞",
+            FormFactor::Microphone => "🎤",
+            FormFactor::LineLevel | FormFactor::Digital => "🔌",
+            FormFactor::Display => "🖵",
+            FormFactor::Network => "🖧",
+            FormFactor::Unknown => "🔊",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            FormFactor::Speakers => "Speakers",
+            FormFactor::Headphones => "Headphones",
+            FormFactor::Headset => "Headset",
+            FormFactor::Handset => "Handset",
+            FormFactor::Microphone => "Microphone",
+            FormFactor::LineLevel => "Line",
+            FormFactor::Digital => "Digital",
+            FormFactor::Display => "Display",
+            FormFactor::Network => "Network",
+            FormFactor::Unknown => "Audio device",
+        }
+    }
+
+    // Devices you wear, where sound suddenly coming out of speakers would be a surprise
+    pub fn is_personal(self) -> bool {
+        matches!(self, FormFactor::Headphones | FormFactor::Headset | FormFactor::Handset)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub enum EndpointState {
+    Active,
+    Disabled,
+    NotPresent,
+    Unplugged,
+}
+
+impl EndpointState {
+    pub fn label(self) -> &'static str {
+        match self {
+            EndpointState::Active => "Active",
+            EndpointState::Disabled => "Disabled",
+            EndpointState::NotPresent => "Not present",
+            EndpointState::Unplugged => "Unplugged",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Endpoint {
+    // Stable identifier that survives renames and reboots
+    pub id: String,
+    pub name: String,
+    pub form_factor: FormFactor,
+    pub state: EndpointState,
+    // Only known for active devices
+    pub volume: Option<f32>,
+    pub muted: Option<bool>,
+}
+
+// List every endpoint for the given direction, including unplugged and disabled ones
+pub fn list_endpoints(flow: Flow) -> Vec<Endpoint> {
+    #[cfg(target_os = "windows")]
+    {
+        unsafe { windows_impl::list_endpoints(flow).unwrap_or_default() }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        // Without the Windows endpoint API all we know is what cpal tells us
+        use cpal::traits::{DeviceTrait, HostTrait};
+
+        let host = cpal::default_host();
+        let devices = match flow {
+            Flow::Output => host.output_devices().map(|devices| devices.collect::<Vec<_>>()),
+            Flow::Input => host.input_devices().map(|devices| devices.collect::<Vec<_>>()),
+        };
+
+        devices
+            .unwrap_or_default()
+            .iter()
+            .filter_map(|device| device.name().ok())
+            .map(|name| Endpoint {
+                id: name.clone(),
+                name,
+                form_factor: FormFactor::Unknown,
+                state: EndpointState::Active,
+                volume: None,
+                muted: None,
+            })
+            .collect()
+    }
+}
+
+// Set the master volume of a device that may not be the default, looked up by name.
+// Returns false if there's no such active device or it can't be changed.
+pub fn set_endpoint_volume(flow: Flow, name: &str, volume: f32) -> bool {
+    #[cfg(target_os = "windows")]
+    {
+        unsafe { windows_impl::set_endpoint_volume(flow, name, volume).is_ok() }
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        let _ = (flow, name, volume);
+        false
+    }
+}
+
+// PulseAudio (or PipeWire) sinks, which are the endpoints on Linux. cpal only sees ALSA's
+// names for them, such as "default" or "pulse".
+#[cfg(target_os = "linux")]
+pub(crate) mod pulse {
+    use super::FormFactor;
+    use std::process::Command;
+
+    // Run pactl with untranslated labels, returning its output
+    pub fn pactl(args: &[&str]) -> Result<String, String> {
+        let output = Command::new("pactl")
+            .args(args)
+            .env("LC_ALL", "C")
+            .output()
+            .map_err(|err| format!("Couldn't run pactl: {err}"))?;
+        if !output.status.success() {
+            return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    pub struct Sink {
+        // Stable identifier, e.g. "alsa_output.pci-0000_00_1f.3.analog-stereo"
+        pub name: String,
+        pub description: String,
+        // Going by the active port, so it changes when headphones are plugged into a jack
+        pub form_factor: FormFactor,
+        pub muted: bool,
+    }
+
+    // A port's type, as PulseAudio 14 and later report it
+    fn port_type(value: &str) -> FormFactor {
+        match value {
+            "Speaker" => FormFactor::Speakers,
+            "Headphones" => FormFactor::Headphones,
+            "Headset" | "Handsfree" => FormFactor::Headset,
+            "Handset" | "Earpiece" => FormFactor::Handset,
+            "Mic" => FormFactor::Microphone,
+            "Line" | "Analog" => FormFactor::LineLevel,
+            "SPDIF" => FormFactor::Digital,
+            "HDMI" | "TV" => FormFactor::Display,
+            "Network" => FormFactor::Network,
+            _ => FormFactor::Unknown,
+        }
+    }
+
+    // Older versions only have the port's name to go by, e.g. "analog-output-headphones"
+    fn port_name(name: &str) -> FormFactor {
+        let name = name.to_ascii_lowercase();
+        if name.contains("headphone") {
+            FormFactor::Headphones
+        } else if name.contains("headset") {
+            FormFactor::Headset
+        } else if name.contains("speaker") {
+            FormFactor::Speakers
+        } else if name.contains("hdmi") {
+            FormFactor::Display
+        } else if name.contains("iec958") || name.contains("spdif") {
+            FormFactor::Digital
+        } else {
+            FormFactor::Unknown
+        }
+    }
+
+    // The sink's `device.form_factor` property, set for Bluetooth and some USB devices
+    fn device_form_factor(value: &str) -> FormFactor {
+        match value {
+            "headphone" => FormFactor::Headphones,
+            "headset" | "hands-free" => FormFactor::Headset,
+            "handset" => FormFactor::Handset,
+            "speaker" | "internal" | "hifi" | "portable" | "car" => FormFactor::Speakers,
+            "tv" => FormFactor::Display,
+            "microphone" | "webcam" => FormFactor::Microphone,
+            _ => FormFactor::Unknown,
+        }
+    }
+
+    // "analog-output-headphones: Headphones (type: Headphones, priority: 9900, available)"
+    fn parse_port(line: &str) -> Option<(String, FormFactor)> {
+        let (name, rest) = line.split_once(": ")?;
+        let form_factor = rest
+            .split(['(', ','])
+            .find_map(|part| part.trim().strip_prefix("type:"))
+            .map(|value| port_type(value.trim()))
+            .filter(|form_factor| *form_factor != FormFactor::Unknown)
+            .unwrap_or_else(|| port_name(name));
+        Some((name.to_string(), form_factor))
+    }
+
+    // Parse the output of `pactl list sinks`
+    pub fn parse_sinks(text: &str) -> Vec<Sink> {
+        struct Parsed {
+            sink: Sink,
+            ports: Vec<(String, FormFactor)>,
+            in_ports: bool,
+            active_port: Option<String>,
+            device: Option<FormFactor>,
+        }
+
+        let mut parsed: Vec<Parsed> = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.starts_with("Sink #") {
+                parsed.push(Parsed {
+                    sink: Sink {
+                        name: String::new(),
+                        description: String::new(),
+                        form_factor: FormFactor::Unknown,
+                        muted: false,
+                    },
+                    ports: Vec::new(),
+                    in_ports: false,
+                    active_port: None,
+                    device: None,
+                });
+                continue;
+            }
+            let Some(current) = parsed.last_mut() else {
+                continue;
+            };
+
+            if let Some(value) = line.strip_prefix("Name:") {
+                current.sink.name = value.trim().to_string();
+            } else if let Some(value) = line.strip_prefix("Description:") {
+                current.sink.description = value.trim().to_string();
+            } else if let Some(value) = line.strip_prefix("Mute:") {
+                current.sink.muted = value.trim() == "yes";
+            } else if let Some(value) = line.strip_prefix("device.form_factor = ") {
+                current.device = Some(device_form_factor(value.trim_matches('"')));
+            } else if line == "Ports:" {
+                current.in_ports = true;
+            } else if let Some(value) = line.strip_prefix("Active Port:") {
+                current.in_ports = false;
+                current.active_port = Some(value.trim().to_string());
+            } else if current.in_ports {
+                current.ports.extend(parse_port(line));
+            }
+        }
+
+        parsed
+            .into_iter()
+            .filter(|parsed| !parsed.sink.name.is_empty())
+            .map(|parsed| {
+                let port = parsed
+                    .active_port
+                    .and_then(|active| parsed.ports.iter().find(|(name, _)| *name == active).map(|(_, form_factor)| *form_factor))
+                    .filter(|form_factor| *form_factor != FormFactor::Unknown);
+                let form_factor = port.or(parsed.device).unwrap_or(FormFactor::Unknown);
+                Sink { form_factor, ..parsed.sink }
+            })
+            .collect()
+    }
+
+    pub fn list_sinks() -> Vec<Sink> {
+        pactl(&["list", "sinks"]).map(|text| parse_sinks(&text)).unwrap_or_default()
+    }
+
+    // Name of the default sink, from `pactl info`, which unlike `get-default-sink` every
+    // version has
+    pub fn default_sink_name() -> Option<String> {
+        let text = pactl(&["info"]).ok()?;
+        text.lines().find_map(|line| line.strip_prefix("Default Sink:")).map(|name| name.trim().to_string())
+    }
+
+    pub fn set_sink_mute(name: &str, mute: bool) -> Result<(), String> {
+        pactl(&["set-sink-mute", name, if mute { "1" } else { "0" }]).map(|_| ())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::auto_mute::should_auto_mute;
+
+        // `pactl list sinks` from PulseAudio 16 with a laptop's jack, trimmed, and a Bluetooth
+        // headset that only has the device's form factor to go by
+        const SINKS: &str = "Sink #0
+	State: RUNNING
+	Name: alsa_output.pci-0000_00_1f.3.analog-stereo
+	Description: Built-in Audio Analog Stereo
+	Driver: module-alsa-card.c
+	Mute: no
+	Volume: front-left: 39322 /  60% / -13.31 dB,   front-right: 39322 /  60% / -13.31 dB
+	Properties:
+		alsa.card = \"0\"
+		device.description = \"Built-in Audio\"
+		device.form_factor = \"internal\"
+	Ports:
+		analog-output-speaker: Speakers (type: Speaker, priority: 10000, availability unknown)
+		analog-output-headphones: Headphones (type: Headphones, priority: 9900, available)
+	Active Port: analog-output-headphones
+	Formats:
+		pcm
+
+Sink #3
+	State: SUSPENDED
+	Name: bluez_output.00_1B_66_AA_BB_CC.1
+	Description: Momentum 4
+	Driver: module-bluez5-device.c
+	Mute: yes
+	Properties:
+		device.form_factor = \"headset\"
+	Ports:
+		headset-output: Headset (type: Unknown, priority: 0, available)
+	Active Port: headset-output
+";
+
+        #[test]
+        fn parses_sinks() {
+            let sinks = parse_sinks(SINKS);
+            assert_eq!(
+                sinks,
+                [
+                    Sink {
+                        name: "alsa_output.pci-0000_00_1f.3.analog-stereo".to_string(),
+                        description: "Built-in Audio Analog Stereo".to_string(),
+                        form_factor: FormFactor::Headphones,
+                        muted: false,
+                    },
+                    Sink {
+                        name: "bluez_output.00_1B_66_AA_BB_CC.1".to_string(),
+                        description: "Momentum 4".to_string(),
+                        form_factor: FormFactor::Headset,
+                        muted: true,
+                    },
+                ]
+            );
+            assert!(parse_sinks("").is_empty());
+            assert!(parse_sinks("Connection failure: Connection refused\n").is_empty());
+        }
+
+        // Older versions don't report the port's type, only its name
+        #[test]
+        fn falls_back_to_port_names() {
+            let old = SINKS.replace("(type: Speaker, ", "(").replace("(type: Headphones, ", "(");
+            assert_eq!(parse_sinks(&old)[0].form_factor, FormFactor::Headphones);
+            let speakers = old.replace("Active Port: analog-output-headphones", "Active Port: analog-output-speaker");
+            assert_eq!(parse_sinks(&speakers)[0].form_factor, FormFactor::Speakers);
+        }
+
+        // Unplugging the headphones moves the same sink's active port to the speakers
+        #[test]
+        fn unplugging_headphones_mutes() {
+            let before = parse_sinks(SINKS);
+            let unplugged = SINKS
+                .replace("Headphones (type: Headphones, priority: 9900, available)", "Headphones (type: Headphones, priority: 9900, not available)")
+                .replace("Active Port: analog-output-headphones", "Active Port: analog-output-speaker");
+            let after = parse_sinks(&unplugged);
+
+            let (from, to) = (&before[0], &after[0]);
+            assert_eq!(from.name, to.name);
+            assert_eq!((from.form_factor, to.form_factor), (FormFactor::Headphones, FormFactor::Speakers));
+            assert!(should_auto_mute(from.form_factor, to.form_factor, true, true));
+            // Plugging them back in doesn't
+            assert!(!should_auto_mute(to.form_factor, from.form_factor, true, true));
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+pub(crate) mod windows_impl {
+    use super::{Endpoint, EndpointState, Flow, FormFactor};
+    use windows::core::PWSTR;
+    use windows::Win32::Devices::FunctionDiscovery::PKEY_Device_FriendlyName;
+    use windows::Win32::Media::Audio::Endpoints::IAudioEndpointVolume;
+    use windows::Win32::Media::Audio::{
+        self, eCapture, eMultimedia, eRender, EDataFlow, IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
+        DEVICE_STATEMASK_ALL, DEVICE_STATE_ACTIVE, DEVICE_STATE_DISABLED, DEVICE_STATE_NOTPRESENT,
+        PKEY_AudioEndpoint_FormFactor,
+    };
+    use windows::Win32::System::Com::StructuredStorage::{
+        PropVariantClear, PropVariantToStringAlloc, PropVariantToUInt32,
+    };
+    use windows::Win32::System::Com::{CoCreateInstance, CoTaskMemFree, CLSCTX_ALL, STGM_READ};
+    use windows::Win32::UI::Shell::PropertiesSystem::{IPropertyStore, PROPERTYKEY};
+
+    pub fn data_flow(flow: Flow) -> EDataFlow {
+        match flow {
+            Flow::Output => eRender,
+            Flow::Input => eCapture,
+        }
+    }
+
+    pub unsafe fn enumerator() -> windows::core::Result<IMMDeviceEnumerator> {
+        CoCreateInstance(&MMDeviceEnumerator, None, CLSCTX_ALL)
+    }
+
+    // Copy a COM-allocated string and free the original
+    pub unsafe fn take_pwstr(value: PWSTR) -> String {
+        let text = value.to_string().unwrap_or_default();
+        CoTaskMemFree(Some(value.0 as *const _));
+        text
+    }
+
+    unsafe fn string_property(store: &IPropertyStore, key: &PROPERTYKEY) -> Option<String> {
+        let mut value = store.GetValue(key).ok()?;
+        let text = PropVariantToStringAlloc(&value).ok().map(|text| take_pwstr(text));
+        let _ = PropVariantClear(&mut value);
+        text
+    }
+
+    unsafe fn u32_property(store: &IPropertyStore, key: &PROPERTYKEY) -> Option<u32> {
+        let mut value = store.GetValue(key).ok()?;
+        let number = PropVariantToUInt32(&value).ok();
+        let _ = PropVariantClear(&mut value);
+        number
+    }
+
+    fn form_factor(value: u32) -> FormFactor {
+        match Audio::EndpointFormFactor(value as i32) {
+            Audio::Speakers => FormFactor::Speakers,
+            Audio::Headphones => FormFactor::Headphones,
+            Audio::Headset => FormFactor::Headset,
+            Audio::Handset => FormFactor::Handset,
+            Audio::Microphone => FormFactor::Microphone,
+            Audio::LineLevel => FormFactor::LineLevel,
+            Audio::SPDIF | Audio::UnknownDigitalPassthrough => FormFactor::Digital,
+            Audio::DigitalAudioDisplayDevice => FormFactor::Display,
+            Audio::RemoteNetworkDevice => FormFactor::Network,
+            _ => FormFactor::Unknown,
+        }
+    }
+
+    fn endpoint_state(state: u32) -> EndpointState {
+        match state {
+            DEVICE_STATE_ACTIVE => EndpointState::Active,
+            DEVICE_STATE_DISABLED => EndpointState::Disabled,
+            DEVICE_STATE_NOTPRESENT => EndpointState::NotPresent,
+            _ => EndpointState::Unplugged,
+        }
+    }
+
+    pub unsafe fn describe(device: &IMMDevice) -> Option<Endpoint> {
+        let id = take_pwstr(device.GetId().ok()?);
+        let state = endpoint_state(device.GetState().ok()?);
+        let store = device.OpenPropertyStore(STGM_READ).ok()?;
+        let name = string_property(&store, &PKEY_Device_FriendlyName)?;
+        let form_factor = u32_property(&store, &PKEY_AudioEndpoint_FormFactor)
+            .map(form_factor)
+            .unwrap_or(FormFactor::Unknown);
+
+        // Only active devices can be asked for their volume
+        let endpoint_volume = if state == EndpointState::Active {
+            device.Activate::<IAudioEndpointVolume>(CLSCTX_ALL, None).ok()
+        } else {
+            None
+        };
+        let volume = endpoint_volume.as_ref().and_then(|v| v.GetMasterVolumeLevelScalar().ok());
+        let muted = endpoint_volume.as_ref().and_then(|v| v.GetMute().ok()).map(|muted| muted.as_bool());
+
+        Some(Endpoint { id, name, form_factor, state, volume, muted })
+    }
+
+    pub unsafe fn default_device(flow: Flow) -> Option<IMMDevice> {
+        enumerator().ok()?.GetDefaultAudioEndpoint(data_flow(flow), eMultimedia).ok()
+    }
+
+    pub unsafe fn mute_device(device: &IMMDevice) -> windows::core::Result<()> {
+        let endpoint_volume: IAudioEndpointVolume = device.Activate(CLSCTX_ALL, None)?;
+        endpoint_volume.SetMute(true, std::ptr::null())
+    }
+
+    // The active device with the given friendly name
+    pub unsafe fn find_device(flow: Flow, name: &str) -> windows::core::Result<IMMDevice> {
+        let collection = enumerator()?.EnumAudioEndpoints(data_flow(flow), DEVICE_STATE_ACTIVE)?;
+        for i in 0..collection.GetCount()? {
+            let device = collection.Item(i)?;
+            let Ok(store) = device.OpenPropertyStore(STGM_READ) else {
+                continue;
+            };
+            if string_property(&store, &PKEY_Device_FriendlyName).as_deref() == Some(name) {
+                return Ok(device);
+            }
+        }
+        Err(windows::core::Error::from(windows::Win32::Foundation::E_INVALIDARG))
+    }
+
+    pub unsafe fn set_endpoint_volume(flow: Flow, name: &str, volume: f32) -> windows::core::Result<()> {
+        let endpoint_volume: IAudioEndpointVolume = find_device(flow, name)?.Activate(CLSCTX_ALL, None)?;
+        endpoint_volume.SetMasterVolumeLevelScalar(volume.clamp(0.0, 1.0), std::ptr::null())
+    }
+
+    pub unsafe fn list_endpoints(flow: Flow) -> windows::core::Result<Vec<Endpoint>> {
+        let collection = enumerator()?.EnumAudioEndpoints(data_flow(flow), DEVICE_STATEMASK_ALL)?;
+
+        let mut endpoints = Vec::new();
+        for i in 0..collection.GetCount()? {
+            if let Some(endpoint) = collection.Item(i).ok().and_then(|device| describe(&device)) {
+                endpoints.push(endpoint);
+            }
+        }
+        Ok(endpoints)
+    }
+}
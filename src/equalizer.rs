@@ -0,0 +1,915 @@
+// System-wide DSP. Audio is played into a virtual device, run through the DSP chain and played
+// on the real one. On Linux the app creates that device itself; on Windows it has to be a
+// virtual audio cable, whose output is captured with WASAPI loopback.
+//
+// Which real device that is, and what's done to its audio, comes from the device profiles in
+// profiles.rs: the processing follows the default output around.
+
+use eframe::egui;
+use egui::{Color32, Pos2, Rect, RichText, Sense, Stroke, Vec2};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::dsp::apo::{self, Skipped};
+use crate::dsp::convolver::{self, ImpulseResponse};
+use crate::dsp::crossfeed::CrossfeedKind;
+use crate::dsp::eq::{Band, BandKind, Equalizer, MAX_BANDS};
+use crate::dsp::virtualizer::Hrtf;
+use crate::dsp::{ChainSettings, LoadedFiles};
+use crate::pipeline::{OutputConfig, OutputControl, Pipeline, PipelineConfig, SinkName, Source};
+use crate::profiles::DeviceProfile;
+use crate::AudioApp;
+
+pub const EQ_SINK: SinkName = SinkName { name: "audio_controller_eq", description: "Audio Controller EQ", channels: 2 };
+// Channels of the virtual device while a profile downmixes surround, so programs can play 7.1
+const SURROUND_CHANNELS: usize = 8;
+
+// The graph covers 20 Hz to 20 kHz and this many dB either side of zero
+const GRAPH_RANGE_DB: f32 = 18.0;
+// Curves are drawn and checked for clipping at this rate; the real rate only matters near Nyquist
+const GRAPH_SAMPLE_RATE: f64 = 48000.0;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct EqPrefs {
+    // Process devices that have a profile
+    pub enabled: bool,
+    // Windows only: the virtual cable whose output is processed
+    pub capture: Option<String>,
+    // The real output most recently switched to, whose profile is loaded
+    pub output: Option<String>,
+    pub latency_ms: u32,
+    // Device ID -> its profile
+    pub profiles: BTreeMap<String, DeviceProfile>,
+}
+
+impl Default for EqPrefs {
+    fn default() -> Self {
+        Self { enabled: false, capture: None, output: None, latency_ms: 40, profiles: BTreeMap::new() }
+    }
+}
+
+// What files were last loaded from, and what came of it
+type Loaded<K, T> = Option<(K, Result<Arc<T>, String>)>;
+
+#[derive(Default)]
+pub struct EqState {
+    pipeline: Option<Pipeline>,
+    control: Option<Arc<OutputControl>>,
+    error: Option<String>,
+    // Device whose profile is shown, if not the current output's
+    pub editing: Option<String>,
+    // Name typed for saving a preset
+    preset_name: String,
+    // File typed for importing or exporting
+    file: String,
+    // What the last import or export did, and the lines an import couldn't use
+    file_result: Option<Result<String, String>>,
+    skipped: Vec<Skipped>,
+    // The last SOFA file loaded for the virtualizer, and what came of it
+    hrtf: Loaded<String, Hrtf>,
+    // The same for the room correction's impulse responses
+    impulse: Loaded<Vec<String>, ImpulseResponse>,
+    // ID of the device being processed
+    pub device: Option<String>,
+    // Linux: the sink processed audio is played on, found before the virtual device became
+    // the default
+    #[cfg(target_os = "linux")]
+    pub sink: Option<String>,
+}
+
+impl EqState {
+    pub fn is_running(&self) -> bool {
+        self.pipeline.is_some()
+    }
+}
+
+// AutoEq names its files after the headphones, e.g. "Sennheiser HD 600 ParametricEQ.txt"
+fn preset_name_for(path: &Path) -> String {
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = ["ParametricEQ", "FixedBandEQ"]
+        .iter()
+        .find_map(|suffix| stem.strip_suffix(suffix))
+        .map(|name| name.trim().to_string())
+        .unwrap_or(stem);
+    // EqualizerAPO's own file is always config.txt, so the folder says more
+    if name.is_empty() || name.eq_ignore_ascii_case("config") {
+        let folder = path.parent().and_then(Path::file_name).map(|name| name.to_string_lossy().into_owned());
+        return folder.unwrap_or_else(|| "Imported".to_string());
+    }
+    name
+}
+
+impl AudioApp {
+    fn eq_source(&self) -> Result<Source, String> {
+        if cfg!(target_os = "linux") {
+            return Ok(Source::VirtualSink);
+        }
+        match &self.settings.eq.capture {
+            Some(capture) if Some(capture) == self.settings.eq.output.as_ref() => {
+                Err("The virtual cable and the output must be different devices".to_string())
+            }
+            Some(capture) => Ok(Source::Loopback(capture.clone())),
+            None => Err("Choose the virtual cable to play through".to_string()),
+        }
+    }
+
+    // The device whose profile the EQ page shows
+    fn eq_editing(&self) -> Option<String> {
+        self.eq.editing.clone().or_else(|| self.settings.eq.output.clone())
+    }
+
+    fn eq_curve(&self) -> Option<&Equalizer> {
+        self.device_profile(&self.eq_editing()?).map(|profile| &profile.eq)
+    }
+
+    // The sink to replay processed audio on. On Linux ALSA's "default" and "pulse" play on the
+    // sound server's default, which is the virtual device while audio is processed, so they're
+    // pinned to the sink that was the default before that rather than playing into themselves.
+    fn processing_sink(&mut self, output: &str) -> Result<Option<String>, String> {
+        #[cfg(target_os = "linux")]
+        if crate::pipeline::follows_default_sink(output) {
+            if let Some(sink) = self.default_sink.clone().filter(|sink| sink != EQ_SINK.name) {
+                self.eq.sink = Some(sink);
+            }
+            return match &self.eq.sink {
+                Some(sink) => Ok(Some(sink.clone())),
+                None => Err(format!("Couldn't find the device behind '{output}' to play the processed sound on")),
+            };
+        }
+        let _ = output;
+        Ok(None)
+    }
+
+    // Head responses from a SOFA file, only read again when the file changes
+    fn load_hrtf(&mut self, path: &str) -> Result<Arc<Hrtf>, String> {
+        let path = path.trim();
+        if path.is_empty() {
+            return Err("Choose a SOFA file with head responses".to_string());
+        }
+        if let Some((loaded, result)) = &self.eq.hrtf {
+            if loaded == path {
+                return result.clone();
+            }
+        }
+        let result = Hrtf::load(Path::new(path)).map(Arc::new);
+        if let Err(err) = &result {
+            eprintln!("ERROR: {err}");
+        }
+        self.eq.hrtf = Some((path.to_string(), result.clone()));
+        result
+    }
+
+    // Impulse responses for room correction, only read again when the files change
+    fn load_impulse(&mut self, files: &[String]) -> Result<Arc<ImpulseResponse>, String> {
+        if let Some((loaded, result)) = &self.eq.impulse {
+            if loaded == files {
+                return result.clone();
+            }
+        }
+        let result = ImpulseResponse::load(files).map(Arc::new);
+        if let Err(err) = &result {
+            eprintln!("ERROR: {err}");
+        }
+        self.eq.impulse = Some((files.to_vec(), result.clone()));
+        result
+    }
+
+    // The output's chain settings, and the files its profile uses
+    fn output_dsp(&mut self) -> Option<(ChainSettings, LoadedFiles)> {
+        let output = self.settings.eq.output.clone()?;
+        let settings = self.device_profile(&output)?.chain();
+        let files = LoadedFiles {
+            hrtf: if settings.virtualizer.enabled { self.load_hrtf(&settings.virtualizer.sofa).ok() } else { None },
+            impulse: if settings.convolution.enabled { self.load_impulse(&settings.convolution.files).ok() } else { None },
+        };
+        Some((settings, files))
+    }
+
+    // Start processing the current output with its profile, or stop if it has none
+    pub fn restart_equalizer(&mut self) {
+        self.eq.pipeline = None;
+        self.eq.control = None;
+        self.eq.error = None;
+        self.eq.device = None;
+
+        if !self.settings.eq.enabled {
+            return;
+        }
+        let Some(output) = self.settings.eq.output.clone() else {
+            return;
+        };
+        let Some((settings, files)) = self.output_dsp() else {
+            return;
+        };
+        let source = match self.eq_source() {
+            Ok(source) => source,
+            Err(err) => {
+                self.eq.error = Some(err);
+                return;
+            }
+        };
+        let sink = match self.processing_sink(&output) {
+            Ok(sink) => sink,
+            Err(err) => {
+                self.eq.error = Some(err);
+                return;
+            }
+        };
+
+        let device = self.device_id(&output);
+        let channels = if settings.virtualizer.enabled { SURROUND_CHANNELS } else { EQ_SINK.channels };
+        let control = OutputControl::new(1.0, 0.0, false);
+        control.set_dsp(settings, files);
+        let config = PipelineConfig {
+            source,
+            outputs: vec![OutputConfig { device: output, control: control.clone(), sink }],
+            latency_ms: self.settings.eq.latency_ms as f32,
+            virtual_sink: SinkName { channels, ..EQ_SINK },
+        };
+
+        match Pipeline::start(config) {
+            Ok(pipeline) => {
+                self.eq.pipeline = Some(pipeline);
+                self.eq.control = Some(control);
+                self.eq.device = Some(device);
+            }
+            Err(err) => {
+                eprintln!("ERROR: Couldn't start processing: {err}");
+                self.eq.error = Some(err);
+            }
+        }
+    }
+
+    // Make the virtual device the default, so everything that plays is processed
+    pub fn route_through_processing(&mut self) {
+        #[cfg(target_os = "windows")]
+        if let Some(capture) = self.settings.eq.capture.clone() {
+            self.switch_default_device(&capture);
+        }
+
+        #[cfg(target_os = "linux")]
+        {
+            match crate::endpoints::pulse::pactl(&["set-default-sink", EQ_SINK.name]) {
+                Ok(_) => self.default_sink = Some(EQ_SINK.name.to_string()),
+                Err(err) => eprintln!("ERROR: Couldn't make {} the default: {err}", EQ_SINK.description),
+            }
+        }
+    }
+
+    // Send the edited profile to the running pipeline
+    pub fn push_profile(&mut self) {
+        let Some((settings, files)) = self.output_dsp() else {
+            return;
+        };
+        if let Some(control) = &self.eq.control {
+            control.set_dsp(settings, files);
+        }
+    }
+
+    // Surround needs the virtual device reopened with more channels, so turning the
+    // virtualizer on or off restarts processing rather than just updating it
+    fn restart_surround(&mut self, device: &str) {
+        self.settings.save();
+        if self.settings.eq.output.as_deref() != Some(device) {
+            return;
+        }
+        self.restart_equalizer();
+        if self.eq.is_running() {
+            self.route_through_processing();
+        }
+    }
+
+    fn virtualizer_ui(&mut self, ui: &mut egui::Ui, device: &str) {
+        let id = self.device_id(device);
+        let Some(virtualizer) = self.settings.eq.profiles.get_mut(&id).map(|profile| &mut profile.virtualizer) else {
+            return;
+        };
+        let mut restart = ui
+            .checkbox(&mut virtualizer.enabled, "Downmix surround to headphones")
+            .on_hover_text("Play 5.1 and 7.1 through virtual speakers placed around your head")
+            .changed();
+        let response = ui.add(
+            egui::TextEdit::singleline(&mut virtualizer.sofa)
+                .desired_width(f32::INFINITY)
+                .hint_text("Head responses (.sofa): type the path or drop the file here"),
+        );
+        restart |= response.lost_focus() && virtualizer.enabled;
+        let (enabled, path) = (virtualizer.enabled, virtualizer.sofa.trim().to_string());
+
+        // Files are read once typing is done, not on every key
+        let result = if restart { Some(self.load_hrtf(&path)) } else { None };
+        if restart {
+            self.restart_surround(device);
+        }
+        if !enabled {
+            return;
+        }
+        let result = result.or_else(|| match &self.eq.hrtf {
+            Some((loaded, result)) if *loaded == path => Some(result.clone()),
+            _ => None,
+        });
+        match result {
+            Some(Ok(hrtf)) => {
+                let text = format!("{} directions measured at {} Hz", hrtf.measurements(), hrtf.sample_rate);
+                ui.label(RichText::new(text).weak());
+            }
+            Some(Err(err)) => {
+                ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+            }
+            None => {}
+        }
+        if cfg!(target_os = "windows") {
+            ui.label(RichText::new("Set the virtual cable to 7.1 in the Sound control panel so programs send surround").weak());
+        }
+    }
+
+    // Impulse responses and wet/dry mix. Returns true if the running chain should be updated.
+    fn convolution_ui(&mut self, ui: &mut egui::Ui, device: &str) -> bool {
+        let id = self.device_id(device);
+        let Some(convolution) = self.settings.eq.profiles.get_mut(&id).map(|profile| &mut profile.convolution) else {
+            return false;
+        };
+        let mut changed = ui
+            .checkbox(&mut convolution.enabled, "Correct the room with impulse responses")
+            .on_hover_text("Filters made by REW or DRC-FIR, as WAV files")
+            .changed();
+        if convolution.files.is_empty() {
+            convolution.files.push(String::new());
+        }
+
+        let single = convolution.files.len() == 1;
+        let mut remove = None;
+        egui::Grid::new("eq_convolution").num_columns(3).spacing([8.0, 4.0]).show(ui, |ui| {
+            for (idx, file) in convolution.files.iter_mut().enumerate() {
+                let label = match idx {
+                    _ if single => "All channels:".to_string(),
+                    0 => "Left:".to_string(),
+                    1 => "Right:".to_string(),
+                    _ => format!("Channel {}:", idx + 1),
+                };
+                ui.label(label).on_hover_text(if single {
+                    "A stereo file's channels go to left and right, a mono file to every channel"
+                } else {
+                    "The first channel of each file is used"
+                });
+                let hint = "Impulse response (.wav): type the path or drop the file here";
+                // Files are read once typing is done, not on every key
+                changed |= ui.add(egui::TextEdit::singleline(file).desired_width(320.0).hint_text(hint)).lost_focus();
+                if !single && ui.small_button("🗑").on_hover_text("Remove this channel's file").clicked() {
+                    remove = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(idx) = remove {
+            convolution.files.remove(idx);
+            changed = true;
+        }
+        if ui.small_button("Add channel").on_hover_text("Use a separate file for each channel").clicked() {
+            convolution.files.push(String::new());
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Mix:");
+            changed |= ui
+                .add(egui::Slider::new(&mut convolution.mix, 0.0..=1.0).custom_formatter(|value, _| format!("{:.0}% wet", value * 100.0)))
+                .on_hover_text("How much of the corrected sound to play, to compare it with the original")
+                .changed();
+        });
+
+        let (enabled, files) = (convolution.enabled, convolution.files.clone());
+        if !enabled {
+            return changed;
+        }
+        let result = if changed {
+            Some(self.load_impulse(&files))
+        } else {
+            match &self.eq.impulse {
+                Some((loaded, result)) if *loaded == files => Some(result.clone()),
+                _ => None,
+            }
+        };
+        match result {
+            Some(Ok(impulse)) => {
+                let rate = impulse.sample_rate;
+                let text = format!(
+                    "{} taps at {rate} Hz, {} channels, peak at {:.1} ms. Adds {} samples of delay.",
+                    impulse.len(),
+                    impulse.channels.len(),
+                    impulse.peak() as f64 / rate * 1000.0,
+                    convolver::BLOCK
+                );
+                ui.label(RichText::new(text).weak());
+            }
+            Some(Err(err)) => {
+                ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+            }
+            None => {}
+        }
+        changed
+    }
+
+    // Load an AutoEq or EqualizerAPO file as a preset of the device being edited and switch to it
+    fn import_eq(&mut self, path: &Path) {
+        let Some(device) = self.eq_editing() else {
+            self.eq.file_result = Some(Err("Choose a device first".to_string()));
+            return;
+        };
+        let import = match apo::load(path) {
+            Ok(import) => import,
+            Err(err) => {
+                self.eq.file_result = Some(Err(err));
+                self.eq.skipped.clear();
+                return;
+            }
+        };
+
+        if self.device_profile(&device).is_none() {
+            self.create_device_profile(&device);
+        }
+        let name = preset_name_for(path);
+        let bands = import.eq.bands.len();
+        if let Some(profile) = self.device_profile_mut(&device) {
+            profile.presets.insert(name.clone(), import.eq.clone());
+            profile.eq = import.eq;
+        }
+        self.settings.save();
+        self.push_profile();
+
+        let summary = match import.skipped.len() {
+            0 => format!("Imported \"{name}\" with {bands} bands"),
+            skipped => format!("Imported \"{name}\" with {bands} bands, {skipped} lines skipped"),
+        };
+        self.eq.preset_name = name;
+        self.eq.file_result = Some(Ok(summary));
+        self.eq.skipped = import.skipped;
+    }
+
+    fn export_eq(&mut self, path: &Path) {
+        let Some(text) = self.eq_curve().map(apo::export) else {
+            return;
+        };
+        self.eq.skipped.clear();
+        self.eq.file_result = Some(match std::fs::write(path, text) {
+            Ok(()) => Ok(format!("Saved {}", path.display())),
+            Err(err) => Err(format!("Couldn't write {}: {err}", path.display())),
+        });
+    }
+
+    fn eq_files_ui(&mut self, ui: &mut egui::Ui) {
+        ui.add(
+            egui::TextEdit::singleline(&mut self.eq.file)
+                .desired_width(f32::INFINITY)
+                .hint_text("ParametricEQ.txt or config.txt: type the path or drop the file here"),
+        );
+        ui.horizontal(|ui| {
+            let path = PathBuf::from(self.eq.file.trim());
+            let has_path = !self.eq.file.trim().is_empty();
+            if ui.add_enabled(has_path, egui::Button::new("Import")).clicked() {
+                self.import_eq(&path);
+            }
+            if ui.add_enabled(has_path, egui::Button::new("Export")).on_hover_text("Save in EqualizerAPO's format").clicked() {
+                self.export_eq(&path);
+            }
+            if ui.button("Copy").on_hover_text("Copy in EqualizerAPO's format").clicked() {
+                if let Some(eq) = self.eq_curve() {
+                    let text = apo::export(eq);
+                    ui.output_mut(|output| output.copied_text = text);
+                }
+            }
+        });
+    }
+
+    // Shown outside the import section, so dropping a file always says what happened
+    fn eq_file_result_ui(&self, ui: &mut egui::Ui) {
+        match &self.eq.file_result {
+            Some(Ok(message)) => {
+                ui.label(RichText::new(message).weak());
+            }
+            Some(Err(err)) => {
+                ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+            }
+            None => {}
+        }
+        for skipped in &self.eq.skipped {
+            let text = format!("{} line {}: {}", skipped.file, skipped.line, skipped.reason);
+            ui.label(RichText::new(text).color(Color32::from_rgb(255, 190, 96)))
+                .on_hover_text(&skipped.text);
+        }
+    }
+
+    pub fn equalizer_ui(&mut self, ui: &mut egui::Ui) {
+        // Files dropped on the window are imported straight away, or used for the virtualizer or
+        // room correction
+        let dropped: Vec<PathBuf> = ui.input(|input| input.raw.dropped_files.iter().filter_map(|file| file.path.clone()).collect());
+        if let Some(path) = dropped.first() {
+            let is = |kind: &str| path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case(kind));
+            if is("wav") {
+                if let Some(device) = self.eq_editing() {
+                    if self.device_profile(&device).is_none() {
+                        self.create_device_profile(&device);
+                    }
+                    if let Some(profile) = self.device_profile_mut(&device) {
+                        profile.convolution.enabled = true;
+                        profile.convolution.files = vec![path.display().to_string()];
+                    }
+                    self.settings.save();
+                    self.push_profile();
+                }
+            } else if is("sofa") {
+                if let Some(device) = self.eq_editing() {
+                    if self.device_profile(&device).is_none() {
+                        self.create_device_profile(&device);
+                    }
+                    if let Some(profile) = self.device_profile_mut(&device) {
+                        profile.virtualizer.enabled = true;
+                        profile.virtualizer.sofa = path.display().to_string();
+                    }
+                    self.restart_surround(&device);
+                }
+            } else {
+                self.eq.file = path.display().to_string();
+                self.import_eq(path);
+            }
+        }
+
+        let mut reroute = ui
+            .checkbox(&mut self.settings.eq.enabled, "Apply device profiles")
+            .on_hover_text("Play devices that have a DSP profile through it, whenever they become the default")
+            .changed();
+        let mut restart = false;
+        let mut edited = false;
+
+        let names: Vec<String> = self.device_names.iter().filter(|name| !self.is_processing_device(name)).cloned().collect();
+        let mut editing = self.eq_editing();
+        egui::Grid::new("eq_devices").num_columns(2).spacing([8.0, 4.0]).show(ui, |ui| {
+            let devices = &self.settings.devices;
+            if cfg!(target_os = "windows") {
+                ui.label("Virtual cable:").on_hover_text("Audio is played into this and processed on its way to the real device");
+                let selected = self.settings.eq.capture.as_deref().map(|name| devices.display_name(name)).unwrap_or("Choose…");
+                egui::ComboBox::from_id_source("eq_capture").selected_text(selected).width(200.0).show_ui(ui, |ui| {
+                    for name in &self.device_names {
+                        reroute |= ui.selectable_value(&mut self.settings.eq.capture, Some(name.clone()), devices.display_name(name)).changed();
+                    }
+                });
+                ui.end_row();
+            }
+            ui.label("Profile for:");
+            let selected = editing.as_deref().map(|name| devices.display_name(name)).unwrap_or("Choose…");
+            egui::ComboBox::from_id_source("eq_editing").selected_text(selected).width(200.0).show_ui(ui, |ui| {
+                for name in &names {
+                    let mut label = devices.display_name(name).to_string();
+                    if self.settings.eq.profiles.contains_key(&self.device_id(name)) {
+                        label.push_str(" · profile");
+                    }
+                    ui.selectable_value(&mut editing, Some(name.clone()), label);
+                }
+            });
+            ui.end_row();
+        });
+        if editing != self.eq_editing() {
+            self.eq.editing = editing.clone();
+        }
+
+        if reroute {
+            self.settings.save();
+            if let Some(output) = self.settings.eq.output.clone() {
+                self.set_default_device_by_name(&output);
+            }
+        }
+
+        let Some(device) = editing else {
+            self.eq_status_ui(ui);
+            return;
+        };
+        let is_output = self.settings.eq.output.as_ref() == Some(&device);
+
+        if self.device_profile(&device).is_none() {
+            ui.add_space(6.0);
+            ui.label(format!("{} has no profile, so its audio plays unprocessed.", self.settings.devices.display_name(&device)));
+            if ui.button("Create profile").clicked() {
+                self.create_device_profile(&device);
+            }
+            self.eq_status_ui(ui);
+            return;
+        }
+
+        let id = self.device_id(&device);
+        let Some(profile) = self.settings.eq.profiles.get_mut(&id) else {
+            return;
+        };
+
+        // Presets saved for this device
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("eq_presets").selected_text("Load preset…").width(130.0).show_ui(ui, |ui| {
+                if profile.presets.is_empty() {
+                    ui.label("No presets saved");
+                }
+                for (name, preset) in &profile.presets {
+                    if ui.selectable_label(false, name).clicked() {
+                        profile.eq = preset.clone();
+                        self.eq.preset_name = name.clone();
+                        edited = true;
+                    }
+                }
+            });
+            ui.add(egui::TextEdit::singleline(&mut self.eq.preset_name).desired_width(100.0).hint_text("Preset name"));
+            let name = self.eq.preset_name.trim().to_string();
+            if ui.add_enabled(!name.is_empty(), egui::Button::new("Save")).clicked() {
+                profile.presets.insert(name.clone(), profile.eq.clone());
+                edited = true;
+            }
+            if ui.add_enabled(profile.presets.contains_key(&name), egui::Button::new("🗑")).on_hover_text("Delete this preset").clicked() {
+                profile.presets.remove(&name);
+                edited = true;
+            }
+            if ui.button("Flat").on_hover_text("Remove every band").clicked() {
+                profile.eq = Equalizer::default();
+                edited = true;
+            }
+        });
+
+        let eq = &mut profile.eq;
+        edited |= response_graph(ui, eq);
+
+        ui.horizontal(|ui| {
+            ui.label("Preamp:");
+            edited |= ui.add(egui::Slider::new(&mut eq.preamp_db, -24.0..=12.0).step_by(0.1).suffix(" dB")).changed();
+            let peak = eq.peak_db(GRAPH_SAMPLE_RATE);
+            if peak > 0.05 {
+                let warning = RichText::new(format!("⚠ +{peak:.1} dB")).color(Color32::from_rgb(255, 190, 96));
+                if ui.add(egui::Button::new(warning).frame(false)).on_hover_text("Loud parts may clip. Click to lower the preamp.").clicked() {
+                    eq.preamp_db = ((eq.preamp_db as f64 - peak) * 10.0).floor() as f32 / 10.0;
+                    edited = true;
+                }
+            }
+        });
+
+        edited |= bands_ui(ui, eq);
+
+        egui::CollapsingHeader::new("Balance, crossfeed and limiter").show(ui, |ui| {
+            edited |= effects_ui(ui, profile);
+        });
+
+        egui::CollapsingHeader::new("Virtual surround").show(ui, |ui| {
+            self.virtualizer_ui(ui, &device);
+        });
+
+        egui::CollapsingHeader::new("Room correction").show(ui, |ui| {
+            edited |= self.convolution_ui(ui, &device);
+        });
+
+        egui::CollapsingHeader::new("Import and export").show(ui, |ui| {
+            self.eq_files_ui(ui);
+        });
+        self.eq_file_result_ui(ui);
+
+        if ui.button("Remove profile").on_hover_text("Play this device's audio unprocessed").clicked() {
+            self.remove_device_profile(&device);
+            return;
+        }
+
+        self.eq_status_ui(ui);
+
+        ui.horizontal(|ui| {
+            ui.label("Buffer:");
+            let response = ui
+                .add(egui::DragValue::new(&mut self.settings.eq.latency_ms).clamp_range(10..=500).suffix(" ms"))
+                .on_hover_text("Lower keeps sound in sync with video, higher copes better with a busy system");
+            if response.drag_released() || response.lost_focus() {
+                restart = true;
+            } else if response.changed() {
+                edited = true;
+            }
+        });
+
+        if edited && is_output {
+            self.push_profile();
+        }
+        if edited || restart {
+            self.settings.save();
+        }
+        if restart {
+            self.restart_equalizer();
+        }
+    }
+
+    fn eq_status_ui(&self, ui: &mut egui::Ui) {
+        let output = self.settings.eq.output.as_deref().map(|name| self.settings.devices.display_name(name));
+        if let Some(err) = &self.eq.error {
+            ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+        } else if let (Some(pipeline), Some(control)) = (&self.eq.pipeline, &self.eq.control) {
+            if control.failed.load(Ordering::Relaxed) {
+                ui.label(RichText::new("Stopped: the output went away").color(Color32::from_rgb(255, 96, 96)));
+            } else {
+                let mut text = format!("Processing {} at {} Hz", output.unwrap_or_default(), pipeline.sample_rate);
+                let latency = control.dsp_latency_ms();
+                if latency > 0.0 {
+                    text.push_str(&format!(" · {latency:.1} ms added delay"));
+                }
+                ui.label(RichText::new(text).weak()).on_hover_text(format!(
+                    "Dropouts: {}, skips: {}",
+                    control.underruns.load(Ordering::Relaxed),
+                    control.overruns.load(Ordering::Relaxed)
+                ));
+            }
+        } else if let (true, Some(output)) = (self.settings.eq.enabled, output) {
+            ui.label(RichText::new(format!("{output} has no profile and plays unprocessed")).weak());
+        }
+    }
+}
+
+// Balance, crossfeed and limiter of a profile. Returns true if anything changed.
+fn effects_ui(ui: &mut egui::Ui, profile: &mut DeviceProfile) -> bool {
+    let mut changed = false;
+    egui::Grid::new("eq_effects").num_columns(2).spacing([8.0, 4.0]).show(ui, |ui| {
+        ui.label("Balance:");
+        ui.horizontal(|ui| {
+            ui.label("L");
+            changed |= ui.add(egui::Slider::new(&mut profile.balance, -1.0..=1.0).show_value(false)).changed();
+            ui.label("R");
+            if ui.add_enabled(profile.balance != 0.0, egui::Button::new("Centre").small()).clicked() {
+                profile.balance = 0.0;
+                changed = true;
+            }
+        });
+        ui.end_row();
+
+        ui.label("Crossfeed:");
+        ui.horizontal(|ui| {
+            let crossfeed = &mut profile.crossfeed;
+            changed |= ui.checkbox(&mut crossfeed.enabled, "").on_hover_text("Feed some bass to the other ear, like speakers do").changed();
+            ui.add_enabled_ui(crossfeed.enabled, |ui| {
+                egui::ComboBox::from_id_source("eq_crossfeed_kind").selected_text(crossfeed.kind.label()).width(100.0).show_ui(ui, |ui| {
+                    for kind in CrossfeedKind::ALL {
+                        changed |= ui.selectable_value(&mut crossfeed.kind, kind, kind.label()).changed();
+                    }
+                })
+                .response
+                .on_hover_text("Meier leaves centred sound untouched; Bauer spreads the bass more evenly");
+                changed |= ui.add(egui::Slider::new(&mut crossfeed.strength, 0.0..=1.0).custom_formatter(|value, _| format!("{:.0}%", value * 100.0))).changed();
+                changed |= ui
+                    .add(egui::DragValue::new(&mut crossfeed.cutoff_hz).clamp_range(200.0..=2000.0).speed(5.0).suffix(" Hz"))
+                    .on_hover_text("Only sound below this crosses over")
+                    .changed();
+            });
+        });
+        ui.end_row();
+
+        ui.label("Limiter:");
+        ui.horizontal(|ui| {
+            let limiter = &mut profile.limiter;
+            changed |= ui.checkbox(&mut limiter.enabled, "").on_hover_text("Keep boosts from clipping").changed();
+            ui.add_enabled_ui(limiter.enabled, |ui| {
+                changed |= ui
+                    .add(egui::DragValue::new(&mut limiter.ceiling_db).clamp_range(-12.0..=0.0).speed(0.1).max_decimals(1).prefix("ceiling ").suffix(" dB"))
+                    .changed();
+                changed |= ui
+                    .add(egui::DragValue::new(&mut limiter.release_ms).clamp_range(10.0..=1000.0).speed(1.0).max_decimals(0).prefix("release ").suffix(" ms"))
+                    .changed();
+            });
+        });
+        ui.end_row();
+    });
+    changed
+}
+
+// One row per band. Returns true if anything changed.
+fn bands_ui(ui: &mut egui::Ui, eq: &mut Equalizer) -> bool {
+    let mut changed = false;
+    let mut remove = None;
+    egui::Grid::new("eq_bands").num_columns(6).striped(true).spacing([6.0, 4.0]).show(ui, |ui| {
+        for (idx, band) in eq.bands.iter_mut().enumerate() {
+            changed |= ui.checkbox(&mut band.enabled, "").changed();
+            egui::ComboBox::from_id_source(("eq_band_kind", idx)).selected_text(band.kind.label()).width(90.0).show_ui(ui, |ui| {
+                for kind in BandKind::ALL {
+                    changed |= ui.selectable_value(&mut band.kind, kind, kind.label()).changed();
+                }
+            });
+            // Move in proportion to the frequency, so dragging feels the same across the range
+            let speed = band.freq * 0.005;
+            changed |= ui
+                .add(egui::DragValue::new(&mut band.freq).clamp_range(10.0..=22000.0).speed(speed).max_decimals(0).suffix(" Hz"))
+                .changed();
+            changed |= ui
+                .add_enabled(
+                    band.kind.has_gain(),
+                    egui::DragValue::new(&mut band.gain_db).clamp_range(-30.0..=30.0).speed(0.1).max_decimals(1).suffix(" dB"),
+                )
+                .changed();
+            changed |= ui
+                .add(egui::DragValue::new(&mut band.q).clamp_range(0.05..=20.0).speed(0.01).max_decimals(2).prefix("Q "))
+                .changed();
+            if ui.small_button("🗑").clicked() {
+                remove = Some(idx);
+            }
+            ui.end_row();
+        }
+    });
+    if let Some(idx) = remove {
+        eq.bands.remove(idx);
+        changed = true;
+    }
+    if ui
+        .add_enabled(eq.bands.len() < MAX_BANDS, egui::Button::new("➕ Add band"))
+        .on_disabled_hover_text(format!("At most {MAX_BANDS} bands"))
+        .clicked()
+    {
+        eq.bands.push(Band::default());
+        changed = true;
+    }
+    changed
+}
+
+pub fn freq_to_x(rect: Rect, freq: f64) -> f32 {
+    rect.left() + rect.width() * ((freq / 20.0).ln() / 1000f64.ln()) as f32
+}
+
+pub fn x_to_freq(rect: Rect, x: f32) -> f32 {
+    20.0 * 1000f32.powf(((x - rect.left()) / rect.width()).clamp(0.0, 1.0))
+}
+
+fn db_to_y(rect: Rect, db: f32) -> f32 {
+    rect.center().y - db.clamp(-GRAPH_RANGE_DB, GRAPH_RANGE_DB) / GRAPH_RANGE_DB * rect.height() / 2.0
+}
+
+fn y_to_db(rect: Rect, y: f32) -> f32 {
+    ((rect.center().y - y) / (rect.height() / 2.0) * GRAPH_RANGE_DB).clamp(-GRAPH_RANGE_DB, GRAPH_RANGE_DB)
+}
+
+// The frequency response of the whole curve, with a handle per band that can be dragged to
+// change its frequency and gain, or scrolled over to change its Q. Returns true if a band moved.
+fn response_graph(ui: &mut egui::Ui, eq: &mut Equalizer) -> bool {
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 180.0), Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 4.0, visuals.extreme_bg_color);
+
+    let grid = Stroke::new(1.0, visuals.widgets.noninteractive.bg_stroke.color);
+    let label_color = visuals.weak_text_color();
+    let font = egui::FontId::proportional(10.0);
+    for freq in [50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0] {
+        let x = freq_to_x(rect, freq);
+        painter.vline(x, rect.y_range(), grid);
+        let label = if freq >= 1000.0 { format!("{}k", freq / 1000.0) } else { format!("{freq}") };
+        painter.text(Pos2::new(x + 2.0, rect.bottom() - 2.0), egui::Align2::LEFT_BOTTOM, label, font.clone(), label_color);
+    }
+    for db in [-12.0, -6.0, 0.0, 6.0, 12.0] {
+        let y = db_to_y(rect, db);
+        painter.hline(rect.x_range(), y, if db == 0.0 { Stroke::new(1.0, label_color) } else { grid });
+        painter.text(Pos2::new(rect.left() + 2.0, y - 1.0), egui::Align2::LEFT_BOTTOM, format!("{db:+}"), font.clone(), label_color);
+    }
+
+    let points: Vec<Pos2> = (0..=rect.width() as usize)
+        .map(|step| {
+            let x = rect.left() + step as f32;
+            let db = eq.response_db(x_to_freq(rect, x) as f64, GRAPH_SAMPLE_RATE) as f32;
+            Pos2::new(x, db_to_y(rect, db))
+        })
+        .collect();
+    let accent = visuals.selection.bg_fill;
+    painter.add(egui::Shape::line(points, Stroke::new(2.0, accent)));
+
+    let mut changed = false;
+    for (idx, band) in eq.bands.iter_mut().enumerate() {
+        if !band.enabled {
+            continue;
+        }
+        let gain = if band.kind.has_gain() { band.gain_db } else { 0.0 };
+        let center = Pos2::new(freq_to_x(rect, band.freq as f64), db_to_y(rect, gain));
+        let response = ui.interact(Rect::from_center_size(center, Vec2::splat(14.0)), ui.id().with(("eq_handle", idx)), Sense::drag());
+
+        if response.dragged() {
+            if let Some(pointer) = response.interact_pointer_pos() {
+                band.freq = x_to_freq(rect, pointer.x).round();
+                if band.kind.has_gain() {
+                    band.gain_db = (y_to_db(rect, pointer.y) * 10.0).round() / 10.0;
+                }
+                changed = true;
+            }
+        }
+        if response.hovered() {
+            let scroll = ui.input_mut(|input| std::mem::take(&mut input.smooth_scroll_delta.y));
+            if scroll != 0.0 {
+                band.q = (band.q * (1.0 + scroll * 0.002)).clamp(0.05, 20.0);
+                changed = true;
+            }
+        }
+
+        let active = response.hovered() || response.dragged();
+        painter.circle(center, if active { 6.0 } else { 4.5 }, accent, Stroke::new(1.0, visuals.strong_text_color()));
+        painter.text(center + Vec2::new(0.0, -8.0), egui::Align2::CENTER_BOTTOM, format!("{}", idx + 1), font.clone(), label_color);
+        response.on_hover_text(format!(
+            "{} {:.0} Hz, {:+.1} dB, Q {:.2}\nDrag to move, scroll to change Q",
+            band.kind.label(),
+            band.freq,
+            band.gain_db,
+            band.q
+        ));
+    }
+    changed
+}
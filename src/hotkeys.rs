@@ -0,0 +1,394 @@
+use std::sync::mpsc::{self, Receiver};
+
+// What a global hotkey does when it's pressed
+#[derive(Clone, Debug, PartialEq)]
+pub enum HotkeyAction {
+    ApplyScene(String),
+    // Only sent by the media key listener, which exists on Linux alone
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    VolumeUp,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    VolumeDown,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    ToggleMute,
+    // Flip mono or swap L/R for the current output
+    ToggleMono,
+    ToggleSwapChannels,
+}
+
+// Modifier flags, matching the MOD_* values RegisterHotKey expects
+const MOD_ALT: u32 = 0x0001;
+const MOD_CONTROL: u32 = 0x0002;
+const MOD_SHIFT: u32 = 0x0004;
+const MOD_WIN: u32 = 0x0008;
+
+// Parse a combination such as "Ctrl+Alt+M" or "Shift+F5" into (modifiers, virtual key code).
+// At least one modifier is required so we never steal plain typing.
+pub fn parse_hotkey(text: &str) -> Option<(u32, u32)> {
+    let mut modifiers = 0;
+    let mut key = None;
+
+    for part in text.split('+').map(str::trim) {
+        match part.to_ascii_lowercase().as_str() {
+            "ctrl" | "control" => modifiers |= MOD_CONTROL,
+            "alt" => modifiers |= MOD_ALT,
+            "shift" => modifiers |= MOD_SHIFT,
+            "win" | "super" => modifiers |= MOD_WIN,
+            other => {
+                if key.is_some() {
+                    return None;
+                }
+                key = Some(virtual_key(other)?);
+            }
+        }
+    }
+
+    if modifiers == 0 {
+        return None;
+    }
+    key.map(|vk| (modifiers, vk))
+}
+
+// Map a key name to its Windows virtual key code
+fn virtual_key(name: &str) -> Option<u32> {
+    let bytes = name.as_bytes();
+    if bytes.len() == 1 && bytes[0].is_ascii_alphanumeric() {
+        // Letters and digits use their uppercase ASCII value
+        return Some(bytes[0].to_ascii_uppercase() as u32);
+    }
+
+    if let Some(number) = name.strip_prefix('f').and_then(|n| n.parse::<u32>().ok()) {
+        if (1..=24).contains(&number) {
+            return Some(0x70 + number - 1); // VK_F1..VK_F24
+        }
+    }
+
+    match name {
+        "space" => Some(0x20),
+        "up" => Some(0x26),
+        "down" => Some(0x28),
+        "left" => Some(0x25),
+        "right" => Some(0x27),
+        _ => None,
+    }
+}
+
+// Listens for system-wide hotkeys on a background thread and reports the matching actions.
+// Dropping the listener unregisters every hotkey.
+pub struct HotkeyListener {
+    receiver: Receiver<HotkeyAction>,
+    #[cfg(target_os = "windows")]
+    thread_id: u32,
+    #[cfg(target_os = "linux")]
+    stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl HotkeyListener {
+    pub fn start(bindings: Vec<(String, HotkeyAction)>) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        #[cfg(target_os = "windows")]
+        {
+            use windows::Win32::Foundation::HWND;
+            use windows::Win32::System::Threading::GetCurrentThreadId;
+            use windows::Win32::UI::Input::KeyboardAndMouse::{
+                RegisterHotKey, UnregisterHotKey, HOT_KEY_MODIFIERS, MOD_NOREPEAT,
+            };
+            use windows::Win32::UI::WindowsAndMessaging::{
+                GetMessageW, PeekMessageW, MSG, PM_NOREMOVE, WM_HOTKEY, WM_USER,
+            };
+
+            // Hotkeys registered without a window are delivered to the registering thread,
+            // so that thread has to own them and pump its own message queue
+            let (id_sender, id_receiver) = mpsc::channel();
+            std::thread::spawn(move || unsafe {
+                // Make sure the message queue exists before anyone can post WM_QUIT to it
+                let mut msg = MSG::default();
+                let _ = PeekMessageW(&mut msg, HWND(0), WM_USER, WM_USER, PM_NOREMOVE);
+                let _ = id_sender.send(GetCurrentThreadId());
+
+                let mut actions = Vec::new();
+                for (combo, action) in bindings {
+                    let Some((modifiers, vk)) = parse_hotkey(&combo) else {
+                        eprintln!("ERROR: Couldn't parse hotkey '{combo}'");
+                        continue;
+                    };
+                    let id = actions.len() as i32;
+                    let flags = HOT_KEY_MODIFIERS(modifiers) | MOD_NOREPEAT;
+                    if let Err(err) = RegisterHotKey(HWND(0), id, flags, vk) {
+                        eprintln!("ERROR: Couldn't register hotkey '{combo}': {err}");
+                        continue;
+                    }
+                    actions.push(action);
+                }
+
+                while GetMessageW(&mut msg, HWND(0), 0, 0).as_bool() {
+                    if msg.message == WM_HOTKEY {
+                        if let Some(action) = actions.get(msg.wParam.0) {
+                            if sender.send(action.clone()).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                for id in 0..actions.len() {
+                    let _ = UnregisterHotKey(HWND(0), id as i32);
+                }
+            });
+
+            let thread_id = id_receiver.recv().unwrap_or(0);
+            Self { receiver, thread_id }
+        }
+
+        // On X11 the keys are grabbed on the root window, like the volume keys below
+        #[cfg(target_os = "linux")]
+        {
+            use std::sync::atomic::{AtomicBool, Ordering};
+            use std::sync::Arc;
+            use std::time::Duration;
+            use x11rb::connection::Connection;
+            use x11rb::protocol::xproto::{ConnectionExt, GrabMode, ModMask};
+            use x11rb::protocol::Event;
+
+            let stopped = Arc::new(AtomicBool::new(false));
+            if bindings.is_empty() {
+                return Self { receiver, stopped };
+            }
+            let (connection, screen) = match x11rb::connect(None) {
+                Ok(connected) => connected,
+                Err(err) => {
+                    eprintln!("ERROR: Global hotkeys need an X11 session: {err}");
+                    return Self { receiver, stopped };
+                }
+            };
+            let root = connection.setup().roots[screen].root;
+            let min = connection.setup().min_keycode;
+            let max = connection.setup().max_keycode;
+            let mapping = match connection.get_keyboard_mapping(min, max - min + 1).map(|cookie| cookie.reply()) {
+                Ok(Ok(mapping)) => mapping,
+                _ => {
+                    eprintln!("ERROR: Couldn't read the keyboard mapping for hotkeys");
+                    return Self { receiver, stopped };
+                }
+            };
+            let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+
+            // (keycode, modifiers, action)
+            let mut keys = Vec::new();
+            for (combo, action) in bindings {
+                let Some((modifiers, vk)) = parse_hotkey(&combo) else {
+                    eprintln!("ERROR: Couldn't parse hotkey '{combo}'");
+                    continue;
+                };
+                let keycode = x11_keysym(vk)
+                    .and_then(|keysym| mapping.keysyms.iter().position(|sym| *sym == keysym))
+                    .map(|idx| min + (idx / per_keycode) as u8);
+                let Some(keycode) = keycode else {
+                    eprintln!("ERROR: This keyboard has no key for hotkey '{combo}'");
+                    continue;
+                };
+                let modifiers = x11_modifiers(modifiers);
+                // Caps Lock and Num Lock count as modifiers too, so grab with each of them on
+                let grabbed = [0, u16::from(ModMask::LOCK), u16::from(ModMask::M2), u16::from(ModMask::LOCK | ModMask::M2)]
+                    .into_iter()
+                    .all(|locks| {
+                        let cookie = connection.grab_key(true, root, ModMask::from(modifiers | locks), keycode, GrabMode::ASYNC, GrabMode::ASYNC);
+                        cookie.map_err(|err| err.to_string()).and_then(|cookie| cookie.check().map_err(|err| err.to_string())).is_ok()
+                    });
+                if !grabbed {
+                    eprintln!("ERROR: Couldn't register hotkey '{combo}', another program has it");
+                    continue;
+                }
+                keys.push((keycode, modifiers, action));
+            }
+
+            let thread_stopped = stopped.clone();
+            std::thread::spawn(move || {
+                let relevant = x11_modifiers(MOD_ALT | MOD_CONTROL | MOD_SHIFT | MOD_WIN);
+                while !thread_stopped.load(Ordering::Relaxed) {
+                    match connection.poll_for_event() {
+                        Ok(Some(Event::KeyPress(event))) => {
+                            let state = u16::from(event.state) & relevant;
+                            let pressed = keys.iter().find(|(keycode, modifiers, _)| *keycode == event.detail && *modifiers == state);
+                            if let Some((_, _, action)) = pressed {
+                                if sender.send(action.clone()).is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        Ok(Some(_)) => {}
+                        Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+                        Err(_) => break,
+                    }
+                }
+                for (keycode, _, _) in &keys {
+                    let _ = connection.ungrab_key(*keycode, root, ModMask::ANY);
+                }
+                let _ = connection.flush();
+            });
+
+            Self { receiver, stopped }
+        }
+
+        #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+        {
+            // Global hotkeys are only implemented on Windows and X11
+            drop((sender, bindings));
+            Self { receiver }
+        }
+    }
+
+    // Return the next pressed hotkey, if any
+    pub fn poll(&self) -> Option<HotkeyAction> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(target_os = "windows")]
+impl Drop for HotkeyListener {
+    fn drop(&mut self) {
+        use windows::Win32::Foundation::{LPARAM, WPARAM};
+        use windows::Win32::UI::WindowsAndMessaging::{PostThreadMessageW, WM_QUIT};
+
+        // Ends the message loop, which unregisters the hotkeys on its way out
+        unsafe {
+            let _ = PostThreadMessageW(self.thread_id, WM_QUIT, WPARAM(0), LPARAM(0));
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for HotkeyListener {
+    fn drop(&mut self) {
+        // The listening thread ungrabs the keys on its way out
+        self.stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
+
+// The X11 keysym for a virtual key code from parse_hotkey
+#[cfg(target_os = "linux")]
+fn x11_keysym(vk: u32) -> Option<u32> {
+    match vk {
+        // Letters are found by their lowercase keysym, digits and space are their ASCII value
+        0x41..=0x5A => Some(vk + 0x20),
+        0x30..=0x39 | 0x20 => Some(vk),
+        0x70..=0x87 => Some(0xFFBE + vk - 0x70),
+        0x25 => Some(0xFF51), // Left
+        0x26 => Some(0xFF52), // Up
+        0x27 => Some(0xFF53), // Right
+        0x28 => Some(0xFF54), // Down
+        _ => None,
+    }
+}
+
+// X11's modifier mask for MOD_* flags. Alt is Mod1 and the Windows key Mod4 on nearly every setup.
+#[cfg(target_os = "linux")]
+fn x11_modifiers(modifiers: u32) -> u16 {
+    use x11rb::protocol::xproto::ModMask;
+
+    [(MOD_ALT, ModMask::M1), (MOD_CONTROL, ModMask::CONTROL), (MOD_SHIFT, ModMask::SHIFT), (MOD_WIN, ModMask::M4)]
+        .into_iter()
+        .filter(|(flag, _)| modifiers & flag != 0)
+        .fold(0, |mask, (_, x11)| mask | u16::from(x11))
+}
+
+// Grabs the volume up, volume down and mute keys (XF86AudioRaiseVolume and friends) on X11 so
+// they go through our own volume logic. Only one program can grab a key, so this fails when the
+// desktop environment already handles them. Dropping the listener releases the keys.
+pub struct MediaKeyListener {
+    receiver: Receiver<HotkeyAction>,
+    #[cfg(target_os = "linux")]
+    stopped: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl MediaKeyListener {
+    #[cfg(target_os = "linux")]
+    pub fn start() -> Result<Self, String> {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+        use x11rb::connection::Connection;
+        use x11rb::protocol::xproto::{ConnectionExt, GrabMode, ModMask};
+        use x11rb::protocol::Event;
+
+        const XF86_AUDIO_LOWER_VOLUME: u32 = 0x1008FF11;
+        const XF86_AUDIO_MUTE: u32 = 0x1008FF12;
+        const XF86_AUDIO_RAISE_VOLUME: u32 = 0x1008FF13;
+
+        let (connection, screen) =
+            x11rb::connect(None).map_err(|err| format!("Volume keys need an X11 session: {err}"))?;
+        let root = connection.setup().roots[screen].root;
+
+        // Find the keycodes that produce each keysym
+        let min = connection.setup().min_keycode;
+        let max = connection.setup().max_keycode;
+        let mapping = connection
+            .get_keyboard_mapping(min, max - min + 1)
+            .map_err(|err| err.to_string())?
+            .reply()
+            .map_err(|err| err.to_string())?;
+        let per_keycode = mapping.keysyms_per_keycode.max(1) as usize;
+        let mut keys = Vec::new();
+        for (keysym, action) in [
+            (XF86_AUDIO_RAISE_VOLUME, HotkeyAction::VolumeUp),
+            (XF86_AUDIO_LOWER_VOLUME, HotkeyAction::VolumeDown),
+            (XF86_AUDIO_MUTE, HotkeyAction::ToggleMute),
+        ] {
+            if let Some(idx) = mapping.keysyms.iter().position(|sym| *sym == keysym) {
+                keys.push((min + (idx / per_keycode) as u8, action));
+            }
+        }
+        if keys.is_empty() {
+            return Err("This keyboard has no volume keys".to_string());
+        }
+
+        for (keycode, _) in &keys {
+            connection
+                .grab_key(true, root, ModMask::ANY, *keycode, GrabMode::ASYNC, GrabMode::ASYNC)
+                .map_err(|err| err.to_string())?
+                .check()
+                .map_err(|_| "The volume keys are already taken, probably by your desktop".to_string())?;
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        std::thread::spawn(move || {
+            while !thread_stopped.load(Ordering::Relaxed) {
+                match connection.poll_for_event() {
+                    Ok(Some(Event::KeyPress(event))) => {
+                        if let Some((_, action)) = keys.iter().find(|(keycode, _)| *keycode == event.detail) {
+                            let _ = sender.send(action.clone());
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) => std::thread::sleep(Duration::from_millis(20)),
+                    Err(_) => break,
+                }
+            }
+            for (keycode, _) in &keys {
+                let _ = connection.ungrab_key(*keycode, root, ModMask::ANY);
+            }
+            let _ = connection.flush();
+        });
+
+        Ok(Self { receiver, stopped })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn start() -> Result<Self, String> {
+        Err("Volume keys are only handled on Linux".to_string())
+    }
+
+    pub fn poll(&self) -> Option<HotkeyAction> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for MediaKeyListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
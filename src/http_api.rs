@@ -0,0 +1,364 @@
+// Optional HTTP server with a REST API and a WebSocket stream of change events. Like the IPC
+// socket it only translates requests into `Command`s; the app carries them out.
+
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tiny_http::{Header, Method, Request, Response, Server, StatusCode};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use crate::control::{Command, ControlError, ControlHandle};
+use crate::remote::{self, Pairing};
+use crate::AudioApp;
+
+// Request bodies are tiny JSON objects; anything bigger is a mistake or an attack
+const MAX_BODY: u64 = 64 * 1024;
+
+// Idle WebSocket connections get a ping this often, which is also how we notice they're gone
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+
+// Clients that authenticate with a subprotocol offer this one as well, and it's the one we
+// accept, so the token is never echoed back
+const EVENTS_PROTOCOL: &str = "audio-controller";
+const BEARER_PROTOCOL_PREFIX: &str = "bearer.";
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct HttpPrefs {
+    pub enabled: bool,
+    pub port: u16,
+    // Clients must send this as "Authorization: Bearer <token>"
+    pub token: String,
+    // Listen on every network interface instead of only this computer
+    pub lan: bool,
+}
+
+impl Default for HttpPrefs {
+    fn default() -> Self {
+        Self { enabled: false, port: 8765, token: String::new(), lan: false }
+    }
+}
+
+// Straight from the OS's random number generator, for tokens and pairing codes. Every system we
+// run on has one, so there's nothing sensible to do if it fails.
+pub fn random_u64() -> u64 {
+    getrandom::u64().expect("the system's random number generator should work")
+}
+
+// A random 128-bit token for a new configuration
+pub fn generate_token() -> String {
+    format!("{:016x}{:016x}", random_u64(), random_u64())
+}
+
+// Compare without bailing out at the first difference, so response times don't leak the token
+fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len() && given.bytes().zip(expected.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+// What every request handler needs
+struct Context {
+    token: String,
+    control: ControlHandle,
+    pairing: Pairing,
+    stopped: AtomicBool,
+}
+
+// Runs until dropped
+pub struct HttpServer {
+    server: Arc<Server>,
+    context: Arc<Context>,
+}
+
+impl HttpServer {
+    pub fn start(prefs: &HttpPrefs, control: ControlHandle) -> Result<Self, String> {
+        if prefs.token.is_empty() {
+            return Err("An access token is required".to_string());
+        }
+
+        let host = if prefs.lan { "0.0.0.0" } else { "127.0.0.1" };
+        let server = Server::http((host, prefs.port)).map_err(|err| format!("Couldn't listen on port {}: {err}", prefs.port))?;
+        let server = Arc::new(server);
+        let context = Arc::new(Context {
+            token: prefs.token.clone(),
+            control,
+            pairing: Pairing::default(),
+            stopped: AtomicBool::new(false),
+        });
+
+        let accepting = (server.clone(), context.clone());
+        std::thread::spawn(move || {
+            let (server, context) = accepting;
+            for request in server.incoming_requests() {
+                let context = context.clone();
+                std::thread::spawn(move || handle_request(request, &context));
+            }
+        });
+
+        Ok(Self { server, context })
+    }
+
+    // The port actually listened on
+    pub fn port(&self) -> u16 {
+        self.server.server_addr().to_ip().map_or(0, |addr| addr.port())
+    }
+
+    pub fn pairing(&self) -> &Pairing {
+        &self.context.pairing
+    }
+}
+
+impl Drop for HttpServer {
+    fn drop(&mut self) {
+        self.context.stopped.store(true, Ordering::Relaxed);
+        self.server.unblock();
+    }
+}
+
+fn json_response(status: u16, body: &Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap();
+    Response::from_string(body.to_string()).with_status_code(StatusCode(status)).with_header(content_type)
+}
+
+fn page_response() -> Response<std::io::Cursor<Vec<u8>>> {
+    let content_type = Header::from_bytes(&b"Content-Type"[..], &b"text/html; charset=utf-8"[..]).unwrap();
+    Response::from_string(remote::PAGE).with_header(content_type)
+}
+
+fn error_response(status: u16, message: &str) -> Response<std::io::Cursor<Vec<u8>>> {
+    json_response(status, &json!({ "error": message }))
+}
+
+fn header<'a>(request: &'a Request, name: &'static str) -> Option<&'a str> {
+    request.headers().iter().find(|header| header.field.equiv(name)).map(|header| header.value.as_str())
+}
+
+// The WebSocket subprotocols a client offered, e.g. "audio-controller, bearer.abc"
+fn offered_protocols(request: &Request) -> impl Iterator<Item = &str> {
+    header(request, "Sec-WebSocket-Protocol").into_iter().flat_map(|value| value.split(',')).map(str::trim)
+}
+
+fn authorized(request: &Request, token: &str) -> bool {
+    // Browsers can't set headers on WebSocket connections, so they offer the token as a
+    // "bearer.<token>" subprotocol instead. Unlike a query string it stays out of logs.
+    let given = header(request, "Authorization")
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or_else(|| offered_protocols(request).find_map(|protocol| protocol.strip_prefix(BEARER_PROTOCOL_PREFIX)));
+    given.is_some_and(|given| tokens_match(given.trim(), token))
+}
+
+fn read_body(request: &mut Request) -> Result<Value, String> {
+    let mut body = String::new();
+    request
+        .as_reader()
+        .take(MAX_BODY)
+        .read_to_string(&mut body)
+        .map_err(|err| format!("Couldn't read the request body: {err}"))?;
+    if body.trim().is_empty() {
+        return Ok(json!({}));
+    }
+    serde_json::from_str(&body).map_err(|err| format!("Invalid JSON: {err}"))
+}
+
+fn handle_request(mut request: Request, context: &Context) {
+    let path = request.url().split('?').next().unwrap_or("").to_string();
+    let method = request.method().clone();
+
+    // The web remote page holds no secrets; it asks for a pairing code itself
+    if method == Method::Get && (path == "/" || path == "/index.html") {
+        let _ = request.respond(page_response());
+        return;
+    }
+    if method == Method::Post && path == "/pair" {
+        let code = read_body(&mut request)
+            .ok()
+            .and_then(|body| body.get("code").and_then(Value::as_str).map(str::to_string))
+            .unwrap_or_default();
+        let response = if context.pairing.redeem(&code) {
+            json_response(200, &json!({ "token": context.token }))
+        } else {
+            error_response(403, "Wrong or expired pairing code")
+        };
+        let _ = request.respond(response);
+        return;
+    }
+
+    if !authorized(&request, &context.token) {
+        let _ = request.respond(error_response(401, "Missing or wrong access token"));
+        return;
+    }
+
+    if method == Method::Get && path == "/events" {
+        stream_events(request, &context.control, &context.stopped);
+        return;
+    }
+
+    let body = match read_body(&mut request) {
+        Ok(body) => body,
+        Err(err) => {
+            let _ = request.respond(error_response(400, &err));
+            return;
+        }
+    };
+
+    let command = match (&method, path.as_str()) {
+        (Method::Get, "/state") => Ok(Command::GetState),
+        (Method::Get, "/devices") => Ok(Command::ListDevices),
+        (Method::Put, "/volume") => Command::from_method("set_volume", &body),
+        (Method::Post, "/default-device") => Command::from_method("set_default_device", &body),
+        // Without a body this flips the current state
+        (Method::Post, "/mute") if body.get("muted").is_none() => Ok(Command::ToggleMute),
+        (Method::Post, "/mute") => Command::from_method("set_mute", &body),
+        (_, "/state" | "/devices" | "/volume" | "/default-device" | "/mute" | "/events") => {
+            let _ = request.respond(error_response(405, "Method not allowed"));
+            return;
+        }
+        _ => {
+            let _ = request.respond(error_response(404, "Not found"));
+            return;
+        }
+    };
+
+    let response = match command.and_then(|command| context.control.call(command)) {
+        Ok(result) => json_response(200, &result),
+        Err(err) => {
+            let status = match err {
+                ControlError::UnknownMethod(_) => 404,
+                ControlError::InvalidParams(_) => 400,
+                ControlError::Failed(_) => 422,
+                ControlError::Unavailable => 503,
+            };
+            error_response(status, &err.to_string())
+        }
+    };
+    let _ = request.respond(response);
+}
+
+// Upgrade to a WebSocket and send every event as a JSON text message until the client leaves
+fn stream_events(request: Request, control: &ControlHandle, stopped: &AtomicBool) {
+    let Some(key) = header(&request, "Sec-WebSocket-Key") else {
+        let _ = request.respond(error_response(400, "Expected a WebSocket upgrade"));
+        return;
+    };
+
+    let accept = tungstenite::handshake::derive_accept_key(key.as_bytes());
+    let mut response = Response::empty(StatusCode(101))
+        .with_header(Header::from_bytes(&b"Upgrade"[..], &b"websocket"[..]).unwrap())
+        .with_header(Header::from_bytes(&b"Sec-WebSocket-Accept"[..], accept.as_bytes()).unwrap());
+    // Browsers drop the connection unless we pick one of the subprotocols they offered
+    if offered_protocols(&request).any(|protocol| protocol == EVENTS_PROTOCOL) {
+        response.add_header(Header::from_bytes(&b"Sec-WebSocket-Protocol"[..], EVENTS_PROTOCOL.as_bytes()).unwrap());
+    }
+
+    let events = control.subscribe();
+    let stream = request.upgrade("websocket", response);
+    let mut socket = WebSocket::from_raw_socket(stream, Role::Server, None);
+
+    // This connection only talks; reading would block until the client sent something. A
+    // client that went away is noticed when the next event or ping can't be delivered.
+    let mut last_sent = Instant::now();
+    while !stopped.load(Ordering::Relaxed) {
+        let message = match events.recv_timeout(Duration::from_millis(500)) {
+            Ok(event) => Message::text(json!(event).to_string()),
+            Err(RecvTimeoutError::Timeout) if last_sent.elapsed() >= PING_INTERVAL => Message::Ping(Vec::new()),
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        if socket.send(message).is_err() {
+            return;
+        }
+        last_sent = Instant::now();
+    }
+    let _ = socket.close(None);
+    let _ = socket.flush();
+}
+
+impl AudioApp {
+    // Start, restart or stop the HTTP server to match the settings
+    pub fn restart_http_server(&mut self) {
+        // Release the port before binding it again
+        self.http_server = None;
+        if !self.settings.http.enabled {
+            return;
+        }
+
+        match HttpServer::start(&self.settings.http, self.control.handle()) {
+            Ok(server) => self.http_server = Some(server),
+            Err(err) => {
+                eprintln!("ERROR: {err}");
+                self.notifications.push(format!("HTTP server not started: {err}"));
+            }
+        }
+    }
+
+    pub fn http_ui(&mut self, ui: &mut egui::Ui) {
+        let prefs = &mut self.settings.http;
+        let mut changed = ui.checkbox(&mut prefs.enabled, "Enable HTTP API on this computer").changed();
+
+        ui.add_enabled_ui(prefs.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Port:");
+                changed |= ui.add(egui::DragValue::new(&mut prefs.port).clamp_range(1024..=65535)).changed();
+            });
+            ui.horizontal(|ui| {
+                ui.label("Token:");
+                ui.add(egui::TextEdit::singleline(&mut prefs.token.as_str()).desired_width(260.0).font(egui::TextStyle::Monospace));
+                if ui.small_button("📋").on_hover_text("Copy").clicked() {
+                    ui.output_mut(|output| output.copied_text = prefs.token.clone());
+                }
+                if ui.small_button("🔄").on_hover_text("Generate a new token").clicked() {
+                    prefs.token = generate_token();
+                    changed = true;
+                }
+            });
+            changed |= ui
+                .checkbox(&mut prefs.lan, "Allow other devices on the network")
+                .on_hover_text("Lets phones and other computers on your network use the web remote")
+                .changed();
+
+            ui.label(format!("Web remote: http://127.0.0.1:{}", prefs.port));
+            if prefs.lan {
+                match remote::lan_address() {
+                    Some(address) => ui.label(format!("On your network: http://{}:{}", address, prefs.port)),
+                    None => ui.label(RichText::new("Not connected to a network").weak()),
+                };
+            }
+
+            // Pairing hands the token to a phone without typing it in
+            if let Some(server) = &self.http_server {
+                match server.pairing().current() {
+                    Some((code, remaining)) => {
+                        ui.horizontal(|ui| {
+                            ui.label("Pairing code:");
+                            ui.label(RichText::new(code).monospace().size(20.0).strong());
+                            ui.label(RichText::new(format!("{}s", remaining.as_secs())).weak());
+                            if ui.small_button("Cancel").clicked() {
+                                server.pairing().cancel();
+                            }
+                        });
+                    }
+                    None => {
+                        if ui.button("Pair a device").clicked() {
+                            server.pairing().start();
+                        }
+                    }
+                }
+            }
+        });
+
+        if changed {
+            // Never run without a token
+            if prefs.enabled && prefs.token.is_empty() {
+                prefs.token = generate_token();
+            }
+            self.settings.save();
+            self.restart_http_server();
+        }
+    }
+}
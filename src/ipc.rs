@@ -0,0 +1,474 @@
+// Local control API: a Unix domain socket on Linux and macOS, a named pipe on Windows.
+// Clients send one JSON-RPC 2.0 request per line and get one response per line back, e.g.
+//
+//   {"jsonrpc": "2.0", "id": 1, "method": "set_volume", "params": {"volume": 0.5}}
+//   {"jsonrpc": "2.0", "id": 1, "result": {"volume": 0.5}}
+//
+// After "subscribe", state changes arrive as notifications on the same connection:
+//
+//   {"jsonrpc": "2.0", "method": "event", "params": {"event": "mute_changed", "muted": true}}
+
+use serde_json::{json, Value};
+use std::io::{self, Write};
+use std::sync::mpsc::Receiver;
+use std::time::Duration;
+
+use crate::control::{Command, ControlError, ControlEvent, ControlHandle};
+
+// How often an idle connection checks for new input and events
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Requests longer than this are rejected, so a client can't make us buffer without end
+const MAX_LINE: usize = 1024 * 1024;
+
+// Standard JSON-RPC error codes
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const SERVER_ERROR: i64 = -32000;
+
+// Where clients connect to
+pub fn address() -> String {
+    #[cfg(target_os = "windows")]
+    {
+        r"\\.\pipe\audio-controller".to_string()
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    {
+        // A folder of our own in the per-user runtime directory. The shared temporary directory
+        // is a last resort; the folder is made private before the socket is created in it.
+        let dir = std::env::var_os("XDG_RUNTIME_DIR")
+            .filter(|dir| !dir.is_empty())
+            .map(std::path::PathBuf::from)
+            .unwrap_or_else(std::env::temp_dir);
+        dir.join("audio-controller").join("control.sock").to_string_lossy().into_owned()
+    }
+}
+
+// Start accepting connections on a background thread
+pub fn start(control: ControlHandle) {
+    let result = std::thread::Builder::new().name("ipc".to_string()).spawn(move || {
+        #[cfg(target_os = "windows")]
+        let result = unsafe { windows_impl::listen(control) };
+        #[cfg(not(target_os = "windows"))]
+        let result = unix_impl::listen(control);
+
+        if let Err(err) = result {
+            eprintln!("ERROR: Control API stopped: {err}");
+        }
+    });
+    if let Err(err) = result {
+        eprintln!("ERROR: Couldn't start the control API: {err}");
+    }
+}
+
+// A connected client that can be checked for input without blocking
+trait Connection: Write {
+    // Ok(None) if nothing has arrived yet, Ok(Some(0)) once the client hung up
+    fn read_available(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>>;
+}
+
+struct Subscription {
+    events: Receiver<ControlEvent>,
+    // Only these events, or all of them if empty
+    filter: Vec<String>,
+}
+
+impl Subscription {
+    fn wants(&self, event: &ControlEvent) -> bool {
+        self.filter.is_empty() || self.filter.iter().any(|name| name == event.name())
+    }
+}
+
+fn write_message(connection: &mut impl Connection, message: &Value) -> io::Result<()> {
+    let mut line = message.to_string();
+    line.push('\n');
+    connection.write_all(line.as_bytes())?;
+    connection.flush()
+}
+
+// Talk to one client until it disconnects
+fn serve(mut connection: impl Connection, control: ControlHandle) {
+    let mut pending: Vec<u8> = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut subscription: Option<Subscription> = None;
+
+    loop {
+        let mut busy = false;
+
+        match connection.read_available(&mut buf) {
+            Ok(Some(0)) | Err(_) => return,
+            Ok(Some(read)) => {
+                busy = true;
+                pending.extend_from_slice(&buf[..read]);
+                while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+                    let line: Vec<u8> = pending.drain(..=end).collect();
+                    if let Some(response) = handle_line(&line, &control, &mut subscription) {
+                        if write_message(&mut connection, &response).is_err() {
+                            return;
+                        }
+                    }
+                }
+                if pending.len() > MAX_LINE {
+                    let _ = write_message(&mut connection, &error_response(Value::Null, INVALID_REQUEST, "Request too long"));
+                    return;
+                }
+            }
+            Ok(None) => {}
+        }
+
+        if let Some(subscription) = &subscription {
+            while let Ok(event) = subscription.events.try_recv() {
+                busy = true;
+                if !subscription.wants(&event) {
+                    continue;
+                }
+                let notification = json!({ "jsonrpc": "2.0", "method": "event", "params": event });
+                if write_message(&mut connection, &notification).is_err() {
+                    return;
+                }
+            }
+        }
+
+        if !busy {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn error_response(id: Value, code: i64, message: &str) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+// Answer a single request line. Notifications (requests without an id) get no answer.
+fn handle_line(line: &[u8], control: &ControlHandle, subscription: &mut Option<Subscription>) -> Option<Value> {
+    let text = String::from_utf8_lossy(line);
+    if text.trim().is_empty() {
+        return None;
+    }
+
+    let request: Value = match serde_json::from_str(&text) {
+        Ok(request) => request,
+        Err(err) => return Some(error_response(Value::Null, PARSE_ERROR, &err.to_string())),
+    };
+
+    let id = request.get("id").cloned();
+    let Some(method) = request.get("method").and_then(Value::as_str) else {
+        return Some(error_response(id.unwrap_or(Value::Null), INVALID_REQUEST, "Missing method"));
+    };
+    let params = request.get("params").cloned().unwrap_or_else(|| json!({}));
+
+    let result = match method {
+        // Subscriptions belong to the connection, so they're handled here rather than by the app
+        "subscribe" => {
+            let filter = params
+                .get("events")
+                .and_then(Value::as_array)
+                .map(|names| names.iter().filter_map(Value::as_str).map(str::to_string).collect())
+                .unwrap_or_default();
+            let events = subscription.take().map(|old| old.events).unwrap_or_else(|| control.subscribe());
+            *subscription = Some(Subscription { events, filter });
+            Ok(json!({ "subscribed": true }))
+        }
+        "unsubscribe" => {
+            *subscription = None;
+            Ok(json!({ "subscribed": false }))
+        }
+        _ => Command::from_method(method, &params).and_then(|command| control.call(command)),
+    };
+
+    let id = id?;
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(err) => {
+            let code = match err {
+                ControlError::UnknownMethod(_) => METHOD_NOT_FOUND,
+                ControlError::InvalidParams(_) => INVALID_PARAMS,
+                ControlError::Failed(_) | ControlError::Unavailable => SERVER_ERROR,
+            };
+            error_response(id, code, &err.to_string())
+        }
+    })
+}
+
+#[cfg(not(target_os = "windows"))]
+mod unix_impl {
+    use super::{address, serve, Connection, ControlHandle};
+    use std::io::{self, Read};
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+    use std::os::unix::net::{UnixListener, UnixStream};
+    use std::path::Path;
+
+    impl Connection for UnixStream {
+        fn read_available(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            // Only reads are non-blocking; writes should wait for a slow client
+            self.set_nonblocking(true)?;
+            let result = self.read(buf);
+            self.set_nonblocking(false)?;
+            match result {
+                Ok(read) => Ok(Some(read)),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(None),
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    // Anyone who can connect can control the app, so the socket goes in a folder only we can
+    // open. Setting its permissions afterwards would leave a moment in which it's open to all.
+    pub(super) fn private_dir(dir: &Path) -> io::Result<()> {
+        match std::fs::DirBuilder::new().mode(0o700).create(dir) {
+            Ok(()) => Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                // Left by an earlier run, or made by someone else to catch our clients. Only its
+                // owner can change its permissions, so this fails if it isn't ours.
+                if !std::fs::symlink_metadata(dir)?.is_dir() {
+                    return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} isn't a folder", dir.display())));
+                }
+                std::fs::set_permissions(dir, std::fs::Permissions::from_mode(0o700))
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn listen(control: ControlHandle) -> io::Result<()> {
+        let path = address();
+        if let Some(dir) = Path::new(&path).parent() {
+            private_dir(dir)?;
+        }
+
+        // A socket file left behind by a crash would stop us from binding, but one that
+        // still accepts connections belongs to another running instance
+        if UnixStream::connect(&path).is_ok() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{path} is already in use")));
+        }
+        let _ = std::fs::remove_file(&path);
+
+        let listener = UnixListener::bind(&path)?;
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let control = control.clone();
+            std::thread::spawn(move || serve(stream, control));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows_impl {
+    use super::{address, serve, Connection, ControlHandle};
+    use std::io::{self, Write};
+    use windows::core::HSTRING;
+    use windows::Win32::Foundation::{CloseHandle, ERROR_PIPE_CONNECTED, HANDLE};
+    use windows::Win32::Storage::FileSystem::{
+        FlushFileBuffers, ReadFile, WriteFile, FILE_FLAGS_AND_ATTRIBUTES, FILE_FLAG_FIRST_PIPE_INSTANCE,
+        PIPE_ACCESS_DUPLEX,
+    };
+    use windows::Win32::System::Pipes::{
+        ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, PeekNamedPipe, PIPE_READMODE_BYTE,
+        PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT,
+    };
+
+    const BUFFER_SIZE: u32 = 64 * 1024;
+
+    struct NamedPipe(HANDLE);
+
+    // The handle is only ever used by the thread serving its client
+    unsafe impl Send for NamedPipe {}
+
+    impl Connection for NamedPipe {
+        fn read_available(&mut self, buf: &mut [u8]) -> io::Result<Option<usize>> {
+            unsafe {
+                // Reading and writing a synchronous pipe can't overlap, so never block in a
+                // read while an event might be waiting to go out
+                let mut available = 0;
+                PeekNamedPipe(self.0, None, 0, None, Some(&mut available), None)?;
+                if available == 0 {
+                    return Ok(None);
+                }
+
+                let mut read = 0;
+                ReadFile(self.0, Some(buf), Some(&mut read), None)?;
+                Ok(Some(read as usize))
+            }
+        }
+    }
+
+    impl Write for NamedPipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut written = 0;
+            unsafe { WriteFile(self.0, Some(buf), Some(&mut written), None)? };
+            Ok(written as usize)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            unsafe { FlushFileBuffers(self.0)? };
+            Ok(())
+        }
+    }
+
+    impl Drop for NamedPipe {
+        fn drop(&mut self) {
+            unsafe {
+                let _ = DisconnectNamedPipe(self.0);
+                let _ = CloseHandle(self.0);
+            }
+        }
+    }
+
+    pub unsafe fn listen(control: ControlHandle) -> io::Result<()> {
+        let name = HSTRING::from(address());
+        let mut first = true;
+
+        loop {
+            // Each client gets its own instance of the pipe. The first one also makes sure no
+            // other running instance already owns the name.
+            let mut open_mode = PIPE_ACCESS_DUPLEX;
+            if first {
+                open_mode |= FILE_FLAG_FIRST_PIPE_INSTANCE;
+            }
+            let handle = CreateNamedPipeW(
+                &name,
+                FILE_FLAGS_AND_ATTRIBUTES(open_mode.0),
+                PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                PIPE_UNLIMITED_INSTANCES,
+                BUFFER_SIZE,
+                BUFFER_SIZE,
+                0,
+                None,
+            );
+            if handle.is_invalid() {
+                return Err(io::Error::last_os_error());
+            }
+            first = false;
+
+            let pipe = NamedPipe(handle);
+            match ConnectNamedPipe(pipe.0, None) {
+                Ok(()) => {}
+                // The client connected before we started waiting for it
+                Err(err) if err.code() == ERROR_PIPE_CONNECTED.to_hresult() => {}
+                Err(err) => {
+                    eprintln!("ERROR: Control API connection failed: {err}");
+                    continue;
+                }
+            }
+
+            let control = control.clone();
+            std::thread::spawn(move || serve(pipe, control));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlHub;
+
+    // Send one line the way a client's connection would, answering what reaches the UI thread
+    // the way the app does. Returns the response and the commands that were run.
+    fn answer(line: &str) -> (Option<Value>, Vec<Command>) {
+        let hub = ControlHub::new();
+        let control = hub.handle();
+        let line = line.to_string();
+        let client = std::thread::spawn(move || handle_line(line.as_bytes(), &control, &mut None));
+
+        let mut commands = Vec::new();
+        while !client.is_finished() {
+            match hub.poll() {
+                Some(request) => {
+                    let result = match &request.command {
+                        Command::SetVolume(volume) => Ok(json!({ "volume": volume })),
+                        _ => Err(ControlError::Failed("not here".to_string())),
+                    };
+                    commands.push(request.command.clone());
+                    request.respond(result);
+                }
+                None => std::thread::sleep(Duration::from_millis(1)),
+            }
+        }
+        (client.join().unwrap(), commands)
+    }
+
+    #[test]
+    fn answers_calls() {
+        let (response, commands) = answer(r#"{"jsonrpc": "2.0", "id": 7, "method": "set_volume", "params": {"volume": 0.5}}"#);
+        assert_eq!(response, Some(json!({ "jsonrpc": "2.0", "id": 7, "result": { "volume": 0.5 } })));
+        assert_eq!(commands, [Command::SetVolume(0.5)]);
+
+        // String ids come back as they were sent
+        let (response, _) = answer(r#"{"jsonrpc": "2.0", "id": "a", "method": "set_volume", "params": {"volume": 1}}"#);
+        assert_eq!(response.unwrap()["id"], json!("a"));
+
+        let (response, _) = answer(r#"{"jsonrpc": "2.0", "id": 1, "method": "set_volume", "params": {"volume": 2}}"#);
+        assert_eq!(response.unwrap()["error"]["code"], json!(INVALID_PARAMS));
+        let (response, _) = answer(r#"{"jsonrpc": "2.0", "id": 1, "method": "get_state"}"#);
+        assert_eq!(response.unwrap()["error"], json!({ "code": SERVER_ERROR, "message": "not here" }));
+    }
+
+    #[test]
+    fn refuses_unknown_methods() {
+        let (response, commands) = answer(r#"{"jsonrpc": "2.0", "id": 2, "method": "explode"}"#);
+        let response = response.unwrap();
+        assert_eq!(response["id"], json!(2));
+        assert_eq!(response["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert!(commands.is_empty());
+
+        let (response, _) = answer(r#"{"jsonrpc": "2.0", "id": 3}"#);
+        assert_eq!(response.unwrap()["error"], json!({ "code": INVALID_REQUEST, "message": "Missing method" }));
+    }
+
+    #[test]
+    fn refuses_malformed_json() {
+        for line in ["{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\"", "volume up", "\u{fffd}\u{0}"] {
+            let (response, commands) = answer(line);
+            let response = response.unwrap();
+            assert_eq!(response["id"], Value::Null, "{line}");
+            assert_eq!(response["error"]["code"], json!(PARSE_ERROR), "{line}");
+            assert!(commands.is_empty());
+        }
+        // Blank lines between requests are fine
+        assert_eq!(answer("  \r\n").0, None);
+    }
+
+    // Requests without an id are notifications: they're carried out but not answered
+    #[test]
+    fn notifications_get_no_answer() {
+        let (response, commands) = answer(r#"{"jsonrpc": "2.0", "method": "set_volume", "params": {"volume": 0.25}}"#);
+        assert_eq!(response, None);
+        assert_eq!(commands, [Command::SetVolume(0.25)]);
+
+        let (response, _) = answer(r#"{"jsonrpc": "2.0", "method": "explode"}"#);
+        assert_eq!(response, None);
+    }
+
+    #[cfg(not(target_os = "windows"))]
+    #[test]
+    fn socket_folder_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let base = std::env::temp_dir().join(format!("audioapp2-ipc-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&base);
+        std::fs::create_dir_all(&base).unwrap();
+        let mode = |path: &std::path::Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        let dir = base.join("new");
+        unix_impl::private_dir(&dir).unwrap();
+        assert_eq!(mode(&dir), 0o700);
+
+        // One left open by someone is closed before it's used
+        let open = base.join("open");
+        std::fs::create_dir(&open).unwrap();
+        std::fs::set_permissions(&open, std::fs::Permissions::from_mode(0o777)).unwrap();
+        unix_impl::private_dir(&open).unwrap();
+        assert_eq!(mode(&open), 0o700);
+
+        // A link could lead anywhere, and a file isn't a folder
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&open, &link).unwrap();
+        assert!(unix_impl::private_dir(&link).is_err());
+        std::fs::write(base.join("file"), "").unwrap();
+        assert!(unix_impl::private_dir(&base.join("file")).is_err());
+        let _ = std::fs::remove_dir_all(&base);
+    }
+}
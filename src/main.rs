@@ -3,8 +3,68 @@
 use eframe::egui;
 use egui::{Color32, RichText, Slider};
 use cpal::traits::{DeviceTrait, HostTrait};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use windows_volume_control::{AudioController, CoinitMode};
 
+mod accessibility;
+mod app_rules;
+mod auto_mute;
+mod channels;
+mod cli;
+mod control;
+mod device_events;
+mod devices;
+mod dsp;
+mod endpoints;
+mod equalizer;
+mod hotkeys;
+mod http_api;
+mod ipc;
+mod measurement;
+mod midi;
+mod monitor;
+mod mqtt;
+mod multi_output;
+mod notifications;
+mod now_playing;
+mod osc;
+mod pipeline;
+mod priority;
+mod profiles;
+mod remote;
+mod routing;
+mod scenes;
+mod scripting;
+mod sessions;
+mod settings;
+mod simulated;
+
+use accessibility::ChannelOption;
+use app_rules::AppRules;
+use auto_mute::AutoMuteSwitch;
+use channels::ChannelsUi;
+use control::ControlHub;
+use device_events::{DeviceEvent, DeviceWatcher};
+use devices::DevicesUi;
+use endpoints::{Endpoint, Flow};
+use hotkeys::{HotkeyAction, HotkeyListener, MediaKeyListener};
+use http_api::HttpServer;
+use measurement::Measurement;
+use midi::{MidiController, MidiUi};
+use mqtt::MqttBridge;
+use equalizer::EqState;
+use monitor::Monitor;
+use multi_output::MultiOutput;
+use notifications::Notifications;
+use now_playing::NowPlayingWatcher;
+use osc::OscServer;
+use routing::Routing;
+use scenes::{AppVolume, ScenesUi};
+use scripting::ScriptHost;
+use sessions::{AudioSession, SessionWatcher};
+use settings::{Settings, APP_NAME};
+
 #[cfg(target_os = "windows")]
 mod win_utils {
     use winapi::um::winuser::{ReleaseCapture, SendMessageW, PostMessageW};
@@ -26,13 +86,70 @@ mod win_utils {
     }
 }
 
+// How long to wait for a new default device to show up after asking for it
+const DEFAULT_SWITCH_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_SWITCH_POLL: Duration = Duration::from_millis(50);
+
+// Which page of the window is showing
+#[derive(PartialEq, Clone, Copy)]
+enum Tab {
+    Device,
+    Mixer,
+    Equalizer,
+    Measure,
+    Scenes,
+    Settings,
+}
+
 // Application state
 struct AudioApp {
     device_names: Vec<String>,
     selected_device_idx: Option<usize>,
+    input_device_names: Vec<String>,
+    // Last known default recording device
+    default_input_name: Option<String>,
     volume: f32,
     is_muted: bool,
     audio_controller: Option<AudioController>,
+    // Type, state and volume of every output endpoint, refreshed on demand
+    output_endpoints: Vec<Endpoint>,
+    // Linux: the sound server's default sink, which ALSA's "default" plays on. Looked up again
+    // along with the endpoints.
+    #[cfg(target_os = "linux")]
+    default_sink: Option<String>,
+    settings: Settings,
+    tab: Tab,
+    scenes_ui: ScenesUi,
+    devices_ui: DevicesUi,
+    channels_ui: ChannelsUi,
+    notifications: Notifications,
+    hotkeys: Option<HotkeyListener>,
+    device_watcher: Option<DeviceWatcher>,
+    // Auto-mute options as seen by the device watcher's thread
+    auto_mute: Arc<AutoMuteSwitch>,
+    // Requests from other programs, see control.rs
+    control: ControlHub,
+    http_server: Option<HttpServer>,
+    mqtt: Option<MqttBridge>,
+    osc: Option<OscServer>,
+    midi: Option<MidiController>,
+    midi_ui: MidiUi,
+    now_playing: Option<NowPlayingWatcher>,
+    media_keys: Option<MediaKeyListener>,
+    media_keys_error: Option<String>,
+    scripts: Option<ScriptHost>,
+    // Applications with audio streams open, kept up to date while something needs them
+    session_watcher: Option<SessionWatcher>,
+    sessions: Vec<AudioSession>,
+    // When a remote client last asked about applications
+    apps_requested: Option<Instant>,
+    // Rules applied while their app is playing
+    app_rules: AppRules,
+    routing: Routing,
+    multi_output: MultiOutput,
+    monitor: Monitor,
+    eq: EqState,
+    measurement: Measurement,
 }
 
 impl AudioApp {
@@ -65,12 +182,124 @@ impl AudioApp {
         // Initialize with default values
         let mut app = Self {
             audio_controller: None,
+            output_endpoints: Vec::new(),
+            #[cfg(target_os = "linux")]
+            default_sink: None,
             device_names,
             selected_device_idx,
+            input_device_names: Vec::new(),
+            default_input_name: None,
             volume: 0.5,
             is_muted: false,
+            settings: Settings::load(),
+            tab: Tab::Device,
+            scenes_ui: ScenesUi::default(),
+            devices_ui: DevicesUi::default(),
+            channels_ui: ChannelsUi::default(),
+            notifications: Notifications::default(),
+            hotkeys: None,
+            device_watcher: None,
+            auto_mute: Arc::default(),
+            control: ControlHub::new(),
+            http_server: None,
+            mqtt: None,
+            osc: None,
+            midi: None,
+            midi_ui: MidiUi::default(),
+            now_playing: None,
+            media_keys: None,
+            media_keys_error: None,
+            scripts: None,
+            session_watcher: None,
+            sessions: Vec::new(),
+            apps_requested: None,
+            app_rules: AppRules::default(),
+            routing: Routing::default(),
+            multi_output: MultiOutput::default(),
+            monitor: Monitor::default(),
+            eq: EqState::default(),
+            measurement: Measurement::default(),
         };
 
+        app.auto_mute.set(&app.settings.auto_mute);
+
+        app.reload_audio_controller();
+        app.refresh_endpoints();
+        app.input_device_names = Self::list_input_devices();
+        app.default_input_name = app.default_input_device_name();
+        app
+    }
+
+    fn list_input_devices() -> Vec<String> {
+        let host = cpal::default_host();
+        match host.input_devices() {
+            Ok(devices) => devices.filter_map(|device| device.name().ok()).collect(),
+            Err(_) => Vec::new(),
+        }
+    }
+
+    // React to devices being plugged in or removed and to the default device changing
+    fn handle_device_events(&mut self) {
+        let mut devices_changed = false;
+        let mut default_changed = false;
+
+        while let Some(event) = self.device_watcher.as_ref().and_then(|watcher| watcher.poll()) {
+            match event {
+                DeviceEvent::DevicesChanged => devices_changed = true,
+                DeviceEvent::DefaultChanged(Flow::Output) => default_changed = true,
+                DeviceEvent::DefaultChanged(Flow::Input) => self.default_input_name = self.default_input_device_name(),
+                DeviceEvent::AutoMuted { from, to } => {
+                    // The watcher already muted the device; this just catches the UI up
+                    default_changed = true;
+                    self.notifications.push(format!(
+                        "{} unplugged, muted {}",
+                        self.settings.devices.display_name(&from),
+                        self.settings.devices.display_name(&to)
+                    ));
+                }
+            }
+        }
+
+        if devices_changed || default_changed {
+            self.channels_ui.forget_device();
+        }
+        if devices_changed {
+            let previous_outputs = self.device_names.clone();
+            let previous_inputs = self.input_device_names.clone();
+            let previous_output = self.selected_device_idx.map(|idx| self.device_names[idx].clone());
+            let previous_input = self.default_input_name.clone();
+
+            self.refresh_devices();
+            self.apply_priority(Flow::Output, &previous_outputs, previous_output);
+            self.apply_priority(Flow::Input, &previous_inputs, previous_input);
+            self.follow_default_device();
+        } else if default_changed {
+            // Follow the new default so the volume controls act on the right device
+            self.reload_audio_controller();
+            self.refresh_endpoints();
+            self.sync_selected_device();
+            self.follow_default_device();
+        }
+    }
+
+    // Point the picker at whatever the system default output currently is
+    fn sync_selected_device(&mut self) {
+        let default_name = self.default_output_device_name().map(|name| self.real_output(name));
+        self.selected_device_idx = default_name.and_then(|name| self.device_names.iter().position(|other| other == &name));
+    }
+
+    // Re-read type, state and volume of the output endpoints
+    fn refresh_endpoints(&mut self) {
+        self.output_endpoints = endpoints::list_endpoints(Flow::Output);
+        #[cfg(target_os = "linux")]
+        {
+            self.default_sink = endpoints::pulse::default_sink_name();
+        }
+    }
+
+    // (Re)create the audio controller. The controller only sees the default device and the
+    // application sessions that existed when it was created, so this also picks up new ones.
+    fn reload_audio_controller(&mut self) {
         // Initialize audio controller with apartment threading
         unsafe {
             let mut controller = AudioController::init(Some(CoinitMode::ApartmentThreaded));
@@ -80,14 +309,56 @@ impl AudioApp {
 
             // Get initial volume
             if let Some(session) = controller.get_session_by_name("master".to_string()) {
-                app.volume = session.getVolume();
-                app.is_muted = session.getMute();
+                self.volume = session.getVolume();
+                self.is_muted = session.getMute();
             }
 
-            app.audio_controller = Some(controller);
+            self.audio_controller = Some(controller);
         }
+    }
 
-        app
+    // Register the global hotkeys from the settings, replacing any previous registration
+    fn restart_hotkeys(&mut self) {
+        // Drop the old listener first so its hotkeys are free to register again
+        self.hotkeys = None;
+
+        let mut bindings: Vec<(String, HotkeyAction)> = self
+            .settings
+            .scenes
+            .iter()
+            .filter_map(|scene| {
+                let hotkey = scene.hotkey.clone()?;
+                Some((hotkey, HotkeyAction::ApplyScene(scene.name.clone())))
+            })
+            .collect();
+        let accessibility = &self.settings.accessibility;
+        bindings.extend(accessibility.mono_hotkey.clone().map(|hotkey| (hotkey, HotkeyAction::ToggleMono)));
+        bindings.extend(accessibility.swap_hotkey.clone().map(|hotkey| (hotkey, HotkeyAction::ToggleSwapChannels)));
+
+        if !bindings.is_empty() {
+            self.hotkeys = Some(HotkeyListener::start(bindings));
+        }
+    }
+
+    fn handle_hotkeys(&mut self) {
+        let step = self.settings.media.volume_step as f32 / 100.0;
+        while let Some(action) = self
+            .hotkeys
+            .as_ref()
+            .and_then(|hotkeys| hotkeys.poll())
+            .or_else(|| self.media_keys.as_ref().and_then(|keys| keys.poll()))
+        {
+            match action {
+                HotkeyAction::ApplyScene(name) => {
+                    self.apply_scene(&name);
+                }
+                HotkeyAction::VolumeUp => self.set_volume((self.volume + step).min(1.0)),
+                HotkeyAction::VolumeDown => self.set_volume((self.volume - step).max(0.0)),
+                HotkeyAction::ToggleMute => self.toggle_mute(),
+                HotkeyAction::ToggleMono => self.toggle_channel_option(ChannelOption::Mono),
+                HotkeyAction::ToggleSwapChannels => self.toggle_channel_option(ChannelOption::SwapChannels),
+            }
+        }
     }
 
     fn update_volume(&mut self) {
@@ -112,6 +383,17 @@ impl AudioApp {
         }
     }
 
+    fn set_mute(&mut self, mute: bool) {
+        if let Some(controller) = &self.audio_controller {
+            unsafe {
+                if let Some(session) = controller.get_session_by_name("master".to_string()) {
+                    session.setMute(mute);
+                    self.is_muted = mute;
+                }
+            }
+        }
+    }
+
     fn toggle_mute(&mut self) {
         if let Some(controller) = &self.audio_controller {
             unsafe {
@@ -124,6 +406,82 @@ impl AudioApp {
         }
     }
 
+    // Volume and mute of every application that currently has an audio session
+    fn app_volumes(&self) -> Vec<AppVolume> {
+        let mut apps: Vec<AppVolume> = Vec::new();
+        if let Some(controller) = &self.audio_controller {
+            unsafe {
+                for name in controller.get_all_session_names() {
+                    // Skip the endpoint itself and apps with several sessions we've already seen
+                    if name == "master" || apps.iter().any(|app| app.name == name) {
+                        continue;
+                    }
+                    if let Some(session) = controller.get_session_by_name(name.clone()) {
+                        apps.push(AppVolume { name, volume: session.getVolume(), muted: session.getMute() });
+                    }
+                }
+            }
+        }
+        apps
+    }
+
+    fn set_app_volume(&mut self, app: &str, volume: f32) {
+        if let Some(controller) = &self.audio_controller {
+            unsafe {
+                if let Some(session) = controller.get_session_by_name(app.to_string()) {
+                    session.setVolume(volume);
+                }
+            }
+        }
+    }
+
+    fn set_app_mute(&mut self, app: &str, mute: bool) {
+        if let Some(controller) = &self.audio_controller {
+            unsafe {
+                if let Some(session) = controller.get_session_by_name(app.to_string()) {
+                    session.setMute(mute);
+                }
+            }
+        }
+    }
+
+    // Name of the current default playback device
+    fn default_output_device_name(&self) -> Option<String> {
+        cpal::default_host().default_output_device().and_then(|device| device.name().ok())
+    }
+
+    // Name of the current default recording device
+    fn default_input_device_name(&self) -> Option<String> {
+        cpal::default_host().default_input_device().and_then(|device| device.name().ok())
+    }
+
+    // Set the default recording device in Windows by name
+    fn set_default_input_device_by_name(&mut self, device_name: &str) {
+        #[cfg(target_os = "windows")]
+        self.set_default_input_device_powershell(device_name);
+        self.default_input_name = Some(device_name.to_string());
+    }
+
+    // Switching devices through PowerShell happens in the background. Wait until `name` is
+    // the default for `flow`, returning false if that didn't happen in time.
+    fn wait_for_default(&self, flow: Flow, name: &str) -> bool {
+        let deadline = Instant::now() + DEFAULT_SWITCH_TIMEOUT;
+        loop {
+            let current = match flow {
+                Flow::Output => self.default_output_device_name().map(|name| self.real_output(name)),
+                Flow::Input => self.default_input_device_name(),
+            };
+            if current.as_deref() == Some(name) {
+                return true;
+            }
+            // Elsewhere switching is either immediate or not supported at all
+            if !cfg!(target_os = "windows") || Instant::now() >= deadline {
+                return false;
+            }
+            std::thread::sleep(DEFAULT_SWITCH_POLL);
+        }
+    }
+
     // Set the default audio device in Windows by index
     fn set_default_device(&mut self, device_idx: usize) {
         if device_idx >= self.device_names.len() {
@@ -133,6 +491,11 @@ impl AudioApp {
         // Get the device name - clone it to avoid borrow issues
         let device_name = self.device_names[device_idx].clone();
         self.set_default_device_by_name(&device_name);
+        self.selected_device_idx = Some(device_idx);
+
+        // Remember it so it shows up at the top of the picker next time
+        self.settings.devices.note_recent(&device_name);
+        self.settings.save();
     }
 
     // Refresh the list of audio devices
@@ -152,17 +515,29 @@ impl AudioApp {
 
         // Update the device list
         self.device_names = device_names;
+        self.input_device_names = Self::list_input_devices();
+        self.default_input_name = self.default_input_device_name();
 
-        // Reset selected device if it's no longer valid
-        if let Some(idx) = self.selected_device_idx {
-            if idx >= self.device_names.len() {
-                self.selected_device_idx = None;
-            }
+        // Pick up the new default device and any applications that started since
+        self.reload_audio_controller();
+        self.refresh_endpoints();
+
+        // Indices shift when devices come and go, so look the default up again by name
+        self.sync_selected_device();
+    }
+
+    // Set the default audio device by name. Devices with a DSP profile are played through the
+    // processing path, so the virtual device becomes the default and the device its output.
+    fn set_default_device_by_name(&mut self, device_name: &str) {
+        if self.apply_device_profile(device_name) {
+            self.route_through_processing();
+        } else {
+            self.switch_default_device(device_name);
         }
     }
 
     // Set the default audio device in Windows by name
-    fn set_default_device_by_name(&mut self, device_name: &str) {
+    fn switch_default_device(&mut self, device_name: &str) {
         #[cfg(target_os = "windows")]
         {
             // First try using the Windows API directly through winapi
@@ -236,6 +611,174 @@ impl AudioApp {
             .args(&["-Command", &full_command])
             .spawn();
     }
+
+    #[cfg(target_os = "windows")]
+    fn set_default_input_device_powershell(&self, device_name: &str) {
+        use std::process::Command;
+
+        // Same tools as for outputs, but only looking at recording devices
+        let ps_command1 = format!(
+            "if (Get-Command Get-AudioDevice -ErrorAction SilentlyContinue) {{ \
+             Get-AudioDevice -List | Where-Object {{ $_.Type -eq 'Recording' -and $_.Name -eq '{}' }} | Set-AudioDevice \
+             }}",
+            device_name.replace("'", "''")
+        );
+        let ps_command2 = format!(
+            "if (Test-Path 'C:\\Windows\\SoundVolumeView.exe') {{ \
+             C:\\Windows\\SoundVolumeView.exe /SetDefault \"{}\" all \
+             }}",
+            device_name.replace("\"", "\\\"")
+        );
+
+        let _ = Command::new("powershell")
+            .args(["-Command", &format!("{} ; {}", ps_command1, ps_command2)])
+            .spawn();
+    }
+
+    // Options that aren't needed day to day
+    fn settings_ui(&mut self, ui: &mut egui::Ui) {
+        egui::CollapsingHeader::new("Fallback priority").default_open(true).show(ui, |ui| {
+            self.priority_ui(ui);
+        });
+        egui::CollapsingHeader::new("Auto-mute").default_open(true).show(ui, |ui| {
+            self.auto_mute_ui(ui);
+        });
+        egui::CollapsingHeader::new("App rules").show(ui, |ui| {
+            self.app_rules_ui(ui);
+        });
+        egui::CollapsingHeader::new("Multi-output").show(ui, |ui| {
+            self.multi_output_ui(ui);
+        });
+        egui::CollapsingHeader::new("Listen to an input").show(ui, |ui| {
+            self.monitor_ui(ui);
+        });
+        egui::CollapsingHeader::new("Accessibility").show(ui, |ui| {
+            self.accessibility_ui(ui);
+        });
+        egui::CollapsingHeader::new("HTTP API").show(ui, |ui| {
+            self.http_ui(ui);
+        });
+        egui::CollapsingHeader::new("MQTT").show(ui, |ui| {
+            self.mqtt_ui(ui);
+        });
+        egui::CollapsingHeader::new("OSC").show(ui, |ui| {
+            self.osc_ui(ui);
+        });
+        egui::CollapsingHeader::new("MIDI").show(ui, |ui| {
+            self.midi_ui(ui);
+        });
+        egui::CollapsingHeader::new("Scripts").show(ui, |ui| {
+            self.scripts_ui(ui);
+        });
+        #[cfg(target_os = "linux")]
+        egui::CollapsingHeader::new("Media").show(ui, |ui| {
+            self.media_ui(ui);
+        });
+    }
+
+    // The main page: output device picker and volume controls
+    fn device_ui(&mut self, ui: &mut egui::Ui) {
+        // Device selection - make it responsive with padding
+        ui.add_space(10.0); // Add more padding above
+
+        let _device_frame = egui::Frame::none()
+            .fill(ui.visuals().extreme_bg_color) // Slightly different background
+            .inner_margin(egui::style::Margin::same(12.0)) // Add more padding inside
+            .rounding(egui::Rounding::same(6.0)) // Add rounded corners
+            .stroke(egui::Stroke::new(1.0, ui.visuals().widgets.noninteractive.bg_stroke.color)) // Add border
+            .show(ui, |ui| {
+                // Use vertical layout for better organization
+                ui.vertical(|ui| {
+                    ui.label(RichText::new("Output Device:").strong().size(16.0));
+                    ui.add_space(8.0);
+
+                    // Searchable device list
+                    self.device_picker_ui(ui);
+
+                    // Mono and swap L/R for the selected device
+                    self.channel_options_ui(ui);
+
+                    // Aliases, hidden devices and favorites
+                    egui::CollapsingHeader::new("Manage devices").show(ui, |ui| {
+                        self.device_manager_ui(ui);
+                    });
+                });
+            });
+
+        ui.add_space(10.0); // Add more padding below
+
+        ui.add_space(8.0);
+        ui.separator();
+        ui.add_space(8.0);
+
+        // Volume control - in a frame with padding for better appearance
+        let _volume_frame = egui::Frame::none()
+            .fill(ui.visuals().extreme_bg_color)
+            .inner_margin(egui::style::Margin::same(10.0))
+            .show(ui, |ui| {
+                // Use a vertical layout for better responsiveness
+                ui.vertical(|ui| {
+                    // First row: Mute button and volume percentage
+                    ui.horizontal(|ui| {
+                        // Use different icons for mute/unmute
+                        let mute_btn_text = if self.is_muted {
+                            RichText::new("🔇").color(Color32::RED).size(20.0)
+                        } else {
+                            RichText::new("🎵").color(Color32::GREEN).size(20.0)
+                        };
+
+                        // Make button a bit larger
+                        if ui.add(egui::Button::new(mute_btn_text).min_size(egui::vec2(36.0, 36.0))).clicked() {
+                            self.toggle_mute();
+                        }
+
+                        // Push volume percentage to the right
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            ui.label(RichText::new(format!("{}%", (self.volume * 100.0) as i32)).size(18.0));
+                        });
+                    });
+
+                    // Second row: Full-width slider with better visibility
+                    ui.add_space(4.0); // Add some space above the slider
+
+                    // Create a frame for the slider to make it more visible
+                    let slider_frame = egui::Frame::none()
+                        .fill(ui.visuals().widgets.inactive.bg_fill)
+                        .inner_margin(egui::style::Margin::same(12.0)) // Increased padding
+                        .rounding(egui::Rounding::same(6.0)) // Increased rounding
+                        .stroke(egui::Stroke::new(1.0, ui.visuals().widgets.noninteractive.bg_stroke.color)) // Add border
+                        .show(ui, |ui| {
+                            // Add some extra space for better visibility
+                            ui.add_space(4.0);
+
+                            // Make the slider larger and more visible
+                            let volume_response = ui.add_sized(
+                                [ui.available_width(), 30.0], // Make the slider taller
+                                Slider::new(&mut self.volume, 0.0..=1.0)
+                                    .text("Volume")
+                                    .show_value(false)
+                                    .trailing_fill(true) // Fill the slider to show current level
+                            );
+
+                            ui.add_space(4.0);
+                            volume_response
+                        }).inner;
+
+                    ui.add_space(4.0); // Add some space below the slider
+
+                    if slider_frame.changed() {
+                        self.set_volume(self.volume);
+                    }
+
+                    // Balance and the level of each speaker
+                    egui::CollapsingHeader::new("Channels").show(ui, |ui| {
+                        self.channels_ui(ui);
+                    });
+                });
+            });
+
+        self.now_playing_ui(ui);
+    }
 }
 
 // Extension trait to get the window handle from eframe
@@ -269,6 +812,26 @@ impl eframe::App for AudioApp {
         // Update volume from system
         self.update_volume();
 
+        // Apply any global hotkeys pressed since the last frame
+        self.handle_hotkeys();
+
+        // Devices plugged in or removed since the last frame
+        self.handle_device_events();
+
+        // Requests from other programs, and telling them what changed
+        self.handle_control_requests();
+        self.handle_midi_learn();
+        self.run_scripts();
+
+        // Applications starting and stopping
+        let sessions_changed = self.watch_sessions();
+        if sessions_changed && self.tab == Tab::Mixer {
+            // Let the mixer's volume controls see the new sessions
+            self.reload_audio_controller();
+        }
+        self.handle_app_rules();
+        self.apply_routes(sessions_changed);
+
         // We'll implement a simpler dragging mechanism
 
         // Use the central panel directly instead of creating a nested window
@@ -340,156 +903,102 @@ impl eframe::App for AudioApp {
                 }).response;
 
             ui.separator();
-            ui.add_space(5.0);
-
-            // Device selection - make it responsive with padding
-            ui.add_space(10.0); // Add more padding above
 
-            let _device_frame = egui::Frame::none()
-                .fill(ui.visuals().extreme_bg_color) // Slightly different background
-                .inner_margin(egui::style::Margin::same(12.0)) // Add more padding inside
-                .rounding(egui::Rounding::same(6.0)) // Add rounded corners
-                .stroke(egui::Stroke::new(1.0, ui.visuals().widgets.noninteractive.bg_stroke.color)) // Add border
-                .show(ui, |ui| {
-                    // Use vertical layout for better organization
-                    ui.vertical(|ui| {
-                        ui.label(RichText::new("Output Device:").strong().size(16.0));
-                        ui.add_space(8.0);
-
-                        // Use full width for the combo box
-                        // Track if a device was selected
-                        let mut selected_device = None;
-
-                        // Make the combo box take the full width with better visibility
-                        let combo = egui::ComboBox::from_label("")
-                            .selected_text(
-                                self.selected_device_idx
-                                    .map(|idx| {
-                                        // Truncate long device names for display
-                                        let name = self.device_names[idx].clone();
-                                        if name.len() > 25 {
-                                            format!("{}...", &name[0..22])
-                                        } else {
-                                            name
-                                        }
-                                    })
-                                    .unwrap_or_else(|| "Select a device".to_string()),
-                            )
-                            .width(ui.available_width()) // Use full width
-                            .height(250.0) // Increase maximum height for the dropdown
-                            .wrap(false); // Prevent text wrapping in dropdown
-
-                        combo.show_ui(ui, |ui| {
-                            // Add a scrolling area for many devices
-                            egui::ScrollArea::vertical().max_height(250.0).show(ui, |ui| {
-                                for (idx, name) in self.device_names.iter().enumerate() {
-                                    let response = ui.selectable_value(&mut self.selected_device_idx, Some(idx), name);
-
-                                    // If a new device is selected, store the index
-                                    if response.clicked() {
-                                        selected_device = Some(idx);
-                                    }
-                                }
-                            });
-                        });
-
-                        // If a device was selected, set it as default
-                        if let Some(idx) = selected_device {
-                            // We need to clone the device name because we'll need to borrow self mutably
-                            let device_name = self.device_names[idx].clone();
-                            self.set_default_device_by_name(&device_name);
-                        }
-                    });
-                });
-
-            ui.add_space(10.0); // Add more padding below
-
-            ui.add_space(8.0);
-            ui.separator();
-            ui.add_space(8.0);
+            // Page selection
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.tab, Tab::Device, "Device");
+                ui.selectable_value(&mut self.tab, Tab::Mixer, "Mixer");
+                ui.selectable_value(&mut self.tab, Tab::Equalizer, "EQ");
+                ui.selectable_value(&mut self.tab, Tab::Measure, "Measure");
+                ui.selectable_value(&mut self.tab, Tab::Scenes, "Scenes");
+                ui.selectable_value(&mut self.tab, Tab::Settings, "Settings");
+            });
 
-            // Volume control - in a frame with padding for better appearance
-            let _volume_frame = egui::Frame::none()
-                .fill(ui.visuals().extreme_bg_color)
-                .inner_margin(egui::style::Margin::same(10.0))
-                .show(ui, |ui| {
-                    // Use a vertical layout for better responsiveness
-                    ui.vertical(|ui| {
-                        // First row: Mute button and volume percentage
-                        ui.horizontal(|ui| {
-                            // Use different icons for mute/unmute
-                            let mute_btn_text = if self.is_muted {
-                                RichText::new("🔇").color(Color32::RED).size(20.0)
-                            } else {
-                                RichText::new("🎵").color(Color32::GREEN).size(20.0)
-                            };
-
-                            // Make button a bit larger
-                            if ui.add(egui::Button::new(mute_btn_text).min_size(egui::vec2(36.0, 36.0))).clicked() {
-                                self.toggle_mute();
-                            }
-
-                            // Push volume percentage to the right
-                            ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
-                                ui.label(RichText::new(format!("{}%", (self.volume * 100.0) as i32)).size(18.0));
-                            });
-                        });
+            ui.add_space(5.0);
 
-                        // Second row: Full-width slider with better visibility
-                        ui.add_space(4.0); // Add some space above the slider
-
-                        // Create a frame for the slider to make it more visible
-                        let slider_frame = egui::Frame::none()
-                            .fill(ui.visuals().widgets.inactive.bg_fill)
-                            .inner_margin(egui::style::Margin::same(12.0)) // Increased padding
-                            .rounding(egui::Rounding::same(6.0)) // Increased rounding
-                            .stroke(egui::Stroke::new(1.0, ui.visuals().widgets.noninteractive.bg_stroke.color)) // Add border
-                            .show(ui, |ui| {
-                                // Add some extra space for better visibility
-                                ui.add_space(4.0);
-
-                                // Make the slider larger and more visible
-                                let volume_response = ui.add_sized(
-                                    [ui.available_width(), 30.0], // Make the slider taller
-                                    Slider::new(&mut self.volume, 0.0..=1.0)
-                                        .text("Volume")
-                                        .show_value(false)
-                                        .trailing_fill(true) // Fill the slider to show current level
-                                );
-
-                                ui.add_space(4.0);
-                                volume_response
-                            }).inner;
-
-                        ui.add_space(4.0); // Add some space below the slider
-
-                        if slider_frame.changed() {
-                            self.set_volume(self.volume);
-                        }
-                    });
-                });
+            egui::ScrollArea::vertical().show(ui, |ui| match self.tab {
+                Tab::Device => self.device_ui(ui),
+                Tab::Mixer => self.mixer_ui(ui),
+                Tab::Equalizer => self.equalizer_ui(ui),
+                Tab::Measure => self.measurement_ui(ui),
+                Tab::Scenes => self.scenes_ui(ui),
+                Tab::Settings => self.settings_ui(ui),
+            });
         });
 
+        self.notifications.show(ctx);
+
         // Request a repaint for smooth updates
         ctx.request_repaint();
     }
 }
 
 fn main() {
+    match cli::parse_args(std::env::args().skip(1)) {
+        Ok(cli::CliCommand::Gui) => {}
+        Ok(command) => {
+            cli::attach_console();
+            run_cli(command);
+            return;
+        }
+        Err(err) => {
+            cli::attach_console();
+            eprintln!("{err}\n\n{}", cli::USAGE);
+            std::process::exit(2);
+        }
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default()
             .with_inner_size([500.0, 350.0])  // Much larger size to ensure all content is visible
             .with_always_on_top()
             .with_decorations(false)  // No default window decorations
             .with_transparent(false)
-            .with_title("Audio Controller"),  // Title for taskbar
+            .with_title(APP_NAME),  // Title for taskbar
         ..Default::default()
     };
 
     eframe::run_native(
-        "Audio Controller",
+        APP_NAME,
         options,
-        Box::new(|_cc| Box::new(AudioApp::new())),
+        Box::new(|cc| {
+            let mut app = AudioApp::new();
+            app.control.set_context(cc.egui_ctx.clone());
+            app.restart_hotkeys();
+            app.device_watcher = Some(DeviceWatcher::start(app.auto_mute.clone()));
+            ipc::start(app.control.handle());
+            app.restart_http_server();
+            app.restart_mqtt();
+            app.restart_osc();
+            app.restart_midi();
+            app.restart_media();
+            app.restart_scripts();
+            app.restart_multi_output();
+            app.restart_monitor();
+            app.follow_default_device();
+            Box::new(app)
+        }),
     )
     .unwrap();
 }
+
+// Run a command line action without opening the window
+fn run_cli(command: cli::CliCommand) {
+    match command {
+        cli::CliCommand::Gui => {}
+        cli::CliCommand::Help => println!("{}", cli::USAGE),
+        cli::CliCommand::Simulate(options) => simulated::run(options),
+        cli::CliCommand::ListScenes => {
+            for scene in Settings::load().scenes {
+                println!("{}", scene.name);
+            }
+        }
+        cli::CliCommand::ApplyScene(name) => {
+            let mut app = AudioApp::new();
+            if !app.apply_scene(&name) {
+                eprintln!("No scene named '{name}'");
+                std::process::exit(1);
+            }
+        }
+    }
+}
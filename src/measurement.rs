@@ -0,0 +1,517 @@
+// Measure a speaker or a room: play a sine sweep on an output, record it through an input and
+// work out the impulse and frequency response, see dsp/sweep.rs. The result is plotted and can
+// be saved for REW, a spreadsheet or a room correction tool.
+//
+// Besides a microphone, the sweep can be recorded straight from the output (WASAPI loopback, to
+// check what the app's own processing does) or from a simulated room that needs no devices.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, SampleFormat, SizedSample};
+use eframe::egui;
+use egui::{Color32, Pos2, RichText, Sense, Stroke, Vec2};
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::HeapRb;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::dsp::biquad::{Biquad, Coefficients};
+use crate::dsp::sweep::{self, Response, SweepSettings, TAIL_SECONDS};
+use crate::dsp::{db_to_gain, resample_response};
+use crate::equalizer::{freq_to_x, x_to_freq};
+use crate::pipeline::{self, SinkName, Source};
+use crate::AudioApp;
+
+// Recorded before the sweep starts, so the input has settled, and after it, for the latency
+const LEAD_SECONDS: f64 = 0.3;
+// Rate and latency of the simulated room
+const SIMULATED_RATE: u32 = 48000;
+const SIMULATED_LATENCY_SECONDS: f64 = 0.012;
+// Fractions of an octave the response can be smoothed over, 0 for none
+const SMOOTHING: [u32; 5] = [0, 48, 12, 6, 3];
+// How much of the impulse response is drawn
+const IMPULSE_VIEW_SECONDS: f64 = 0.1;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Recorder {
+    // A microphone or other recording device
+    #[default]
+    Input,
+    // What the output itself plays (Windows only)
+    Loopback,
+    // A made-up room, without touching any device
+    Simulated,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MeasurementPrefs {
+    pub output: Option<String>,
+    pub input: Option<String>,
+    pub recorder: Recorder,
+    // Play the sweep on every channel (0) or only on this one, counting from 1
+    pub channel: usize,
+    pub sweep: SweepSettings,
+    // Smooth the plotted and exported response over 1/N octave, 0 for none
+    pub smoothing: u32,
+}
+
+impl Default for MeasurementPrefs {
+    fn default() -> Self {
+        Self { output: None, input: None, recorder: Recorder::Input, channel: 0, sweep: SweepSettings::default(), smoothing: 12 }
+    }
+}
+
+fn smoothing_label(smoothing: u32) -> String {
+    match smoothing {
+        0 => "No smoothing".to_string(),
+        n => format!("1/{n} octave"),
+    }
+}
+
+fn smoothing_octaves(smoothing: u32) -> f64 {
+    if smoothing == 0 {
+        0.0
+    } else {
+        1.0 / smoothing as f64
+    }
+}
+
+struct Job {
+    receiver: Receiver<Result<Response, String>>,
+    started: Instant,
+    seconds: f64,
+}
+
+#[derive(Default)]
+pub struct Measurement {
+    job: Option<Job>,
+    result: Option<Result<Response, String>>,
+    // Path typed for saving
+    file: String,
+    file_result: Option<Result<String, String>>,
+}
+
+// Everything a measurement needs, copied for its thread
+struct Plan {
+    output: Option<String>,
+    recorder: Recorder,
+    input: Option<String>,
+    channel: usize,
+    sweep: SweepSettings,
+}
+
+fn measure(plan: Plan) -> Result<Response, String> {
+    if plan.recorder == Recorder::Simulated {
+        let sweep = sweep::sweep(&plan.sweep, SIMULATED_RATE);
+        let recording = simulate_room(&sweep, SIMULATED_RATE);
+        return sweep::deconvolve(&sweep, &recording, SIMULATED_RATE as f64);
+    }
+
+    let output = plan.output.ok_or("Choose an output to play the sweep on")?;
+    let source = match plan.recorder {
+        Recorder::Input => Source::Input(plan.input.ok_or("Choose an input to record with")?),
+        _ => Source::Loopback(output.clone()),
+    };
+    let device = pipeline::find_device(cpal::default_host().output_devices(), &output).ok_or_else(|| format!("{output} isn't connected"))?;
+    let format = device.default_output_config().map_err(|err| err.to_string())?;
+    let rate = format.sample_rate().0;
+    let sweep = sweep::sweep(&plan.sweep, rate);
+    let mut signal = sweep.clone();
+    signal.resize(sweep.len() + (TAIL_SECONDS * rate as f64) as usize, 0.0);
+
+    // Record into a buffer big enough for all of it, with a second to spare
+    let (in_rate, in_channels) = pipeline::capture_format(&source, SinkName::default())?;
+    let seconds = signal.len() as f64 / rate as f64 + LEAD_SECONDS * 2.0 + 1.0;
+    let (mut producer, mut consumer) = HeapRb::<f32>::new((seconds * in_rate as f64) as usize * in_channels).split();
+    let capture = pipeline::start_capture(&source, SinkName::default(), in_rate, in_channels, move |data| {
+        producer.push_slice(data);
+    })?;
+    std::thread::sleep(Duration::from_secs_f64(LEAD_SECONDS));
+
+    let finished = Arc::new(AtomicBool::new(false));
+    let config = format.config();
+    let length = signal.len();
+    let stream = match format.sample_format() {
+        SampleFormat::F32 => play::<f32>(&device, &config, signal, plan.channel, finished.clone()),
+        SampleFormat::I16 => play::<i16>(&device, &config, signal, plan.channel, finished.clone()),
+        SampleFormat::U16 => play::<u16>(&device, &config, signal, plan.channel, finished.clone()),
+        SampleFormat::I32 => play::<i32>(&device, &config, signal, plan.channel, finished.clone()),
+        other => return Err(format!("Unsupported sample format {other}")),
+    }
+    .map_err(|err| err.to_string())?;
+    stream.play().map_err(|err| err.to_string())?;
+
+    let deadline = Instant::now() + Duration::from_secs_f64(length as f64 / rate as f64 + 5.0);
+    while !finished.load(Ordering::Relaxed) {
+        if Instant::now() > deadline {
+            return Err(format!("{output} stopped playing"));
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    std::thread::sleep(Duration::from_secs_f64(LEAD_SECONDS));
+    drop(stream);
+    drop(capture);
+
+    // The first channel is the microphone's
+    let recorded: Vec<f32> = consumer.pop_iter().step_by(in_channels.max(1)).collect();
+    let recording = resample_response(&recorded, in_rate as f64, rate as f64);
+    sweep::deconvolve(&sweep, &recording, rate as f64)
+}
+
+fn play<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    signal: Vec<f32>,
+    channel: usize,
+    finished: Arc<AtomicBool>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels as usize;
+    let mut position = 0;
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            for frame in data.chunks_mut(channels) {
+                let sample = signal.get(position).copied().unwrap_or(0.0);
+                position += 1;
+                for (idx, out) in frame.iter_mut().enumerate() {
+                    *out = T::from_sample(if channel == 0 || idx + 1 == channel { sample } else { 0.0 });
+                }
+            }
+            if position >= signal.len() {
+                finished.store(true, Ordering::Relaxed);
+            }
+        },
+        |err| eprintln!("ERROR: Measurement playback failed: {err}"),
+        None,
+    )
+}
+
+// What a small room with a speaker in it might do to the sweep: a bass-shy speaker with a
+// room mode at 55 Hz, a few reflections, some reverb, latency and background noise
+fn simulate_room(sweep: &[f32], sample_rate: u32) -> Vec<f32> {
+    let rate = sample_rate as f64;
+    let filters = [
+        Coefficients::high_pass(35.0, 0.7, rate),
+        Coefficients::peaking(55.0, 8.0, 4.0, rate),
+        Coefficients::high_shelf(8000.0, -4.0, 0.7, rate),
+    ];
+    let mut states = [Biquad::default(); 3];
+    let length = sample_rate as usize / 2;
+    let speaker: Vec<f32> = (0..length)
+        .map(|idx| {
+            let input = if idx == 0 { 1.0 } else { 0.0 };
+            filters.iter().zip(&mut states).fold(input, |sample, (filter, state)| state.process(filter, sample)) as f32
+        })
+        .collect();
+
+    let mut noise_state = 0x2545_f491_4f6c_dd1du64;
+    let mut noise = move || {
+        noise_state ^= noise_state << 13;
+        noise_state ^= noise_state >> 7;
+        noise_state ^= noise_state << 17;
+        (noise_state >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    };
+    let mut room = speaker.clone();
+    for (seconds, gain) in [(0.0034, 0.45), (0.0079, -0.3), (0.015, 0.2)] {
+        let delay = (seconds * rate) as usize;
+        for (out, sample) in room[delay..].iter_mut().zip(&speaker) {
+            *out += sample * gain;
+        }
+    }
+    for (idx, out) in room.iter_mut().enumerate().skip((0.02 * rate) as usize) {
+        *out += noise() * 0.03 * (-(idx as f64) / rate / 0.08).exp() as f32;
+    }
+
+    let mut signal = vec![0.0; (SIMULATED_LATENCY_SECONDS * rate) as usize];
+    signal.extend_from_slice(sweep);
+    signal.resize(signal.len() + (TAIL_SECONDS * rate) as usize, 0.0);
+    let floor = db_to_gain(-85.0) as f32;
+    sweep::convolve(&signal, &room).into_iter().take(signal.len()).map(|sample| sample + noise() * floor).collect()
+}
+
+impl AudioApp {
+    fn start_measurement(&mut self) {
+        let prefs = &self.settings.measurement;
+        let plan = Plan {
+            output: prefs.output.clone(),
+            recorder: prefs.recorder,
+            input: prefs.input.clone(),
+            channel: prefs.channel,
+            sweep: prefs.sweep.clone(),
+        };
+        let seconds = prefs.sweep.seconds + TAIL_SECONDS + LEAD_SECONDS * 2.0;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let result = measure(plan);
+            if let Err(err) = &result {
+                eprintln!("ERROR: Measurement failed: {err}");
+            }
+            let _ = sender.send(result);
+        });
+        self.measurement.job = Some(Job { receiver, started: Instant::now(), seconds });
+        self.measurement.file_result = None;
+    }
+
+    fn save_measurement(&mut self) {
+        let Some(Ok(response)) = &self.measurement.result else {
+            return;
+        };
+        let path = PathBuf::from(self.measurement.file.trim());
+        let is_wav = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+        let result = if is_wav {
+            response.write_wav(&path)
+        } else {
+            let csv = response.to_csv(smoothing_octaves(self.settings.measurement.smoothing));
+            std::fs::write(&path, csv).map_err(|err| format!("Couldn't write {}: {err}", path.display()))
+        };
+        self.measurement.file_result = Some(result.map(|()| format!("Saved {}", path.display())));
+    }
+
+    pub fn measurement_ui(&mut self, ui: &mut egui::Ui) {
+        if let Some(job) = &self.measurement.job {
+            match job.receiver.try_recv() {
+                Ok(result) => {
+                    self.measurement.result = Some(result);
+                    self.measurement.job = None;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    self.measurement.result = Some(Err("The measurement stopped unexpectedly".to_string()));
+                    self.measurement.job = None;
+                }
+                Err(TryRecvError::Empty) => {}
+            }
+        }
+
+        let mut edited = false;
+        let prefs = &mut self.settings.measurement;
+        let devices = &self.settings.devices;
+        let running = self.measurement.job.is_some();
+        ui.add_enabled_ui(!running, |ui| {
+            egui::Grid::new("measurement").num_columns(2).spacing([8.0, 4.0]).show(ui, |ui| {
+                ui.label("Play on:");
+                let simulated = prefs.recorder == Recorder::Simulated;
+                let selected = match &prefs.output {
+                    _ if simulated => "Simulated room".to_string(),
+                    Some(name) => devices.display_name(name).to_string(),
+                    None => "Choose…".to_string(),
+                };
+                ui.add_enabled_ui(!simulated, |ui| {
+                    egui::ComboBox::from_id_source("measurement_output").selected_text(selected).width(220.0).show_ui(ui, |ui| {
+                        for name in &self.device_names {
+                            edited |= ui.selectable_value(&mut prefs.output, Some(name.clone()), devices.display_name(name)).changed();
+                        }
+                    });
+                });
+                ui.end_row();
+
+                ui.label("Record with:");
+                let selected = match (prefs.recorder, &prefs.input) {
+                    (Recorder::Input, Some(name)) => devices.display_name(name).to_string(),
+                    (Recorder::Input, None) => "Choose…".to_string(),
+                    (Recorder::Loopback, _) => "What the output plays".to_string(),
+                    (Recorder::Simulated, _) => "Simulated room (no devices)".to_string(),
+                };
+                egui::ComboBox::from_id_source("measurement_input").selected_text(selected).width(220.0).show_ui(ui, |ui| {
+                    for name in &self.input_device_names {
+                        let chosen = prefs.recorder == Recorder::Input && prefs.input.as_ref() == Some(name);
+                        if ui.selectable_label(chosen, devices.display_name(name)).clicked() {
+                            prefs.recorder = Recorder::Input;
+                            prefs.input = Some(name.clone());
+                            edited = true;
+                        }
+                    }
+                    if cfg!(target_os = "windows") {
+                        edited |= ui
+                            .selectable_value(&mut prefs.recorder, Recorder::Loopback, "What the output plays")
+                            .on_hover_text("Measure the sound on its way to the device, including the app's own processing")
+                            .changed();
+                    }
+                    edited |= ui
+                        .selectable_value(&mut prefs.recorder, Recorder::Simulated, "Simulated room (no devices)")
+                        .on_hover_text("Try the measurement without playing anything")
+                        .changed();
+                });
+                ui.end_row();
+
+                ui.label("Channel:");
+                let label = |channel: usize| match channel {
+                    0 => "All channels".to_string(),
+                    1 => "Left".to_string(),
+                    2 => "Right".to_string(),
+                    n => format!("Channel {n}"),
+                };
+                egui::ComboBox::from_id_source("measurement_channel").selected_text(label(prefs.channel)).width(120.0).show_ui(ui, |ui| {
+                    for channel in 0..=2 {
+                        edited |= ui.selectable_value(&mut prefs.channel, channel, label(channel)).changed();
+                    }
+                });
+                ui.end_row();
+
+                ui.label("Sweep:");
+                ui.horizontal(|ui| {
+                    let sweep = &mut prefs.sweep;
+                    edited |= ui.add(egui::DragValue::new(&mut sweep.start_hz).clamp_range(5.0..=1000.0).speed(1.0).suffix(" Hz")).changed();
+                    ui.label("to");
+                    edited |= ui.add(egui::DragValue::new(&mut sweep.end_hz).clamp_range(1000.0..=24000.0).speed(50.0).suffix(" Hz")).changed();
+                    edited |= ui
+                        .add(egui::DragValue::new(&mut sweep.seconds).clamp_range(1.0..=30.0).speed(0.1).max_decimals(1).suffix(" s"))
+                        .on_hover_text("Longer sweeps rise further above background noise")
+                        .changed();
+                    edited |= ui
+                        .add(egui::DragValue::new(&mut sweep.level_db).clamp_range(-60.0..=0.0).speed(0.5).max_decimals(1).suffix(" dBFS"))
+                        .on_hover_text("Level of the sweep before the device's volume")
+                        .changed();
+                });
+                ui.end_row();
+            });
+        });
+
+        ui.horizontal(|ui| {
+            if let Some(job) = &self.measurement.job {
+                let progress = (job.started.elapsed().as_secs_f64() / job.seconds).min(1.0) as f32;
+                ui.add(egui::ProgressBar::new(progress).desired_width(220.0).text("Measuring…"));
+            } else if ui.button("▶ Measure").clicked() {
+                self.start_measurement();
+            }
+            if self.settings.measurement.recorder != Recorder::Simulated {
+                ui.label(RichText::new("Turn the volume down before the first sweep").weak());
+            }
+        });
+
+        match &self.measurement.result {
+            Some(Ok(response)) => {
+                let delay_ms = response.delay as f64 / response.sample_rate * 1000.0;
+                let level = response.recorded_peak_db;
+                ui.label(RichText::new(format!("Delay {delay_ms:.1} ms · recording peaked at {level:.1} dBFS")).weak())
+                    .on_hover_text("The delay is the devices' latency plus the time sound takes to reach the microphone");
+                if level > -0.5 {
+                    ui.label(RichText::new("The recording clipped: turn the sweep or the input down").color(Color32::from_rgb(255, 190, 96)));
+                } else if level < -50.0 {
+                    ui.label(RichText::new("The recording is very quiet: turn the sweep or the input up").color(Color32::from_rgb(255, 190, 96)));
+                }
+
+                let smoothing = &mut self.settings.measurement.smoothing;
+                egui::ComboBox::from_id_source("measurement_smoothing").selected_text(smoothing_label(*smoothing)).width(120.0).show_ui(ui, |ui| {
+                    for option in SMOOTHING {
+                        edited |= ui.selectable_value(smoothing, option, smoothing_label(option)).changed();
+                    }
+                });
+                frequency_graph(ui, response, smoothing_octaves(*smoothing));
+                impulse_graph(ui, response);
+
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut self.measurement.file)
+                            .desired_width(300.0)
+                            .hint_text("response.csv or impulse.wav"),
+                    );
+                    let has_path = !self.measurement.file.trim().is_empty();
+                    if ui
+                        .add_enabled(has_path, egui::Button::new("Save"))
+                        .on_hover_text("A .wav path saves the impulse response, anything else the frequency response as CSV")
+                        .clicked()
+                    {
+                        self.save_measurement();
+                    }
+                });
+                match &self.measurement.file_result {
+                    Some(Ok(message)) => {
+                        ui.label(RichText::new(message).weak());
+                    }
+                    Some(Err(err)) => {
+                        ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+                    }
+                    None => {}
+                }
+            }
+            Some(Err(err)) => {
+                ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+            }
+            None => {}
+        }
+
+        if edited {
+            self.settings.save();
+        }
+    }
+}
+
+// Level against frequency from 20 Hz to 20 kHz, 60 dB high with the loudest part near the top
+fn frequency_graph(ui: &mut egui::Ui, response: &Response, smoothing: f64) {
+    let (rect, hover) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 200.0), Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 4.0, visuals.extreme_bg_color);
+
+    let levels: Vec<f64> = (0..=rect.width() as usize)
+        .map(|step| response.magnitude_db(x_to_freq(rect, rect.left() + step as f32) as f64, smoothing))
+        .collect();
+    let loudest = levels.iter().copied().fold(f64::MIN, f64::max);
+    let top = ((loudest / 10.0).ceil() * 10.0 + 10.0) as f32;
+    let db_to_y = |db: f32| rect.top() + ((top - db) / 60.0).clamp(0.0, 1.0) * rect.height();
+
+    let grid = Stroke::new(1.0, visuals.widgets.noninteractive.bg_stroke.color);
+    let label_color = visuals.weak_text_color();
+    let font = egui::FontId::proportional(10.0);
+    for freq in [50.0, 100.0, 200.0, 500.0, 1000.0, 2000.0, 5000.0, 10000.0] {
+        let x = freq_to_x(rect, freq);
+        painter.vline(x, rect.y_range(), grid);
+        let label = if freq >= 1000.0 { format!("{}k", freq / 1000.0) } else { format!("{freq}") };
+        painter.text(Pos2::new(x + 2.0, rect.bottom() - 2.0), egui::Align2::LEFT_BOTTOM, label, font.clone(), label_color);
+    }
+    for step in 1..6 {
+        let db = top - step as f32 * 10.0;
+        let y = db_to_y(db);
+        painter.hline(rect.x_range(), y, grid);
+        painter.text(Pos2::new(rect.left() + 2.0, y - 1.0), egui::Align2::LEFT_BOTTOM, format!("{db} dB"), font.clone(), label_color);
+    }
+
+    let points: Vec<Pos2> = levels.iter().enumerate().map(|(step, db)| Pos2::new(rect.left() + step as f32, db_to_y(*db as f32))).collect();
+    painter.add(egui::Shape::line(points, Stroke::new(2.0, visuals.selection.bg_fill)));
+
+    if let Some(pointer) = hover.hover_pos() {
+        let freq = x_to_freq(rect, pointer.x) as f64;
+        painter.vline(pointer.x, rect.y_range(), Stroke::new(1.0, label_color));
+        hover.on_hover_text(format!("{freq:.0} Hz: {:.1} dB", response.magnitude_db(freq, smoothing)));
+    }
+}
+
+// The start of the impulse response, scaled to its peak
+fn impulse_graph(ui: &mut egui::Ui, response: &Response) {
+    let (rect, _) = ui.allocate_exact_size(Vec2::new(ui.available_width(), 100.0), Sense::hover());
+    let painter = ui.painter_at(rect);
+    let visuals = ui.visuals();
+    painter.rect_filled(rect, 4.0, visuals.extreme_bg_color);
+    painter.hline(rect.x_range(), rect.center().y, Stroke::new(1.0, visuals.widgets.noninteractive.bg_stroke.color));
+
+    let shown = ((IMPULSE_VIEW_SECONDS * response.sample_rate) as usize).min(response.impulse.len());
+    if shown == 0 {
+        return;
+    }
+    let samples = &response.impulse[..shown];
+    let peak = samples.iter().fold(0.0f32, |peak, sample| peak.max(sample.abs())).max(1e-12);
+    let columns = rect.width().max(1.0) as usize;
+    let color = visuals.selection.bg_fill;
+    for column in 0..columns {
+        let first = (column * shown / columns).min(shown - 1);
+        let last = ((column + 1) * shown / columns).clamp(first + 1, shown);
+        let (low, high) = samples[first..last].iter().fold((0.0f32, 0.0f32), |(low, high), sample| (low.min(*sample), high.max(*sample)));
+        let to_y = |value: f32| rect.center().y - value / peak * rect.height() / 2.0;
+        let x = rect.left() + column as f32 + 0.5;
+        painter.line_segment([Pos2::new(x, to_y(high)), Pos2::new(x, to_y(low))], Stroke::new(1.0, color));
+    }
+    // Mark the peak the delay is measured to
+    let x = rect.left() + response.peak as f32 / shown as f32 * rect.width();
+    painter.vline(x, rect.y_range(), Stroke::new(1.0, visuals.weak_text_color()));
+    let label = format!("{:.0} ms", IMPULSE_VIEW_SECONDS * 1000.0);
+    let font = egui::FontId::proportional(10.0);
+    painter.text(rect.right_bottom() - Vec2::new(2.0, 2.0), egui::Align2::RIGHT_BOTTOM, label, font, visuals.weak_text_color());
+}
@@ -0,0 +1,995 @@
+// MIDI control surfaces: faders, knobs and buttons mapped to the master volume, mute,
+// application volumes and the output device. Motorized faders and button LEDs get feedback
+// so they follow changes made elsewhere, and soft takeover keeps plain faders from making the
+// volume jump when they're out of step with it.
+
+use eframe::egui;
+use egui::RichText;
+use midir::{Ignore, MidiIO, MidiInput, MidiInputConnection, MidiOutput, MidiOutputConnection};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::{Command, ControlEvent, ControlHandle};
+use crate::endpoints::Flow;
+use crate::settings::APP_NAME;
+use crate::AudioApp;
+
+// How close a fader has to come to the real value before soft takeover lets it through
+const TAKEOVER_WINDOW: f32 = 0.03;
+
+// After moving a control, changes to what it controls are assumed to be our own doing for this
+// long, so a motorized fader isn't pushed around by the echoes of its own movement
+const TOUCH_HOLDOFF: Duration = Duration::from_millis(300);
+
+// Application volumes don't produce events, so we look at them this often
+const APP_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum MidiKind {
+    Cc,
+    Note,
+    PitchBend,
+}
+
+// One physical control. Channels are 0-15 here and shown as 1-16.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct MidiControl {
+    pub kind: MidiKind,
+    pub channel: u8,
+    // Controller or note number, unused for pitch bend
+    pub number: u8,
+}
+
+impl fmt::Display for MidiControl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            MidiKind::Cc => write!(f, "CC {} (ch {})", self.number, self.channel + 1),
+            MidiKind::Note => write!(f, "Note {} (ch {})", self.number, self.channel + 1),
+            MidiKind::PitchBend => write!(f, "Pitch bend (ch {})", self.channel + 1),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Hash, Debug)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum MidiAction {
+    Volume,
+    ToggleMute,
+    AppVolume { app: String },
+    AppMute { app: String },
+    Device { name: String },
+}
+
+impl MidiAction {
+    // Faders and knobs, as opposed to buttons
+    fn is_continuous(&self) -> bool {
+        matches!(self, MidiAction::Volume | MidiAction::AppVolume { .. })
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            MidiAction::Volume => "Master volume",
+            MidiAction::ToggleMute => "Toggle mute",
+            MidiAction::AppVolume { .. } => "App volume",
+            MidiAction::AppMute { .. } => "Toggle app mute",
+            MidiAction::Device { .. } => "Switch output",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct MidiMapping {
+    // None until learned
+    pub control: Option<MidiControl>,
+    #[serde(flatten)]
+    pub action: MidiAction,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MidiPrefs {
+    pub enabled: bool,
+    // Port names without ALSA's client:port numbers, see port_key
+    pub input_port: String,
+    // Where feedback goes, empty for none
+    pub output_port: String,
+    // Create ports other programs can connect to instead of opening a device (not on Windows)
+    pub virtual_ports: bool,
+    pub soft_takeover: bool,
+    pub mappings: Vec<MidiMapping>,
+}
+
+impl Default for MidiPrefs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            input_port: String::new(),
+            output_port: String::new(),
+            virtual_ports: false,
+            soft_takeover: true,
+            mappings: Vec::new(),
+        }
+    }
+}
+
+// A control's position, 0.0 - 1.0, from a channel message we understand. All of them have
+// two data bytes, which never have the top bit set.
+pub fn parse_message(bytes: &[u8]) -> Option<(MidiControl, f32)> {
+    let [status, data1, data2, ..] = *bytes else {
+        return None;
+    };
+    if data1 > 0x7F || data2 > 0x7F {
+        return None;
+    }
+    let channel = status & 0x0F;
+    let control = |kind, number| MidiControl { kind, channel, number };
+
+    match status & 0xF0 {
+        0xB0 => Some((control(MidiKind::Cc, data1), data2 as f32 / 127.0)),
+        0x90 => Some((control(MidiKind::Note, data1), if data2 > 0 { 1.0 } else { 0.0 })),
+        0x80 => Some((control(MidiKind::Note, data1), 0.0)),
+        0xE0 => {
+            let value = (data2 as u16) << 7 | data1 as u16;
+            Some((control(MidiKind::PitchBend, 0), value as f32 / 16383.0))
+        }
+        _ => None,
+    }
+}
+
+// The message that puts a control at `value`: fader position, or LED on or off
+pub fn feedback_message(control: MidiControl, value: f32) -> Vec<u8> {
+    let channel = control.channel & 0x0F;
+    match control.kind {
+        MidiKind::Cc => vec![0xB0 | channel, control.number, (value.clamp(0.0, 1.0) * 127.0).round() as u8],
+        MidiKind::Note => vec![0x90 | channel, control.number, if value >= 0.5 { 127 } else { 0 }],
+        MidiKind::PitchBend => {
+            let value = (value.clamp(0.0, 1.0) * 16383.0).round() as u16;
+            vec![0xE0 | channel, (value & 0x7F) as u8, (value >> 7) as u8]
+        }
+    }
+}
+
+// ALSA appends the client and port numbers ("nanoKONTROL2:nanoKONTROL2 _ CTRL 24:0"), and
+// those change between boots, so we remember ports without them
+pub fn port_key(name: &str) -> &str {
+    let number = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    match name.rsplit_once(' ') {
+        Some((rest, ids)) if ids.split_once(':').is_some_and(|(client, port)| number(client) && number(port)) => rest,
+        _ => name,
+    }
+}
+
+fn find_port<T: MidiIO>(io: &T, key: &str) -> Option<T::Port> {
+    io.ports().into_iter().find(|port| io.port_name(port).is_ok_and(|name| port_key(&name) == key))
+}
+
+// Names of the connected input and output ports
+pub fn port_names() -> (Vec<String>, Vec<String>) {
+    fn names<T: MidiIO>(io: Option<T>) -> Vec<String> {
+        let Some(io) = io else {
+            return Vec::new();
+        };
+        io.ports().iter().filter_map(|port| io.port_name(port).ok()).map(|name| port_key(&name).to_string()).collect()
+    }
+    (names(MidiInput::new(APP_NAME).ok()), names(MidiOutput::new(APP_NAME).ok()))
+}
+
+// State shared between the UI and the engine thread
+struct Shared {
+    mappings: Mutex<Vec<MidiMapping>>,
+    soft_takeover: AtomicBool,
+    learning: AtomicBool,
+    learned: Mutex<Option<MidiControl>>,
+    last_message: Mutex<Option<(MidiControl, f32)>>,
+}
+
+// Turns MIDI messages into commands and state changes into feedback
+struct Engine {
+    control: ControlHandle,
+    shared: Arc<Shared>,
+    output: Option<MidiOutputConnection>,
+    volume: f32,
+    muted: bool,
+    device: Option<String>,
+    // Volume and mute of each application with a mapping
+    apps: HashMap<String, (f32, bool)>,
+    apps_polled: Option<Instant>,
+    // When we last changed each target, to tell our own changes from everyone else's
+    touched: HashMap<MidiAction, Instant>,
+    // Controls that have caught up with the value they control
+    picked_up: HashSet<MidiControl>,
+    last_input: HashMap<MidiControl, f32>,
+    // Last feedback sent to each control, so we only send changes
+    sent: HashMap<MidiControl, Vec<u8>>,
+}
+
+impl Engine {
+    fn new(control: ControlHandle, shared: Arc<Shared>, output: Option<MidiOutputConnection>) -> Self {
+        let mut engine = Self {
+            control,
+            shared,
+            output,
+            volume: 0.0,
+            muted: false,
+            device: None,
+            apps: HashMap::new(),
+            apps_polled: None,
+            touched: HashMap::new(),
+            picked_up: HashSet::new(),
+            last_input: HashMap::new(),
+            sent: HashMap::new(),
+        };
+        if let Ok(state) = engine.control.call(Command::GetState) {
+            engine.volume = state["volume"].as_f64().unwrap_or_default() as f32;
+            engine.muted = state["muted"].as_bool().unwrap_or_default();
+            engine.device = state["output_device"].as_str().map(str::to_string);
+        }
+        engine
+    }
+
+    fn mappings(&self) -> Vec<MidiMapping> {
+        self.shared.mappings.lock().map(|mappings| mappings.clone()).unwrap_or_default()
+    }
+
+    fn run(mut self, messages: Receiver<Vec<u8>>, events: Receiver<ControlEvent>) {
+        loop {
+            let first = match messages.recv_timeout(POLL_INTERVAL) {
+                Ok(bytes) => Some(bytes),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            // A fader sends a flood of messages; only the latest position of each matters
+            let mappings = self.mappings();
+            let mut pending: Vec<(MidiControl, f32)> = Vec::new();
+            let mut next = first;
+            while let Some(bytes) = next {
+                let parsed = parse_message(&bytes);
+                if let Some((control, value)) = parsed.filter(|(control, value)| self.accept(&mappings, *control, *value)) {
+                    let continuous =
+                        mappings.iter().any(|mapping| mapping.control == Some(control) && mapping.action.is_continuous());
+                    match pending.iter_mut().find(|(seen, _)| continuous && *seen == control) {
+                        Some(entry) => entry.1 = value,
+                        None => pending.push((control, value)),
+                    }
+                }
+                next = match messages.try_recv() {
+                    Ok(bytes) => Some(bytes),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                };
+            }
+            for (control, value) in pending {
+                self.handle_input(&mappings, control, value);
+            }
+
+            loop {
+                match events.try_recv() {
+                    Ok(event) => self.handle_event(&mappings, event),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            }
+            self.poll_apps(&mappings);
+            self.send_feedback(&mappings);
+        }
+    }
+
+    fn value(&self, action: &MidiAction) -> Option<f32> {
+        match action {
+            MidiAction::Volume => Some(self.volume),
+            MidiAction::ToggleMute => Some(self.muted as u8 as f32),
+            MidiAction::AppVolume { app } => self.apps.get(app).map(|(volume, _)| *volume),
+            MidiAction::AppMute { app } => self.apps.get(app).map(|(_, muted)| *muted as u8 as f32),
+            MidiAction::Device { name } => Some((self.device.as_ref() == Some(name)) as u8 as f32),
+        }
+    }
+
+    fn recently_touched(&self, action: &MidiAction) -> bool {
+        self.touched.get(action).is_some_and(|when| when.elapsed() < TOUCH_HOLDOFF)
+    }
+
+    // Soft takeover: a control only takes effect once it has reached the current value, by
+    // coming close to it or by passing it
+    fn caught_up(&mut self, control: MidiControl, value: f32, current: Option<f32>) -> bool {
+        let Some(current) = current else {
+            return true;
+        };
+        if !self.shared.soft_takeover.load(Ordering::Relaxed) || self.picked_up.contains(&control) {
+            return true;
+        }
+        let crossed = self.last_input.get(&control).is_some_and(|previous| (previous - current) * (value - current) <= 0.0);
+        if crossed || (value - current).abs() <= TAKEOVER_WINDOW {
+            self.picked_up.insert(control);
+            return true;
+        }
+        false
+    }
+
+    // Something else changed what these controls point at, so they have to catch up again
+    fn drop_takeover(&mut self, mappings: &[MidiMapping], action: &MidiAction) {
+        for mapping in mappings.iter().filter(|mapping| &mapping.action == action) {
+            if let Some(control) = mapping.control {
+                self.picked_up.remove(&control);
+            }
+        }
+    }
+
+    // Look at every message as it comes in, before the flood is thinned out: learning takes
+    // the first one, and soft takeover needs to see a fader pass the current value
+    fn accept(&mut self, mappings: &[MidiMapping], control: MidiControl, value: f32) -> bool {
+        if let Ok(mut last) = self.shared.last_message.lock() {
+            *last = Some((control, value));
+        }
+        if self.shared.learning.swap(false, Ordering::Relaxed) {
+            if let Ok(mut learned) = self.shared.learned.lock() {
+                *learned = Some(control);
+            }
+            return false;
+        }
+
+        let action = mappings
+            .iter()
+            .find(|mapping| mapping.control == Some(control) && mapping.action.is_continuous())
+            .map(|mapping| mapping.action.clone());
+        let accepted = match action {
+            Some(action) => self.caught_up(control, value, self.value(&action)),
+            None => true,
+        };
+        self.last_input.insert(control, value);
+        accepted
+    }
+
+    fn handle_input(&mut self, mappings: &[MidiMapping], control: MidiControl, value: f32) {
+        for mapping in mappings.iter().filter(|mapping| mapping.control == Some(control)) {
+            let action = &mapping.action;
+            if !action.is_continuous() && value < 0.5 {
+                // Buttons act when pressed, not when released
+                continue;
+            }
+
+            let command = match action {
+                MidiAction::Volume => {
+                    self.volume = value;
+                    Command::SetVolume(value)
+                }
+                MidiAction::ToggleMute => {
+                    self.muted = !self.muted;
+                    Command::SetMute(self.muted)
+                }
+                MidiAction::AppVolume { app } => {
+                    self.apps.entry(app.clone()).or_insert((value, false)).0 = value;
+                    Command::SetAppVolume { app: app.clone(), volume: value }
+                }
+                MidiAction::AppMute { app } => {
+                    let muted = !self.apps.get(app).is_some_and(|(_, muted)| *muted);
+                    self.apps.entry(app.clone()).or_insert((1.0, muted)).1 = muted;
+                    Command::SetAppMute { app: app.clone(), muted }
+                }
+                MidiAction::Device { name } => {
+                    self.device = Some(name.clone());
+                    Command::SetDefaultDevice { flow: Flow::Output, name: name.clone() }
+                }
+            };
+            self.touched.insert(action.clone(), Instant::now());
+            if let Err(err) = self.control.call(command) {
+                eprintln!("ERROR: MIDI {} failed: {err}", action.label());
+            }
+        }
+    }
+
+    fn handle_event(&mut self, mappings: &[MidiMapping], event: ControlEvent) {
+        match event {
+            ControlEvent::VolumeChanged { volume } => {
+                if !self.recently_touched(&MidiAction::Volume) && (volume - self.volume).abs() > 0.001 {
+                    self.drop_takeover(mappings, &MidiAction::Volume);
+                }
+                self.volume = volume;
+            }
+            ControlEvent::MuteChanged { muted } => self.muted = muted,
+            ControlEvent::DefaultDeviceChanged { flow: Flow::Output, name } => self.device = name,
+            _ => {}
+        }
+    }
+
+    fn poll_apps(&mut self, mappings: &[MidiMapping]) {
+        let wanted = mappings.iter().any(|mapping| {
+            matches!(mapping.action, MidiAction::AppVolume { .. } | MidiAction::AppMute { .. })
+        });
+        if !wanted || self.apps_polled.is_some_and(|when| when.elapsed() < APP_POLL_INTERVAL) {
+            return;
+        }
+        self.apps_polled = Some(Instant::now());
+
+        let Ok(Value::Array(apps)) = self.control.call(Command::ListApps) else {
+            return;
+        };
+        for app in apps {
+            let Some(name) = app["name"].as_str() else {
+                continue;
+            };
+            let volume = app["volume"].as_f64().unwrap_or_default() as f32;
+            let muted = app["muted"].as_bool().unwrap_or_default();
+            let action = MidiAction::AppVolume { app: name.to_string() };
+            let previous = self.apps.insert(name.to_string(), (volume, muted));
+            if previous.is_some_and(|(old, _)| (old - volume).abs() > 0.001) && !self.recently_touched(&action) {
+                self.drop_takeover(mappings, &action);
+            }
+        }
+    }
+
+    fn send_feedback(&mut self, mappings: &[MidiMapping]) {
+        if self.output.is_none() {
+            return;
+        }
+        let mut messages = Vec::new();
+        for mapping in mappings {
+            let Some(control) = mapping.control else {
+                continue;
+            };
+            // Leave a fader alone while it's being moved
+            if mapping.action.is_continuous() && self.recently_touched(&mapping.action) {
+                continue;
+            }
+            let Some(value) = self.value(&mapping.action) else {
+                continue;
+            };
+            let message = feedback_message(control, value);
+            if self.sent.get(&control) != Some(&message) {
+                self.sent.insert(control, message.clone());
+                messages.push(message);
+            }
+        }
+
+        if let Some(output) = self.output.as_mut() {
+            for message in messages {
+                if let Err(err) = output.send(&message) {
+                    eprintln!("ERROR: Couldn't send MIDI feedback: {err}");
+                }
+            }
+        }
+    }
+}
+
+// Open ports and a running engine; everything closes when dropped
+pub struct MidiController {
+    shared: Arc<Shared>,
+    _input: MidiInputConnection<()>,
+    description: String,
+}
+
+impl MidiController {
+    pub fn start(prefs: &MidiPrefs, control: ControlHandle) -> Result<Self, String> {
+        let shared = Arc::new(Shared {
+            mappings: Mutex::new(prefs.mappings.clone()),
+            soft_takeover: AtomicBool::new(prefs.soft_takeover),
+            learning: AtomicBool::new(false),
+            learned: Mutex::new(None),
+            last_message: Mutex::new(None),
+        });
+
+        let (sender, messages) = mpsc::channel();
+        let callback = move |_timestamp: u64, bytes: &[u8], _: &mut ()| {
+            let _ = sender.send(bytes.to_vec());
+        };
+        let mut input = MidiInput::new(APP_NAME).map_err(|err| format!("MIDI isn't available: {err}"))?;
+        input.ignore(Ignore::All);
+        let output = MidiOutput::new(APP_NAME).map_err(|err| format!("MIDI isn't available: {err}"))?;
+
+        let (input, output, description) = if prefs.virtual_ports {
+            open_virtual(input, output, callback)?
+        } else {
+            if prefs.input_port.is_empty() {
+                return Err("Pick a MIDI input".to_string());
+            }
+            let port = find_port(&input, &prefs.input_port).ok_or_else(|| format!("{} isn't connected", prefs.input_port))?;
+            let input = input.connect(&port, APP_NAME, callback, ()).map_err(|err| err.to_string())?;
+
+            let output = if prefs.output_port.is_empty() {
+                None
+            } else {
+                let port =
+                    find_port(&output, &prefs.output_port).ok_or_else(|| format!("{} isn't connected", prefs.output_port))?;
+                Some(output.connect(&port, APP_NAME).map_err(|err| err.to_string())?)
+            };
+            let description = match &output {
+                Some(_) => format!("Listening to {}, feedback to {}", prefs.input_port, prefs.output_port),
+                None => format!("Listening to {}", prefs.input_port),
+            };
+            (input, output, description)
+        };
+
+        let events = control.subscribe();
+        let engine_shared = shared.clone();
+        std::thread::spawn(move || Engine::new(control, engine_shared, output).run(messages, events));
+
+        Ok(Self { shared, _input: input, description })
+    }
+
+    pub fn description(&self) -> &str {
+        &self.description
+    }
+
+    pub fn set_mappings(&self, mappings: &[MidiMapping], soft_takeover: bool) {
+        if let Ok(mut shared) = self.shared.mappings.lock() {
+            *shared = mappings.to_vec();
+        }
+        self.shared.soft_takeover.store(soft_takeover, Ordering::Relaxed);
+    }
+
+    // The next control that moves is reported by take_learned instead of doing anything
+    pub fn learn(&self, enabled: bool) {
+        if let Ok(mut learned) = self.shared.learned.lock() {
+            *learned = None;
+        }
+        self.shared.learning.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn take_learned(&self) -> Option<MidiControl> {
+        self.shared.learned.lock().ok()?.take()
+    }
+
+    pub fn last_message(&self) -> Option<(MidiControl, f32)> {
+        *self.shared.last_message.lock().ok()?
+    }
+}
+
+// Ports other programs connect to, e.g. with aconnect, for trying mappings out without hardware
+#[cfg(unix)]
+fn open_virtual(
+    input: MidiInput,
+    output: MidiOutput,
+    callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<(MidiInputConnection<()>, Option<MidiOutputConnection>, String), String> {
+    use midir::os::unix::{VirtualInput, VirtualOutput};
+
+    let input = input.create_virtual("Control", callback, ()).map_err(|err| err.to_string())?;
+    let output = output.create_virtual("Feedback").map_err(|err| err.to_string())?;
+    Ok((input, Some(output), "Virtual ports \"Control\" and \"Feedback\" are open".to_string()))
+}
+
+#[cfg(not(unix))]
+fn open_virtual(
+    _input: MidiInput,
+    _output: MidiOutput,
+    _callback: impl FnMut(u64, &[u8], &mut ()) + Send + 'static,
+) -> Result<(MidiInputConnection<()>, Option<MidiOutputConnection>, String), String> {
+    Err("Virtual MIDI ports aren't available on Windows, use a loopback driver such as loopMIDI".to_string())
+}
+
+// Settings page state that isn't saved
+#[derive(Default)]
+pub struct MidiUi {
+    inputs: Vec<String>,
+    outputs: Vec<String>,
+    scanned: bool,
+    // Mapping waiting for a control to move
+    learning: Option<usize>,
+    status: Option<String>,
+}
+
+impl AudioApp {
+    // Open, reopen or close the MIDI ports to match the settings
+    pub fn restart_midi(&mut self) {
+        self.midi = None;
+        self.midi_ui.learning = None;
+        self.midi_ui.status = None;
+        if !self.settings.midi.enabled {
+            return;
+        }
+        match MidiController::start(&self.settings.midi, self.control.handle()) {
+            Ok(controller) => self.midi = Some(controller),
+            Err(err) => {
+                eprintln!("ERROR: {err}");
+                self.midi_ui.status = Some(err);
+            }
+        }
+    }
+
+    // Called every frame, so learning works while the settings page is closed too
+    pub fn handle_midi_learn(&mut self) {
+        let (Some(controller), Some(idx)) = (&self.midi, self.midi_ui.learning) else {
+            return;
+        };
+        let Some(control) = controller.take_learned() else {
+            return;
+        };
+        self.midi_ui.learning = None;
+        if let Some(mapping) = self.settings.midi.mappings.get_mut(idx) {
+            mapping.control = Some(control);
+            self.settings.save();
+            controller.set_mappings(&self.settings.midi.mappings, self.settings.midi.soft_takeover);
+        }
+    }
+
+    pub fn midi_ui(&mut self, ui: &mut egui::Ui) {
+        if !self.midi_ui.scanned {
+            (self.midi_ui.inputs, self.midi_ui.outputs) = port_names();
+            self.midi_ui.scanned = true;
+        }
+
+        let app_names: Vec<String> = self.app_volumes().into_iter().map(|app| app.name).collect();
+        let prefs = &mut self.settings.midi;
+        let mut restart = ui.checkbox(&mut prefs.enabled, "Use a MIDI controller").changed();
+
+        egui::Grid::new("midi_ports").num_columns(2).show(ui, |ui| {
+            for (label, port, names) in [
+                ("Input:", &mut prefs.input_port, &self.midi_ui.inputs),
+                ("Feedback to:", &mut prefs.output_port, &self.midi_ui.outputs),
+            ] {
+                ui.label(label);
+                let selected = if port.is_empty() { "None".to_string() } else { port.clone() };
+                egui::ComboBox::from_id_source(label).selected_text(selected).width(200.0).show_ui(ui, |ui| {
+                    restart |= ui.selectable_value(port, String::new(), "None").changed();
+                    for name in names {
+                        restart |= ui.selectable_value(port, name.clone(), name).changed();
+                    }
+                });
+                ui.end_row();
+            }
+        });
+
+        ui.horizontal(|ui| {
+            if ui.button("⟳ Rescan").on_hover_text("Look for newly connected devices").clicked() {
+                self.midi_ui.scanned = false;
+            }
+            #[cfg(unix)]
+            {
+                restart |= ui
+                    .checkbox(&mut prefs.virtual_ports, "Virtual ports")
+                    .on_hover_text("Open ports other programs can connect to instead of a device")
+                    .changed();
+            }
+        });
+        let mut edited = ui
+            .checkbox(&mut prefs.soft_takeover, "Soft takeover")
+            .on_hover_text("Ignore a fader until it reaches the current value, so the volume never jumps")
+            .changed();
+
+        ui.add_space(6.0);
+        ui.label(RichText::new("Mappings").strong());
+
+        let mut remove = None;
+        let mut learn = None;
+        for (idx, mapping) in prefs.mappings.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                let learning = self.midi_ui.learning == Some(idx);
+                let control = match (learning, mapping.control) {
+                    (true, _) => "Move a control…".to_string(),
+                    (false, Some(control)) => control.to_string(),
+                    (false, None) => "Not learned".to_string(),
+                };
+                if ui
+                    .add_sized([140.0, 18.0], egui::SelectableLabel::new(learning, control))
+                    .on_hover_text("Click, then move a fader or press a button on the controller")
+                    .clicked()
+                {
+                    learn = Some(if learning { None } else { Some(idx) });
+                }
+
+                ui.label("→");
+                egui::ComboBox::from_id_source(("midi_action", idx)).selected_text(mapping.action.label()).show_ui(ui, |ui| {
+                    let app = match &mapping.action {
+                        MidiAction::AppVolume { app } | MidiAction::AppMute { app } => app.clone(),
+                        _ => app_names.first().cloned().unwrap_or_default(),
+                    };
+                    let device = self.device_names.first().cloned().unwrap_or_default();
+                    for action in [
+                        MidiAction::Volume,
+                        MidiAction::ToggleMute,
+                        MidiAction::AppVolume { app: app.clone() },
+                        MidiAction::AppMute { app },
+                        MidiAction::Device { name: device },
+                    ] {
+                        let same_kind = std::mem::discriminant(&action) == std::mem::discriminant(&mapping.action);
+                        if ui.selectable_label(same_kind, action.label()).clicked() && !same_kind {
+                            mapping.action = action;
+                            edited = true;
+                        }
+                    }
+                });
+
+                match &mut mapping.action {
+                    MidiAction::AppVolume { app } | MidiAction::AppMute { app } => {
+                        edited |=
+                            ui.add(egui::TextEdit::singleline(app).hint_text("app.exe").desired_width(100.0)).changed();
+                        ui.menu_button("▾", |ui| {
+                            for name in &app_names {
+                                if ui.button(name).clicked() {
+                                    *app = name.clone();
+                                    edited = true;
+                                    ui.close_menu();
+                                }
+                            }
+                        });
+                    }
+                    MidiAction::Device { name } => {
+                        let selected = self.settings.devices.display_name(name).to_string();
+                        egui::ComboBox::from_id_source(("midi_device", idx)).selected_text(selected).width(140.0).show_ui(
+                            ui,
+                            |ui| {
+                                for device in &self.device_names {
+                                    let label = self.settings.devices.display_name(device);
+                                    edited |= ui.selectable_value(name, device.clone(), label).changed();
+                                }
+                            },
+                        );
+                    }
+                    _ => {}
+                }
+
+                if ui.small_button("🗑").clicked() {
+                    remove = Some(idx);
+                }
+            });
+        }
+
+        if ui.button("➕ Add mapping").clicked() {
+            prefs.mappings.push(MidiMapping { control: None, action: MidiAction::Volume });
+            learn = Some(Some(prefs.mappings.len() - 1));
+            edited = true;
+        }
+        if let Some(idx) = remove {
+            prefs.mappings.remove(idx);
+            learn = Some(None);
+            edited = true;
+        }
+
+        if let Some(idx) = learn {
+            // Without open ports there's nothing to learn from
+            if let Some(controller) = &self.midi {
+                self.midi_ui.learning = idx;
+                controller.learn(idx.is_some());
+            }
+        }
+        if edited {
+            self.settings.save();
+            if let Some(controller) = &self.midi {
+                controller.set_mappings(&self.settings.midi.mappings, self.settings.midi.soft_takeover);
+            }
+        }
+
+        ui.horizontal(|ui| {
+            if self.settings.midi.enabled && ui.button("Reconnect").clicked() {
+                restart = true;
+            }
+            match (&self.midi, &self.midi_ui.status) {
+                (Some(controller), _) => {
+                    ui.label(controller.description());
+                }
+                (None, Some(err)) => {
+                    ui.label(RichText::new(err).color(egui::Color32::from_rgb(255, 96, 96)));
+                }
+                (None, None) => {}
+            }
+        });
+        if let Some((control, value)) = self.midi.as_ref().and_then(MidiController::last_message) {
+            ui.label(RichText::new(format!("Last message: {control} = {}", (value * 127.0).round())).weak());
+        }
+
+        if restart {
+            self.settings.save();
+            self.restart_midi();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlHub;
+    #[cfg(target_os = "linux")]
+    use crate::control::ControlState;
+    #[cfg(target_os = "linux")]
+    use serde_json::json;
+
+    fn cc(channel: u8, number: u8) -> MidiControl {
+        MidiControl { kind: MidiKind::Cc, channel, number }
+    }
+
+    fn note(channel: u8, number: u8) -> MidiControl {
+        MidiControl { kind: MidiKind::Note, channel, number }
+    }
+
+    #[test]
+    fn parses_channel_messages() {
+        assert_eq!(parse_message(&[0xB0, 7, 127]), Some((cc(0, 7), 1.0)));
+        assert_eq!(parse_message(&[0xB3, 16, 0]), Some((cc(3, 16), 0.0)));
+        let (control, value) = parse_message(&[0xBF, 1, 64]).unwrap();
+        assert_eq!(control, cc(15, 1));
+        assert!((value - 64.0 / 127.0).abs() < 1e-6);
+
+        // Any velocity is a press; note on with velocity 0 is how many devices send note off
+        assert_eq!(parse_message(&[0x90, 60, 1]), Some((note(0, 60), 1.0)));
+        assert_eq!(parse_message(&[0x91, 60, 100]), Some((note(1, 60), 1.0)));
+        assert_eq!(parse_message(&[0x91, 60, 0]), Some((note(1, 60), 0.0)));
+        assert_eq!(parse_message(&[0x81, 60, 64]), Some((note(1, 60), 0.0)));
+
+        let bend = |channel| MidiControl { kind: MidiKind::PitchBend, channel, number: 0 };
+        assert_eq!(parse_message(&[0xE2, 0x7F, 0x7F]), Some((bend(2), 1.0)));
+        assert_eq!(parse_message(&[0xE2, 0, 0]), Some((bend(2), 0.0)));
+    }
+
+    #[test]
+    fn ignores_short_and_garbage_messages() {
+        for bytes in [
+            &[][..],
+            &[0xB0],
+            &[0xB0, 7],
+            &[0x90, 60],
+            // Data bytes without a status byte, or with the top bit set
+            &[7, 127, 0],
+            &[0xB0, 0x80, 0],
+            &[0xB0, 7, 0xFF],
+            // Messages we don't map: program change, aftertouch, clock, SysEx
+            &[0xC0, 5, 0],
+            &[0xA0, 60, 10],
+            &[0xD0, 10, 0],
+            &[0xF8, 0, 0],
+            &[0xF0, 0x7E, 0x7F, 0xF7],
+        ] {
+            assert_eq!(parse_message(bytes), None, "{bytes:02X?}");
+        }
+        // Anything after a complete message is left alone
+        assert_eq!(parse_message(&[0xB0, 7, 0, 0]), Some((cc(0, 7), 0.0)));
+    }
+
+    #[test]
+    fn feedback_is_always_valid_midi() {
+        assert_eq!(feedback_message(cc(0, 7), 0.5), [0xB0, 7, 64]);
+        assert_eq!(feedback_message(cc(15, 7), 1.0), [0xBF, 7, 127]);
+        // Values out of range are clamped, never spilling into the status bit
+        assert_eq!(feedback_message(cc(0, 7), 1.5), [0xB0, 7, 127]);
+        assert_eq!(feedback_message(cc(0, 7), -0.5), [0xB0, 7, 0]);
+        assert_eq!(feedback_message(cc(0, 7), f32::INFINITY), [0xB0, 7, 127]);
+
+        assert_eq!(feedback_message(note(2, 60), 1.0), [0x92, 60, 127]);
+        assert_eq!(feedback_message(note(2, 60), 0.2), [0x92, 60, 0]);
+
+        let bend = MidiControl { kind: MidiKind::PitchBend, channel: 1, number: 0 };
+        assert_eq!(feedback_message(bend, 1.0), [0xE1, 0x7F, 0x7F]);
+        assert_eq!(feedback_message(bend, 2.0), [0xE1, 0x7F, 0x7F]);
+        assert_eq!(feedback_message(bend, 0.0), [0xE1, 0, 0]);
+
+        // What we send reads back as the same control and value
+        for value in [0.0, 0.25, 1.0] {
+            let (control, read) = parse_message(&feedback_message(cc(4, 20), value)).unwrap();
+            assert_eq!(control, cc(4, 20));
+            assert!((read - value).abs() < 0.005);
+        }
+    }
+
+    #[test]
+    fn port_keys_drop_alsa_numbers() {
+        assert_eq!(port_key("nanoKONTROL2:nanoKONTROL2 _ CTRL 24:0"), "nanoKONTROL2:nanoKONTROL2 _ CTRL");
+        assert_eq!(port_key("Audio Controller:Control 128:0"), "Audio Controller:Control");
+        // Names as other systems report them are kept whole
+        assert_eq!(port_key("X-Touch"), "X-Touch");
+        assert_eq!(port_key("loopMIDI Port 1"), "loopMIDI Port 1");
+        assert_eq!(port_key("Device 24:"), "Device 24:");
+        assert_eq!(port_key("Device :0"), "Device :0");
+        assert_eq!(port_key("Device a:0"), "Device a:0");
+        assert_eq!(port_key(""), "");
+    }
+
+    // An engine with nothing on the other end, for looking at its decisions
+    fn engine(mappings: &[MidiMapping], soft_takeover: bool) -> Engine {
+        let shared = Arc::new(Shared {
+            mappings: Mutex::new(mappings.to_vec()),
+            soft_takeover: AtomicBool::new(soft_takeover),
+            learning: AtomicBool::new(false),
+            learned: Mutex::new(None),
+            last_message: Mutex::new(None),
+        });
+        Engine {
+            control: ControlHub::new().handle(),
+            shared,
+            output: None,
+            volume: 0.5,
+            muted: false,
+            device: None,
+            apps: HashMap::new(),
+            apps_polled: None,
+            touched: HashMap::new(),
+            picked_up: HashSet::new(),
+            last_input: HashMap::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn soft_takeover_waits_for_the_fader() {
+        let fader = cc(0, 7);
+        let mappings = [MidiMapping { control: Some(fader), action: MidiAction::Volume }];
+        let mut midi = engine(&mappings, true);
+
+        // The volume is at 0.5: a fader far below it doesn't move it
+        assert!(!midi.accept(&mappings, fader, 0.1));
+        assert!(!midi.accept(&mappings, fader, 0.3));
+        // Passing the volume picks it up, even without landing close to it
+        assert!(midi.accept(&mappings, fader, 0.7));
+        assert!(midi.accept(&mappings, fader, 0.0));
+
+        // Someone else moved the volume, so the fader has to catch up again
+        midi.handle_event(&mappings, ControlEvent::VolumeChanged { volume: 0.9 });
+        assert!(!midi.accept(&mappings, fader, 0.2));
+        // Coming close enough also counts
+        assert!(midi.accept(&mappings, fader, 0.88));
+
+        // No known value, or takeover turned off, lets everything through
+        assert!(midi.caught_up(cc(0, 8), 0.0, None));
+        assert!(engine(&mappings, false).accept(&mappings, fader, 0.0));
+
+        // Buttons don't take over
+        let button = note(0, 1);
+        let mappings = [MidiMapping { control: Some(button), action: MidiAction::ToggleMute }];
+        assert!(engine(&mappings, true).accept(&mappings, button, 1.0));
+    }
+
+    // Answer the engine's requests the way the app would, until `done` or five seconds are up
+    #[cfg(target_os = "linux")]
+    fn serve(hub: &mut ControlHub, state: &mut ControlState, mut done: impl FnMut(&ControlState) -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done(state) {
+            assert!(Instant::now() < deadline, "timed out");
+            while let Some(request) = hub.poll() {
+                if let Command::SetVolume(volume) = request.command {
+                    state.volume = volume;
+                }
+                request.respond(Ok(json!(state)));
+            }
+            hub.publish(state.clone());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    // Plays a controller on the virtual ports through the ALSA sequencer. Needs one, so it only
+    // runs when asked, e.g. after `modprobe snd-seq-dummy`:
+    //
+    //   cargo test virtual_ports -- --ignored
+    #[cfg(target_os = "linux")]
+    #[test]
+    #[ignore = "needs the ALSA sequencer"]
+    fn virtual_ports_round_trip() {
+        let fader = cc(0, 7);
+        let prefs = MidiPrefs {
+            enabled: true,
+            virtual_ports: true,
+            soft_takeover: false,
+            mappings: vec![MidiMapping { control: Some(fader), action: MidiAction::Volume }],
+            ..MidiPrefs::default()
+        };
+        let mut hub = ControlHub::new();
+        let _controller = MidiController::start(&prefs, hub.handle()).unwrap();
+
+        // A motorized controller on the other side of our ports
+        let output = MidiOutput::new("audioapp2 test").unwrap();
+        let port = find_port(&output, &format!("{APP_NAME}:Control")).expect("our input port");
+        let mut output = output.connect(&port, "fader").unwrap();
+        let input = MidiInput::new("audioapp2 test").unwrap();
+        let port = find_port(&input, &format!("{APP_NAME}:Feedback")).expect("our feedback port");
+        let (sender, feedback) = mpsc::channel();
+        let _input = input.connect(&port, "motor", move |_, bytes, _| drop(sender.send(bytes.to_vec())), ()).unwrap();
+        let mut state = ControlState { volume: 0.5, ..ControlState::default() };
+
+        // The fader is sent to where the volume is
+        serve(&mut hub, &mut state, |_| feedback.try_iter().any(|message| message == [0xB0, 7, 64]));
+
+        // Moving it sets the volume
+        output.send(&[0xB0, 7, 127]).unwrap();
+        serve(&mut hub, &mut state, |state| state.volume == 1.0);
+
+        // And it follows changes made elsewhere
+        state.volume = 0.25;
+        serve(&mut hub, &mut state, |_| feedback.try_iter().any(|message| message == [0xB0, 7, 32]));
+    }
+}
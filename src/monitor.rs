@@ -0,0 +1,161 @@
+// Listen to a recording device on an output, e.g. to hear an audio interface or check how a
+// microphone sounds. Built on the same pipeline as multi-output, with a single output.
+
+use eframe::egui;
+use egui::{Color32, RichText};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::dsp::db_to_gain;
+use crate::pipeline::{OutputConfig, OutputControl, Pipeline, PipelineConfig, SinkName, Source};
+use crate::AudioApp;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MonitorPrefs {
+    pub enabled: bool,
+    pub input: Option<String>,
+    pub output: Option<String>,
+    pub gain_db: f32,
+    pub muted: bool,
+    pub latency_ms: u32,
+}
+
+impl Default for MonitorPrefs {
+    fn default() -> Self {
+        Self { enabled: false, input: None, output: None, gain_db: 0.0, muted: false, latency_ms: 30 }
+    }
+}
+
+#[derive(Default)]
+pub struct Monitor {
+    pipeline: Option<Pipeline>,
+    control: Option<Arc<OutputControl>>,
+    error: Option<String>,
+}
+
+impl AudioApp {
+    pub fn restart_monitor(&mut self) {
+        self.monitor.pipeline = None;
+        self.monitor.control = None;
+        self.monitor.error = None;
+
+        let prefs = &self.settings.monitor;
+        if !prefs.enabled {
+            return;
+        }
+        let (Some(input), Some(output)) = (prefs.input.clone(), prefs.output.clone()) else {
+            self.monitor.error = Some("Choose an input and an output".to_string());
+            return;
+        };
+
+        let control = OutputControl::new(db_to_gain(prefs.gain_db as f64) as f32, 0.0, prefs.muted);
+        let config = PipelineConfig {
+            source: Source::Input(input),
+            outputs: vec![OutputConfig { device: output, control: control.clone(), sink: None }],
+            latency_ms: prefs.latency_ms as f32,
+            virtual_sink: SinkName::default(),
+        };
+
+        match Pipeline::start(config) {
+            Ok(pipeline) => {
+                self.monitor.pipeline = Some(pipeline);
+                self.monitor.control = Some(control);
+            }
+            Err(err) => {
+                eprintln!("ERROR: Couldn't start listening: {err}");
+                self.monitor.error = Some(err);
+            }
+        }
+    }
+
+    pub fn monitor_ui(&mut self, ui: &mut egui::Ui) {
+        let mut restart = ui.checkbox(&mut self.settings.monitor.enabled, "Listen to an input").changed();
+        let mut edited = false;
+
+        let prefs = &mut self.settings.monitor;
+        let devices = &self.settings.devices;
+        let device_combo = |ui: &mut egui::Ui, id: &str, value: &mut Option<String>, names: &[String]| {
+            let selected = value.as_deref().map(|name| devices.display_name(name)).unwrap_or("Choose…");
+            let mut changed = false;
+            egui::ComboBox::from_id_source(id).selected_text(selected).width(220.0).show_ui(ui, |ui| {
+                for name in names {
+                    changed |= ui.selectable_value(value, Some(name.clone()), devices.display_name(name)).changed();
+                }
+            });
+            changed
+        };
+
+        egui::Grid::new("monitor").num_columns(2).spacing([8.0, 4.0]).show(ui, |ui| {
+            ui.label("Input:");
+            restart |= device_combo(ui, "monitor_input", &mut prefs.input, &self.input_device_names);
+            ui.end_row();
+
+            ui.label("Output:");
+            restart |= device_combo(ui, "monitor_output", &mut prefs.output, &self.device_names);
+            ui.end_row();
+
+            ui.label("Gain:");
+            ui.horizontal(|ui| {
+                let icon = if prefs.muted { RichText::new("🔇").color(Color32::RED) } else { RichText::new("🔊") };
+                if ui.small_button(icon).on_hover_text("Mute").clicked() {
+                    prefs.muted = !prefs.muted;
+                    if let Some(control) = &self.monitor.control {
+                        control.set_muted(prefs.muted);
+                    }
+                    edited = true;
+                }
+                if ui.add(egui::Slider::new(&mut prefs.gain_db, -40.0..=20.0).suffix(" dB")).changed() {
+                    if let Some(control) = &self.monitor.control {
+                        control.set_gain(db_to_gain(prefs.gain_db as f64) as f32);
+                    }
+                    edited = true;
+                }
+            });
+            ui.end_row();
+
+            ui.label("Buffer:");
+            ui.horizontal(|ui| {
+                ui.label(RichText::new("Lower latency").weak());
+                let response = ui.add(egui::Slider::new(&mut prefs.latency_ms, 5..=250).logarithmic(true).suffix(" ms"));
+                ui.label(RichText::new("More stable").weak());
+                // Only restart once the value has settled
+                if response.drag_released() || response.lost_focus() {
+                    restart = true;
+                } else if response.changed() {
+                    edited = true;
+                }
+            });
+            ui.end_row();
+        });
+
+        if let Some(err) = &self.monitor.error {
+            ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+        } else if let Some(control) = &self.monitor.control {
+            if control.failed.load(Ordering::Relaxed) {
+                ui.label(RichText::new("Stopped: the output went away").color(Color32::from_rgb(255, 96, 96)));
+            } else {
+                let underruns = control.underruns.load(Ordering::Relaxed);
+                let overruns = control.overruns.load(Ordering::Relaxed);
+                let text = format!("{:.0} ms buffered · {underruns} dropouts · {overruns} skips", control.buffered_ms());
+                let status = if underruns + overruns > 0 {
+                    RichText::new(text).color(Color32::from_rgb(255, 190, 96))
+                } else {
+                    RichText::new(text).weak()
+                };
+                ui.label(status).on_hover_text(
+                    "Dropouts: the output ran out of audio. Skips: audio was thrown away to catch up.\n\
+                     If either keeps counting up, raise the buffer.",
+                );
+            }
+        }
+
+        if edited || restart {
+            self.settings.save();
+        }
+        if restart {
+            self.restart_monitor();
+        }
+    }
+}
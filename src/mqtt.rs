@@ -0,0 +1,379 @@
+// MQTT bridge for home and office automation. Publishes the output device, volume and mute
+// to retained topics, takes commands from the matching ".../set" topics and announces itself
+// to Home Assistant through MQTT discovery. All topics live under
+// "audio_controller/<computer name>", for example:
+//
+//   audio_controller/meeting-room/volume            0-100
+//   audio_controller/meeting-room/volume/set
+//   audio_controller/meeting-room/mute              ON / OFF
+//   audio_controller/meeting-room/mute/set          ON / OFF / TOGGLE
+//   audio_controller/meeting-room/output_device     device name or alias
+//   audio_controller/meeting-room/output_device/set
+//   audio_controller/meeting-room/availability      online / offline (last will)
+
+use eframe::egui;
+use egui::RichText;
+use rumqttc::{Client, Event, LastWill, MqttOptions, Outgoing, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::control::{Command, ControlEvent, ControlHandle};
+use crate::endpoints::Flow;
+use crate::AudioApp;
+
+// Wait between attempts to reach the broker
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MqttPrefs {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    pub password: String,
+    // Announce our entities to Home Assistant
+    pub discovery: bool,
+    pub discovery_prefix: String,
+}
+
+impl Default for MqttPrefs {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: "localhost".to_string(),
+            port: 1883,
+            username: String::new(),
+            password: String::new(),
+            discovery: true,
+            discovery_prefix: "homeassistant".to_string(),
+        }
+    }
+}
+
+// Name of this computer, used to tell several PCs apart on the same broker
+fn computer_name() -> String {
+    std::env::var("COMPUTERNAME")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .ok()
+        .or_else(|| std::fs::read_to_string("/etc/hostname").ok())
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "pc".to_string())
+}
+
+// Topics and discovery IDs may only contain a safe subset of characters
+fn node_id(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+struct Topics {
+    base: String,
+}
+
+impl Topics {
+    fn new(node_id: &str) -> Self {
+        Self { base: format!("audio_controller/{node_id}") }
+    }
+
+    fn state(&self, name: &str) -> String {
+        format!("{}/{name}", self.base)
+    }
+
+    fn command(&self, name: &str) -> String {
+        format!("{}/{name}/set", self.base)
+    }
+
+    fn availability(&self) -> String {
+        self.state("availability")
+    }
+}
+
+fn on_off(value: bool) -> &'static str {
+    if value {
+        "ON"
+    } else {
+        "OFF"
+    }
+}
+
+// Turn a message on one of our ".../set" topics into a command
+fn parse_command(topics: &Topics, topic: &str, payload: &str) -> Option<Command> {
+    let payload = payload.trim();
+    if topic == topics.command("volume") {
+        let percent: f32 = payload.parse().ok()?;
+        Some(Command::SetVolume((percent / 100.0).clamp(0.0, 1.0)))
+    } else if topic == topics.command("mute") {
+        match payload.to_ascii_uppercase().as_str() {
+            "ON" | "TRUE" | "1" => Some(Command::SetMute(true)),
+            "OFF" | "FALSE" | "0" => Some(Command::SetMute(false)),
+            "TOGGLE" => Some(Command::ToggleMute),
+            _ => None,
+        }
+    } else if topic == topics.command("output_device") {
+        Some(Command::SetDefaultDevice { flow: Flow::Output, name: payload.to_string() })
+    } else {
+        None
+    }
+}
+
+// Home Assistant discovery messages: (topic, config) for each entity
+fn discovery_messages(prefs: &MqttPrefs, node_id: &str, name: &str, topics: &Topics, devices: &[String]) -> Vec<(String, Value)> {
+    let device = json!({
+        "identifiers": [format!("audio_controller_{node_id}")],
+        "name": name,
+        "manufacturer": "Audio Controller",
+    });
+    let entity = |component: &str, key: &str, title: &str, mut config: Value| {
+        config["name"] = json!(title);
+        config["unique_id"] = json!(format!("audio_controller_{node_id}_{key}"));
+        config["state_topic"] = json!(topics.state(key));
+        config["command_topic"] = json!(topics.command(key));
+        config["availability_topic"] = json!(topics.availability());
+        config["device"] = device.clone();
+        (format!("{}/{component}/{node_id}/{key}/config", prefs.discovery_prefix), config)
+    };
+
+    vec![
+        entity(
+            "number",
+            "volume",
+            "Volume",
+            json!({ "min": 0, "max": 100, "step": 1, "unit_of_measurement": "%", "icon": "mdi:volume-high" }),
+        ),
+        entity("switch", "mute", "Mute", json!({ "icon": "mdi:volume-off" })),
+        entity("select", "output_device", "Output device", json!({ "options": devices, "icon": "mdi:speaker" })),
+    ]
+}
+
+// Keeps a connection to the broker until dropped
+pub struct MqttBridge {
+    client: Client,
+    availability: String,
+    stopped: Arc<AtomicBool>,
+    status: Arc<Mutex<String>>,
+}
+
+impl MqttBridge {
+    pub fn start(prefs: &MqttPrefs, control: ControlHandle) -> Self {
+        let name = computer_name();
+        let node_id = node_id(&name);
+        let topics = Topics::new(&node_id);
+        let availability = topics.availability();
+
+        let mut options = MqttOptions::new(format!("audio-controller-{node_id}"), prefs.host.clone(), prefs.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        options.set_last_will(LastWill::new(topics.availability(), "offline", QoS::AtLeastOnce, true));
+        if !prefs.username.is_empty() {
+            options.set_credentials(prefs.username.clone(), prefs.password.clone());
+        }
+
+        let (client, mut connection) = Client::new(options, 64);
+        let stopped = Arc::new(AtomicBool::new(false));
+        let status = Arc::new(Mutex::new("Connecting…".to_string()));
+        // Set when the broker needs everything again, i.e. after every (re)connect
+        let resync = Arc::new(AtomicBool::new(false));
+
+        // Drive the connection and act on incoming commands
+        {
+            let client = client.clone();
+            let control = control.clone();
+            let stopped = stopped.clone();
+            let status = status.clone();
+            let resync = resync.clone();
+            let topics = Topics::new(&node_id);
+            let set_status = move |text: String| {
+                if let Ok(mut status) = status.lock() {
+                    *status = text;
+                }
+            };
+
+            std::thread::spawn(move || {
+                for notification in connection.iter() {
+                    match notification {
+                        Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                            set_status("Connected".to_string());
+                            let _ = client.try_subscribe(format!("{}/+/set", topics.base), QoS::AtLeastOnce);
+                            resync.store(true, Ordering::Relaxed);
+                        }
+                        // A retained command is a stale one, e.g. left behind by a misconfigured client
+                        Ok(Event::Incoming(Packet::Publish(publish))) if !publish.retain => {
+                            let payload = String::from_utf8_lossy(&publish.payload);
+                            if let Some(command) = parse_command(&topics, &publish.topic, &payload) {
+                                if let Err(err) = control.call(command) {
+                                    eprintln!("ERROR: MQTT command on {} failed: {err}", publish.topic);
+                                }
+                            }
+                        }
+                        // Sent by Drop, after the goodbye message
+                        Ok(Event::Outgoing(Outgoing::Disconnect)) => break,
+                        Ok(_) => {}
+                        Err(_) if stopped.load(Ordering::Relaxed) => break,
+                        Err(err) => {
+                            // Carrying on with the iterator reconnects
+                            set_status(format!("Disconnected: {err}"));
+                            for _ in 0..RECONNECT_DELAY.as_millis() / 100 {
+                                if stopped.load(Ordering::Relaxed) {
+                                    return;
+                                }
+                                std::thread::sleep(Duration::from_millis(100));
+                            }
+                        }
+                    }
+                }
+            });
+        }
+
+        // Publish state changes
+        {
+            let client = client.clone();
+            let stopped = stopped.clone();
+            let prefs = prefs.clone();
+            let events = control.subscribe();
+
+            std::thread::spawn(move || {
+                // System name -> name shown to the user, which is what we publish
+                let mut display_names: HashMap<String, String> = HashMap::new();
+                let publish = |topic: String, payload: String| {
+                    let _ = client.try_publish(topic, QoS::AtLeastOnce, true, payload);
+                };
+
+                while !stopped.load(Ordering::Relaxed) {
+                    if resync.swap(false, Ordering::Relaxed) {
+                        let (Ok(state), Ok(devices)) = (control.call(Command::GetState), control.call(Command::ListDevices))
+                        else {
+                            // Try again shortly
+                            resync.store(true, Ordering::Relaxed);
+                            std::thread::sleep(Duration::from_secs(1));
+                            continue;
+                        };
+
+                        display_names = devices["outputs"]
+                            .as_array()
+                            .into_iter()
+                            .flatten()
+                            .filter_map(|device| {
+                                Some((device["name"].as_str()?.to_string(), device["display_name"].as_str()?.to_string()))
+                            })
+                            .collect();
+
+                        publish(topics.availability(), "online".to_string());
+                        if prefs.discovery {
+                            let mut options: Vec<String> = display_names.values().cloned().collect();
+                            options.sort();
+                            for (topic, config) in discovery_messages(&prefs, &node_id, &name, &topics, &options) {
+                                publish(topic, config.to_string());
+                            }
+                        }
+                        let volume = state["volume"].as_f64().unwrap_or_default();
+                        publish(topics.state("volume"), format!("{}", (volume * 100.0).round()));
+                        publish(topics.state("mute"), on_off(state["muted"].as_bool().unwrap_or_default()).to_string());
+                        if let Some(device) = state["output_device"].as_str() {
+                            let shown = display_names.get(device).cloned().unwrap_or_else(|| device.to_string());
+                            publish(topics.state("output_device"), shown);
+                        }
+                    }
+
+                    match events.recv_timeout(Duration::from_millis(250)) {
+                        Ok(ControlEvent::VolumeChanged { volume }) => {
+                            publish(topics.state("volume"), format!("{}", (volume * 100.0).round()));
+                        }
+                        Ok(ControlEvent::MuteChanged { muted }) => publish(topics.state("mute"), on_off(muted).to_string()),
+                        Ok(ControlEvent::DefaultDeviceChanged { flow: Flow::Output, name: Some(device) }) => {
+                            let shown = display_names.get(&device).cloned().unwrap_or(device);
+                            publish(topics.state("output_device"), shown);
+                        }
+                        // The select entity's options have to be announced again
+                        Ok(ControlEvent::DevicesChanged { .. }) => resync.store(true, Ordering::Relaxed),
+                        Ok(_) => {}
+                        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {}
+                        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            });
+        }
+
+        Self { client, availability, stopped, status }
+    }
+
+    // What the connection is doing, for the settings page
+    pub fn status(&self) -> String {
+        self.status.lock().map(|status| status.clone()).unwrap_or_default()
+    }
+}
+
+impl Drop for MqttBridge {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        // Say goodbye properly; the last will only covers crashes and lost connections
+        let _ = self.client.try_publish(self.availability.clone(), QoS::AtLeastOnce, true, "offline");
+        let _ = self.client.try_disconnect();
+    }
+}
+
+impl AudioApp {
+    // Connect, reconnect or disconnect to match the settings
+    pub fn restart_mqtt(&mut self) {
+        self.mqtt = None;
+        if self.settings.mqtt.enabled {
+            self.mqtt = Some(MqttBridge::start(&self.settings.mqtt, self.control.handle()));
+        }
+    }
+
+    pub fn mqtt_ui(&mut self, ui: &mut egui::Ui) {
+        let prefs = &mut self.settings.mqtt;
+        let mut restart = ui.checkbox(&mut prefs.enabled, "Connect to an MQTT broker").changed();
+        let mut edited = false;
+
+        egui::Grid::new("mqtt_settings").num_columns(2).show(ui, |ui| {
+            ui.label("Broker:");
+            ui.horizontal(|ui| {
+                edited |= ui.add(egui::TextEdit::singleline(&mut prefs.host).desired_width(160.0)).changed();
+                edited |= ui.add(egui::DragValue::new(&mut prefs.port).clamp_range(1..=65535)).changed();
+            });
+            ui.end_row();
+
+            ui.label("Username:");
+            edited |= ui.add(egui::TextEdit::singleline(&mut prefs.username).desired_width(160.0)).changed();
+            ui.end_row();
+
+            ui.label("Password:");
+            edited |= ui.add(egui::TextEdit::singleline(&mut prefs.password).password(true).desired_width(160.0)).changed();
+            ui.end_row();
+
+            ui.label("Discovery prefix:");
+            ui.horizontal(|ui| {
+                edited |= ui.checkbox(&mut prefs.discovery, "").on_hover_text("Announce to Home Assistant").changed();
+                edited |= ui
+                    .add_enabled(prefs.discovery, egui::TextEdit::singleline(&mut prefs.discovery_prefix).desired_width(130.0))
+                    .changed();
+            });
+            ui.end_row();
+        });
+
+        ui.label(RichText::new(format!("Topics: audio_controller/{}/…", node_id(&computer_name()))).weak());
+
+        if edited {
+            self.settings.save();
+        }
+        ui.horizontal(|ui| {
+            if self.settings.mqtt.enabled && ui.button("Reconnect").on_hover_text("Apply the settings above").clicked() {
+                restart = true;
+            }
+            if let Some(bridge) = &self.mqtt {
+                ui.label(bridge.status());
+            }
+        });
+
+        if restart {
+            self.settings.save();
+            self.restart_mqtt();
+        }
+    }
+}
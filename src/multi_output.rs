@@ -0,0 +1,207 @@
+// Play one source on several output devices at once, e.g. the room speakers and a recording
+// interface during a presentation. Each output has its own volume and delay trim so they can
+// be lined up by ear.
+
+use eframe::egui;
+use egui::{Color32, RichText};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::pipeline::{OutputConfig, OutputControl, Pipeline, PipelineConfig, SinkName, Source, MAX_DELAY_MS};
+use crate::AudioApp;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MultiOutputPrefs {
+    pub enabled: bool,
+    pub source: Option<Source>,
+    pub outputs: Vec<MultiOutputTarget>,
+    pub latency_ms: u32,
+}
+
+impl Default for MultiOutputPrefs {
+    fn default() -> Self {
+        Self { enabled: false, source: None, outputs: Vec::new(), latency_ms: 60 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MultiOutputTarget {
+    pub device: String,
+    pub volume: f32,
+    pub delay_ms: f32,
+}
+
+impl Default for MultiOutputTarget {
+    fn default() -> Self {
+        Self { device: String::new(), volume: 1.0, delay_ms: 0.0 }
+    }
+}
+
+#[derive(Default)]
+pub struct MultiOutput {
+    pipeline: Option<Pipeline>,
+    // One per output in the settings, in the same order
+    controls: Vec<Arc<OutputControl>>,
+    error: Option<String>,
+}
+
+impl AudioApp {
+    pub fn restart_multi_output(&mut self) {
+        // Release the devices before opening them again
+        self.multi_output.pipeline = None;
+        self.multi_output.controls.clear();
+        self.multi_output.error = None;
+
+        let prefs = &self.settings.multi_output;
+        if !prefs.enabled {
+            return;
+        }
+        let Some(source) = prefs.source.clone() else {
+            self.multi_output.error = Some("Choose a source".to_string());
+            return;
+        };
+
+        let controls: Vec<Arc<OutputControl>> =
+            prefs.outputs.iter().map(|output| OutputControl::new(output.volume, output.delay_ms, false)).collect();
+        let config = PipelineConfig {
+            source,
+            outputs: prefs
+                .outputs
+                .iter()
+                .zip(&controls)
+                .map(|(output, control)| OutputConfig {
+                    device: output.device.clone(),
+                    control: control.clone(),
+                    sink: self.output_sink(&output.device),
+                })
+                .collect(),
+            latency_ms: prefs.latency_ms as f32,
+            virtual_sink: SinkName::default(),
+        };
+
+        match Pipeline::start(config) {
+            Ok(pipeline) => {
+                self.multi_output.pipeline = Some(pipeline);
+                self.multi_output.controls = controls;
+            }
+            Err(err) => {
+                eprintln!("ERROR: Couldn't start multi-output: {err}");
+                self.multi_output.error = Some(err);
+            }
+        }
+    }
+
+    pub fn multi_output_ui(&mut self, ui: &mut egui::Ui) {
+        let mut restart = ui.checkbox(&mut self.settings.multi_output.enabled, "Play on several outputs").changed();
+        let mut edited = false;
+
+        let sources = Source::available(&self.input_device_names, &self.device_names);
+        let prefs = &mut self.settings.multi_output;
+        ui.horizontal(|ui| {
+            ui.label("Source:");
+            let selected = prefs.source.as_ref().map(Source::label).unwrap_or_else(|| "Choose…".to_string());
+            egui::ComboBox::from_id_source("multi_output_source").selected_text(selected).width(220.0).show_ui(ui, |ui| {
+                for source in sources {
+                    let label = source.label();
+                    restart |= ui.selectable_value(&mut prefs.source, Some(source), label).changed();
+                }
+            });
+        });
+
+        let mut remove = None;
+        egui::Grid::new("multi_outputs").num_columns(5).striped(true).spacing([8.0, 4.0]).show(ui, |ui| {
+            for (idx, output) in prefs.outputs.iter_mut().enumerate() {
+                let control = self.multi_output.controls.get(idx);
+                ui.label(self.settings.devices.display_name(&output.device));
+
+                let mut percent = (output.volume * 100.0).round();
+                if ui.add(egui::DragValue::new(&mut percent).clamp_range(0.0..=100.0).suffix("%")).changed() {
+                    output.volume = percent / 100.0;
+                    if let Some(control) = control {
+                        control.set_gain(output.volume);
+                    }
+                    edited = true;
+                }
+
+                let delay = ui
+                    .add(egui::DragValue::new(&mut output.delay_ms).clamp_range(0.0..=MAX_DELAY_MS).speed(0.5).suffix(" ms"))
+                    .on_hover_text("Delay this output to line it up with the others");
+                if delay.changed() {
+                    if let Some(control) = control {
+                        control.set_delay_ms(output.delay_ms);
+                    }
+                    edited = true;
+                }
+
+                match control {
+                    Some(control) if control.failed.load(Ordering::Relaxed) => {
+                        ui.label(RichText::new("Stopped").color(Color32::from_rgb(255, 96, 96)));
+                    }
+                    Some(control) => {
+                        ui.label(RichText::new(format!("{:.0} ms · {:+} ppm", control.buffered_ms(), control.drift_ppm())).weak())
+                            .on_hover_text(format!(
+                                "Buffered audio and clock drift correction.\nDropouts: {}, skips: {}",
+                                control.underruns.load(Ordering::Relaxed),
+                                control.overruns.load(Ordering::Relaxed)
+                            ));
+                    }
+                    None => {
+                        ui.label("");
+                    }
+                }
+
+                if ui.small_button("🗑").clicked() {
+                    remove = Some(idx);
+                }
+                ui.end_row();
+            }
+        });
+
+        if let Some(idx) = remove {
+            prefs.outputs.remove(idx);
+            restart = true;
+        }
+
+        let mut add = None;
+        egui::ComboBox::from_id_source("multi_output_add").selected_text("Add output…").width(220.0).show_ui(ui, |ui| {
+            for name in self.device_names.iter().filter(|name| !prefs.outputs.iter().any(|output| &output.device == *name)) {
+                if ui.selectable_label(false, self.settings.devices.display_name(name)).clicked() {
+                    add = Some(name.clone());
+                }
+            }
+        });
+        if let Some(device) = add {
+            prefs.outputs.push(MultiOutputTarget { device, ..MultiOutputTarget::default() });
+            restart = true;
+        }
+
+        ui.horizontal(|ui| {
+            ui.label("Buffer:");
+            let response = ui
+                .add(egui::DragValue::new(&mut prefs.latency_ms).clamp_range(10..=500).suffix(" ms"))
+                .on_hover_text("Lower is more in sync with the source, higher copes better with a busy system");
+            // Only restart once the value has settled
+            if response.drag_released() || response.lost_focus() {
+                restart = true;
+            } else if response.changed() {
+                edited = true;
+            }
+        });
+
+        if let Some(err) = &self.multi_output.error {
+            ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+        } else if let Some(pipeline) = &self.multi_output.pipeline {
+            ui.label(RichText::new(format!("Running at {} Hz, {} channels", pipeline.sample_rate, pipeline.channels)).weak());
+        }
+
+        if edited || restart {
+            self.settings.save();
+        }
+        if restart {
+            self.restart_multi_output();
+        }
+    }
+}
@@ -0,0 +1,47 @@
+use eframe::egui;
+use egui::RichText;
+use std::time::{Duration, Instant};
+
+// How long a notification stays on screen
+const NOTIFICATION_TIMEOUT: Duration = Duration::from_secs(6);
+
+// Short messages shown in the corner of the window, e.g. after switching devices automatically
+#[derive(Default)]
+pub struct Notifications {
+    items: Vec<(String, Instant)>,
+}
+
+impl Notifications {
+    pub fn push(&mut self, message: impl Into<String>) {
+        self.items.push((message.into(), Instant::now()));
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) {
+        self.items.retain(|(_, shown)| shown.elapsed() < NOTIFICATION_TIMEOUT);
+        if self.items.is_empty() {
+            return;
+        }
+
+        let mut dismissed = None;
+        egui::Area::new("notifications")
+            .anchor(egui::Align2::RIGHT_BOTTOM, [-10.0, -10.0])
+            .order(egui::Order::Foreground)
+            .show(ctx, |ui| {
+                for (idx, (message, _)) in self.items.iter().enumerate() {
+                    egui::Frame::popup(ui.style()).show(ui, |ui| {
+                        ui.set_max_width(300.0);
+                        ui.horizontal(|ui| {
+                            ui.label(RichText::new(message));
+                            if ui.small_button("✖").clicked() {
+                                dismissed = Some(idx);
+                            }
+                        });
+                    });
+                }
+            });
+
+        if let Some(idx) = dismissed {
+            self.items.remove(idx);
+        }
+    }
+}
@@ -0,0 +1,498 @@
+// What's playing right now, read from MPRIS media players over the D-Bus session bus, with
+// play/pause, next and previous. MPRIS only exists on Linux; elsewhere nothing is ever playing.
+
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::hotkeys::MediaKeyListener;
+use crate::AudioApp;
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MediaPrefs {
+    pub now_playing: bool,
+    // Handle the volume up, down and mute keys ourselves
+    pub media_keys: bool,
+    // Percent per key press
+    pub volume_step: u8,
+}
+
+impl Default for MediaPrefs {
+    fn default() -> Self {
+        Self { now_playing: true, media_keys: false, volume_step: 5 }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NowPlaying {
+    // e.g. "Spotify"
+    pub player: String,
+    pub title: String,
+    pub artist: String,
+    pub playing: bool,
+    pub can_play: bool,
+    pub can_go_next: bool,
+    pub can_go_previous: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaCommand {
+    PlayPause,
+    Next,
+    Previous,
+}
+
+// Keeps an eye on the media players from a background thread
+pub struct NowPlayingWatcher {
+    current: Arc<Mutex<Option<NowPlaying>>>,
+    commands: Sender<MediaCommand>,
+}
+
+impl NowPlayingWatcher {
+    #[cfg(target_os = "linux")]
+    pub fn start() -> Self {
+        Self::start_on(zbus::blocking::Connection::session)
+    }
+
+    // Nothing to watch, and commands go nowhere
+    #[cfg(not(target_os = "linux"))]
+    pub fn start() -> Self {
+        let (commands, _) = mpsc::channel();
+        Self { current: Arc::new(Mutex::new(None)), commands }
+    }
+
+    // Watch the players on the bus `connect` reaches
+    #[cfg(target_os = "linux")]
+    fn start_on(connect: impl FnOnce() -> zbus::Result<zbus::blocking::Connection> + Send + 'static) -> Self {
+        let current = Arc::new(Mutex::new(None));
+        let (commands, receiver) = mpsc::channel();
+        {
+            let current = current.clone();
+            std::thread::spawn(move || mpris::watch(connect, current, receiver));
+        }
+        Self { current, commands }
+    }
+
+    pub fn current(&self) -> Option<NowPlaying> {
+        self.current.lock().ok()?.clone()
+    }
+
+    pub fn send(&self, command: MediaCommand) {
+        let _ = self.commands.send(command);
+    }
+}
+
+// Ask every player to pause, e.g. because sound is about to come out of the speakers
+#[cfg(target_os = "linux")]
+pub fn pause_all() {
+    if let Err(err) = mpris::pause_all() {
+        eprintln!("ERROR: Couldn't pause the media players: {err}");
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod mpris {
+    use std::collections::HashMap;
+    use std::sync::mpsc::{Receiver, RecvTimeoutError};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use zbus::blocking::{fdo::DBusProxy, Connection, Proxy, ProxyBuilder};
+    use zbus::zvariant::OwnedValue;
+    use zbus::CacheProperties;
+
+    use super::{MediaCommand, NowPlaying};
+
+    const PREFIX: &str = "org.mpris.MediaPlayer2.";
+    const PATH: &str = "/org/mpris/MediaPlayer2";
+
+    // Players announce changes with signals, but looking once a second is plenty for a label
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn proxy<'a>(connection: &Connection, name: &'a str, interface: &'a str) -> zbus::Result<Proxy<'a>> {
+        ProxyBuilder::new_bare(connection)
+            .destination(name)?
+            .path(PATH)?
+            .interface(interface)?
+            .cache_properties(CacheProperties::No)
+            .build()
+    }
+
+    fn players(connection: &Connection) -> Vec<String> {
+        let Ok(bus) = DBusProxy::new(connection) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = bus
+            .list_names()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|name| name.to_string())
+            .filter(|name| name.starts_with(PREFIX))
+            .collect();
+        names.sort();
+        names
+    }
+
+    fn read(connection: &Connection, name: &str) -> zbus::Result<NowPlaying> {
+        let player = proxy(connection, name, "org.mpris.MediaPlayer2.Player")?;
+        let status: String = player.get_property("PlaybackStatus")?;
+        let metadata: HashMap<String, OwnedValue> = player.get_property("Metadata").unwrap_or_default();
+        let text = |key: &str| metadata.get(key).and_then(|value| String::try_from(value.clone()).ok());
+        let artist = metadata
+            .get("xesam:artist")
+            .and_then(|value| <Vec<String>>::try_from(value.clone()).ok())
+            .map(|artists| artists.join(", "));
+
+        let identity = proxy(connection, name, "org.mpris.MediaPlayer2")
+            .and_then(|root| root.get_property::<String>("Identity"))
+            .unwrap_or_else(|_| name.trim_start_matches(PREFIX).to_string());
+
+        Ok(NowPlaying {
+            player: identity,
+            title: text("xesam:title").unwrap_or_default(),
+            artist: artist.unwrap_or_default(),
+            playing: status == "Playing",
+            can_play: player.get_property("CanPlay").unwrap_or(false) || player.get_property("CanPause").unwrap_or(false),
+            can_go_next: player.get_property("CanGoNext").unwrap_or(false),
+            can_go_previous: player.get_property("CanGoPrevious").unwrap_or(false),
+        })
+    }
+
+    // The player worth showing: one that's playing, or else the first one with something loaded
+    fn choose(connection: &Connection) -> Option<(String, NowPlaying)> {
+        let mut fallback = None;
+        for name in players(connection) {
+            let Ok(playing) = read(connection, &name) else {
+                continue;
+            };
+            if playing.playing {
+                return Some((name, playing));
+            }
+            if fallback.is_none() && !playing.title.is_empty() {
+                fallback = Some((name, playing));
+            }
+        }
+        fallback
+    }
+
+    pub fn pause_all() -> zbus::Result<()> {
+        let connection = Connection::session()?;
+        for name in players(&connection) {
+            // Players that can't pause say so with an error; the others should still pause
+            let result = proxy(&connection, &name, "org.mpris.MediaPlayer2.Player")
+                .and_then(|player| player.call_method("Pause", &()).map(|_| ()));
+            if let Err(err) = result {
+                eprintln!("ERROR: Pause failed on {name}: {err}");
+            }
+        }
+        Ok(())
+    }
+
+    pub fn watch(
+        connect: impl FnOnce() -> zbus::Result<Connection>,
+        current: Arc<Mutex<Option<NowPlaying>>>,
+        commands: Receiver<MediaCommand>,
+    ) {
+        let connection = match connect() {
+            Ok(connection) => connection,
+            Err(err) => {
+                eprintln!("ERROR: Couldn't connect to the session bus: {err}");
+                return;
+            }
+        };
+
+        let mut chosen: Option<String> = None;
+        loop {
+            match commands.recv_timeout(REFRESH_INTERVAL) {
+                Ok(command) => {
+                    if let Some(name) = &chosen {
+                        let method = match command {
+                            MediaCommand::PlayPause => "PlayPause",
+                            MediaCommand::Next => "Next",
+                            MediaCommand::Previous => "Previous",
+                        };
+                        let result = proxy(&connection, name, "org.mpris.MediaPlayer2.Player")
+                            .and_then(|player| player.call_method(method, &()).map(|_| ()));
+                        if let Err(err) = result {
+                            eprintln!("ERROR: {method} failed on {name}: {err}");
+                        }
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            let found = choose(&connection);
+            chosen = found.as_ref().map(|(name, _)| name.clone());
+            if let Ok(mut current) = current.lock() {
+                *current = found.map(|(_, playing)| playing);
+            }
+        }
+    }
+}
+
+impl AudioApp {
+    // Start or stop watching players and listening for media keys to match the settings
+    pub fn restart_media(&mut self) {
+        self.now_playing = self.settings.media.now_playing.then(NowPlayingWatcher::start);
+
+        self.media_keys = None;
+        self.media_keys_error = None;
+        if self.settings.media.media_keys {
+            match MediaKeyListener::start() {
+                Ok(listener) => self.media_keys = Some(listener),
+                Err(err) => {
+                    eprintln!("ERROR: {err}");
+                    self.media_keys_error = Some(err);
+                }
+            }
+        }
+    }
+
+    // Shown under the volume controls while something is playing
+    pub fn now_playing_ui(&mut self, ui: &mut egui::Ui) {
+        let Some(watcher) = &self.now_playing else {
+            return;
+        };
+        let Some(playing) = watcher.current() else {
+            return;
+        };
+
+        ui.add_space(4.0);
+        egui::Frame::none()
+            .fill(ui.visuals().extreme_bg_color)
+            .inner_margin(egui::style::Margin::same(10.0))
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.add_enabled(playing.can_go_previous, egui::Button::new("⏮")).clicked() {
+                        watcher.send(MediaCommand::Previous);
+                    }
+                    let play_pause = if playing.playing { "⏸" } else { "▶" };
+                    if ui.add_enabled(playing.can_play, egui::Button::new(play_pause)).clicked() {
+                        watcher.send(MediaCommand::PlayPause);
+                    }
+                    if ui.add_enabled(playing.can_go_next, egui::Button::new("⏭")).clicked() {
+                        watcher.send(MediaCommand::Next);
+                    }
+
+                    ui.vertical(|ui| {
+                        let title = if playing.title.is_empty() { "Unknown title" } else { &playing.title };
+                        ui.label(RichText::new(title).strong());
+                        let source = if playing.artist.is_empty() {
+                            playing.player.clone()
+                        } else {
+                            format!("{} · {}", playing.artist, playing.player)
+                        };
+                        ui.label(RichText::new(source).weak());
+                    });
+                });
+            });
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn media_ui(&mut self, ui: &mut egui::Ui) {
+        let prefs = &mut self.settings.media;
+        let mut restart = ui.checkbox(&mut prefs.now_playing, "Show what's playing").changed();
+        restart |= ui
+            .checkbox(&mut prefs.media_keys, "Handle the volume keys")
+            .on_hover_text("Volume up, volume down and mute, on X11. Turn this off if your desktop already handles them.")
+            .changed();
+        let edited = ui
+            .horizontal(|ui| {
+                ui.label("Volume step:");
+                ui.add(egui::DragValue::new(&mut prefs.volume_step).clamp_range(1..=25).suffix("%")).changed()
+            })
+            .inner;
+
+        if let Some(err) = &self.media_keys_error {
+            ui.label(RichText::new(err).color(egui::Color32::from_rgb(255, 96, 96)));
+        }
+
+        if edited || restart {
+            self.settings.save();
+        }
+        if restart {
+            self.restart_media();
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::time::{Duration, Instant};
+    use zbus::blocking::{Connection, ConnectionBuilder};
+    use zbus::dbus_interface;
+    use zbus::zvariant::Value;
+
+    const TRACKS: [(&str, &str); 3] = [
+        ("Stand-in Overture", "The Placeholders"),
+        ("Lorem Ipsum Blues", "Dolor Sit"),
+        ("Test Tone in A", "Oscillator"),
+    ];
+
+    // A session bus of our own, so real players stay out of it
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+    }
+
+    impl PrivateBus {
+        fn start() -> Self {
+            let mut daemon = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("dbus-daemon should be installed");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap()).read_line(&mut address).unwrap();
+            Self { daemon, address: address.trim().to_string() }
+        }
+
+        fn connect(&self) -> ConnectionBuilder<'static> {
+            ConnectionBuilder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    struct Root;
+
+    #[dbus_interface(name = "org.mpris.MediaPlayer2")]
+    impl Root {
+        #[dbus_interface(property)]
+        fn identity(&self) -> String {
+            "Stand-in Player".to_string()
+        }
+    }
+
+    // A pretend player that remembers what it was asked to do
+    struct Player {
+        playing: bool,
+        track: usize,
+        calls: Arc<Mutex<Vec<&'static str>>>,
+    }
+
+    #[dbus_interface(name = "org.mpris.MediaPlayer2.Player")]
+    impl Player {
+        fn play_pause(&mut self) {
+            self.calls.lock().unwrap().push("PlayPause");
+            self.playing = !self.playing;
+        }
+
+        fn pause(&mut self) {
+            self.calls.lock().unwrap().push("Pause");
+            self.playing = false;
+        }
+
+        fn next(&mut self) {
+            self.calls.lock().unwrap().push("Next");
+            self.track = (self.track + 1) % TRACKS.len();
+        }
+
+        fn previous(&mut self) {
+            self.calls.lock().unwrap().push("Previous");
+            self.track = (self.track + TRACKS.len() - 1) % TRACKS.len();
+        }
+
+        #[dbus_interface(property)]
+        fn playback_status(&self) -> String {
+            if self.playing { "Playing" } else { "Paused" }.to_string()
+        }
+
+        #[dbus_interface(property)]
+        fn metadata(&self) -> HashMap<String, Value<'static>> {
+            let (title, artist) = TRACKS[self.track];
+            HashMap::from([
+                ("mpris:trackid".to_string(), Value::from(format!("/org/mpris/standin/track/{}", self.track))),
+                ("xesam:title".to_string(), Value::from(title)),
+                ("xesam:artist".to_string(), Value::from(vec![artist])),
+            ])
+        }
+
+        #[dbus_interface(property)]
+        fn can_play(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn can_go_next(&self) -> bool {
+            true
+        }
+
+        #[dbus_interface(property)]
+        fn can_go_previous(&self) -> bool {
+            false
+        }
+    }
+
+    // Poll until `done` is happy with what the watcher shows, which takes up to a refresh
+    fn wait_for(watcher: &NowPlayingWatcher, done: impl Fn(&NowPlaying) -> bool) -> NowPlaying {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            if let Some(playing) = watcher.current().filter(|playing| done(playing)) {
+                return playing;
+            }
+            assert!(Instant::now() < deadline, "still showing {:?}", watcher.current());
+            std::thread::sleep(Duration::from_millis(50));
+        }
+    }
+
+    // Needs `dbus-daemon`, so it only runs when asked:
+    //
+    //   cargo test now_playing -- --ignored
+    #[test]
+    #[ignore = "needs dbus-daemon"]
+    fn shows_the_player_and_controls_it() {
+        let bus = PrivateBus::start();
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let player = Player { playing: true, track: 0, calls: calls.clone() };
+        let _player: Connection = bus
+            .connect()
+            .name("org.mpris.MediaPlayer2.standin")
+            .unwrap()
+            .serve_at("/org/mpris/MediaPlayer2", Root)
+            .unwrap()
+            .serve_at("/org/mpris/MediaPlayer2", player)
+            .unwrap()
+            .build()
+            .unwrap();
+
+        let watcher = NowPlayingWatcher::start_on({
+            let address = bus.address.clone();
+            move || ConnectionBuilder::address(address.as_str())?.build()
+        });
+
+        let shown = wait_for(&watcher, |_| true);
+        assert_eq!(
+            shown,
+            NowPlaying {
+                player: "Stand-in Player".to_string(),
+                title: "Stand-in Overture".to_string(),
+                artist: "The Placeholders".to_string(),
+                playing: true,
+                can_play: true,
+                can_go_next: true,
+                can_go_previous: false,
+            }
+        );
+
+        watcher.send(MediaCommand::PlayPause);
+        wait_for(&watcher, |playing| !playing.playing);
+        watcher.send(MediaCommand::Next);
+        let shown = wait_for(&watcher, |playing| playing.title == "Lorem Ipsum Blues");
+        assert_eq!(shown.artist, "Dolor Sit");
+        assert_eq!(*calls.lock().unwrap(), ["PlayPause", "Next"]);
+    }
+}
@@ -0,0 +1,459 @@
+// Open Sound Control over UDP, for control surfaces such as TouchOSC and show software such
+// as QLab. Incoming messages:
+//
+//   /audioapp/volume f              master volume, 0.0 - 1.0
+//   /audioapp/mute i                1 = muted, 0 = unmuted, no argument toggles
+//   /audioapp/device s              output device by name or alias
+//   /audioapp/app/<name>/volume f   one application's volume
+//   /audioapp/app/<name>/mute i
+//   /audioapp/sync                  send the current state back
+//
+// Whenever the volume, mute or device changes, however it happened, the same addresses are
+// sent back as feedback so motorized faders and buttons stay in sync.
+
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::control::{Command, ControlEvent, ControlHandle};
+use crate::endpoints::Flow;
+use crate::AudioApp;
+
+const PREFIX: &str = "/audioapp";
+
+// How often the threads check whether they should stop, which is also about how long
+// stopping the server takes
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Senders that have been quiet this long stop getting feedback, e.g. a tablet that left
+const PEER_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct OscPrefs {
+    pub enabled: bool,
+    pub listen_port: u16,
+    // Where feedback goes. Empty means back to whoever sent us a message.
+    pub feedback_host: String,
+    pub feedback_port: u16,
+    // Accept messages from other devices on the network, not just this computer
+    pub lan: bool,
+}
+
+impl Default for OscPrefs {
+    fn default() -> Self {
+        // TouchOSC's defaults, seen from our side
+        Self { enabled: false, listen_port: 8000, feedback_host: String::new(), feedback_port: 9000, lan: false }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Str(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(value) => Some(*value as f32),
+            OscArg::Float(value) => Some(*value),
+            OscArg::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            OscArg::Str(_) => None,
+        }
+    }
+
+    fn as_bool(&self) -> Option<bool> {
+        self.as_f32().map(|value| value >= 0.5)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    fn new(address: impl Into<String>, args: Vec<OscArg>) -> Self {
+        Self { address: address.into(), args }
+    }
+}
+
+// OSC strings are NUL-terminated and padded to a multiple of four bytes
+fn read_string(data: &[u8], pos: &mut usize) -> Option<String> {
+    let end = *pos + data.get(*pos..)?.iter().position(|byte| *byte == 0)?;
+    let text = String::from_utf8_lossy(&data[*pos..end]).into_owned();
+    *pos = (end + 4) & !3;
+    Some(text)
+}
+
+fn read_bytes<const N: usize>(data: &[u8], pos: &mut usize) -> Option<[u8; N]> {
+    let bytes = data.get(*pos..*pos + N)?.try_into().ok()?;
+    *pos += N;
+    Some(bytes)
+}
+
+fn write_string(out: &mut Vec<u8>, text: &str) {
+    out.extend_from_slice(text.as_bytes());
+    out.push(0);
+    while !out.len().is_multiple_of(4) {
+        out.push(0);
+    }
+}
+
+// Decode a packet into its messages, unpacking bundles. Arguments of types we don't use are
+// skipped where their size is known.
+pub fn decode(data: &[u8]) -> Option<Vec<OscMessage>> {
+    let mut pos = 0;
+    if data.starts_with(b"#bundle\0") {
+        pos += 16; // "#bundle" and the time tag
+        let mut messages = Vec::new();
+        while pos < data.len() {
+            let size = u32::from_be_bytes(read_bytes(data, &mut pos)?) as usize;
+            messages.extend(decode(data.get(pos..pos + size)?)?);
+            pos += size;
+        }
+        return Some(messages);
+    }
+
+    let address = read_string(data, &mut pos)?;
+    if !address.starts_with('/') {
+        return None;
+    }
+    // Very old senders leave out the type tags altogether
+    let tags = if pos < data.len() { read_string(data, &mut pos)? } else { ",".to_string() };
+
+    let mut args = Vec::new();
+    for tag in tags.chars().skip(1) {
+        match tag {
+            'i' => args.push(OscArg::Int(i32::from_be_bytes(read_bytes(data, &mut pos)?))),
+            'f' => args.push(OscArg::Float(f32::from_be_bytes(read_bytes(data, &mut pos)?))),
+            'h' => args.push(OscArg::Int(i64::from_be_bytes(read_bytes(data, &mut pos)?) as i32)),
+            'd' => args.push(OscArg::Float(f64::from_be_bytes(read_bytes(data, &mut pos)?) as f32)),
+            's' | 'S' => args.push(OscArg::Str(read_string(data, &mut pos)?)),
+            'T' => args.push(OscArg::Bool(true)),
+            'F' => args.push(OscArg::Bool(false)),
+            'b' => {
+                let size = u32::from_be_bytes(read_bytes(data, &mut pos)?) as usize;
+                pos = (pos + size + 3) & !3;
+            }
+            'N' | 'I' => {}
+            _ => return None,
+        }
+    }
+    Some(vec![OscMessage { address, args }])
+}
+
+pub fn encode(message: &OscMessage) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_string(&mut out, &message.address);
+
+    let mut tags = ",".to_string();
+    for arg in &message.args {
+        tags.push(match arg {
+            OscArg::Int(_) => 'i',
+            OscArg::Float(_) => 'f',
+            OscArg::Str(_) => 's',
+            OscArg::Bool(true) => 'T',
+            OscArg::Bool(false) => 'F',
+        });
+    }
+    write_string(&mut out, &tags);
+
+    for arg in &message.args {
+        match arg {
+            OscArg::Int(value) => out.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Float(value) => out.extend_from_slice(&value.to_be_bytes()),
+            OscArg::Str(text) => write_string(&mut out, text),
+            OscArg::Bool(_) => {}
+        }
+    }
+    out
+}
+
+// What an incoming message asks for
+enum Request {
+    Command(Command),
+    Sync,
+}
+
+fn parse_request(message: &OscMessage) -> Option<Request> {
+    let path = message.address.strip_prefix(PREFIX)?;
+    let first = message.args.first();
+
+    let command = match path {
+        "/volume" => Command::SetVolume(first?.as_f32()?.clamp(0.0, 1.0)),
+        "/mute" => match first.and_then(OscArg::as_bool) {
+            Some(muted) => Command::SetMute(muted),
+            None => Command::ToggleMute,
+        },
+        "/device" => match first? {
+            OscArg::Str(name) => Command::SetDefaultDevice { flow: Flow::Output, name: name.clone() },
+            _ => return None,
+        },
+        "/sync" => return Some(Request::Sync),
+        _ => {
+            // /app/<name>/volume and /app/<name>/mute
+            let rest = path.strip_prefix("/app/")?;
+            let (app, control) = rest.rsplit_once('/')?;
+            match control {
+                "volume" => Command::SetAppVolume { app: app.to_string(), volume: first?.as_f32()?.clamp(0.0, 1.0) },
+                "mute" => Command::SetAppMute { app: app.to_string(), muted: first?.as_bool()? },
+                _ => return None,
+            }
+        }
+    };
+    Some(Request::Command(command))
+}
+
+fn state_messages(state: &Value) -> Vec<OscMessage> {
+    let mut messages = vec![
+        OscMessage::new(format!("{PREFIX}/volume"), vec![OscArg::Float(state["volume"].as_f64().unwrap_or_default() as f32)]),
+        OscMessage::new(format!("{PREFIX}/mute"), vec![OscArg::Int(state["muted"].as_bool().unwrap_or_default() as i32)]),
+    ];
+    if let Some(device) = state["output_device"].as_str() {
+        messages.push(OscMessage::new(format!("{PREFIX}/device"), vec![OscArg::Str(device.to_string())]));
+    }
+    messages
+}
+
+// Where feedback goes
+struct Feedback {
+    fixed: Option<SocketAddr>,
+    port: u16,
+    // Who has sent us something lately and when, when there's no fixed destination
+    senders: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Feedback {
+    fn heard_from(&self, ip: IpAddr) {
+        if let Ok(mut senders) = self.senders.lock() {
+            senders.insert(ip, Instant::now());
+        }
+    }
+
+    fn targets(&self) -> Vec<SocketAddr> {
+        if let Some(target) = self.fixed {
+            return vec![target];
+        }
+        let Ok(mut senders) = self.senders.lock() else {
+            return Vec::new();
+        };
+        senders.retain(|_, last_heard| last_heard.elapsed() < PEER_TIMEOUT);
+        senders.keys().map(|ip| SocketAddr::new(*ip, self.port)).collect()
+    }
+
+    fn send(&self, socket: &UdpSocket, messages: &[OscMessage]) {
+        let targets = self.targets();
+        for message in messages {
+            let packet = encode(message);
+            for target in &targets {
+                let _ = socket.send_to(&packet, target);
+            }
+        }
+    }
+}
+
+// Listens until dropped
+pub struct OscServer {
+    stopped: Arc<AtomicBool>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl OscServer {
+    pub fn start(prefs: &OscPrefs, control: ControlHandle) -> Result<Self, String> {
+        let host = if prefs.lan { "0.0.0.0" } else { "127.0.0.1" };
+        let socket = UdpSocket::bind((host, prefs.listen_port))
+            .map_err(|err| format!("Couldn't listen on UDP port {}: {err}", prefs.listen_port))?;
+        socket.set_read_timeout(Some(POLL_INTERVAL)).map_err(|err| err.to_string())?;
+        // Feedback about changes made elsewhere goes out from its own socket, so only the
+        // receiving thread holds on to the port
+        let event_socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).map_err(|err| err.to_string())?;
+
+        let fixed = if prefs.feedback_host.trim().is_empty() {
+            None
+        } else {
+            use std::net::ToSocketAddrs;
+            let target = (prefs.feedback_host.trim(), prefs.feedback_port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addresses| addresses.next())
+                .ok_or_else(|| format!("Unknown feedback host '{}'", prefs.feedback_host))?;
+            Some(target)
+        };
+        let feedback = Arc::new(Feedback { fixed, port: prefs.feedback_port, senders: Mutex::new(HashMap::new()) });
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut threads = Vec::new();
+
+        // Incoming messages
+        {
+            let control = control.clone();
+            let feedback = feedback.clone();
+            let stopped = stopped.clone();
+            threads.push(std::thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                while !stopped.load(Ordering::Relaxed) {
+                    // Timeouts just give us a chance to check whether we should stop
+                    let Ok((size, sender)) = socket.recv_from(&mut buf) else {
+                        continue;
+                    };
+                    feedback.heard_from(sender.ip());
+
+                    for message in decode(&buf[..size]).unwrap_or_default() {
+                        match parse_request(&message) {
+                            Some(Request::Command(command)) => {
+                                // Per-app changes don't produce events, so confirm them here
+                                let echo = matches!(command, Command::SetAppVolume { .. } | Command::SetAppMute { .. });
+                                match control.call(command) {
+                                    Ok(_) if echo => feedback.send(&socket, std::slice::from_ref(&message)),
+                                    Ok(_) => {}
+                                    Err(err) => eprintln!("ERROR: OSC {} failed: {err}", message.address),
+                                }
+                            }
+                            Some(Request::Sync) => {
+                                if let Ok(state) = control.call(Command::GetState) {
+                                    feedback.send(&socket, &state_messages(&state));
+                                }
+                            }
+                            None => eprintln!("ERROR: Unknown OSC message {}", message.address),
+                        }
+                    }
+                }
+            }));
+        }
+
+        // Feedback for changes made anywhere else
+        {
+            let stopped = stopped.clone();
+            let events = control.subscribe();
+            threads.push(std::thread::spawn(move || {
+                while !stopped.load(Ordering::Relaxed) {
+                    let message = match events.recv_timeout(POLL_INTERVAL) {
+                        Ok(ControlEvent::VolumeChanged { volume }) => {
+                            OscMessage::new(format!("{PREFIX}/volume"), vec![OscArg::Float(volume)])
+                        }
+                        Ok(ControlEvent::MuteChanged { muted }) => {
+                            OscMessage::new(format!("{PREFIX}/mute"), vec![OscArg::Int(muted as i32)])
+                        }
+                        Ok(ControlEvent::DefaultDeviceChanged { flow: Flow::Output, name: Some(name) }) => {
+                            OscMessage::new(format!("{PREFIX}/device"), vec![OscArg::Str(name)])
+                        }
+                        Ok(_) | Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+                    feedback.send(&event_socket, &[message]);
+                }
+            }));
+        }
+
+        Ok(Self { stopped, threads })
+    }
+}
+
+impl Drop for OscServer {
+    // Returns once the port is free again, so a new server can bind it straight away
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl AudioApp {
+    // Start, restart or stop the OSC server to match the settings
+    pub fn restart_osc(&mut self) {
+        // Dropping the old server releases its port
+        self.osc = None;
+        if !self.settings.osc.enabled {
+            return;
+        }
+
+        match OscServer::start(&self.settings.osc, self.control.handle()) {
+            Ok(server) => self.osc = Some(server),
+            Err(err) => {
+                eprintln!("ERROR: {err}");
+                self.notifications.push(format!("OSC not started: {err}"));
+            }
+        }
+    }
+
+    pub fn osc_ui(&mut self, ui: &mut egui::Ui) {
+        let prefs = &mut self.settings.osc;
+        let mut restart = ui.checkbox(&mut prefs.enabled, "Listen for OSC messages").changed();
+        let mut edited = false;
+
+        egui::Grid::new("osc_settings").num_columns(2).show(ui, |ui| {
+            ui.label("Listen on port:");
+            edited |= ui.add(egui::DragValue::new(&mut prefs.listen_port).clamp_range(1024..=65535)).changed();
+            ui.end_row();
+
+            ui.label("Feedback to:");
+            ui.horizontal(|ui| {
+                edited |= ui
+                    .add(egui::TextEdit::singleline(&mut prefs.feedback_host).hint_text("sender").desired_width(130.0))
+                    .on_hover_text("Leave empty to answer whoever sent the last messages")
+                    .changed();
+                edited |= ui.add(egui::DragValue::new(&mut prefs.feedback_port).clamp_range(1..=65535)).changed();
+            });
+            ui.end_row();
+        });
+        restart |= ui.checkbox(&mut prefs.lan, "Accept messages from other devices on the network").changed();
+        ui.label(RichText::new(format!("Addresses: {PREFIX}/volume, {PREFIX}/mute, {PREFIX}/device, {PREFIX}/app/<name>/volume")).weak());
+
+        if edited {
+            self.settings.save();
+        }
+        if self.settings.osc.enabled && ui.button("Restart").on_hover_text("Apply the settings above").clicked() {
+            restart = true;
+        }
+        if restart {
+            self.settings.save();
+            self.restart_osc();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlHub;
+
+    #[test]
+    fn port_is_free_as_soon_as_the_server_is_dropped() {
+        let port = UdpSocket::bind(("127.0.0.1", 0)).unwrap().local_addr().unwrap().port();
+        let prefs = OscPrefs { enabled: true, listen_port: port, ..OscPrefs::default() };
+        let hub = ControlHub::new();
+        for _ in 0..3 {
+            let server = OscServer::start(&prefs, hub.handle()).unwrap();
+            drop(server);
+        }
+    }
+
+    #[test]
+    fn quiet_senders_stop_getting_feedback() {
+        let feedback = Feedback { fixed: None, port: 9000, senders: Mutex::new(HashMap::new()) };
+        let recent: IpAddr = "192.168.1.20".parse().unwrap();
+        let gone: IpAddr = "192.168.1.21".parse().unwrap();
+        // Only possible once the clock has been running for longer than the timeout
+        let Some(long_ago) = Instant::now().checked_sub(PEER_TIMEOUT) else {
+            return;
+        };
+        feedback.heard_from(recent);
+        feedback.senders.lock().unwrap().insert(gone, long_ago);
+
+        assert_eq!(feedback.targets(), [SocketAddr::new(recent, 9000)]);
+        assert!(!feedback.senders.lock().unwrap().contains_key(&gone));
+    }
+}
@@ -0,0 +1,884 @@
+// Audio that passes through the app itself: captured from one place and played on one or more
+// output devices, each with its own volume and delay. Outputs rarely share a clock with the
+// source, even at the same nominal rate, so every output resamples and nudges its ratio to
+// keep its buffer at the target fill instead of slowly drifting into dropouts or lag.
+
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{FromSample, Sample, SampleFormat, SizedSample};
+use ringbuf::traits::{Consumer, Observer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
+use rubato::{Resampler, SincFixedOut, SincInterpolationParameters, SincInterpolationType, WindowFunction};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::dsp::virtualizer::Virtualizer;
+use crate::dsp::{Chain, ChainSettings, Format, LoadedFiles, PreparedFiles};
+
+// Frames produced per resampler run
+const CHUNK_FRAMES: usize = 128;
+// How far the drift correction may speed up or slow down an output
+const MAX_DRIFT: f64 = 0.005;
+// Relative speed change per second of buffer error
+const DRIFT_GAIN: f64 = 0.1;
+// How long a buffer error has to last to be taken as a difference between the clocks, which is
+// then corrected for on its own so the buffer settles on its target rather than beside it
+const DRIFT_LEARN_SECONDS: f64 = 20.0;
+// The buffer fill jumps by a whole device period on every callback, so it's averaged over a
+// few seconds before it steers the ratio, otherwise the jitter shows up as wow
+const DRIFT_SMOOTHING_SECONDS: f64 = 2.0;
+// Buffer errors beyond this are a stall, not drift, and are fixed by skipping ahead
+const RESYNC_SECONDS: f64 = 0.25;
+// Longest delay trim we keep room for
+pub const MAX_DELAY_MS: f32 = 1000.0;
+
+// Where the audio comes from
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "kind", content = "device", rename_all = "snake_case")]
+pub enum Source {
+    // A recording device
+    Input(String),
+    // Whatever is playing on an output device (WASAPI loopback, Windows only)
+    Loopback(String),
+    // A sink the app creates for other programs to play into (PulseAudio/PipeWire only)
+    VirtualSink,
+}
+
+impl Source {
+    pub fn label(&self) -> String {
+        match self {
+            Source::Input(name) => name.clone(),
+            Source::Loopback(name) => format!("{name} (what's playing)"),
+            Source::VirtualSink => format!("{} virtual device", crate::settings::APP_NAME),
+        }
+    }
+
+    // Every source this system can capture from
+    pub fn available(inputs: &[String], outputs: &[String]) -> Vec<Source> {
+        let mut sources: Vec<Source> = inputs.iter().cloned().map(Source::Input).collect();
+        if cfg!(target_os = "windows") {
+            sources.extend(outputs.iter().cloned().map(Source::Loopback));
+        }
+        if cfg!(target_os = "linux") {
+            sources.push(Source::VirtualSink);
+        }
+        sources
+    }
+}
+
+// Settings and counters for one output, shared between the UI and the audio callback
+pub struct OutputControl {
+    // f32 bits
+    gain: AtomicU32,
+    delay_ms: AtomicU32,
+    muted: AtomicBool,
+    pub underruns: AtomicU64,
+    pub overruns: AtomicU64,
+    // How much audio is waiting to be played, f32 bits in milliseconds
+    buffered_ms: AtomicU32,
+    // Current speed correction in parts per million
+    drift_ppm: AtomicI32,
+    // Delay added by processing, such as convolution, f32 bits in milliseconds
+    dsp_latency_ms: AtomicU32,
+    // Set once the device has gone away or the stream broke
+    pub failed: AtomicBool,
+    // When the source last delivered audio, in nanoseconds since `epoch`, and how many frames.
+    // The source and output usually both run in blocks of around 10 ms, so whether the latest
+    // block has arrived yet swings the buffer fill by a whole block, for minutes at a time as
+    // the two clocks slowly slide past each other. Counting what the source has captured since
+    // then but not yet delivered smooths that out.
+    epoch: Instant,
+    pushed_at: AtomicU64,
+    pushed_frames: AtomicU32,
+    // New processing settings and the files they use, picked up by the audio callback when it
+    // can take the lock without waiting
+    dsp: Mutex<Option<(ChainSettings, PreparedFiles)>>,
+    // The latest settings and files from the UI, and the files prepared from them once the
+    // output's format is known
+    loaded: Mutex<LoadedDsp>,
+}
+
+#[derive(Default)]
+struct LoadedDsp {
+    settings: Option<(ChainSettings, LoadedFiles)>,
+    format: Option<Format>,
+    prepared: PreparedFiles,
+}
+
+impl OutputControl {
+    pub fn new(gain: f32, delay_ms: f32, muted: bool) -> Arc<Self> {
+        Arc::new(Self {
+            gain: AtomicU32::new(gain.to_bits()),
+            delay_ms: AtomicU32::new(delay_ms.clamp(0.0, MAX_DELAY_MS).to_bits()),
+            muted: AtomicBool::new(muted),
+            underruns: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            buffered_ms: AtomicU32::new(0),
+            drift_ppm: AtomicI32::new(0),
+            dsp_latency_ms: AtomicU32::new(0),
+            failed: AtomicBool::new(false),
+            epoch: Instant::now(),
+            pushed_at: AtomicU64::new(0),
+            pushed_frames: AtomicU32::new(0),
+            dsp: Mutex::new(None),
+            loaded: Mutex::new(LoadedDsp::default()),
+        })
+    }
+
+    pub fn set_gain(&self, gain: f32) {
+        self.gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    pub fn set_dsp(&self, settings: ChainSettings, files: LoadedFiles) {
+        if let Ok(mut loaded) = self.loaded.lock() {
+            loaded.settings = Some((settings, files));
+            self.prepare_dsp(&mut loaded);
+        }
+    }
+
+    // Called once the output is open and its format known
+    fn set_format(&self, format: Format) {
+        if let Ok(mut loaded) = self.loaded.lock() {
+            loaded.format = Some(format);
+            self.prepare_dsp(&mut loaded);
+        }
+    }
+
+    // Resample and transform the files for the output here rather than in the audio callback,
+    // which only has to swap them in
+    fn prepare_dsp(&self, loaded: &mut LoadedDsp) {
+        let (Some((settings, files)), Some(format)) = (&loaded.settings, loaded.format) else {
+            return;
+        };
+        loaded.prepared.update(files, format);
+        if let Ok(mut dsp) = self.dsp.lock() {
+            *dsp = Some((settings.clone(), loaded.prepared.clone()));
+        }
+    }
+
+    pub fn set_delay_ms(&self, delay_ms: f32) {
+        self.delay_ms.store(delay_ms.clamp(0.0, MAX_DELAY_MS).to_bits(), Ordering::Relaxed);
+    }
+
+    fn gain(&self) -> f32 {
+        if self.muted.load(Ordering::Relaxed) {
+            0.0
+        } else {
+            f32::from_bits(self.gain.load(Ordering::Relaxed))
+        }
+    }
+
+    fn delay_ms(&self) -> f32 {
+        f32::from_bits(self.delay_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn buffered_ms(&self) -> f32 {
+        f32::from_bits(self.buffered_ms.load(Ordering::Relaxed))
+    }
+
+    pub fn drift_ppm(&self) -> i32 {
+        self.drift_ppm.load(Ordering::Relaxed)
+    }
+
+    pub fn dsp_latency_ms(&self) -> f32 {
+        f32::from_bits(self.dsp_latency_ms.load(Ordering::Relaxed))
+    }
+
+    fn mark_pushed(&self, now: Instant, frames: usize) {
+        self.pushed_at.store(now.saturating_duration_since(self.epoch).as_nanos() as u64, Ordering::Relaxed);
+        self.pushed_frames.store(frames as u32, Ordering::Relaxed);
+    }
+
+    // Frames the source has captured since its last delivery, at most one delivery's worth
+    fn pending_frames(&self, now: Instant, rate: f64) -> usize {
+        let pushed_at = self.pushed_at.load(Ordering::Relaxed);
+        let elapsed = now.saturating_duration_since(self.epoch).as_nanos() as u64;
+        let frames = (elapsed.saturating_sub(pushed_at) as f64 / 1e9 * rate) as usize;
+        frames.min(self.pushed_frames.load(Ordering::Relaxed) as usize)
+    }
+}
+
+pub struct OutputConfig {
+    pub device: String,
+    pub control: Arc<OutputControl>,
+    // Linux: the sound server's sink to play on, when `device` is ALSA's way into the server
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub sink: Option<String>,
+}
+
+pub struct PipelineConfig {
+    pub source: Source,
+    pub outputs: Vec<OutputConfig>,
+    // Audio kept buffered ahead of each output: lower is snappier, higher survives hiccups
+    pub latency_ms: f32,
+    // The device to create when the source is Source::VirtualSink
+    pub virtual_sink: SinkName,
+}
+
+// A virtual device's name in the sound server and the name people see. Each feature that
+// plays through one gets its own so they can run side by side.
+#[derive(Clone, Copy)]
+pub struct SinkName {
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub name: &'static str,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    pub description: &'static str,
+    // Stereo, or 8 for programs to play 7.1 into
+    pub channels: usize,
+}
+
+impl Default for SinkName {
+    fn default() -> Self {
+        Self { name: "audio_controller", description: crate::settings::APP_NAME, channels: 2 }
+    }
+}
+
+// Runs until dropped
+pub struct Pipeline {
+    _capture: Capture,
+    _outputs: Vec<cpal::Stream>,
+    pub sample_rate: u32,
+    pub channels: usize,
+}
+
+impl Pipeline {
+    pub fn start(config: PipelineConfig) -> Result<Self, String> {
+        if config.outputs.is_empty() {
+            return Err("No output devices selected".to_string());
+        }
+
+        let (sample_rate, channels) = capture_format(&config.source, config.virtual_sink)?;
+        let latency = (config.latency_ms.max(1.0) as f64 / 1000.0 * sample_rate as f64) as usize;
+        // Room for the longest delay on top of the latency, with a second to spare
+        let capacity = (sample_rate as f64 * (MAX_DELAY_MS as f64 / 1000.0 + 1.0)) as usize * channels + latency * channels;
+
+        let mut producers = Vec::new();
+        let mut streams = Vec::new();
+        for output in config.outputs {
+            let (producer, consumer) = HeapRb::<f32>::new(capacity).split();
+            let stream = start_output(&output, consumer, sample_rate, channels, latency)
+                .map_err(|err| format!("{}: {err}", output.device))?;
+            producers.push((producer, output.control));
+            streams.push(stream);
+        }
+
+        let capture = start_capture(&config.source, config.virtual_sink, sample_rate, channels, move |data| {
+            fan_out(&mut producers, data, channels, Instant::now())
+        })?;
+        Ok(Self { _capture: capture, _outputs: streams, sample_rate, channels })
+    }
+}
+
+// Copy captured audio to every output's buffer, whole frames only so channels stay aligned
+fn fan_out(producers: &mut [(HeapProd<f32>, Arc<OutputControl>)], data: &[f32], channels: usize, now: Instant) {
+    for (producer, control) in producers.iter_mut() {
+        let room = producer.vacant_len() / channels * channels;
+        let count = data.len().min(room);
+        producer.push_slice(&data[..count]);
+        control.mark_pushed(now, data.len() / channels);
+        if count < data.len() {
+            control.overruns.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+pub fn find_device(devices: Result<impl Iterator<Item = cpal::Device>, cpal::DevicesError>, name: &str) -> Option<cpal::Device> {
+    devices.ok()?.find(|device| device.name().ok().as_deref() == Some(name))
+}
+
+// ALSA devices that play on whatever the sound server's default sink is
+#[cfg(target_os = "linux")]
+pub fn follows_default_sink(name: &str) -> bool {
+    matches!(name, "default" | "pulse" | "pipewire")
+}
+
+// The sink-inputs this process has open
+#[cfg(target_os = "linux")]
+fn own_streams() -> Vec<u32> {
+    let pid = std::process::id();
+    crate::sessions::pulse::list_sink_inputs()
+        .into_iter()
+        .filter(|session| session.pid == pid)
+        .flat_map(|session| session.streams)
+        .collect()
+}
+
+pub fn capture_format(source: &Source, sink: SinkName) -> Result<(u32, usize), String> {
+    let host = cpal::default_host();
+    let config = match source {
+        Source::Input(name) => find_device(host.input_devices(), name)
+            .ok_or_else(|| format!("No input device named '{name}'"))?
+            .default_input_config(),
+        Source::Loopback(name) => find_device(host.output_devices(), name)
+            .ok_or_else(|| format!("No output device named '{name}'"))?
+            .default_output_config(),
+        Source::VirtualSink => return Ok((virtual_sink::SAMPLE_RATE, sink.channels)),
+    };
+    let config = config.map_err(|err| err.to_string())?;
+    Ok((config.sample_rate().0, config.channels() as usize))
+}
+
+// Held only to keep the capture running
+pub enum Capture {
+    Stream { _stream: cpal::Stream },
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    VirtualSink { _sink: virtual_sink::VirtualSink },
+}
+
+pub fn start_capture(
+    source: &Source,
+    sink: SinkName,
+    sample_rate: u32,
+    channels: usize,
+    on_data: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<Capture, String> {
+    let host = cpal::default_host();
+    let device = match source {
+        Source::Input(name) => find_device(host.input_devices(), name),
+        // cpal records what an output is playing when asked for an input stream on it
+        Source::Loopback(name) if cfg!(target_os = "windows") => find_device(host.output_devices(), name),
+        Source::Loopback(_) => return Err("Capturing an output is only possible on Windows".to_string()),
+        Source::VirtualSink => {
+            return virtual_sink::VirtualSink::start(sink, on_data).map(|sink| Capture::VirtualSink { _sink: sink })
+        }
+    };
+    let device = device.ok_or_else(|| format!("{} isn't connected", source.label()))?;
+    let format = match source {
+        Source::Loopback(_) => device.default_output_config(),
+        _ => device.default_input_config(),
+    }
+    .map_err(|err| err.to_string())?;
+
+    let config = cpal::StreamConfig {
+        channels: channels as u16,
+        sample_rate: cpal::SampleRate(sample_rate),
+        buffer_size: cpal::BufferSize::Default,
+    };
+    let stream = match format.sample_format() {
+        SampleFormat::F32 => input_stream::<f32>(&device, &config, on_data),
+        SampleFormat::I16 => input_stream::<i16>(&device, &config, on_data),
+        SampleFormat::U16 => input_stream::<u16>(&device, &config, on_data),
+        SampleFormat::I32 => input_stream::<i32>(&device, &config, on_data),
+        other => return Err(format!("Unsupported sample format {other}")),
+    }
+    .map_err(|err| err.to_string())?;
+    stream.play().map_err(|err| err.to_string())?;
+    Ok(Capture::Stream { _stream: stream })
+}
+
+fn input_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut on_data: impl FnMut(&[f32]) + Send + 'static,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut buffer = Vec::new();
+    device.build_input_stream(
+        config,
+        move |data: &[T], _| {
+            buffer.clear();
+            buffer.extend(data.iter().map(|sample| f32::from_sample(*sample)));
+            on_data(&buffer);
+        },
+        |err| eprintln!("ERROR: Capture stream failed: {err}"),
+        None,
+    )
+}
+
+fn start_output(output: &OutputConfig, consumer: HeapCons<f32>, in_rate: u32, in_channels: usize, latency: usize) -> Result<cpal::Stream, String> {
+    #[cfg(target_os = "linux")]
+    let before = output.sink.as_ref().map(|_| own_streams());
+    let stream = open_output(output, consumer, in_rate, in_channels, latency)?;
+
+    // The sound server's ALSA plugins play on its default sink, so the stream is moved to the
+    // one asked for before it starts. It's whichever of our streams is new.
+    #[cfg(target_os = "linux")]
+    if let (Some(sink), Some(before)) = (&output.sink, before) {
+        let new: Vec<u32> = own_streams().into_iter().filter(|stream| !before.contains(stream)).collect();
+        if new.is_empty() {
+            return Err(format!("couldn't find the stream to move to {sink}"));
+        }
+        crate::sessions::pulse::move_streams(&new, Some(sink))?;
+    }
+
+    stream.play().map_err(|err| err.to_string())?;
+    Ok(stream)
+}
+
+// Opened but not started yet
+fn open_output(output: &OutputConfig, consumer: HeapCons<f32>, in_rate: u32, in_channels: usize, latency: usize) -> Result<cpal::Stream, String> {
+    let control = output.control.clone();
+    let device = find_device(cpal::default_host().output_devices(), &output.device).ok_or("not connected")?;
+    let format = device.default_output_config().map_err(|err| err.to_string())?;
+    let config = format.config();
+
+    let renderer = Renderer::new(consumer, control.clone(), in_rate, in_channels, config.sample_rate.0, config.channels as usize, latency)?;
+    control.set_format(Format { sample_rate: config.sample_rate.0, source_channels: in_channels, channels: config.channels as usize });
+    match format.sample_format() {
+        SampleFormat::F32 => output_stream::<f32>(&device, &config, renderer, control),
+        SampleFormat::I16 => output_stream::<i16>(&device, &config, renderer, control),
+        SampleFormat::U16 => output_stream::<u16>(&device, &config, renderer, control),
+        SampleFormat::I32 => output_stream::<i32>(&device, &config, renderer, control),
+        other => return Err(format!("unsupported sample format {other}")),
+    }
+    .map_err(|err| err.to_string())
+}
+
+fn output_stream<T>(
+    device: &cpal::Device,
+    config: &cpal::StreamConfig,
+    mut renderer: Renderer,
+    control: Arc<OutputControl>,
+) -> Result<cpal::Stream, cpal::BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut buffer = Vec::new();
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _| {
+            buffer.resize(data.len(), 0.0);
+            renderer.render(&mut buffer, Instant::now());
+            for (out, sample) in data.iter_mut().zip(&buffer) {
+                *out = T::from_sample(*sample);
+            }
+        },
+        move |err| {
+            eprintln!("ERROR: Output stream failed: {err}");
+            control.failed.store(true, Ordering::Relaxed);
+        },
+        None,
+    )
+}
+
+// The playing end of one output: pulls from its buffer, resamples to the device's rate with
+// drift correction, applies delay and gain and maps or virtualizes channels
+struct Renderer {
+    consumer: HeapCons<f32>,
+    control: Arc<OutputControl>,
+    resampler: SincFixedOut<f32>,
+    in_rate: f64,
+    out_rate: f64,
+    in_channels: usize,
+    out_channels: usize,
+    // Deinterleaved resampler input and output
+    input: Vec<Vec<f32>>,
+    output: Vec<Vec<f32>>,
+    scratch: Vec<f32>,
+    // Resampled frames ready to play, and how many of them have been played
+    ready: usize,
+    played: usize,
+    // Buffer fill we aim for, in input frames, not counting the delay
+    latency: usize,
+    // Delay currently in effect, in input frames
+    delay: usize,
+    // Output frames of silence still to play, to open up a longer delay
+    silence: usize,
+    // Waiting for the buffer to fill before playing, at the start and after an underrun
+    priming: bool,
+    // Smoothed buffer error in seconds
+    error: f64,
+    // Speed correction for the difference between the clocks, learned from the error
+    drift: f64,
+    // Runs on the output's rate and channels, after mapping
+    chain: Chain,
+    bypass: bool,
+    // Takes the place of mapping when surround is played on headphones, fed from `surround`
+    virtualizer: Virtualizer,
+    surround: Vec<f32>,
+}
+
+impl Renderer {
+    fn new(
+        consumer: HeapCons<f32>,
+        control: Arc<OutputControl>,
+        in_rate: u32,
+        in_channels: usize,
+        out_rate: u32,
+        out_channels: usize,
+        latency: usize,
+    ) -> Result<Self, String> {
+        let parameters = SincInterpolationParameters {
+            sinc_len: 64,
+            f_cutoff: rubato::calculate_cutoff(64, WindowFunction::BlackmanHarris2),
+            oversampling_factor: 128,
+            interpolation: SincInterpolationType::Cubic,
+            window: WindowFunction::BlackmanHarris2,
+        };
+        let resampler = SincFixedOut::<f32>::new(
+            out_rate as f64 / in_rate as f64,
+            1.0 + MAX_DRIFT * 2.0,
+            parameters,
+            CHUNK_FRAMES,
+            in_channels,
+        )
+        .map_err(|err| err.to_string())?;
+        let input = resampler.input_buffer_allocate(true);
+        let output = resampler.output_buffer_allocate(true);
+
+        Ok(Self {
+            consumer,
+            control,
+            resampler,
+            in_rate: in_rate as f64,
+            out_rate: out_rate as f64,
+            in_channels,
+            out_channels,
+            scratch: Vec::with_capacity(input[0].len() * in_channels),
+            input,
+            output,
+            ready: 0,
+            played: 0,
+            latency,
+            delay: 0,
+            silence: 0,
+            priming: true,
+            error: 0.0,
+            drift: 0.0,
+            chain: Chain::new(out_rate, out_channels),
+            bypass: true,
+            virtualizer: Virtualizer::new(in_channels, out_channels),
+            surround: Vec::new(),
+        })
+    }
+
+    fn render(&mut self, out: &mut [f32], now: Instant) {
+        if let Some((settings, files)) = self.control.dsp.try_lock().ok().and_then(|mut dsp| dsp.take()) {
+            self.chain.configure(&settings);
+            self.chain.set_impulse(files.impulse);
+            self.bypass = settings.is_bypass();
+            self.virtualizer.configure(&settings.virtualizer);
+            self.virtualizer.set_filters(files.hrtf);
+            let latency = if self.bypass { 0 } else { self.chain.latency() };
+            let latency_ms = latency as f64 / self.out_rate * 1000.0;
+            self.control.dsp_latency_ms.store((latency_ms as f32).to_bits(), Ordering::Relaxed);
+        }
+        self.apply_delay();
+        let gain = self.control.gain();
+        let target = self.latency + self.delay;
+        let pending = self.control.pending_frames(now, self.in_rate);
+        let virtualize = self.virtualizer.is_active();
+        self.surround.clear();
+
+        for frame in out.chunks_mut(self.out_channels) {
+            let playing = if self.silence > 0 {
+                self.silence -= 1;
+                false
+            } else {
+                self.played < self.ready || self.next_chunk(target, pending)
+            };
+            if !playing {
+                frame.fill(0.0);
+                if virtualize {
+                    self.surround.extend(std::iter::repeat_n(0.0, self.in_channels));
+                }
+                continue;
+            }
+
+            let position = self.played;
+            if virtualize {
+                self.surround.extend(self.output.iter().map(|channel| channel[position] * gain));
+            } else if self.out_channels == 1 {
+                let sum: f32 = self.output.iter().map(|channel| channel[position]).sum();
+                frame[0] = sum / self.in_channels as f32 * gain;
+            } else {
+                for (idx, sample) in frame.iter_mut().enumerate() {
+                    *sample = self.output[idx % self.in_channels][position] * gain;
+                }
+            }
+            self.played += 1;
+        }
+        if virtualize {
+            self.virtualizer.process(&self.surround, out);
+        }
+        if !self.bypass {
+            self.chain.process(out);
+        }
+
+        let buffered = self.consumer.occupied_len() / self.in_channels + pending;
+        let buffered_ms = buffered as f64 / self.in_rate * 1000.0;
+        self.control.buffered_ms.store((buffered_ms as f32).to_bits(), Ordering::Relaxed);
+    }
+
+    // Follow changes to the delay trim straight away: play silence to open up more delay, or
+    // skip buffered audio to close it
+    fn apply_delay(&mut self) {
+        let wanted = (self.control.delay_ms() as f64 / 1000.0 * self.in_rate) as usize;
+        if wanted > self.delay {
+            self.silence += ((wanted - self.delay) as f64 * self.out_rate / self.in_rate) as usize;
+        } else if wanted < self.delay {
+            let skip = (self.delay - wanted) * self.in_channels;
+            let available = self.consumer.occupied_len() / self.in_channels * self.in_channels;
+            self.consumer.skip(skip.min(available));
+        }
+        self.delay = wanted;
+    }
+
+    // Resample the next chunk. Returns false if there isn't enough audio buffered.
+    fn next_chunk(&mut self, target: usize, pending: usize) -> bool {
+        let available = self.consumer.occupied_len() / self.in_channels;
+        if self.priming {
+            if available < target {
+                return false;
+            }
+            self.priming = false;
+            self.error = 0.0;
+        }
+
+        let needed = self.resampler.input_frames_next();
+        if available < needed {
+            self.priming = true;
+            self.control.underruns.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+
+        // Too far ahead to catch up smoothly, e.g. after the output stalled: skip to the target
+        let error = ((available + pending) as f64 - target as f64) / self.in_rate;
+        if error > RESYNC_SECONDS {
+            let skip = available.saturating_sub(target) * self.in_channels;
+            self.consumer.skip(skip);
+            self.control.overruns.fetch_add(1, Ordering::Relaxed);
+            self.error = 0.0;
+        } else {
+            // Too much buffered means the source runs fast compared to this output, so play
+            // slightly faster by producing fewer output frames per input frame
+            let seconds = CHUNK_FRAMES as f64 / self.out_rate;
+            self.error += (error - self.error) * seconds / DRIFT_SMOOTHING_SECONDS;
+            self.drift = (self.drift + self.error * DRIFT_GAIN * seconds / DRIFT_LEARN_SECONDS).clamp(-MAX_DRIFT, MAX_DRIFT);
+            let correction = (self.drift + self.error * DRIFT_GAIN).clamp(-MAX_DRIFT, MAX_DRIFT);
+            let _ = self.resampler.set_resample_ratio_relative(1.0 - correction, true);
+            self.control.drift_ppm.store((-correction * 1e6) as i32, Ordering::Relaxed);
+        }
+
+        self.scratch.resize(needed * self.in_channels, 0.0);
+        let popped = self.consumer.pop_slice(&mut self.scratch);
+        for (idx, frame) in self.scratch[..popped].chunks(self.in_channels).enumerate() {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.input[channel][idx] = *sample;
+            }
+        }
+
+        match self.resampler.process_into_buffer(&self.input, &mut self.output, None) {
+            Ok((_, frames)) => {
+                self.ready = frames;
+                self.played = 0;
+                frames > 0
+            }
+            Err(err) => {
+                eprintln!("ERROR: Resampling failed: {err}");
+                false
+            }
+        }
+    }
+}
+
+// A null sink other programs can play into, recorded from its monitor with parec
+mod virtual_sink {
+    pub const SAMPLE_RATE: u32 = 48000;
+
+    // Without a map PulseAudio orders more than two channels its own way; this is WAVE's order,
+    // which the virtualizer expects
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    fn channel_map(channels: usize) -> Option<&'static str> {
+        match channels {
+            6 => Some("front-left,front-right,front-center,lfe,rear-left,rear-right"),
+            8 => Some("front-left,front-right,front-center,lfe,rear-left,rear-right,side-left,side-right"),
+            _ => None,
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    pub use linux::VirtualSink;
+
+    #[cfg(not(target_os = "linux"))]
+    pub struct VirtualSink;
+
+    #[cfg(not(target_os = "linux"))]
+    impl VirtualSink {
+        pub fn start(_sink: super::SinkName, _on_data: impl FnMut(&[f32]) + Send + 'static) -> Result<Self, String> {
+            Err("The virtual device needs PulseAudio or PipeWire".to_string())
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    mod linux {
+        use std::io::Read;
+        use std::process::{Child, Command, Stdio};
+
+        use super::super::SinkName;
+        use super::{channel_map, SAMPLE_RATE};
+
+        pub struct VirtualSink {
+            recorder: Child,
+            // Only set if we loaded the module, so we don't unload someone else's
+            module: Option<String>,
+        }
+
+        fn pactl(args: &[&str]) -> Result<String, String> {
+            let output = Command::new("pactl")
+                .args(args)
+                .env("LC_ALL", "C")
+                .output()
+                .map_err(|err| format!("Couldn't run pactl: {err}"))?;
+            if !output.status.success() {
+                return Err(String::from_utf8_lossy(&output.stderr).trim().to_string());
+            }
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        }
+
+        impl VirtualSink {
+            pub fn start(sink: SinkName, mut on_data: impl FnMut(&[f32]) + Send + 'static) -> Result<Self, String> {
+                let exists = pactl(&["list", "short", "sinks"])?
+                    .lines()
+                    .any(|line| line.split('\t').nth(1) == Some(sink.name));
+                let module = if exists {
+                    None
+                } else {
+                    let description = sink.description.replace(' ', "\\ ");
+                    let mut args = vec![
+                        "load-module".to_string(),
+                        "module-null-sink".to_string(),
+                        format!("sink_name={}", sink.name),
+                        format!("rate={SAMPLE_RATE}"),
+                        format!("channels={}", sink.channels),
+                        format!("sink_properties=device.description={description}"),
+                    ];
+                    args.extend(channel_map(sink.channels).map(|map| format!("channel_map={map}")));
+                    Some(pactl(&args.iter().map(String::as_str).collect::<Vec<_>>())?)
+                };
+
+                let channels = sink.channels;
+                let mut args = vec![
+                    format!("--device={}.monitor", sink.name),
+                    "--format=float32le".to_string(),
+                    format!("--rate={SAMPLE_RATE}"),
+                    format!("--channels={channels}"),
+                    "--latency-msec=10".to_string(),
+                    "--raw".to_string(),
+                ];
+                args.extend(channel_map(channels).map(|map| format!("--channel-map={map}")));
+                let recorder = Command::new("parec")
+                    .args(&args)
+                    .stdout(Stdio::piped())
+                    .stderr(Stdio::null())
+                    .spawn();
+                let mut recorder = match recorder {
+                    Ok(recorder) => recorder,
+                    Err(err) => {
+                        if let Some(module) = &module {
+                            let _ = pactl(&["unload-module", module]);
+                        }
+                        return Err(format!("Couldn't run parec: {err}"));
+                    }
+                };
+
+                let mut stdout = recorder.stdout.take().ok_or("parec has no output")?;
+                std::thread::spawn(move || {
+                    let mut bytes = vec![0u8; 4096];
+                    let mut leftover = Vec::new();
+                    let mut samples = Vec::new();
+                    // Ends when parec exits
+                    while let Ok(count) = stdout.read(&mut bytes) {
+                        if count == 0 {
+                            break;
+                        }
+                        leftover.extend_from_slice(&bytes[..count]);
+                        let whole = leftover.len() / (4 * channels) * (4 * channels);
+                        samples.clear();
+                        samples.extend(
+                            leftover[..whole].chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+                        );
+                        leftover.drain(..whole);
+                        on_data(&samples);
+                    }
+                });
+
+                Ok(Self { recorder, module })
+            }
+        }
+
+        impl Drop for VirtualSink {
+            fn drop(&mut self) {
+                let _ = self.recorder.kill();
+                let _ = self.recorder.wait();
+                if let Some(module) = &self.module {
+                    if let Err(err) = pactl(&["unload-module", module]) {
+                        eprintln!("ERROR: Couldn't remove the virtual device: {err}");
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const RATE: u32 = 48000;
+    // Both ends run in 10 ms blocks
+    const BLOCK: usize = 480;
+    const LATENCY: usize = 2880;
+
+    // Play `seconds` of `input` through a renderer, from a source running `speed` times as fast
+    // as the output. Returns the output, and the buffer fill in ms after each output block.
+    fn play(input: impl Fn(usize) -> f32, speed: f64, delay_ms: f32, seconds: f64) -> (Arc<OutputControl>, Vec<f32>, Vec<f32>) {
+        let control = OutputControl::new(1.0, delay_ms, false);
+        let (producer, consumer) = HeapRb::<f32>::new(RATE as usize * 3).split();
+        let mut producers = [(producer, control.clone())];
+        let mut renderer = Renderer::new(consumer, control.clone(), RATE, 1, RATE, 1, LATENCY).unwrap();
+        let block_seconds = BLOCK as f64 / RATE as f64;
+
+        let mut output = Vec::new();
+        let mut buffered = Vec::new();
+        let mut pushed = 0;
+        let mut block = vec![0.0; BLOCK];
+        for played in 0..(seconds / block_seconds) as usize {
+            // The output's callbacks fall halfway between the source's
+            let now = (played as f64 + 0.5) * block_seconds;
+            while pushed as f64 * block_seconds / speed <= now {
+                let data: Vec<f32> = (pushed * BLOCK..(pushed + 1) * BLOCK).map(&input).collect();
+                fan_out(&mut producers, &data, 1, control.epoch + Duration::from_secs_f64(pushed as f64 * block_seconds / speed));
+                pushed += 1;
+            }
+            renderer.render(&mut block, control.epoch + Duration::from_secs_f64(now));
+            output.extend_from_slice(&block);
+            buffered.push(control.buffered_ms());
+        }
+        (control, output, buffered)
+    }
+
+    #[test]
+    fn follows_drift_without_resyncing() {
+        let target_ms = LATENCY as f32 / RATE as f32 * 1000.0;
+        for speed in [1.003, 0.997] {
+            let (control, _, buffered) = play(|_| 0.0, speed, 0.0, 80.0);
+            let last = &buffered[buffered.len() - 1000..];
+            // Measured after each block has been played, so somewhat under the target
+            assert!(last.iter().all(|ms| (ms - target_ms).abs() < 10.0), "{speed}: {last:?}");
+            assert_eq!(control.underruns.load(Ordering::Relaxed), 0, "{speed}");
+            assert_eq!(control.overruns.load(Ordering::Relaxed), 0, "{speed}");
+            let ppm = (speed - 1.0) * 1e6;
+            assert!((control.drift_ppm() as f64 + ppm).abs() < 100.0, "{speed}: {} ppm", control.drift_ppm());
+        }
+    }
+
+    #[test]
+    fn delay_shows_up_as_an_offset() {
+        let click = |frame| if frame == RATE as usize { 1.0 } else { 0.0 };
+        let peak = |output: &[f32]| (0..output.len()).max_by(|a, b| output[*a].total_cmp(&output[*b])).unwrap();
+        let (_, plain, _) = play(click, 1.0, 0.0, 2.0);
+        let (_, delayed, _) = play(click, 1.0, 20.0, 2.0);
+        assert!(plain[peak(&plain)] > 0.5);
+        assert_eq!(peak(&delayed) - peak(&plain), RATE as usize / 50);
+    }
+}
@@ -0,0 +1,252 @@
+use eframe::egui;
+use egui::RichText;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoints::Flow;
+use crate::AudioApp;
+
+// Ordered lists of preferred devices to fall back to when the default goes away
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct PriorityPrefs {
+    pub enabled: bool,
+    // Most preferred first
+    pub outputs: Vec<String>,
+    pub inputs: Vec<String>,
+}
+
+impl PriorityPrefs {
+    pub fn list(&self, flow: Flow) -> &Vec<String> {
+        match flow {
+            Flow::Output => &self.outputs,
+            Flow::Input => &self.inputs,
+        }
+    }
+
+    pub fn list_mut(&mut self, flow: Flow) -> &mut Vec<String> {
+        match flow {
+            Flow::Output => &mut self.outputs,
+            Flow::Input => &mut self.inputs,
+        }
+    }
+}
+
+// Why the priority list picked a different device
+#[derive(Clone, Debug, PartialEq)]
+pub enum Fallback {
+    // The current default disappeared
+    Vanished { lost: String, to: String },
+    // A device we prefer over the current one came back
+    Returned { to: String },
+}
+
+// Decide whether to switch devices after the device list changed from `previous` to `present`.
+// When the current default was there before and is gone now we move to the most preferred
+// device still present. While it's still there we only move if a device ranked above it has
+// just reappeared, so picking a device by hand isn't undone by unrelated plugging and
+// unplugging. Without a known default, as at startup or when the system couldn't tell us,
+// nothing has vanished and we stay put.
+pub fn choose_fallback(
+    priority: &[String],
+    previous: &[String],
+    present: &[String],
+    current: Option<&str>,
+) -> Option<Fallback> {
+    let rank = |name: &str| priority.iter().position(|preferred| preferred == name);
+    let best = priority.iter().find(|preferred| present.contains(preferred))?;
+    let current = current?;
+
+    let was_present = previous.iter().any(|device| device == current);
+    let is_present = present.iter().any(|device| device == current);
+    if was_present && !is_present {
+        return Some(Fallback::Vanished { lost: current.to_string(), to: best.clone() });
+    }
+    if !is_present || best == current {
+        return None;
+    }
+
+    let just_returned = !previous.contains(best);
+    let outranks_current = rank(current).is_none_or(|current_rank| rank(best) < Some(current_rank));
+    if just_returned && outranks_current {
+        return Some(Fallback::Returned { to: best.clone() });
+    }
+    None
+}
+
+impl AudioApp {
+    // Switch devices if the priority list says so, and tell the user about it.
+    // `previous_default` is the default from before the change: by the time we hear about a
+    // device vanishing the system has usually already picked its own replacement.
+    pub fn apply_priority(&mut self, flow: Flow, previous: &[String], previous_default: Option<String>) {
+        if !self.settings.priority.enabled {
+            return;
+        }
+
+        let present = match flow {
+            Flow::Output => self.device_names.clone(),
+            Flow::Input => self.input_device_names.clone(),
+        };
+
+        let Some(fallback) =
+            choose_fallback(self.settings.priority.list(flow), previous, &present, previous_default.as_deref())
+        else {
+            return;
+        };
+
+        let kind = match flow {
+            Flow::Output => "output",
+            Flow::Input => "input",
+        };
+        let (to, message) = match fallback {
+            Fallback::Vanished { lost, to } => {
+                let message = format!(
+                    "{} disconnected, switched {} to {}",
+                    self.settings.devices.display_name(&lost),
+                    kind,
+                    self.settings.devices.display_name(&to)
+                );
+                (to, message)
+            }
+            Fallback::Returned { to } => {
+                let message = format!("{} is back, switched {} to it", self.settings.devices.display_name(&to), kind);
+                (to, message)
+            }
+        };
+
+        match flow {
+            Flow::Output => {
+                if let Some(idx) = self.device_names.iter().position(|name| name == &to) {
+                    self.set_default_device(idx);
+                }
+            }
+            Flow::Input => self.set_default_input_device_by_name(&to),
+        }
+        self.notifications.push(message);
+    }
+
+    pub fn priority_ui(&mut self, ui: &mut egui::Ui) {
+        let mut changed = ui
+            .checkbox(&mut self.settings.priority.enabled, "Switch to the highest-priority device when the default goes away")
+            .changed();
+
+        for flow in [Flow::Output, Flow::Input] {
+            ui.add_space(6.0);
+            ui.label(RichText::new(if flow == Flow::Output { "Outputs" } else { "Inputs" }).strong());
+
+            let known: Vec<String> = match flow {
+                Flow::Output => self.output_endpoints.iter().map(|endpoint| endpoint.name.clone()).collect(),
+                Flow::Input => self.input_device_names.clone(),
+            };
+
+            let list = self.settings.priority.list_mut(flow);
+            let mut remove = None;
+            let mut swap = None;
+
+            for (idx, name) in list.iter().enumerate() {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{}.", idx + 1));
+                    let label = self.settings.devices.display_name(name);
+                    if known.contains(name) {
+                        ui.label(label);
+                    } else {
+                        ui.label(RichText::new(label).weak()).on_hover_text("Not connected");
+                    }
+
+                    ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                        if ui.small_button("🗑").clicked() {
+                            remove = Some(idx);
+                        }
+                        if ui.add_enabled(idx + 1 < list.len(), egui::Button::new("⬇").small()).clicked() {
+                            swap = Some((idx, idx + 1));
+                        }
+                        if ui.add_enabled(idx > 0, egui::Button::new("⬆").small()).clicked() {
+                            swap = Some((idx - 1, idx));
+                        }
+                    });
+                });
+            }
+
+            // Add any known device that isn't in the list yet
+            let mut add = None;
+            egui::ComboBox::from_id_source(("priority_add", flow == Flow::Output))
+                .selected_text("Add device…")
+                .width(ui.available_width())
+                .show_ui(ui, |ui| {
+                    for name in known.iter().filter(|name| !list.contains(name)) {
+                        if ui.selectable_label(false, self.settings.devices.display_name(name)).clicked() {
+                            add = Some(name.clone());
+                        }
+                    }
+                });
+
+            if let Some(idx) = remove {
+                list.remove(idx);
+                changed = true;
+            }
+            if let Some((a, b)) = swap {
+                list.swap(a, b);
+                changed = true;
+            }
+            if let Some(name) = add {
+                list.push(name);
+                changed = true;
+            }
+        }
+
+        if changed {
+            self.settings.save();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn chooses_fallbacks() {
+        let priority = names(&["Headset", "Headphones", "Speakers"]);
+        let vanished = |lost: &str, to: &str| Some(Fallback::Vanished { lost: lost.to_string(), to: to.to_string() });
+        let returned = |to: &str| Some(Fallback::Returned { to: to.to_string() });
+
+        // (previous, present, current default, expected)
+        let cases = [
+            // The default went away: the best one left takes over
+            (&["Headset", "Speakers"][..], &["Speakers"][..], Some("Headset"), vanished("Headset", "Speakers")),
+            (&["Headset", "Headphones", "Speakers"], &["Headphones", "Speakers"], Some("Headset"), vanished("Headset", "Headphones")),
+            // Devices that aren't in the list can vanish too
+            (&["Monitor", "Speakers"], &["Speakers"], Some("Monitor"), vanished("Monitor", "Speakers")),
+            // A preferred device came back
+            (&["Speakers"], &["Headset", "Speakers"], Some("Speakers"), returned("Headset")),
+            (&["Monitor"], &["Monitor", "Speakers"], Some("Monitor"), returned("Speakers")),
+            // Already on the best device
+            (&["Headset", "Speakers"], &["Headset", "Speakers"], Some("Headset"), None),
+            // Picked by hand: unrelated changes, or a device ranked below it returning, leave it
+            (&["Headset", "Speakers"], &["Headset", "Speakers"], Some("Speakers"), None),
+            (&["Headset", "Headphones"], &["Headset", "Headphones", "Speakers"], Some("Headphones"), None),
+            (&["Headset", "Speakers"], &["Headset", "Speakers", "Monitor"], Some("Speakers"), None),
+            // Nothing from the list is present
+            (&["Monitor", "TV"], &["TV"], Some("Monitor"), None),
+            // No known default
+            (&["Headset"], &["Speakers"], None, None),
+            // The default wasn't there before either
+            (&["Speakers"], &["Speakers"], Some("Headset"), None),
+        ];
+        for (previous, present, current, expected) in cases {
+            assert_eq!(
+                choose_fallback(&priority, &names(previous), &names(present), current),
+                expected,
+                "{previous:?} -> {present:?} on {current:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn empty_list_never_switches() {
+        assert_eq!(choose_fallback(&[], &names(&["Headset", "Speakers"]), &names(&["Speakers"]), Some("Headset")), None);
+    }
+}
@@ -0,0 +1,239 @@
+// Per-device DSP profiles. Each output device can have its own EQ, room correction, balance,
+// crossfeed, limiter and virtual surround settings, stored under the device's stable ID so
+// renaming it in Windows doesn't lose them. Whenever the default output changes, from the
+// picker, a hotkey, a scene or a device being plugged in, the new device's profile is loaded
+// and its audio is played through the processing path. Devices without a profile are left alone
+// and play unprocessed.
+
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::dsp::convolver::ConvolutionSettings;
+use crate::dsp::crossfeed::CrossfeedSettings;
+use crate::dsp::eq::Equalizer;
+use crate::dsp::limiter::LimiterSettings;
+use crate::dsp::virtualizer::VirtualizerSettings;
+use crate::dsp::ChainSettings;
+use crate::{AudioApp, Tab};
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct DeviceProfile {
+    // The device's name when the profile was last used, to show profiles of absent devices
+    pub name: String,
+    pub swap_channels: bool,
+    pub mono: bool,
+    pub eq: Equalizer,
+    pub balance: f32,
+    pub crossfeed: CrossfeedSettings,
+    pub limiter: LimiterSettings,
+    pub virtualizer: VirtualizerSettings,
+    pub convolution: ConvolutionSettings,
+    // Saved EQ curves
+    pub presets: BTreeMap<String, Equalizer>,
+}
+
+impl DeviceProfile {
+    pub fn chain(&self) -> ChainSettings {
+        ChainSettings {
+            swap_channels: self.swap_channels,
+            mono: self.mono,
+            eq: self.eq.clone(),
+            balance: self.balance,
+            crossfeed: self.crossfeed.clone(),
+            limiter: self.limiter.clone(),
+            virtualizer: self.virtualizer.clone(),
+            convolution: self.convolution.clone(),
+        }
+    }
+}
+
+impl AudioApp {
+    // Stable ID of an output device. On Linux ALSA's "default" and "pulse" stand for the sound
+    // server's default sink, whose name is the ID. Without one the device's name stands in.
+    pub fn device_id(&self, name: &str) -> String {
+        #[cfg(target_os = "linux")]
+        if crate::pipeline::follows_default_sink(name) {
+            if let Some(sink) = self.real_default_sink() {
+                return sink;
+            }
+        }
+        self.output_endpoints
+            .iter()
+            .find(|endpoint| endpoint.name == name)
+            .map(|endpoint| endpoint.id.clone())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    pub fn device_profile(&self, name: &str) -> Option<&DeviceProfile> {
+        self.settings.eq.profiles.get(&self.device_id(name))
+    }
+
+    pub fn device_profile_mut(&mut self, name: &str) -> Option<&mut DeviceProfile> {
+        let id = self.device_id(name);
+        self.settings.eq.profiles.get_mut(&id)
+    }
+
+    pub fn create_device_profile(&mut self, name: &str) {
+        let id = self.device_id(name);
+        self.settings.eq.profiles.entry(id).or_default().name = name.to_string();
+        self.settings.save();
+        self.reapply_device_profile(name);
+    }
+
+    pub fn remove_device_profile(&mut self, name: &str) {
+        let id = self.device_id(name);
+        self.settings.eq.profiles.remove(&id);
+        self.settings.save();
+        self.reapply_device_profile(name);
+    }
+
+    // Whether `name` is the virtual device audio is played into to be processed, rather than
+    // a real output
+    pub fn is_processing_device(&self, name: &str) -> bool {
+        #[cfg(target_os = "windows")]
+        return self.settings.eq.capture.as_deref() == Some(name);
+
+        #[cfg(target_os = "linux")]
+        if crate::pipeline::follows_default_sink(name) {
+            return self.default_sink.as_deref() == Some(crate::equalizer::EQ_SINK.name);
+        }
+        #[cfg(not(target_os = "windows"))]
+        return name == crate::equalizer::EQ_SINK.name || name == crate::equalizer::EQ_SINK.description;
+    }
+
+    // The sink ALSA's "default" ends up playing on. While audio is processed the sound server's
+    // default is the virtual device, and the sink it's replayed on is the real one.
+    #[cfg(target_os = "linux")]
+    pub fn real_default_sink(&self) -> Option<String> {
+        match self.default_sink.as_deref() {
+            Some(sink) if sink == crate::equalizer::EQ_SINK.name => self.eq.sink.clone(),
+            sink => sink.map(str::to_string),
+        }
+    }
+
+    // The sink a pipeline plays `device` on. ALSA's "default" would play into the virtual device
+    // while audio is processed, and from there back into the processing path, so it's pinned to
+    // the real sink instead.
+    pub fn output_sink(&self, device: &str) -> Option<String> {
+        #[cfg(target_os = "linux")]
+        if crate::pipeline::follows_default_sink(device) {
+            return self.real_default_sink();
+        }
+        let _ = device;
+        None
+    }
+
+    // The real device behind the default: while audio goes through the processing path the
+    // default is the virtual device, but the picker should show where the sound ends up
+    pub fn real_output(&self, name: String) -> String {
+        match &self.settings.eq.output {
+            Some(output) if self.is_processing_device(&name) => output.clone(),
+            _ => name,
+        }
+    }
+
+    // Make `name` the device audio is processed for, loading its profile. Returns true if
+    // its audio should be played into the processing path, false if it's bypassed.
+    pub fn apply_device_profile(&mut self, name: &str) -> bool {
+        if self.is_processing_device(name) {
+            return self.eq.is_running();
+        }
+        let same_device = self.eq.device.as_deref() == Some(self.device_id(name).as_str());
+        if self.settings.eq.output.as_deref() == Some(name) && same_device && self.eq.is_running() && self.device_profile(name).is_some() {
+            // Already processing this device, so there's no need to interrupt the sound
+            self.push_profile();
+            return true;
+        }
+        if self.settings.eq.output.as_deref() != Some(name) {
+            self.settings.eq.output = Some(name.to_string());
+            if let Some(profile) = self.device_profile_mut(name) {
+                profile.name = name.to_string();
+            }
+            self.settings.save();
+        }
+        self.restart_equalizer();
+        self.eq.is_running()
+    }
+
+    // Switch again to the device processing is set up for, after its profile was added or
+    // removed or processing was turned on or off
+    pub fn reapply_device_profile(&mut self, name: &str) {
+        let target = self.settings.eq.output.clone().or_else(|| self.default_output_device_name().map(|name| self.real_output(name)));
+        if target.as_deref() == Some(name) {
+            self.set_default_device_by_name(name);
+        }
+    }
+
+    // Catch up with the default output changing outside the app, and at startup
+    pub fn follow_default_device(&mut self) {
+        let Some(default) = self.default_output_device_name() else {
+            return;
+        };
+
+        if self.is_processing_device(&default) {
+            // Already routed into the processing path. If that can't run, play on the real
+            // device instead of into a virtual one nobody listens to.
+            if !self.eq.is_running() {
+                self.restart_equalizer();
+            }
+            if !self.eq.is_running() {
+                if let Some(output) = self.settings.eq.output.clone() {
+                    self.switch_default_device(&output);
+                }
+            }
+            return;
+        }
+
+        let wants_processing = self.settings.eq.enabled && self.device_profile(&default).is_some();
+        // On Linux "default" stays the name while the device behind it changes
+        let moved = self.eq.is_running() && self.eq.device != Some(self.device_id(&default));
+        let changed = self.settings.eq.output.as_deref() != Some(default.as_str()) || wants_processing != self.eq.is_running() || moved;
+        if changed && self.apply_device_profile(&default) {
+            self.route_through_processing();
+        }
+    }
+
+    pub fn apply_profile_action(&mut self, name: &str, action: ProfileAction) {
+        match action {
+            ProfileAction::Create => {
+                self.create_device_profile(name);
+                self.edit_device_profile(name);
+            }
+            ProfileAction::Edit => self.edit_device_profile(name),
+            ProfileAction::Remove => self.remove_device_profile(name),
+        }
+    }
+
+    pub fn edit_device_profile(&mut self, name: &str) {
+        self.eq.editing = Some(name.to_string());
+        self.tab = Tab::Equalizer;
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum ProfileAction {
+    Create,
+    Edit,
+    Remove,
+}
+
+// Menu shown when right-clicking a device
+pub fn profile_menu_ui(ui: &mut egui::Ui, has_profile: bool) -> Option<ProfileAction> {
+    let mut action = None;
+    if has_profile {
+        if ui.button("Edit DSP profile…").clicked() {
+            action = Some(ProfileAction::Edit);
+        }
+        if ui.button("Remove DSP profile").on_hover_text("Play this device's audio unprocessed").clicked() {
+            action = Some(ProfileAction::Remove);
+        }
+    } else if ui.button("Create DSP profile…").on_hover_text("Process this device's audio with its own EQ and effects").clicked() {
+        action = Some(ProfileAction::Create);
+    }
+    if action.is_some() {
+        ui.close_menu();
+    }
+    action
+}
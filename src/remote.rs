@@ -0,0 +1,155 @@
+// The web remote: a single page served by the HTTP server that mirrors the main window, so
+// the volume can be changed from a phone. Phones get the access token by pairing: the app
+// shows a short code and the page trades it for the token.
+
+use std::net::{IpAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::http_api::random_u64;
+
+pub const PAGE: &str = include_str!("../assets/remote.html");
+
+// How long a pairing code can be used for
+const PAIRING_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Wrong guesses allowed before the code is thrown away
+const PAIRING_ATTEMPTS: u32 = 5;
+
+struct PairingCode {
+    code: String,
+    created: Instant,
+    attempts_left: u32,
+}
+
+// The pairing code currently on screen, if any. Shared between the UI and the server threads.
+#[derive(Clone)]
+pub struct Pairing {
+    current: Arc<Mutex<Option<PairingCode>>>,
+    timeout: Duration,
+}
+
+impl Default for Pairing {
+    fn default() -> Self {
+        Self { current: Arc::default(), timeout: PAIRING_TIMEOUT }
+    }
+}
+
+impl Pairing {
+    #[cfg(test)]
+    fn with_timeout(timeout: Duration) -> Self {
+        Self { timeout, ..Self::default() }
+    }
+
+    // Show a new six-digit code, replacing any previous one
+    pub fn start(&self) {
+        let code = format!("{:06}", random_u64() % 1_000_000);
+        if let Ok(mut current) = self.current.lock() {
+            *current = Some(PairingCode { code, created: Instant::now(), attempts_left: PAIRING_ATTEMPTS });
+        }
+    }
+
+    pub fn cancel(&self) {
+        if let Ok(mut current) = self.current.lock() {
+            *current = None;
+        }
+    }
+
+    // The code to show and how long it stays valid
+    pub fn current(&self) -> Option<(String, Duration)> {
+        let current = self.current.lock().ok()?;
+        let pairing = current.as_ref()?;
+        let remaining = self.timeout.checked_sub(pairing.created.elapsed())?;
+        Some((pairing.code.clone(), remaining))
+    }
+
+    // Check a code entered on a phone. A code works once; too many wrong guesses void it.
+    pub fn redeem(&self, code: &str) -> bool {
+        let Ok(mut current) = self.current.lock() else {
+            return false;
+        };
+        let Some(pairing) = current.as_mut() else {
+            return false;
+        };
+
+        if pairing.created.elapsed() > self.timeout {
+            *current = None;
+            return false;
+        }
+        if pairing.code == code.trim() {
+            *current = None;
+            return true;
+        }
+
+        pairing.attempts_left -= 1;
+        if pairing.attempts_left == 0 {
+            *current = None;
+        }
+        false
+    }
+}
+
+// Our address on the local network, for telling the user where to point their phone.
+// Connecting a UDP socket sends nothing; it just makes the OS pick the outgoing interface.
+pub fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind("0.0.0.0:0").ok()?;
+    socket.connect("192.0.2.1:80").ok()?;
+    let address = socket.local_addr().ok()?.ip();
+    (!address.is_loopback()).then_some(address)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code(pairing: &Pairing) -> String {
+        pairing.current().unwrap().0
+    }
+
+    #[test]
+    fn codes_work_once() {
+        let pairing = Pairing::default();
+        assert!(!pairing.redeem("123456"), "there's no code before pairing starts");
+        pairing.start();
+        let (shown, remaining) = pairing.current().unwrap();
+        assert_eq!(shown.len(), 6);
+        assert!(shown.chars().all(|c| c.is_ascii_digit()));
+        assert!(remaining <= PAIRING_TIMEOUT && remaining > PAIRING_TIMEOUT - Duration::from_secs(5));
+
+        assert!(pairing.redeem(&format!(" {shown}\n")));
+        assert!(!pairing.redeem(&shown));
+        assert!(pairing.current().is_none());
+    }
+
+    #[test]
+    fn wrong_guesses_void_the_code() {
+        let pairing = Pairing::default();
+        pairing.start();
+        let shown = code(&pairing);
+        let wrong = if shown == "000000" { "000001" } else { "000000" };
+        for _ in 1..PAIRING_ATTEMPTS {
+            assert!(!pairing.redeem(wrong));
+        }
+        assert_eq!(code(&pairing), shown, "the code lasts until the last guess");
+        assert!(!pairing.redeem(wrong));
+        assert!(pairing.current().is_none());
+        assert!(!pairing.redeem(&shown));
+    }
+
+    #[test]
+    fn expired_codes_are_refused() {
+        let pairing = Pairing::with_timeout(Duration::from_millis(50));
+        pairing.start();
+        let shown = code(&pairing);
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(pairing.current().is_none());
+        assert!(!pairing.redeem(&shown));
+
+        // Starting again gives a fresh code with the full time
+        pairing.start();
+        assert!(pairing.redeem(&code(&pairing)));
+        pairing.start();
+        pairing.cancel();
+        assert!(pairing.current().is_none());
+    }
+}
@@ -0,0 +1,323 @@
+// Per-application output devices: send the browser to the headphones and the music player to
+// the speakers, whatever the system default is. Routes are saved by app name and applied again
+// whenever that app opens a new audio stream, so they survive the app restarting.
+//
+// Windows keeps a per-process default endpoint (the "App volume and device preferences" page);
+// the interface behind it isn't documented, but it's stable and what the Settings app uses. On
+// Linux each PulseAudio or PipeWire stream is moved to the chosen sink, and routes are saved by
+// sink name, which unlike the description stays the same across languages and renames.
+
+use eframe::egui;
+use egui::{Color32, RichText};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::scenes::AppVolume;
+use crate::sessions::AudioSession;
+use crate::AudioApp;
+
+// How often the mixer reads the app volumes again, to catch changes made elsewhere
+const VOLUME_REFRESH: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct RoutingPrefs {
+    // App name -> output device name, or sink name on Linux
+    pub routes: BTreeMap<String, String>,
+}
+
+// Which sessions have already been sent where they belong, by process ID
+#[derive(Default)]
+pub struct Routing {
+    routed: HashMap<u32, Vec<u32>>,
+    // Output devices an app can be sent to, as (what a route saves, device name), looked up
+    // when the mixer is shown. They're the same except on Linux: sink name and description.
+    targets: Vec<(String, String)>,
+    // The mixer's volume controls and when they were read. Read again when the sessions
+    // change, and every so often.
+    volumes: Option<(Instant, Vec<AppVolume>)>,
+    error: Option<String>,
+}
+
+impl Routing {
+    // The device name for what a route saved, if the device is still there
+    fn device_name<'a>(&'a self, target: &'a str) -> &'a str {
+        self.targets.iter().find(|(other, _)| other == target).map_or(target, |(_, name)| name)
+    }
+}
+
+// Send one app's audio to `device`, or back to the system default when `device` is None
+fn route_session(session: &AudioSession, device: Option<&str>) -> Result<(), String> {
+    #[cfg(target_os = "windows")]
+    {
+        let _ = &session.streams;
+        unsafe { policy_config::set_app_default(session.pid, device) }.map_err(|err| err.to_string())
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        crate::sessions::pulse::move_streams(&session.streams, device)
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        let _ = (session, device);
+        Err("Routing apps isn't supported on this system".to_string())
+    }
+}
+
+#[cfg(target_os = "windows")]
+#[allow(non_snake_case)]
+mod policy_config {
+    use std::ffi::c_void;
+    use windows::core::{interface, IInspectable, IUnknown, IUnknown_Vtbl, HRESULT, HSTRING};
+    use windows::Win32::Media::Audio::{eConsole, eMultimedia, eRender, EDataFlow, ERole};
+    use windows::Win32::System::WinRT::RoGetActivationFactory;
+
+    use crate::endpoints::windows_impl::{find_device, take_pwstr};
+    use crate::endpoints::Flow;
+
+    // The Windows 10 21H2 and later layout. Only SetPersistedDefaultAudioEndpoint is ever
+    // called; the rest just hold their places in the vtable, starting with IInspectable's.
+    #[interface("ab3d4648-e242-459f-b02f-541c70306324")]
+    unsafe trait IAudioPolicyConfigFactory: IUnknown {
+        fn GetIids(&self) -> HRESULT;
+        fn GetRuntimeClassName(&self) -> HRESULT;
+        fn GetTrustLevel(&self) -> HRESULT;
+        fn add_CtxVolumeChange(&self) -> HRESULT;
+        fn remove_CtxVolumeChanged(&self) -> HRESULT;
+        fn add_RingerVibrateStateChanged(&self) -> HRESULT;
+        fn remove_RingerVibrateStateChange(&self) -> HRESULT;
+        fn SetVolumeGroupGainForId(&self) -> HRESULT;
+        fn GetVolumeGroupGainForId(&self) -> HRESULT;
+        fn GetActiveVolumeGroupForEndpointId(&self) -> HRESULT;
+        fn GetVolumeGroupsForEndpoint(&self) -> HRESULT;
+        fn GetCurrentVolumeContext(&self) -> HRESULT;
+        fn SetVolumeGroupMuteForId(&self) -> HRESULT;
+        fn GetVolumeGroupMuteForId(&self) -> HRESULT;
+        fn SetRingerVibrateState(&self) -> HRESULT;
+        fn GetRingerVibrateState(&self) -> HRESULT;
+        fn SetPreferredChatApplication(&self) -> HRESULT;
+        fn ResetPreferredChatApplication(&self) -> HRESULT;
+        fn GetPreferredChatApplication(&self) -> HRESULT;
+        fn GetCurrentChatApplications(&self) -> HRESULT;
+        fn add_ChatContextChanged(&self) -> HRESULT;
+        fn remove_ChatContextChanged(&self) -> HRESULT;
+        fn SetPersistedDefaultAudioEndpoint(&self, process_id: u32, flow: EDataFlow, role: ERole, device_id: *mut c_void) -> HRESULT;
+        fn GetPersistedDefaultAudioEndpoint(&self, process_id: u32, flow: EDataFlow, role: ERole, device_id: *mut *mut c_void) -> HRESULT;
+        fn ClearAllPersistedApplicationDefaultEndpoints(&self) -> HRESULT;
+    }
+
+    // Earlier Windows 10 releases have the same methods under a different IID
+    const LEGACY_IID: windows::core::GUID = windows::core::GUID::from_u128(0x2a59116d_6c4f_45e0_a74f_707e3fef9258);
+
+    unsafe fn factory() -> windows::core::Result<IAudioPolicyConfigFactory> {
+        let class = HSTRING::from("Windows.Media.Internal.AudioPolicyConfig");
+        let inspectable: IInspectable = RoGetActivationFactory(&class)?;
+        if let Ok(factory) = windows::core::ComInterface::cast::<IAudioPolicyConfigFactory>(&inspectable) {
+            return Ok(factory);
+        }
+        let mut raw = std::ptr::null_mut();
+        windows::core::ComInterface::query(&inspectable, &LEGACY_IID, &mut raw).ok()?;
+        Ok(windows::core::Interface::from_raw(raw))
+    }
+
+    // The policy interface wants the device interface path rather than the endpoint ID
+    fn device_path(endpoint_id: &str) -> String {
+        format!("\\\\?\\SWD#MMDEVAPI#{endpoint_id}#{{e6327cad-dcec-4949-ae8a-991e976a79d2}}")
+    }
+
+    pub unsafe fn set_app_default(pid: u32, device: Option<&str>) -> windows::core::Result<()> {
+        let path = match device {
+            Some(name) => HSTRING::from(device_path(&take_pwstr(find_device(Flow::Output, name)?.GetId()?))),
+            // An empty ID clears the preference, so the app follows the system default again
+            None => HSTRING::new(),
+        };
+        let factory = factory()?;
+        for role in [eConsole, eMultimedia] {
+            factory.SetPersistedDefaultAudioEndpoint(pid, eRender, role, std::mem::transmute_copy(&path)).ok()?;
+        }
+        Ok(())
+    }
+}
+
+impl AudioApp {
+    // Send newly started sessions of routed apps to their device
+    pub fn apply_routes(&mut self, sessions_changed: bool) {
+        if !sessions_changed {
+            return;
+        }
+        self.routing.volumes = None;
+        self.routing.routed.retain(|pid, _| self.sessions.iter().any(|session| session.pid == *pid));
+
+        for session in &self.sessions {
+            let Some(device) = self.settings.routing.routes.get(&session.app) else {
+                continue;
+            };
+            // Windows routes the whole process once; on Linux each new stream needs moving
+            let routed = self.routing.routed.get(&session.pid);
+            let fresh: Vec<u32> = session
+                .streams
+                .iter()
+                .copied()
+                .filter(|stream| !routed.is_some_and(|streams| streams.contains(stream)))
+                .collect();
+            if routed.is_some() && fresh.is_empty() {
+                continue;
+            }
+
+            let pending = AudioSession { streams: fresh, ..session.clone() };
+            match route_session(&pending, Some(device)) {
+                Ok(()) => {
+                    self.routing.routed.entry(session.pid).or_default().extend(pending.streams);
+                }
+                Err(err) => {
+                    eprintln!("ERROR: Couldn't send {} to {device}: {err}", session.app);
+                    // Don't try again every time the sessions change
+                    self.routing.routed.entry(session.pid).or_default().extend(pending.streams);
+                    self.routing.error = Some(format!("{}: {err}", session.app));
+                }
+            }
+        }
+    }
+
+    // Remember where an app should play and send it there now
+    fn set_app_route(&mut self, app: &str, device: Option<String>) {
+        match &device {
+            Some(device) => self.settings.routing.routes.insert(app.to_string(), device.clone()),
+            None => self.settings.routing.routes.remove(app),
+        };
+        self.settings.save();
+
+        self.routing.error = None;
+        for session in self.sessions.iter().filter(|session| session.app == app) {
+            if let Err(err) = route_session(session, device.as_deref()) {
+                eprintln!("ERROR: Couldn't route {app}: {err}");
+                self.routing.error = Some(format!("{app}: {err}"));
+            }
+            self.routing.routed.insert(session.pid, session.streams.clone());
+        }
+    }
+
+    fn refresh_route_targets(&mut self) {
+        #[cfg(target_os = "linux")]
+        {
+            // cpal's ALSA names don't match what the sound server calls its sinks
+            self.routing.targets =
+                crate::endpoints::pulse::list_sinks().into_iter().map(|sink| (sink.name, sink.description)).collect();
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            self.routing.targets = self.device_names.iter().map(|name| (name.clone(), name.clone())).collect();
+        }
+    }
+
+    // Every app with an audio session: its volume, mute and where it plays
+    pub fn mixer_ui(&mut self, ui: &mut egui::Ui) {
+        if self.routing.targets.is_empty() || ui.input(|input| input.key_pressed(egui::Key::F5)) {
+            self.refresh_route_targets();
+            self.routing.volumes = None;
+        }
+        // Reading every session's volume isn't free, so only when something changed
+        let (read, mut volumes) = match self.routing.volumes.take() {
+            Some((read, volumes)) if read.elapsed() < VOLUME_REFRESH => (read, volumes),
+            _ => (Instant::now(), self.app_volumes()),
+        };
+
+        let mut apps: Vec<String> = self.sessions.iter().map(|session| session.app.clone()).collect();
+        apps.extend(volumes.iter().map(|app| app.name.clone()));
+        apps.sort_by_key(|app| app.to_lowercase());
+        apps.dedup();
+
+        if apps.is_empty() {
+            ui.label("No applications are playing audio.");
+        }
+
+        let mut route = None;
+        egui::Grid::new("mixer").num_columns(3).striped(true).spacing([8.0, 6.0]).show(ui, |ui| {
+            for app in &apps {
+                let playing = self.sessions.iter().any(|session| &session.app == app && session.active);
+                let label = RichText::new(app);
+                ui.label(if playing { label.strong() } else { label.weak() });
+
+                match volumes.iter_mut().find(|volume| &volume.name == app) {
+                    Some(volume) => {
+                        ui.horizontal(|ui| {
+                            let icon = if volume.muted { RichText::new("🔇").color(Color32::RED) } else { RichText::new("🔊") };
+                            if ui.small_button(icon).clicked() {
+                                volume.muted = !volume.muted;
+                                self.set_app_mute(app, volume.muted);
+                            }
+                            if ui.add(egui::Slider::new(&mut volume.volume, 0.0..=1.0).show_value(false)).changed() {
+                                self.set_app_volume(app, volume.volume);
+                            }
+                        });
+                    }
+                    None => {
+                        ui.label("");
+                    }
+                }
+
+                let current = self.settings.routing.routes.get(app).cloned();
+                let selected = current
+                    .as_deref()
+                    .map(|target| self.settings.devices.display_name(self.routing.device_name(target)))
+                    .unwrap_or("Default");
+                egui::ComboBox::from_id_source(("mixer_route", app)).selected_text(selected).width(150.0).show_ui(ui, |ui| {
+                    if ui.selectable_label(current.is_none(), "Default").clicked() && current.is_some() {
+                        route = Some((app.clone(), None));
+                    }
+                    for (target, name) in &self.routing.targets {
+                        let label = self.settings.devices.display_name(name);
+                        if ui.selectable_label(current.as_ref() == Some(target), label).clicked() {
+                            route = Some((app.clone(), Some(target.clone())));
+                        }
+                    }
+                });
+                ui.end_row();
+            }
+        });
+        self.routing.volumes = Some((read, volumes));
+
+        if let Some((app, device)) = route {
+            self.set_app_route(&app, device);
+        }
+
+        // Routes for apps that aren't running right now
+        let idle: Vec<(String, String)> = self
+            .settings
+            .routing
+            .routes
+            .iter()
+            .filter(|(app, _)| !apps.contains(app))
+            .map(|(app, device)| (app.clone(), device.clone()))
+            .collect();
+        if !idle.is_empty() {
+            ui.add_space(6.0);
+            ui.label(RichText::new("Saved routes").strong());
+            for (app, device) in idle {
+                ui.horizontal(|ui| {
+                    ui.label(format!("{app} → {}", self.settings.devices.display_name(self.routing.device_name(&device))));
+                    if ui.small_button("🗑").on_hover_text("Forget this route").clicked() {
+                        self.set_app_route(&app, None);
+                    }
+                });
+            }
+        }
+
+        ui.add_space(6.0);
+        ui.horizontal(|ui| {
+            let any = !self.settings.routing.routes.is_empty();
+            if ui.add_enabled(any, egui::Button::new("Reset all to default")).clicked() {
+                let apps: Vec<String> = self.settings.routing.routes.keys().cloned().collect();
+                for app in apps {
+                    self.set_app_route(&app, None);
+                }
+            }
+            if let Some(err) = &self.routing.error {
+                ui.label(RichText::new(err).color(Color32::from_rgb(255, 96, 96)));
+            }
+        });
+    }
+}
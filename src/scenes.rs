@@ -0,0 +1,398 @@
+use eframe::egui;
+use egui::{Color32, RichText};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::endpoints::Flow;
+use crate::AudioApp;
+
+// Volume differences smaller than this are not worth touching (half a percent)
+const VOLUME_EPSILON: f32 = 0.005;
+
+// Volume and mute of a single application's audio session
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct AppVolume {
+    pub name: String,
+    pub volume: f32,
+    pub muted: bool,
+}
+
+// Snapshot of everything a scene controls
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct MixerState {
+    pub output_device: Option<String>,
+    pub input_device: Option<String>,
+    pub volume: f32,
+    pub muted: bool,
+    pub app_volumes: Vec<AppVolume>,
+}
+
+// A named, saved mixer state such as "meeting" or "gaming"
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Scene {
+    pub name: String,
+    #[serde(default)]
+    pub hotkey: Option<String>,
+    pub state: MixerState,
+}
+
+// A single step needed to get from the current mixer state to a scene
+#[derive(Clone, Debug, PartialEq)]
+pub enum SceneChange {
+    OutputDevice { from: Option<String>, to: String },
+    InputDevice { from: Option<String>, to: String },
+    Volume { from: f32, to: f32 },
+    Mute { from: bool, to: bool },
+    AppVolume { app: String, from: f32, to: f32 },
+    AppMute { app: String, from: bool, to: bool },
+}
+
+fn percent(volume: f32) -> i32 {
+    (volume * 100.0).round() as i32
+}
+
+fn on_off(muted: bool) -> &'static str {
+    if muted {
+        "muted"
+    } else {
+        "unmuted"
+    }
+}
+
+impl fmt::Display for SceneChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let none = "none".to_string();
+        match self {
+            SceneChange::OutputDevice { from, to } => {
+                write!(f, "Output: {} → {}", from.as_ref().unwrap_or(&none), to)
+            }
+            SceneChange::InputDevice { from, to } => {
+                write!(f, "Input: {} → {}", from.as_ref().unwrap_or(&none), to)
+            }
+            SceneChange::Volume { from, to } => {
+                write!(f, "Volume: {}% → {}%", percent(*from), percent(*to))
+            }
+            SceneChange::Mute { from, to } => write!(f, "Master: {} → {}", on_off(*from), on_off(*to)),
+            SceneChange::AppVolume { app, from, to } => {
+                write!(f, "{}: {}% → {}%", app, percent(*from), percent(*to))
+            }
+            SceneChange::AppMute { app, from, to } => {
+                write!(f, "{}: {} → {}", app, on_off(*from), on_off(*to))
+            }
+        }
+    }
+}
+
+// Work out what applying `target` on top of `current` would change.
+// Applications that aren't running right now are skipped since there is no session to adjust.
+pub fn diff(current: &MixerState, target: &MixerState) -> Vec<SceneChange> {
+    let mut changes = Vec::new();
+
+    if let Some(to) = &target.output_device {
+        if current.output_device.as_ref() != Some(to) {
+            changes.push(SceneChange::OutputDevice { from: current.output_device.clone(), to: to.clone() });
+        }
+    }
+
+    if let Some(to) = &target.input_device {
+        if current.input_device.as_ref() != Some(to) {
+            changes.push(SceneChange::InputDevice { from: current.input_device.clone(), to: to.clone() });
+        }
+    }
+
+    if (current.volume - target.volume).abs() > VOLUME_EPSILON {
+        changes.push(SceneChange::Volume { from: current.volume, to: target.volume });
+    }
+
+    if current.muted != target.muted {
+        changes.push(SceneChange::Mute { from: current.muted, to: target.muted });
+    }
+
+    for wanted in &target.app_volumes {
+        let Some(running) = current.app_volumes.iter().find(|app| app.name == wanted.name) else {
+            continue;
+        };
+
+        if (running.volume - wanted.volume).abs() > VOLUME_EPSILON {
+            changes.push(SceneChange::AppVolume {
+                app: wanted.name.clone(),
+                from: running.volume,
+                to: wanted.volume,
+            });
+        }
+
+        if running.muted != wanted.muted {
+            changes.push(SceneChange::AppMute {
+                app: wanted.name.clone(),
+                from: running.muted,
+                to: wanted.muted,
+            });
+        }
+    }
+
+    changes
+}
+
+fn is_device_change(change: &SceneChange) -> bool {
+    matches!(change, SceneChange::OutputDevice { .. } | SceneChange::InputDevice { .. })
+}
+
+// UI-only state for the scenes tab
+#[derive(Default)]
+pub struct ScenesUi {
+    new_scene_name: String,
+    // Index of the scene whose changes are being previewed, and the mixer state when the
+    // preview was opened. Reading the state means enumerating every session, too slow to
+    // repeat every frame.
+    preview: Option<(usize, MixerState)>,
+}
+
+impl AudioApp {
+    // Capture the current mixer state so it can be saved as a scene
+    pub fn mixer_state(&self) -> MixerState {
+        MixerState {
+            output_device: self.selected_device_idx.map(|idx| self.device_names[idx].clone()),
+            input_device: self.default_input_device_name(),
+            volume: self.volume,
+            muted: self.is_muted,
+            app_volumes: self.app_volumes(),
+        }
+    }
+
+    fn apply_scene_change(&mut self, change: &SceneChange) {
+        match change {
+            SceneChange::OutputDevice { to, .. } => {
+                self.set_default_device_by_name(to);
+                if !self.wait_for_default(Flow::Output, to) {
+                    self.notifications.push(format!("Couldn't switch output to {}", self.settings.devices.display_name(to)));
+                }
+                self.sync_selected_device();
+            }
+            SceneChange::InputDevice { to, .. } => {
+                self.set_default_input_device_by_name(to);
+                if !self.wait_for_default(Flow::Input, to) {
+                    self.notifications.push(format!("Couldn't switch input to {}", self.settings.devices.display_name(to)));
+                }
+                self.default_input_name = self.default_input_device_name();
+            }
+            SceneChange::Volume { to, .. } => self.set_volume(*to),
+            SceneChange::Mute { to, .. } => self.set_mute(*to),
+            SceneChange::AppVolume { app, to, .. } => self.set_app_volume(app, *to),
+            SceneChange::AppMute { app, to, .. } => self.set_app_mute(app, *to),
+        }
+    }
+
+    // Bring the mixer to `target`. Master volume and mute belong to the default output, so
+    // devices are switched first and the controller is re-created for the new device before
+    // the remaining changes are worked out against it.
+    pub fn apply_mixer_state(&mut self, target: &MixerState) {
+        // Make sure applications started since the last refresh are included
+        self.reload_audio_controller();
+        let mut changes = diff(&self.mixer_state(), target);
+
+        if changes.iter().any(is_device_change) {
+            for change in changes.iter().filter(|change| is_device_change(change)) {
+                self.apply_scene_change(change);
+            }
+            self.reload_audio_controller();
+            changes = diff(&self.mixer_state(), target);
+        }
+
+        for change in changes.iter().filter(|change| !is_device_change(change)) {
+            self.apply_scene_change(change);
+        }
+    }
+
+    // Apply a saved scene by name, returning false if there is no such scene
+    pub fn apply_scene(&mut self, name: &str) -> bool {
+        let Some(scene) = self.settings.scenes.iter().find(|scene| scene.name == name) else {
+            return false;
+        };
+        let target = scene.state.clone();
+        self.apply_mixer_state(&target);
+        true
+    }
+
+    pub fn save_scene(&mut self, name: &str) {
+        self.reload_audio_controller();
+        let state = self.mixer_state();
+        if let Some(scene) = self.settings.scenes.iter_mut().find(|scene| scene.name == name) {
+            scene.state = state;
+        } else {
+            self.settings.scenes.push(Scene { name: name.to_string(), hotkey: None, state });
+        }
+        self.settings.save();
+    }
+
+    pub fn scenes_ui(&mut self, ui: &mut egui::Ui) {
+        // Save the current state as a new scene (or overwrite one with the same name)
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(&mut self.scenes_ui.new_scene_name).hint_text("Scene name"));
+            let name = self.scenes_ui.new_scene_name.trim().to_string();
+            if ui.add_enabled(!name.is_empty(), egui::Button::new("Save current")).clicked() {
+                self.save_scene(&name);
+                self.scenes_ui.new_scene_name.clear();
+            }
+        });
+
+        ui.add_space(8.0);
+
+        if self.settings.scenes.is_empty() {
+            ui.label("No scenes yet. Set things up the way you like and save them as a scene.");
+            return;
+        }
+
+        let mut to_delete = None;
+        let mut hotkeys_changed = false;
+
+        for idx in 0..self.settings.scenes.len() {
+            egui::Frame::none()
+                .fill(ui.visuals().extreme_bg_color)
+                .inner_margin(egui::style::Margin::same(8.0))
+                .rounding(egui::Rounding::same(6.0))
+                .show(ui, |ui| {
+                    ui.horizontal(|ui| {
+                        ui.label(RichText::new(&self.settings.scenes[idx].name).strong().size(16.0));
+
+                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                            if ui.button("🗑").on_hover_text("Delete scene").clicked() {
+                                to_delete = Some(idx);
+                            }
+                            if ui.button("Preview").clicked() {
+                                self.reload_audio_controller();
+                                self.scenes_ui.preview = Some((idx, self.mixer_state()));
+                            }
+                            if ui.button("Apply").clicked() {
+                                let name = self.settings.scenes[idx].name.clone();
+                                self.apply_scene(&name);
+                            }
+                        });
+                    });
+
+                    // Optional global hotkey, e.g. "Ctrl+Alt+1"
+                    ui.horizontal(|ui| {
+                        ui.label("Hotkey:");
+                        let scene = &mut self.settings.scenes[idx];
+                        let mut hotkey = scene.hotkey.clone().unwrap_or_default();
+                        let response = ui.add(egui::TextEdit::singleline(&mut hotkey).hint_text("Ctrl+Alt+1").desired_width(120.0));
+                        if response.changed() {
+                            scene.hotkey = if hotkey.trim().is_empty() { None } else { Some(hotkey) };
+                        }
+                        if response.lost_focus() {
+                            hotkeys_changed = true;
+                        }
+                        if let Some(hotkey) = &scene.hotkey {
+                            if crate::hotkeys::parse_hotkey(hotkey).is_none() {
+                                ui.colored_label(Color32::RED, "invalid");
+                            }
+                        }
+                    });
+
+                    // Diff preview: show what applying this scene would change before doing it
+                    let previewed = self.scenes_ui.preview.as_ref().filter(|(preview, _)| *preview == idx);
+                    if let Some((_, current)) = previewed {
+                        let target = self.settings.scenes[idx].state.clone();
+                        let changes = diff(current, &target);
+                        ui.separator();
+                        if changes.is_empty() {
+                            ui.label("Nothing would change.");
+                        } else {
+                            for change in &changes {
+                                ui.label(format!("• {change}"));
+                            }
+                        }
+                        ui.horizontal(|ui| {
+                            if ui.add_enabled(!changes.is_empty(), egui::Button::new("Confirm")).clicked() {
+                                self.apply_mixer_state(&target);
+                                self.scenes_ui.preview = None;
+                            }
+                            if ui.button("Cancel").clicked() {
+                                self.scenes_ui.preview = None;
+                            }
+                        });
+                    }
+                });
+            ui.add_space(4.0);
+        }
+
+        if let Some(idx) = to_delete {
+            self.settings.scenes.remove(idx);
+            self.scenes_ui.preview = None;
+            hotkeys_changed = true;
+        }
+
+        if hotkeys_changed {
+            self.settings.save();
+            self.restart_hotkeys();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(name: &str, volume: f32, muted: bool) -> AppVolume {
+        AppVolume { name: name.to_string(), volume, muted }
+    }
+
+    fn state() -> MixerState {
+        MixerState {
+            output_device: Some("Speakers".to_string()),
+            input_device: Some("Microphone".to_string()),
+            volume: 0.5,
+            muted: false,
+            app_volumes: vec![app("firefox", 0.8, false), app("Zoom", 1.0, false)],
+        }
+    }
+
+    #[test]
+    fn unchanged_state_needs_nothing() {
+        assert!(diff(&state(), &state()).is_empty());
+
+        // Differences too small to hear don't count either
+        let target = MixerState { volume: 0.503, app_volumes: vec![app("firefox", 0.797, false)], ..state() };
+        assert!(diff(&state(), &target).is_empty());
+    }
+
+    #[test]
+    fn device_only() {
+        let target = MixerState { output_device: Some("Headphones".to_string()), ..state() };
+        let changes = diff(&state(), &target);
+        assert_eq!(changes, [SceneChange::OutputDevice { from: Some("Speakers".to_string()), to: "Headphones".to_string() }]);
+        assert!(changes.iter().all(is_device_change));
+
+        // A scene without devices leaves them alone
+        let target = MixerState { output_device: None, input_device: None, ..state() };
+        assert!(diff(&state(), &target).is_empty());
+
+        let current = MixerState { input_device: None, ..state() };
+        assert_eq!(diff(&current, &state()), [SceneChange::InputDevice { from: None, to: "Microphone".to_string() }]);
+    }
+
+    #[test]
+    fn app_volume_only() {
+        let target = MixerState { app_volumes: vec![app("Zoom", 0.4, true)], ..state() };
+        let changes = diff(&state(), &target);
+        assert_eq!(
+            changes,
+            [
+                SceneChange::AppVolume { app: "Zoom".to_string(), from: 1.0, to: 0.4 },
+                SceneChange::AppMute { app: "Zoom".to_string(), from: false, to: true },
+            ]
+        );
+        assert!(!changes.iter().any(is_device_change));
+        assert_eq!(changes[0].to_string(), "Zoom: 100% → 40%");
+    }
+
+    // There's no session to adjust for an app that isn't running, so it's left out
+    #[test]
+    fn missing_apps_are_skipped() {
+        let target = MixerState { app_volumes: vec![app("Spotify", 0.2, true), app("firefox", 0.3, false)], ..state() };
+        assert_eq!(diff(&state(), &target), [SceneChange::AppVolume { app: "firefox".to_string(), from: 0.8, to: 0.3 }]);
+
+        let current = MixerState { app_volumes: Vec::new(), ..state() };
+        assert!(diff(&current, &state()).is_empty());
+    }
+}
@@ -0,0 +1,640 @@
+// User scripts for automation the app doesn't have built in, written in Rhai
+// (https://rhai.rs). Every .rhai file in the scripts folder is loaded, and reloaded whenever it
+// changes. Scripts react to events by defining functions with these names:
+//
+//   on_device_added(name, flow)      on_device_removed(name, flow)
+//   on_default_changed(flow, name)   on_volume_changed(volume)     on_mute_changed(muted)
+//   on_app_started(app)              on_app_stopped(app)           on_timer()   (once a second)
+//
+// and act through the functions registered in ScriptHost::new. Scripts can't touch files,
+// the network or other programs, and each call is cut off after a fixed amount of work so a
+// runaway loop can't freeze the window.
+
+use eframe::egui;
+use egui::RichText;
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, AST};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::control::{Command, ControlEvent, ControlState};
+use crate::endpoints::Flow;
+use crate::settings::APP_NAME;
+use crate::AudioApp;
+
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+const TIMER_INTERVAL: Duration = Duration::from_secs(1);
+
+// Work allowed per call before the script is stopped
+const MAX_OPERATIONS: u64 = 500_000;
+
+// Lines of print() output kept for the settings page
+const LOG_LINES: usize = 50;
+
+// How long to wait for the event a script's own change causes
+const ECHO_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Key in Script::errors for loading the script and running its top level
+const LOAD: &str = "";
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ScriptPrefs {
+    pub enabled: bool,
+}
+
+// Where scripts live, e.g. %APPDATA%\Audio Controller\scripts
+pub fn scripts_dir() -> Option<PathBuf> {
+    eframe::storage_dir(APP_NAME).map(|dir| dir.join("scripts"))
+}
+
+// What scripts asked for, carried out once they return
+enum ScriptAction {
+    Command(Command),
+    Notify(String),
+}
+
+struct Script {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    ast: Option<AST>,
+    // The last error of each handler, kept until the script is reloaded so a later call that
+    // works doesn't hide it
+    errors: BTreeMap<String, String>,
+}
+
+impl Script {
+    fn name(&self) -> String {
+        self.path.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default()
+    }
+
+    fn defines(&self, function: &str, arity: usize) -> bool {
+        self.ast
+            .as_ref()
+            .is_some_and(|ast| ast.iter_functions().any(|f| f.name == function && f.params.len() == arity))
+    }
+}
+
+// Volumes are f32 internally; don't hand scripts 0.8999999761581421 for 0.9
+fn volume_value(volume: f32) -> f64 {
+    (volume as f64 * 10_000.0).round() / 10_000.0
+}
+
+fn flow_name(flow: Flow) -> &'static str {
+    match flow {
+        Flow::Output => "output",
+        Flow::Input => "input",
+    }
+}
+
+pub struct ScriptHost {
+    engine: Engine,
+    dir: PathBuf,
+    scripts: Vec<Script>,
+    // Shared with the functions registered on the engine
+    actions: Rc<RefCell<Vec<ScriptAction>>>,
+    state: Rc<RefCell<ControlState>>,
+    log: Rc<RefCell<VecDeque<String>>>,
+    events: Receiver<ControlEvent>,
+    // Events that scripts caused themselves and when. They don't call the handlers again, or
+    // set_volume() in on_volume_changed would keep calling itself.
+    echoes: Vec<(ControlEvent, Instant)>,
+    outputs: Vec<String>,
+    inputs: Vec<String>,
+    // Applications with an audio session, once someone cares
+    apps: Option<HashSet<String>>,
+    last_reload: Option<Instant>,
+    last_timer: Instant,
+}
+
+impl ScriptHost {
+    pub fn new(dir: PathBuf, events: Receiver<ControlEvent>, state: ControlState) -> Self {
+        let actions: Rc<RefCell<Vec<ScriptAction>>> = Rc::default();
+        let log: Rc<RefCell<VecDeque<String>>> = Rc::default();
+        let outputs = state.outputs.clone();
+        let inputs = state.inputs.clone();
+        let state = Rc::new(RefCell::new(state));
+
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.set_max_call_levels(32);
+        engine.set_max_expr_depths(64, 32);
+        engine.set_max_string_size(100_000);
+        engine.set_max_array_size(10_000);
+        engine.set_max_map_size(10_000);
+        engine.set_module_resolver(rhai::module_resolvers::DummyModuleResolver::new());
+        engine.disable_symbol("eval");
+
+        {
+            let log = log.clone();
+            engine.on_print(move |text| {
+                let mut log = log.borrow_mut();
+                if log.len() == LOG_LINES {
+                    log.pop_front();
+                }
+                log.push_back(text.to_string());
+            });
+        }
+
+        // Reading the current state
+        {
+            let state = state.clone();
+            engine.register_fn("volume", move || volume_value(state.borrow().volume));
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("is_muted", move || state.borrow().muted);
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("default_device", move || state.borrow().output_device.clone().unwrap_or_default());
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("default_input_device", move || state.borrow().input_device.clone().unwrap_or_default());
+        }
+        {
+            let state = state.clone();
+            engine.register_fn("devices", move || -> Array { state.borrow().outputs.iter().cloned().map(Dynamic::from).collect() });
+        }
+
+        // Changing things. The state is updated straight away so the rest of the script sees it.
+        let command = {
+            let actions = actions.clone();
+            move |command: Command| actions.borrow_mut().push(ScriptAction::Command(command))
+        };
+        {
+            let (command, state) = (command.clone(), state.clone());
+            engine.register_fn("set_volume", move |volume: f64| -> Result<(), Box<EvalAltResult>> {
+                if !(0.0..=1.0).contains(&volume) {
+                    return Err(format!("Volume must be between 0.0 and 1.0, not {volume}").into());
+                }
+                state.borrow_mut().volume = volume as f32;
+                command(Command::SetVolume(volume as f32));
+                Ok(())
+            });
+        }
+        {
+            let (command, state) = (command.clone(), state.clone());
+            engine.register_fn("set_mute", move |muted: bool| {
+                state.borrow_mut().muted = muted;
+                command(Command::SetMute(muted));
+            });
+        }
+        {
+            let (command, state) = (command.clone(), state.clone());
+            engine.register_fn("toggle_mute", move || {
+                let muted = !state.borrow().muted;
+                state.borrow_mut().muted = muted;
+                command(Command::SetMute(muted));
+            });
+        }
+        {
+            let (command, state) = (command.clone(), state.clone());
+            engine.register_fn("set_default_device_by_name", move |name: &str| {
+                state.borrow_mut().output_device = Some(name.to_string());
+                command(Command::SetDefaultDevice { flow: Flow::Output, name: name.to_string() });
+            });
+        }
+        {
+            let (command, state) = (command.clone(), state.clone());
+            engine.register_fn("set_default_input_device_by_name", move |name: &str| {
+                state.borrow_mut().input_device = Some(name.to_string());
+                command(Command::SetDefaultDevice { flow: Flow::Input, name: name.to_string() });
+            });
+        }
+        {
+            let command = command.clone();
+            engine.register_fn("set_app_volume", move |app: &str, volume: f64| -> Result<(), Box<EvalAltResult>> {
+                if !(0.0..=1.0).contains(&volume) {
+                    return Err(format!("Volume must be between 0.0 and 1.0, not {volume}").into());
+                }
+                command(Command::SetAppVolume { app: app.to_string(), volume: volume as f32 });
+                Ok(())
+            });
+        }
+        {
+            let command = command.clone();
+            engine.register_fn("set_app_mute", move |app: &str, muted: bool| {
+                command(Command::SetAppMute { app: app.to_string(), muted });
+            });
+        }
+        engine.register_fn("apply_scene", move |name: &str| command(Command::ApplyScene(name.to_string())));
+        {
+            let actions = actions.clone();
+            engine.register_fn("notify", move |text: &str| actions.borrow_mut().push(ScriptAction::Notify(text.to_string())));
+        }
+
+        Self {
+            engine,
+            dir,
+            scripts: Vec::new(),
+            actions,
+            state,
+            log,
+            events,
+            echoes: Vec::new(),
+            outputs,
+            inputs,
+            apps: None,
+            last_reload: None,
+            last_timer: Instant::now(),
+        }
+    }
+
+    fn wants(&self, function: &str, arity: usize) -> bool {
+        self.scripts.iter().any(|script| script.defines(function, arity))
+    }
+
+    // Load new and changed scripts and forget deleted ones. Returns the scripts that were
+    // (re)loaded, whose top level still has to run.
+    fn reload(&mut self) -> Vec<usize> {
+        if self.last_reload.is_some_and(|when| when.elapsed() < RELOAD_INTERVAL) {
+            return Vec::new();
+        }
+        self.last_reload = Some(Instant::now());
+
+        let mut found: Vec<(PathBuf, Option<SystemTime>)> = std::fs::read_dir(&self.dir)
+            .map(|entries| {
+                entries
+                    .flatten()
+                    .map(|entry| entry.path())
+                    .filter(|path| path.extension().is_some_and(|ext| ext == "rhai"))
+                    .map(|path| {
+                        let modified = std::fs::metadata(&path).and_then(|meta| meta.modified()).ok();
+                        (path, modified)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        found.sort();
+
+        self.scripts.retain(|script| found.iter().any(|(path, _)| path == &script.path));
+        let mut loaded = Vec::new();
+        for (path, modified) in found {
+            let idx = match self.scripts.iter().position(|script| script.path == path) {
+                Some(idx) if self.scripts[idx].modified == modified => continue,
+                Some(idx) => idx,
+                None => {
+                    self.scripts.push(Script { path: path.clone(), modified, ast: None, errors: BTreeMap::new() });
+                    self.scripts.len() - 1
+                }
+            };
+
+            let script = &mut self.scripts[idx];
+            script.modified = modified;
+            script.errors.clear();
+            let source = match std::fs::read_to_string(&path) {
+                Ok(source) => source,
+                Err(err) => {
+                    script.ast = None;
+                    script.errors.insert(LOAD.to_string(), err.to_string());
+                    continue;
+                }
+            };
+            match self.engine.compile(&source) {
+                Ok(ast) => {
+                    script.ast = Some(ast);
+                    loaded.push(idx);
+                }
+                Err(err) => {
+                    script.ast = None;
+                    script.errors.insert(LOAD.to_string(), err.to_string());
+                }
+            }
+        }
+        loaded
+    }
+
+    // Work out which handlers the events since last time call for
+    fn pending_calls(&mut self, apps: impl FnOnce() -> Vec<String>) -> Vec<(&'static str, Vec<Dynamic>)> {
+        let mut calls = Vec::new();
+        while let Ok(event) = self.events.try_recv() {
+            let echo = self.is_echo(&event);
+            match event {
+                ControlEvent::VolumeChanged { volume } => {
+                    self.state.borrow_mut().volume = volume;
+                    if !echo {
+                        calls.push(("on_volume_changed", vec![Dynamic::from(volume_value(volume))]));
+                    }
+                }
+                ControlEvent::MuteChanged { muted } => {
+                    self.state.borrow_mut().muted = muted;
+                    if !echo {
+                        calls.push(("on_mute_changed", vec![Dynamic::from(muted)]));
+                    }
+                }
+                ControlEvent::DefaultDeviceChanged { flow, name } => {
+                    match flow {
+                        Flow::Output => self.state.borrow_mut().output_device = name.clone(),
+                        Flow::Input => self.state.borrow_mut().input_device = name.clone(),
+                    }
+                    if !echo {
+                        calls.push(("on_default_changed", vec![flow_name(flow).into(), name.unwrap_or_default().into()]));
+                    }
+                }
+                ControlEvent::DevicesChanged { outputs, inputs } => {
+                    for (flow, previous, current) in
+                        [(Flow::Output, &self.outputs, &outputs), (Flow::Input, &self.inputs, &inputs)]
+                    {
+                        for name in current.iter().filter(|name| !previous.contains(name)) {
+                            calls.push(("on_device_added", vec![name.clone().into(), flow_name(flow).into()]));
+                        }
+                        for name in previous.iter().filter(|name| !current.contains(name)) {
+                            calls.push(("on_device_removed", vec![name.clone().into(), flow_name(flow).into()]));
+                        }
+                    }
+                    let mut state = self.state.borrow_mut();
+                    state.outputs = outputs.clone();
+                    state.inputs = inputs.clone();
+                    self.outputs = outputs;
+                    self.inputs = inputs;
+                }
+            }
+        }
+
+        if self.last_timer.elapsed() >= TIMER_INTERVAL {
+            self.last_timer = Instant::now();
+            calls.push(("on_timer", Vec::new()));
+
+            // Looking at sessions isn't free, so only when a script wants to know
+            if self.wants("on_app_started", 1) || self.wants("on_app_stopped", 1) {
+                let current: HashSet<String> = apps().into_iter().collect();
+                if let Some(previous) = &self.apps {
+                    for app in current.difference(previous) {
+                        calls.push(("on_app_started", vec![app.clone().into()]));
+                    }
+                    for app in previous.difference(&current) {
+                        calls.push(("on_app_stopped", vec![app.clone().into()]));
+                    }
+                }
+                self.apps = Some(current);
+            } else {
+                self.apps = None;
+            }
+        }
+        calls
+    }
+
+    fn call(&mut self, idx: usize, function: &str, args: &[Dynamic]) {
+        let script = &mut self.scripts[idx];
+        let Some(ast) = &script.ast else {
+            return;
+        };
+        if !script.defines(function, args.len()) {
+            return;
+        }
+        // Only the function: the top level already ran when the script was loaded
+        let options = CallFnOptions::new().eval_ast(false);
+        let result =
+            self.engine.call_fn_with_options::<Dynamic>(options, &mut rhai::Scope::new(), ast, function, args.to_vec());
+        if let Err(err) = result {
+            script.errors.insert(function.to_string(), format!("{function}: {err}"));
+        }
+    }
+
+    fn run_top_level(&mut self, idx: usize) {
+        let script = &mut self.scripts[idx];
+        if let Some(ast) = &script.ast {
+            if let Err(err) = self.engine.run_ast(ast) {
+                script.errors.insert(LOAD.to_string(), err.to_string());
+            }
+        }
+    }
+
+    // Remember the event a script's command will cause, so it isn't handed back to the scripts
+    fn expect_echo(&mut self, command: &Command) {
+        let event = match command {
+            Command::SetVolume(volume) => ControlEvent::VolumeChanged { volume: *volume },
+            Command::SetMute(muted) => ControlEvent::MuteChanged { muted: *muted },
+            Command::SetDefaultDevice { flow, name } => ControlEvent::DefaultDeviceChanged { flow: *flow, name: Some(name.clone()) },
+            _ => return,
+        };
+        self.echoes.push((event, Instant::now()));
+    }
+
+    // Whether a script caused this event itself. Each expected echo is used up once seen.
+    fn is_echo(&mut self, event: &ControlEvent) -> bool {
+        self.echoes.retain(|(_, when)| when.elapsed() < ECHO_TIMEOUT);
+        let matches = |echo: &ControlEvent| match (echo, event) {
+            // The device may not store the exact value it was given
+            (ControlEvent::VolumeChanged { volume: expected }, ControlEvent::VolumeChanged { volume }) => {
+                (expected - volume).abs() < 0.005
+            }
+            _ => echo == event,
+        };
+        match self.echoes.iter().position(|(echo, _)| matches(echo)) {
+            Some(idx) => {
+                self.echoes.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn take_actions(&self) -> Vec<ScriptAction> {
+        std::mem::take(&mut *self.actions.borrow_mut())
+    }
+
+    fn errors(&self) -> Vec<(String, String)> {
+        self.scripts
+            .iter()
+            .flat_map(|script| script.errors.values().map(|error| (script.name(), error.clone())))
+            .collect()
+    }
+}
+
+fn open_folder(dir: &Path) {
+    let _ = std::fs::create_dir_all(dir);
+    #[cfg(target_os = "windows")]
+    let program = "explorer";
+    #[cfg(target_os = "macos")]
+    let program = "open";
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let program = "xdg-open";
+    if let Err(err) = std::process::Command::new(program).arg(dir).spawn() {
+        eprintln!("ERROR: Couldn't open {}: {err}", dir.display());
+    }
+}
+
+impl AudioApp {
+    pub fn restart_scripts(&mut self) {
+        self.scripts = None;
+        if !self.settings.scripts.enabled {
+            return;
+        }
+        let Some(dir) = scripts_dir() else {
+            return;
+        };
+        let _ = std::fs::create_dir_all(&dir);
+        self.scripts = Some(ScriptHost::new(dir, self.control.handle().subscribe(), self.control_state()));
+    }
+
+    // Called every frame: reload changed scripts, call handlers for what happened and carry
+    // out what they asked for
+    pub fn run_scripts(&mut self) {
+        let Some(mut host) = self.scripts.take() else {
+            return;
+        };
+        let errors_before = host.errors();
+
+        for idx in host.reload() {
+            host.run_top_level(idx);
+        }
+        let calls = host.pending_calls(|| self.app_volumes().into_iter().map(|app| app.name).collect());
+        for (function, args) in &calls {
+            for idx in 0..host.scripts.len() {
+                host.call(idx, function, args);
+            }
+        }
+
+        for action in host.take_actions() {
+            match action {
+                ScriptAction::Command(command) => {
+                    host.expect_echo(&command);
+                    if let Err(err) = self.execute(&command) {
+                        self.notifications.push(format!("Script: {err}"));
+                    }
+                }
+                ScriptAction::Notify(text) => self.notifications.push(text),
+            }
+        }
+
+        // Tell the user about new errors, once
+        for (name, error) in host.errors() {
+            if !errors_before.contains(&(name.clone(), error.clone())) {
+                eprintln!("ERROR: {name}: {error}");
+                self.notifications.push(format!("{name}: {error}"));
+            }
+        }
+        self.scripts = Some(host);
+    }
+
+    pub fn scripts_ui(&mut self, ui: &mut egui::Ui) {
+        if ui.checkbox(&mut self.settings.scripts.enabled, "Run scripts").changed() {
+            self.settings.save();
+            self.restart_scripts();
+        }
+
+        if let Some(dir) = scripts_dir() {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(dir.display().to_string()).weak());
+                if ui.button("Open folder").clicked() {
+                    open_folder(&dir);
+                }
+            });
+        }
+
+        let Some(host) = &self.scripts else {
+            return;
+        };
+        if host.scripts.is_empty() {
+            ui.label("No scripts yet. Save a .rhai file in the folder above and it loads straight away.");
+        }
+        for script in &host.scripts {
+            ui.horizontal(|ui| {
+                if script.errors.is_empty() {
+                    ui.label(RichText::new("✔").color(egui::Color32::GREEN));
+                    ui.label(script.name());
+                } else {
+                    ui.label(RichText::new("⚠").color(egui::Color32::from_rgb(255, 96, 96)));
+                    ui.label(script.name());
+                    ui.vertical(|ui| {
+                        for err in script.errors.values() {
+                            ui.label(RichText::new(err).color(egui::Color32::from_rgb(255, 96, 96)));
+                        }
+                    });
+                }
+            });
+        }
+
+        let log = host.log.borrow();
+        if !log.is_empty() {
+            ui.add_space(4.0);
+            ui.label(RichText::new("Output").strong());
+            egui::ScrollArea::vertical().max_height(120.0).stick_to_bottom(true).show(ui, |ui| {
+                for line in log.iter() {
+                    ui.label(RichText::new(line).monospace());
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::{self, Sender};
+
+    fn host(name: &str, source: &str) -> (ScriptHost, Sender<ControlEvent>, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("audioapp2-scripts-{name}-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("test.rhai"), source).unwrap();
+        let (sender, events) = mpsc::channel();
+        let mut host = ScriptHost::new(dir.clone(), events, ControlState { volume: 0.5, ..ControlState::default() });
+        for idx in host.reload() {
+            host.run_top_level(idx);
+        }
+        (host, sender, dir)
+    }
+
+    // What AudioApp::run_scripts does, minus the app
+    fn run(host: &mut ScriptHost) -> Vec<Command> {
+        for (function, args) in host.pending_calls(Vec::new) {
+            for idx in 0..host.scripts.len() {
+                host.call(idx, function, &args);
+            }
+        }
+        let mut commands = Vec::new();
+        for action in host.take_actions() {
+            if let ScriptAction::Command(command) = action {
+                host.expect_echo(&command);
+                commands.push(command);
+            }
+        }
+        commands
+    }
+
+    #[test]
+    fn errors_stay_until_the_script_is_reloaded() {
+        let source = "fn on_mute_changed(muted) { throw \"broken\"; }\nfn on_volume_changed(volume) {}";
+        let (mut host, events, dir) = host("errors", source);
+
+        events.send(ControlEvent::MuteChanged { muted: true }).unwrap();
+        run(&mut host);
+        events.send(ControlEvent::VolumeChanged { volume: 0.2 }).unwrap();
+        run(&mut host);
+        assert_eq!(host.errors().len(), 1);
+        assert!(host.errors()[0].1.starts_with("on_mute_changed: "));
+
+        std::fs::write(dir.join("test.rhai"), "fn on_mute_changed(muted) {}\n").unwrap();
+        host.last_reload = None;
+        assert_eq!(host.reload(), [0]);
+        assert!(host.errors().is_empty());
+        let _ = std::fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn own_changes_dont_call_the_handlers_again() {
+        let source = "fn on_volume_changed(volume) { if volume < 0.9 { set_volume(volume + 0.1); } }";
+        let (mut host, events, dir) = host("echo", source);
+
+        events.send(ControlEvent::VolumeChanged { volume: 0.3 }).unwrap();
+        let commands = run(&mut host);
+        assert!(matches!(commands[..], [Command::SetVolume(volume)] if (volume - 0.4).abs() < 1e-6));
+
+        // The change the script made comes back as an event, which stops here
+        events.send(ControlEvent::VolumeChanged { volume: 0.4 }).unwrap();
+        assert!(run(&mut host).is_empty());
+        assert_eq!(host.state.borrow().volume, 0.4);
+
+        // Someone else moving the volume still counts
+        events.send(ControlEvent::VolumeChanged { volume: 0.4 }).unwrap();
+        assert_eq!(run(&mut host).len(), 1);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,375 @@
+// Live view of which applications have audio streams open and whether they're playing.
+// The audio controller only sees sessions that existed when it was created and can't tell a
+// playing app from a paused one, so rules that react to apps starting need a fresh look.
+// On Windows this walks the sessions of every active output; on Linux it reads PulseAudio's
+// (or PipeWire's) sink-inputs through pactl.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::scenes::AppVolume;
+use crate::{AudioApp, Tab};
+
+// How often the watcher looks at the sessions
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// How long the watcher keeps running after a remote client last asked about applications.
+// Faders send a stream of requests, and each would otherwise have to list the sessions.
+const REMOTE_WATCH_TIME: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct AudioSession {
+    // Executable name without ".exe", matching the names the mixer uses
+    pub app: String,
+    pub pid: u32,
+    // Actually producing sound right now, as opposed to open but paused or silent
+    pub active: bool,
+    // PulseAudio sink-input indexes; Windows routes whole processes so these stay empty
+    pub streams: Vec<u32>,
+}
+
+pub fn list_sessions() -> Vec<AudioSession> {
+    #[cfg(target_os = "windows")]
+    {
+        unsafe { windows_impl::list_sessions().unwrap_or_default() }
+    }
+
+    #[cfg(target_os = "linux")]
+    {
+        pulse::list_sink_inputs()
+    }
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
+    {
+        Vec::new()
+    }
+}
+
+// Lists the sessions on a background thread and reports whenever they change, until dropped
+pub struct SessionWatcher {
+    receiver: Receiver<Vec<AudioSession>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl SessionWatcher {
+    pub fn start() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+
+        std::thread::spawn(move || {
+            #[cfg(target_os = "windows")]
+            unsafe {
+                let _ = windows::Win32::System::Com::CoInitializeEx(None, windows::Win32::System::Com::COINIT_MULTITHREADED);
+            }
+
+            let mut previous = None;
+            while !stop.load(Ordering::Relaxed) {
+                let sessions = list_sessions();
+                if previous.as_ref() != Some(&sessions) {
+                    if sender.send(sessions.clone()).is_err() {
+                        break;
+                    }
+                    previous = Some(sessions);
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        });
+
+        Self { receiver, stopped }
+    }
+
+    // The latest list, if it changed since the last call
+    pub fn poll(&self) -> Option<Vec<AudioSession>> {
+        self.receiver.try_iter().last()
+    }
+}
+
+impl Drop for SessionWatcher {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+}
+
+impl AudioApp {
+    // Run the session watcher only while app rules, routes, the mixer or remote clients need
+    // it, and pick up its latest list. Returns true when the list changed.
+    pub fn watch_sessions(&mut self) -> bool {
+        let remote = self.apps_requested.is_some_and(|requested| requested.elapsed() < REMOTE_WATCH_TIME);
+        let wanted = self.settings.app_rules.enabled
+            || !self.settings.routing.routes.is_empty()
+            || self.tab == Tab::Mixer
+            || remote;
+        if !wanted {
+            self.session_watcher = None;
+            return false;
+        }
+
+        let watcher = self.session_watcher.get_or_insert_with(SessionWatcher::start);
+        match watcher.poll() {
+            Some(sessions) => {
+                self.sessions = sessions;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // The per-app volumes for a remote client. Nothing may have been watching the sessions, in
+    // which case they're listed once now; the watcher keeps them current for the requests
+    // that follow.
+    pub fn remote_app_volumes(&mut self) -> Vec<AppVolume> {
+        if self.session_watcher.is_none() {
+            self.sessions = list_sessions();
+        }
+        self.apps_requested = Some(Instant::now());
+        self.current_app_volumes()
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod windows_impl {
+    use super::AudioSession;
+    use crate::endpoints::windows_impl::enumerator;
+    use windows::core::{ComInterface, PWSTR};
+    use windows::Win32::Foundation::CloseHandle;
+    use windows::Win32::Media::Audio::{
+        eRender, AudioSessionStateActive, AudioSessionStateExpired, IAudioSessionControl2, IAudioSessionManager2,
+        DEVICE_STATE_ACTIVE,
+    };
+    use windows::Win32::System::Com::CLSCTX_ALL;
+    use windows::Win32::System::Threading::{
+        OpenProcess, QueryFullProcessImageNameW, PROCESS_NAME_WIN32, PROCESS_QUERY_LIMITED_INFORMATION,
+    };
+
+    // "C:\Program Files\Zoom\bin\Zoom.exe" -> "Zoom"
+    pub unsafe fn process_name(pid: u32) -> Option<String> {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, false, pid).ok()?;
+        let mut buffer = [0u16; 260];
+        let mut len = buffer.len() as u32;
+        let result = QueryFullProcessImageNameW(process, PROCESS_NAME_WIN32, PWSTR(buffer.as_mut_ptr()), &mut len);
+        let _ = CloseHandle(process);
+        result.ok()?;
+
+        let path = String::from_utf16_lossy(&buffer[..len as usize]);
+        let file = path.rsplit('\\').next()?;
+        Some(file.strip_suffix(".exe").unwrap_or(file).to_string())
+    }
+
+    pub unsafe fn list_sessions() -> windows::core::Result<Vec<AudioSession>> {
+        let devices = enumerator()?.EnumAudioEndpoints(eRender, DEVICE_STATE_ACTIVE)?;
+
+        let mut sessions: Vec<AudioSession> = Vec::new();
+        for i in 0..devices.GetCount()? {
+            let Ok(manager) = devices.Item(i).and_then(|device| device.Activate::<IAudioSessionManager2>(CLSCTX_ALL, None))
+            else {
+                continue;
+            };
+            let Ok(list) = manager.GetSessionEnumerator() else {
+                continue;
+            };
+            for j in 0..list.GetCount()? {
+                let Ok(control) = list.GetSession(j) else {
+                    continue;
+                };
+                let state = control.GetState()?;
+                if state == AudioSessionStateExpired {
+                    continue;
+                }
+                // The system sounds session belongs to no process
+                let Ok(pid) = control.cast::<IAudioSessionControl2>().and_then(|control| control.GetProcessId()) else {
+                    continue;
+                };
+                if pid == 0 {
+                    continue;
+                }
+                let Some(app) = process_name(pid) else {
+                    continue;
+                };
+                let active = state == AudioSessionStateActive;
+                // One app can have a session on several devices
+                match sessions.iter_mut().find(|session| session.pid == pid) {
+                    Some(session) => session.active |= active,
+                    None => sessions.push(AudioSession { app, pid, active, streams: Vec::new() }),
+                }
+            }
+        }
+        Ok(sessions)
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod pulse {
+    use super::AudioSession;
+    use std::process::Command;
+
+    // Pull the value out of a property line like `application.process.binary = "firefox"`
+    fn property<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+        let value = line.strip_prefix(key)?.trim_start().strip_prefix('=')?.trim();
+        Some(value.trim_matches('"'))
+    }
+
+    // One sink-input as we read it
+    #[derive(Default)]
+    struct Stream {
+        index: u32,
+        corked: bool,
+        binary: Option<String>,
+        name: Option<String>,
+        pid: u32,
+    }
+
+    // Parse the output of `pactl list sink-inputs`. The labels aren't translated by pactl
+    // when LC_ALL=C, which is how we run it.
+    pub fn parse_sink_inputs(text: &str) -> Vec<AudioSession> {
+        let mut streams: Vec<Stream> = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if let Some(index) = line.strip_prefix("Sink Input #") {
+                streams.push(Stream { index: index.trim().parse().unwrap_or(0), ..Stream::default() });
+                continue;
+            }
+            let Some(stream) = streams.last_mut() else {
+                continue;
+            };
+            if let Some(value) = line.strip_prefix("Corked:") {
+                stream.corked = value.trim() == "yes";
+            } else if let Some(value) = property(line, "application.process.binary") {
+                stream.binary = Some(value.to_string());
+            } else if let Some(value) = property(line, "application.name") {
+                stream.name = Some(value.to_string());
+            } else if let Some(value) = property(line, "application.process.id") {
+                stream.pid = value.parse().unwrap_or(0);
+            }
+        }
+
+        let mut sessions: Vec<AudioSession> = Vec::new();
+        for Stream { index, corked, binary, name, pid } in streams {
+            let Some(app) = binary.or(name) else {
+                continue;
+            };
+            match sessions.iter_mut().find(|session| session.app == app && session.pid == pid) {
+                Some(session) => {
+                    session.active |= !corked;
+                    session.streams.push(index);
+                }
+                None => sessions.push(AudioSession { app, pid, active: !corked, streams: vec![index] }),
+            }
+        }
+        sessions
+    }
+
+    fn pactl(args: &[&str]) -> Option<String> {
+        match Command::new("pactl").args(args).env("LC_ALL", "C").output() {
+            Ok(output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+            // No pactl or no sound server; there's nothing to see either way
+            _ => None,
+        }
+    }
+
+    pub fn list_sink_inputs() -> Vec<AudioSession> {
+        pactl(&["list", "sink-inputs"]).map(|text| parse_sink_inputs(&text)).unwrap_or_default()
+    }
+
+    // Send the streams to the sink with the given name, or back to the default sink
+    pub fn move_streams(streams: &[u32], sink: Option<&str>) -> Result<(), String> {
+        let sink = sink.unwrap_or("@DEFAULT_SINK@");
+        for stream in streams {
+            pactl(&["move-sink-input", &stream.to_string(), sink])
+                .ok_or_else(|| format!("Couldn't move stream {stream} to {sink}"))?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // `pactl list sink-inputs` from PipeWire 1.0, trimmed: Firefox with one tab playing and
+        // one paused, a paused Spotify, and a notification sound from something without a binary
+        const SINK_INPUTS: &str = "Sink Input #41
+	Driver: PipeWire
+	Sink: 57
+	Corked: no
+	Mute: no
+	Properties:
+		application.name = \"Firefox\"
+		application.process.id = \"2211\"
+		application.process.binary = \"firefox\"
+		media.name = \"AudioStream\"
+
+Sink Input #44
+	Driver: PipeWire
+	Sink: 57
+	Corked: yes
+	Mute: no
+	Properties:
+		application.name = \"Firefox\"
+		application.process.id = \"2211\"
+		application.process.binary = \"firefox\"
+
+Sink Input #52
+	Driver: PipeWire
+	Sink: 57
+	Corked: yes
+	Mute: no
+	Properties:
+		application.name = \"Spotify\"
+		application.process.id = \"3050\"
+		application.process.binary = \"spotify\"
+
+Sink Input #60
+	Driver: PipeWire
+	Sink: 57
+	Corked: no
+	Mute: no
+	Properties:
+		application.name = \"libcanberra\"
+		media.role = \"event\"
+
+Sink Input #61
+	Driver: PipeWire
+	Sink: 57
+	Corked: no
+	Mute: no
+	Properties:
+		media.name = \"loopback\"
+";
+
+        fn session(app: &str, pid: u32, active: bool, streams: &[u32]) -> AudioSession {
+            AudioSession { app: app.to_string(), pid, active, streams: streams.to_vec() }
+        }
+
+        #[test]
+        fn parses_sink_inputs() {
+            assert_eq!(
+                parse_sink_inputs(SINK_INPUTS),
+                [
+                    session("firefox", 2211, true, &[41, 44]),
+                    session("spotify", 3050, false, &[52]),
+                    session("libcanberra", 0, true, &[60]),
+                ]
+            );
+        }
+
+        #[test]
+        fn keeps_separate_processes_apart() {
+            // Two instances of the same player, only one of them playing
+            let text = "Sink Input #7
+	Corked: yes
+	Properties:
+		application.process.id = \"800\"
+		application.process.binary = \"vlc\"
+
+Sink Input #9
+	Corked: no
+	Properties:
+		application.process.id = \"912\"
+		application.process.binary = \"vlc\"
+";
+            assert_eq!(parse_sink_inputs(text), [session("vlc", 800, false, &[7]), session("vlc", 912, true, &[9])]);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::accessibility::AccessibilityPrefs;
+use crate::app_rules::AppRulePrefs;
+use crate::auto_mute::AutoMutePrefs;
+use crate::channels::ChannelPrefs;
+use crate::devices::DevicePrefs;
+use crate::equalizer::EqPrefs;
+use crate::http_api::HttpPrefs;
+use crate::measurement::MeasurementPrefs;
+use crate::midi::MidiPrefs;
+use crate::monitor::MonitorPrefs;
+use crate::mqtt::MqttPrefs;
+use crate::multi_output::MultiOutputPrefs;
+use crate::now_playing::MediaPrefs;
+use crate::osc::OscPrefs;
+use crate::priority::PriorityPrefs;
+use crate::routing::RoutingPrefs;
+use crate::scenes::Scene;
+use crate::scripting::ScriptPrefs;
+
+// Name used for the window title and for the folder our settings live in
+pub const APP_NAME: &str = "Audio Controller";
+
+// Everything the user configures that should survive a restart.
+// Stored as pretty-printed JSON next to eframe's own state so it can be edited by hand.
+#[derive(Serialize, Deserialize, Default, Clone)]
+#[serde(default)]
+pub struct Settings {
+    pub scenes: Vec<Scene>,
+    pub devices: DevicePrefs,
+    pub channels: ChannelPrefs,
+    pub priority: PriorityPrefs,
+    pub auto_mute: AutoMutePrefs,
+    pub app_rules: AppRulePrefs,
+    pub routing: RoutingPrefs,
+    pub multi_output: MultiOutputPrefs,
+    pub monitor: MonitorPrefs,
+    pub eq: EqPrefs,
+    pub measurement: MeasurementPrefs,
+    pub http: HttpPrefs,
+    pub mqtt: MqttPrefs,
+    pub osc: OscPrefs,
+    pub midi: MidiPrefs,
+    pub media: MediaPrefs,
+    pub accessibility: AccessibilityPrefs,
+    pub scripts: ScriptPrefs,
+}
+
+impl Settings {
+    // Location of the settings file, e.g. %APPDATA%\Audio Controller\settings.json
+    pub fn path() -> Option<PathBuf> {
+        eframe::storage_dir(APP_NAME).map(|dir| dir.join("settings.json"))
+    }
+
+    // Load the settings, falling back to defaults if the file is missing or broken
+    pub fn load() -> Self {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Self::default(),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Self {
+        match std::fs::read_to_string(path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|err| {
+                eprintln!("ERROR: Couldn't parse {}: {err}", path.display());
+                Self::default()
+            }),
+            Err(_) => Self::default(),
+        }
+    }
+
+    pub fn save(&self) {
+        let Some(path) = Self::path() else {
+            return;
+        };
+
+        if let Some(dir) = path.parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        match serde_json::to_string_pretty(self) {
+            Ok(contents) => {
+                if let Err(err) = std::fs::write(&path, contents) {
+                    eprintln!("ERROR: Couldn't write {}: {err}", path.display());
+                }
+            }
+            Err(err) => eprintln!("ERROR: Couldn't serialize settings: {err}"),
+        }
+    }
+}
@@ -0,0 +1,171 @@
+// A pretend audio system that lives entirely in memory. `--simulate` serves the control APIs
+// from it without a window, so clients can be developed and tested end to end without
+// touching the real devices.
+
+use serde_json::{json, Value};
+use std::time::Duration;
+
+use crate::cli::SimulateOptions;
+use crate::control::{Command, ControlError, ControlHub, ControlState};
+use crate::endpoints::Flow;
+use crate::http_api::{generate_token, HttpServer};
+use crate::midi::MidiController;
+use crate::mqtt::MqttBridge;
+use crate::osc::OscServer;
+use crate::scenes::AppVolume;
+use crate::settings::Settings;
+
+pub struct SimulatedAudio {
+    state: ControlState,
+    apps: Vec<AppVolume>,
+}
+
+impl SimulatedAudio {
+    pub fn new() -> Self {
+        let outputs = vec!["Speakers (Simulated)".to_string(), "Headphones (Simulated)".to_string()];
+        let inputs = vec!["Microphone (Simulated)".to_string()];
+        Self {
+            state: ControlState {
+                output_device: outputs.first().cloned(),
+                input_device: inputs.first().cloned(),
+                volume: 0.5,
+                muted: false,
+                outputs,
+                inputs,
+            },
+            apps: vec![AppVolume { name: "music.exe".to_string(), volume: 1.0, muted: false }],
+        }
+    }
+
+    pub fn state(&self) -> ControlState {
+        self.state.clone()
+    }
+
+    fn app_mut(&mut self, name: &str) -> Result<&mut AppVolume, ControlError> {
+        self.apps
+            .iter_mut()
+            .find(|app| app.name == name)
+            .ok_or_else(|| ControlError::Failed(format!("No application named '{name}'")))
+    }
+
+    fn device_list(&self, flow: Flow) -> Value {
+        let (names, default) = match flow {
+            Flow::Output => (&self.state.outputs, &self.state.output_device),
+            Flow::Input => (&self.state.inputs, &self.state.input_device),
+        };
+        names
+            .iter()
+            .map(|name| json!({ "name": name, "display_name": name, "default": Some(name) == default.as_ref(), "hidden": false }))
+            .collect()
+    }
+
+    // Same results as AudioApp::execute, minus the side effects
+    pub fn execute(&mut self, command: &Command) -> Result<Value, ControlError> {
+        match command {
+            Command::GetState => Ok(json!(self.state)),
+            Command::ListDevices => Ok(json!({
+                "outputs": self.device_list(Flow::Output),
+                "inputs": self.device_list(Flow::Input),
+            })),
+            Command::SetDefaultDevice { flow, name } => {
+                let (names, default) = match flow {
+                    Flow::Output => (&self.state.outputs, &mut self.state.output_device),
+                    Flow::Input => (&self.state.inputs, &mut self.state.input_device),
+                };
+                if !names.contains(name) {
+                    return Err(ControlError::Failed(format!("No device named '{name}'")));
+                }
+                *default = Some(name.clone());
+                Ok(json!({ "name": name }))
+            }
+            Command::SetVolume(volume) => {
+                self.state.volume = *volume;
+                Ok(json!({ "volume": volume }))
+            }
+            Command::SetMute(muted) => {
+                self.state.muted = *muted;
+                Ok(json!({ "muted": muted }))
+            }
+            Command::ToggleMute => {
+                self.state.muted = !self.state.muted;
+                Ok(json!({ "muted": self.state.muted }))
+            }
+            Command::ListApps => Ok(json!(self.apps)),
+            Command::SetAppVolume { app, volume } => {
+                self.app_mut(app)?.volume = *volume;
+                Ok(json!({ "app": app, "volume": volume }))
+            }
+            Command::SetAppMute { app, muted } => {
+                self.app_mut(app)?.muted = *muted;
+                Ok(json!({ "app": app, "muted": muted }))
+            }
+            Command::ListScenes => Ok(json!([])),
+            Command::ApplyScene(name) => Err(ControlError::Failed(format!("No scene named '{name}'"))),
+        }
+    }
+}
+
+// Serve the HTTP API, and MQTT, OSC and MIDI if they're enabled, from a simulated audio system until the
+// process is killed
+pub fn run(options: SimulateOptions) {
+    let settings = match &options.settings {
+        Some(path) => Settings::load_from(path),
+        None => Settings::load(),
+    };
+    let mut prefs = settings.http;
+    if let Some(port) = options.port {
+        prefs.port = port;
+    }
+    if let Some(token) = options.token {
+        prefs.token = token;
+    }
+    if prefs.token.is_empty() {
+        prefs.token = generate_token();
+    }
+
+    let mut audio = SimulatedAudio::new();
+    let mut hub = ControlHub::new();
+    let server = match HttpServer::start(&prefs, hub.handle()) {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("ERROR: {err}");
+            std::process::exit(1);
+        }
+    };
+    // With port 0 the system picked one, and clients need to know which
+    println!("Serving a simulated audio system on http://127.0.0.1:{}", server.port());
+    println!("Token: {}", prefs.token);
+    server.pairing().start();
+    if let Some((code, remaining)) = server.pairing().current() {
+        println!("Pairing code for the web remote: {code} (valid for {}s)", remaining.as_secs());
+    }
+
+    let _mqtt = settings.mqtt.enabled.then(|| {
+        println!("Connecting to the MQTT broker at {}:{}", settings.mqtt.host, settings.mqtt.port);
+        MqttBridge::start(&settings.mqtt, hub.handle())
+    });
+    let _midi = settings.midi.enabled.then(|| {
+        let midi = MidiController::start(&settings.midi, hub.handle()).unwrap_or_else(|err| {
+            eprintln!("ERROR: {err}");
+            std::process::exit(1);
+        });
+        println!("{}", midi.description());
+        midi
+    });
+    let _osc = settings.osc.enabled.then(|| {
+        println!("Listening for OSC on UDP port {}", settings.osc.listen_port);
+        OscServer::start(&settings.osc, hub.handle()).unwrap_or_else(|err| {
+            eprintln!("ERROR: {err}");
+            std::process::exit(1);
+        })
+    });
+
+    loop {
+        while let Some(request) = hub.poll() {
+            let result = audio.execute(&request.command);
+            request.respond(result);
+        }
+        hub.publish(audio.state());
+        std::thread::sleep(Duration::from_millis(10));
+    }
+}
@@ -0,0 +1,104 @@
+// Runs `audioapp2 --simulate` for tests that talk to it the way a client would
+
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+pub const TOKEN: &str = "0123456789abcdef0123456789abcdef";
+
+// The simulated server, killed when the test ends however it ends
+pub struct Simulator {
+    child: Child,
+    pub port: u16,
+    // The code the web remote can trade for the token
+    #[allow(dead_code)]
+    pub pairing_code: String,
+    settings: PathBuf,
+}
+
+impl Simulator {
+    // With default settings, i.e. only the HTTP API
+    #[allow(dead_code)]
+    pub fn start(name: &str) -> Self {
+        Self::start_with(name, json!({}), &[])
+    }
+
+    // `settings` replaces the user's own, e.g. to turn on the MQTT bridge
+    pub fn start_with(name: &str, settings: Value, env: &[(&str, &str)]) -> Self {
+        let path = std::env::temp_dir().join(format!("audioapp2-{name}-{}.json", std::process::id()));
+        std::fs::write(&path, settings.to_string()).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_audioapp2"))
+            .args(["--simulate", "--port", "0", "--token", TOKEN, "--settings"])
+            .arg(&path)
+            .envs(env.iter().copied())
+            .stdout(Stdio::piped())
+            .spawn()
+            .expect("the binary should start");
+
+        let mut stdout = BufReader::new(child.stdout.take().unwrap()).lines();
+        let port = stdout
+            .by_ref()
+            .map_while(Result::ok)
+            .find_map(|line| line.strip_prefix("Serving a simulated audio system on http://127.0.0.1:")?.trim().parse().ok());
+        let pairing_code = stdout.by_ref().map_while(Result::ok).find_map(|line| {
+            let code = line.strip_prefix("Pairing code for the web remote: ")?;
+            Some(code.split(' ').next()?.to_string())
+        });
+        // Keep reading, so printing the rest doesn't fail on a closed pipe
+        std::thread::spawn(move || stdout.for_each(drop));
+
+        let mut simulator = Self { child, port: 0, pairing_code: String::new(), settings: path };
+        simulator.port = port.expect("the simulator should print its address");
+        simulator.pairing_code = pairing_code.expect("the simulator should print a pairing code");
+        simulator
+    }
+
+    // Stop without any chance to clean up, like a crash
+    pub fn kill(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    // One request on a fresh connection. Returns the status code and the JSON body.
+    #[allow(dead_code)]
+    pub fn request(&self, method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> (u16, Value) {
+        self.request_to(IpAddr::from([127, 0, 0, 1]), method, path, token, body).unwrap()
+    }
+
+    // The same on `address`, failing if it can't connect
+    #[allow(dead_code)]
+    pub fn request_to(&self, address: IpAddr, method: &str, path: &str, token: Option<&str>, body: Option<Value>) -> std::io::Result<(u16, Value)> {
+        let mut stream = TcpStream::connect_timeout(&(address, self.port).into(), Duration::from_secs(5))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+
+        let body = body.map(|body| body.to_string()).unwrap_or_default();
+        let mut request = format!("{method} {path} HTTP/1.1\r\nHost: 127.0.0.1\r\nConnection: close\r\n");
+        if let Some(token) = token {
+            request.push_str(&format!("Authorization: Bearer {token}\r\n"));
+        }
+        if !body.is_empty() {
+            request.push_str(&format!("Content-Type: application/json\r\nContent-Length: {}\r\n", body.len()));
+        }
+        request.push_str("\r\n");
+        request.push_str(&body);
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let (head, body) = response.split_once("\r\n\r\n").expect("a complete response");
+        let status = head.split(' ').nth(1).and_then(|code| code.parse().ok()).expect("a status line");
+        let body = if body.is_empty() { Value::Null } else { serde_json::from_str(body).expect("a JSON body") };
+        Ok((status, body))
+    }
+}
+
+impl Drop for Simulator {
+    fn drop(&mut self) {
+        self.kill();
+        let _ = std::fs::remove_file(&self.settings);
+    }
+}
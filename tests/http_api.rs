@@ -0,0 +1,150 @@
+// Talks to the HTTP API of `audioapp2 --simulate` over a real socket, the way a client would
+
+mod common;
+
+use common::{Simulator, TOKEN};
+use serde_json::{json, Value};
+use std::net::{IpAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+use tungstenite::client::IntoClientRequest;
+use tungstenite::stream::MaybeTlsStream;
+use tungstenite::Message;
+
+type Socket = tungstenite::WebSocket<MaybeTlsStream<TcpStream>>;
+
+fn connect_events(url: &str, configure: impl FnOnce(&mut tungstenite::handshake::client::Request)) -> Result<Socket, Box<tungstenite::Error>> {
+    let mut request = url.into_client_request()?;
+    configure(&mut request);
+    let (socket, _) = tungstenite::connect(request)?;
+    if let MaybeTlsStream::Plain(stream) = socket.get_ref() {
+        stream.set_read_timeout(Some(Duration::from_secs(10))).unwrap();
+    }
+    Ok(socket)
+}
+
+// The next event, skipping pings
+fn next_event(socket: &mut Socket) -> Value {
+    loop {
+        match socket.read().expect("an event before the timeout") {
+            Message::Text(text) => return serde_json::from_str(&text).unwrap(),
+            Message::Ping(_) | Message::Pong(_) => continue,
+            other => panic!("unexpected message {other:?}"),
+        }
+    }
+}
+
+#[test]
+fn lists_devices() {
+    let simulator = Simulator::start("devices");
+    let (status, body) = simulator.request("GET", "/devices", Some(TOKEN), None);
+    assert_eq!(status, 200);
+
+    let outputs: Vec<&str> = body["outputs"].as_array().unwrap().iter().map(|device| device["name"].as_str().unwrap()).collect();
+    assert_eq!(outputs, ["Speakers (Simulated)", "Headphones (Simulated)"]);
+    assert_eq!(body["outputs"][0]["default"], json!(true));
+    assert_eq!(body["inputs"][0]["name"], json!("Microphone (Simulated)"));
+}
+
+#[test]
+fn sets_volume_and_mute() {
+    let simulator = Simulator::start("volume");
+
+    let (status, body) = simulator.request("PUT", "/volume", Some(TOKEN), Some(json!({ "volume": 0.25 })));
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "volume": 0.25 }));
+
+    let (status, body) = simulator.request("POST", "/mute", Some(TOKEN), Some(json!({ "muted": true })));
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "muted": true }));
+
+    let (_, state) = simulator.request("GET", "/state", Some(TOKEN), None);
+    assert_eq!(state["volume"], json!(0.25));
+    assert_eq!(state["muted"], json!(true));
+
+    let (status, _) = simulator.request("PUT", "/volume", Some(TOKEN), Some(json!({ "volume": "loud" })));
+    assert_eq!(status, 400);
+}
+
+#[test]
+fn rejects_missing_and_wrong_tokens() {
+    let simulator = Simulator::start("tokens");
+    assert_eq!(simulator.request("GET", "/devices", None, None).0, 401);
+    assert_eq!(simulator.request("GET", "/devices", Some("0000000000000000000000000000000"), None).0, 401);
+    assert_eq!(simulator.request("PUT", "/volume", Some("wrong"), Some(json!({ "volume": 0.0 }))).0, 401);
+
+    // Tokens in the URL aren't accepted, since they end up in logs and browser history
+    let url = format!("ws://127.0.0.1:{}/events?token={TOKEN}", simulator.port);
+    let error = connect_events(&url, |_| {}).expect_err("a token in the URL shouldn't connect");
+    assert!(matches!(*error, tungstenite::Error::Http(response) if response.status() == 401));
+    let (_, state) = simulator.request("GET", "/state", Some(TOKEN), None);
+    assert_eq!(state["volume"], json!(0.5));
+}
+
+#[test]
+fn streams_events_to_header_and_subprotocol_clients() {
+    let simulator = Simulator::start("events");
+    let url = format!("ws://127.0.0.1:{}/events", simulator.port);
+
+    let mut with_header = connect_events(&url, |request| {
+        request.headers_mut().insert("Authorization", format!("Bearer {TOKEN}").parse().unwrap());
+    })
+    .unwrap();
+    let mut with_protocol = connect_events(&url, |request| {
+        let protocols = format!("audio-controller, bearer.{TOKEN}");
+        request.headers_mut().insert("Sec-WebSocket-Protocol", protocols.parse().unwrap());
+    })
+    .unwrap();
+
+    simulator.request("PUT", "/volume", Some(TOKEN), Some(json!({ "volume": 0.75 })));
+    let expected = json!({ "event": "volume_changed", "volume": 0.75 });
+    assert_eq!(next_event(&mut with_header), expected);
+    assert_eq!(next_event(&mut with_protocol), expected);
+}
+
+#[test]
+fn pairing_code_gives_the_token_once() {
+    let simulator = Simulator::start("pair");
+    let code = json!({ "code": simulator.pairing_code });
+
+    let (status, body) = simulator.request("POST", "/pair", None, Some(code.clone()));
+    assert_eq!(status, 200);
+    assert_eq!(body, json!({ "token": TOKEN }));
+
+    let (status, body) = simulator.request("POST", "/pair", None, Some(code));
+    assert_eq!(status, 403);
+    assert!(body.get("token").is_none());
+}
+
+#[test]
+fn wrong_pairing_codes_void_the_code() {
+    let simulator = Simulator::start("pair-guesses");
+    let wrong = if simulator.pairing_code == "000000" { "000001" } else { "000000" };
+    for _ in 0..5 {
+        assert_eq!(simulator.request("POST", "/pair", None, Some(json!({ "code": wrong }))).0, 403);
+    }
+    assert_eq!(simulator.request("POST", "/pair", None, Some(json!({ "code": simulator.pairing_code }))).0, 403);
+    // A missing code counts as a wrong one and gives nothing away
+    let (status, body) = simulator.request("POST", "/pair", None, None);
+    assert_eq!((status, body), (403, json!({ "error": "Wrong or expired pairing code" })));
+}
+
+// This computer's address on the network, which connections from other devices arrive at
+fn lan_address() -> IpAddr {
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    socket.connect("192.0.2.1:80").expect("a network interface besides loopback");
+    let address = socket.local_addr().unwrap().ip();
+    assert!(!address.is_loopback());
+    address
+}
+
+#[test]
+fn only_listens_on_the_network_when_allowed() {
+    let address = lan_address();
+
+    let local = Simulator::start("lan-off");
+    assert!(local.request_to(address, "GET", "/devices", Some(TOKEN), None).is_err(), "{address} shouldn't connect");
+    assert_eq!(local.request("GET", "/devices", Some(TOKEN), None).0, 200);
+
+    let shared = Simulator::start_with("lan-on", json!({ "http": { "lan": true } }), &[]);
+    assert_eq!(shared.request_to(address, "GET", "/devices", Some(TOKEN), None).unwrap().0, 200);
+}
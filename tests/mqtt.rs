@@ -0,0 +1,152 @@
+// Runs the MQTT bridge of `audioapp2 --simulate` against a real broker. Needs one running,
+// e.g. `mosquitto -p 1883`, so it only runs when asked:
+//
+//   cargo test --test mqtt -- --ignored
+//
+// MQTT_BROKER=host:port points it somewhere other than localhost:1883.
+
+mod common;
+
+use common::Simulator;
+use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+fn broker() -> (String, u16) {
+    let address = std::env::var("MQTT_BROKER").unwrap_or_else(|_| "localhost:1883".to_string());
+    let (host, port) = address.rsplit_once(':').expect("MQTT_BROKER should be host:port");
+    (host.to_string(), port.parse().expect("MQTT_BROKER should end in a port number"))
+}
+
+// Someone watching the broker
+struct Watcher {
+    client: Client,
+    // (topic, payload, retained)
+    messages: Receiver<(String, String, bool)>,
+    // Everything received so far, since messages on different topics may come in any order
+    seen: RefCell<Vec<(String, String, bool)>>,
+}
+
+impl Watcher {
+    fn subscribe(id: &str, filters: &[String]) -> Self {
+        let (host, port) = broker();
+        let (client, mut connection) = Client::new(MqttOptions::new(id, host, port), 64);
+        for filter in filters {
+            client.subscribe(filter, QoS::AtLeastOnce).unwrap();
+        }
+
+        let (sender, messages) = mpsc::channel();
+        std::thread::spawn(move || {
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let payload = String::from_utf8_lossy(&publish.payload).into_owned();
+                        if sender.send((publish.topic, payload, publish.retain)).is_err() {
+                            return;
+                        }
+                    }
+                    Ok(_) => {}
+                    // Gone, or dropped at the end of the test
+                    Err(_) => return,
+                }
+            }
+        });
+        Self { client, messages, seen: RefCell::new(Vec::new()) }
+    }
+
+    // The first message on `topic` that `accept` likes, waiting for it if it hasn't come yet.
+    // Returns its payload and whether it was retained.
+    fn find(&self, topic: &str, accept: impl Fn(&str) -> bool) -> Option<(String, bool)> {
+        let matches = |(got, payload, _): &(String, String, bool)| got == topic && accept(payload);
+        if let Some((_, payload, retained)) = self.seen.borrow().iter().find(|message| matches(message)) {
+            return Some((payload.clone(), *retained));
+        }
+
+        let deadline = Instant::now() + TIMEOUT;
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+            let message = self.messages.recv_timeout(remaining).ok()?;
+            let found = matches(&message).then(|| (message.1.clone(), message.2));
+            self.seen.borrow_mut().push(message);
+            if found.is_some() {
+                return found;
+            }
+        }
+        None
+    }
+
+    // Wait for `payload` on `topic`. Returns whether it was retained.
+    fn expect(&self, topic: &str, payload: &str) -> bool {
+        let (_, retained) = self.find(topic, |got| got == payload).unwrap_or_else(|| panic!("no '{payload}' on {topic}"));
+        retained
+    }
+
+    fn expect_json(&self, topic: &str) -> Value {
+        let (payload, _) = self.find(topic, |_| true).unwrap_or_else(|| panic!("nothing on {topic}"));
+        serde_json::from_str(&payload).unwrap()
+    }
+
+    fn publish(&self, topic: &str, payload: &str, retain: bool) {
+        self.client.publish(topic, QoS::AtLeastOnce, retain, payload).unwrap();
+    }
+}
+
+#[test]
+#[ignore = "needs an MQTT broker, e.g. mosquitto on localhost:1883"]
+fn bridges_state_commands_discovery_and_last_will() {
+    // Unique names, so retained messages from other runs can't get in the way
+    let node = format!("test-{}", std::process::id());
+    let base = format!("audio_controller/{node}");
+    let discovery = format!("{node}-discovery");
+    let topic = |name: &str| format!("{base}/{name}");
+
+    let watcher = Watcher::subscribe(&format!("{node}-watcher"), &[format!("{base}/#"), format!("{discovery}/#")]);
+    // Give the subscription time to reach the broker before anything is published
+    std::thread::sleep(Duration::from_millis(500));
+
+    let (host, port) = broker();
+    let settings = json!({
+        "mqtt": { "enabled": true, "host": host, "port": port, "discovery": true, "discovery_prefix": discovery },
+    });
+    let mut simulator = Simulator::start_with("mqtt", settings, &[("COMPUTERNAME", &node)]);
+
+    watcher.expect(&topic("availability"), "online");
+    watcher.expect(&topic("volume"), "50");
+    watcher.expect(&topic("mute"), "OFF");
+    watcher.expect(&topic("output_device"), "Speakers (Simulated)");
+
+    let config = watcher.expect_json(&format!("{discovery}/number/{node}/volume/config"));
+    assert_eq!(config["state_topic"], json!(topic("volume")));
+    assert_eq!(config["command_topic"], json!(topic("volume/set")));
+    assert_eq!(config["availability_topic"], json!(topic("availability")));
+    let config = watcher.expect_json(&format!("{discovery}/select/{node}/output_device/config"));
+    assert_eq!(config["options"], json!(["Headphones (Simulated)", "Speakers (Simulated)"]));
+
+    // State is retained, so someone who subscribes later still gets it
+    let latecomer = Watcher::subscribe(&format!("{node}-latecomer"), &[topic("volume")]);
+    assert!(latecomer.expect(&topic("volume"), "50"), "the volume should be retained");
+
+    watcher.publish(&topic("volume/set"), "25", false);
+    watcher.expect(&topic("volume"), "25");
+    watcher.publish(&topic("mute/set"), "TOGGLE", false);
+    watcher.expect(&topic("mute"), "ON");
+    watcher.publish(&topic("output_device/set"), "Headphones (Simulated)", false);
+    watcher.expect(&topic("output_device"), "Headphones (Simulated)");
+
+    // Without a goodbye, the broker announces the last will
+    simulator.kill();
+    watcher.expect(&topic("availability"), "offline");
+
+    // Don't leave retained messages behind
+    for name in ["availability", "volume", "mute", "output_device"] {
+        watcher.publish(&topic(name), "", true);
+    }
+    for entity in ["number/volume", "switch/mute", "select/output_device"] {
+        let (component, key) = entity.split_once('/').unwrap();
+        watcher.publish(&format!("{discovery}/{component}/{node}/{key}/config"), "", true);
+    }
+    std::thread::sleep(Duration::from_millis(500));
+}